@@ -1,4 +1,3 @@
-use std::env;
 use std::net::{Ipv4Addr, SocketAddr};
 
 use axum::{Router};
@@ -14,6 +13,11 @@ use utoipa_swagger_ui::SwaggerUi;
 use crate::api::docs::ApiDoc;
 use crate::api::extensions::try_obtain_paseto_secret;
 use crate::data::AstralDatabase;
+use crate::jobs::fingerprinter::spawn_fingerprint_backfill;
+use crate::jobs::hasher::spawn_hash_backfill;
+use crate::jobs::search_refresher::{spawn_search_committer, spawn_search_refresher};
+use crate::jobs::sweeper::spawn_pending_sweeper;
+use crate::search::engine::SearchIndex;
 
 use paths::*;
 
@@ -22,6 +26,8 @@ pub mod model;
 mod docs;
 pub mod paths;
 pub mod extensions;
+#[cfg(test)]
+mod tests;
 
 /// Shared app state
 #[derive(Clone)]
@@ -30,24 +36,41 @@ pub struct AppState {
     pub paseto_key: SymmetricKey<V4>,
     /// Database access
     pub db: AstralDatabase,
+    /// Embedded full text search index
+    pub search_index: SearchIndex,
 }
 
 /// Starts the axum server
 pub async fn start_axum() -> anyhow::Result<()> {
     let paseto_key = try_obtain_paseto_secret()?;
-    let db = AstralDatabase::connect(env::var("MONGODB_URI")?).await?;
+    let db = AstralDatabase::connect().await?;
+
+    let search_index = SearchIndex::open()?;
+
+    spawn_pending_sweeper(db.clone());
+    spawn_search_refresher(db.clone(), search_index.clone());
+    spawn_search_committer(search_index.clone());
+    spawn_fingerprint_backfill(db.clone());
+    spawn_hash_backfill(db.clone());
 
     let state = AppState {
         paseto_key,
         db,
+        search_index,
     };
 
+    let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080));
+    axum::serve(TcpListener::bind(&address).await.unwrap(), router(state).into_make_service()).await.map_err(anyhow::Error::from)
+}
+
+/// Creates the router with all endpoints
+pub fn router(state: AppState) -> Router {
     let cors = CorsLayer::new()
         .allow_methods(Any)
         .allow_origin(Any)
         .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
 
-    let router = Router::new()
+    Router::new()
         .merge(SwaggerUi::new("/docs").url("/docs/openapi.json", ApiDoc::openapi())) // swagger
 
         // metadata
@@ -56,6 +79,8 @@ pub async fn start_axum() -> anyhow::Result<()> {
         .route("/metadata/album/:uuid", get(metadata::get_album_metadata))
         .route("/metadata/album/:uuid/cover", get(metadata::get_album_cover_art))
         .route("/metadata/track/:uuid/cover", get(metadata::get_track_cover_art))
+        .route("/metadata/artist/:uuid/photo", get(metadata::get_artist_photo))
+        .route("/metadata/artist/:uuid/banner", get(metadata::get_artist_banner))
 
         // lyrics
         .route("/lyrics/:uuid", get(lyrics::get_lyrics))
@@ -69,10 +94,18 @@ pub async fn start_axum() -> anyhow::Result<()> {
         // upload
         .route("/upload/track/:hint", post(upload::upload_track))
         .route("/upload/guess_metadata/:uuid", post(upload::guess_metadata))
+        .route("/upload/guess_metadata/:uuid/preview", post(upload::preview_metadata))
+        .route("/upload/commit_metadata/:uuid", post(upload::commit_metadata))
+        .route("/upload/pending", get(upload::list_pending_uploads))
+        .route("/upload/pending/:uuid/discard", post(upload::discard_pending_upload))
         .route("/upload/track/:uuid/patch", patch(upload::patch_track_metadata))
         .route("/upload/album/:uuid/patch", patch(upload::patch_album_metadata))
         .route("/upload/artist/:uuid/patch", patch(upload::patch_artist_metadata))
         .route("/upload/cover/:uuid", post(upload::change_cover))
+        .route("/upload/track/:uuid/cover", post(upload::change_track_cover))
+        .route("/upload/artist/:uuid/photo", post(upload::change_artist_photo))
+        .route("/upload/artist/:uuid/banner", post(upload::change_artist_banner))
+        .route("/upload/write_tags", post(upload::batch_write_tags))
         .route("/upload/track/:uuid/delete", post(upload::delete_track))
         .route("/upload/album/:uuid/delete", post(upload::delete_album))
 
@@ -80,23 +113,42 @@ pub async fn start_axum() -> anyhow::Result<()> {
         .route("/stream/:uuid", get(stream::stream_track))
         .route("/stream/:track_id/:quality", get(stream::stream_track_transcoded))
 
+        // downloads
+        .route("/download/track/:uuid", get(download::download_track))
+        .route("/download/album/:uuid", get(download::download_album))
+
         // indexation
         .route("/index/albums", get(index::index_albums))
         .route("/index/artists", get(index::index_artists))
         .route("/index/tracks", get(index::index_tracks))
+        .route("/v2/index/albums", get(index::index_albums_page))
+        .route("/v2/index/artists", get(index::index_artists_page))
+        .route("/v2/index/tracks", get(index::index_tracks_page))
+        .route("/index/genres", get(index::index_genres))
+
+        // search
+        .route("/search", get(search::search))
+        .route("/search/suggest", get(search::suggest))
+        .route("/search/rebuild", post(search::rebuild_index))
 
         // personal endpoints
         .route("/user/love/track/:track", post(user::love_track))
         .route("/user/unlove/track/:track", post(user::unlove_track))
         .route("/user/love/album/:album", post(user::love_album))
         .route("/user/unlove/album/:album", post(user::unlove_album))
+        .route("/user/preferences", patch(user::patch_preferences))
+
+        // library maintenance
+        .route("/library/artist/:uuid/merge", post(library::merge_artist))
+        .route("/library/album/:uuid/merge", post(library::merge_album))
+        .route("/library/album/:uuid/split", post(library::split_album_tracks))
+        .route("/library/duplicates", get(library::duplicate_report))
+        .route("/library/duplicates/resolve", post(library::resolve_duplicate_tracks))
+        .route("/library/integrity", post(library::integrity_check))
 
         // metadata (creepy edition)
         .route("/metadata/musixmatch", get(metadata::pass_to_musixmatch))
 
         .layer(cors)
-        .with_state(state);
-
-    let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080));
-    axum::serve(TcpListener::bind(&address).await.unwrap(), router.into_make_service()).await.map_err(anyhow::Error::from)
+        .with_state(state)
 }
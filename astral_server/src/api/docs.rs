@@ -7,10 +7,20 @@ use super::paths::auth::*;
 use super::paths::upload::*;
 use super::paths::lyrics::*;
 use super::paths::stream::*;
+use super::paths::download::*;
 use super::paths::index::*;
+use super::paths::search::*;
 use super::paths::user::*;
+use super::paths::library::*;
 
+use crate::data::integrity::IntegrityIssue;
 use crate::err::AstralError;
+use crate::metadata::artwork::CoverFormat;
+use crate::metadata::duplicates::{DuplicateCandidate, DuplicateGroup, DuplicateReason};
+use crate::metadata::merged::{MergePolicy, MetadataProvenance};
+use crate::metadata::preview::{MetadataProposal, ProposalAttachments, ProposedCover};
+use crate::metadata::provider::{ManualOverrides, MetadataSource};
+use crate::search::engine::SearchKind;
 
 #[derive(OpenApi)]
 #[openapi(
@@ -18,27 +28,34 @@ use crate::err::AstralError;
         responses(
             TrackMetadataResponse, ArtistMetadataResponse, AlbumMetadataResponse,
             AuthenticationResponse, InviteCodeCheckResponse,
-            UploadTrackResponse,
+            UploadTrackResponse, GuessMetadataResponse, MetadataPreviewResponse,
             LyricsResponse,
             AstralError,
         ),
         schemas(
-            FullTrackMetadata, FullArtistMetadata, FullAlbumMetadata, MinifiedTrackMetadata, MinifiedAlbumMetadata, MinifiedArtistMetadata,
+            FullTrackMetadata, FullArtistMetadata, FullAlbumMetadata, AlbumDiscMetadata, AlbumTypeGroup, AlbumType, MinifiedTrackMetadata, MinifiedAlbumMetadata, MinifiedArtistMetadata,
             AuthenticationRequest, RegisterRequest,
             PatchTrackMetadata, PatchArtistMetadata, PatchAlbumMetadata,
-            TrackFormat, BinaryFile,
+            GuessMetadataRequest, MergePolicy, MetadataProvenance, MetadataSource, ManualOverrides,
+            MetadataProposal, ProposedCover, ProposalAttachments, PendingUpload, PendingTagPreview,
+            TrackFormat, BinaryFile, CoverFormat, CoverPalette,
             SyncedLyricLine,
-            IndexedAlbum, IndexedArtist, IndexedTrack,
+            IndexedAlbum, IndexedArtist, IndexedTrack, IndexedGenre,
+            AlbumIndexPage, ArtistIndexPage, TrackIndexPage, IndexSort, SortOrder,
+            SearchResponse, TrackSearchHit, AlbumSearchHit, ArtistSearchHit, LyricsSearchHit, LyricsLineHit, SearchSuggestion, SearchKind,
+            PatchUserPreferences,
+            MergeEntriesRequest, SplitAlbumRequest, ResolveDuplicatesRequest, DuplicateGroup, DuplicateCandidate, DuplicateReason, IntegrityIssue,
         )
     ),
     paths(
-        get_track_metadata, get_artist_metadata, get_album_metadata, get_album_cover_art, get_track_cover_art,
+        get_track_metadata, get_artist_metadata, get_album_metadata, get_album_cover_art, get_track_cover_art, get_artist_photo, get_artist_banner,
         register_with_token, login, obtain_access_token, verify,
-        upload_track, guess_metadata, patch_track_metadata, patch_album_metadata, patch_artist_metadata, change_cover, delete_album, delete_track,
+        upload_track, guess_metadata, preview_metadata, commit_metadata, list_pending_uploads, discard_pending_upload, patch_track_metadata, patch_album_metadata, patch_artist_metadata, change_cover, change_track_cover, change_artist_photo, change_artist_banner, batch_write_tags, delete_album, delete_track,
         get_lyrics,
-        stream_track, stream_track_transcoded,
-        index_albums, index_artists, index_tracks,
-        love_track, unlove_track, love_album, unlove_album,
+        stream_track, stream_track_transcoded, download_track, download_album,
+        index_albums, index_artists, index_tracks, index_albums_page, index_artists_page, index_tracks_page, index_genres, search, suggest, rebuild_index,
+        love_track, unlove_track, love_album, unlove_album, patch_preferences,
+        merge_artist, merge_album, split_album_tracks, duplicate_report, resolve_duplicate_tracks, integrity_check,
     ),
     tags(
         (name = "metadata", description = "Operations related to reading metadata"),
@@ -47,6 +64,7 @@ use crate::err::AstralError;
         (name = "stream", description = "Operations related to streaming track content"),
         (name = "index", description = "Operations related to indexation of albums/artists/tracks/etc."),
         (name = "user", description = "User related and personal requests"),
+        (name = "library", description = "Library maintenance operations, only available to admins"),
     )
 )]
 pub struct ApiDoc;
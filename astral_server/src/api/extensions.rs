@@ -51,6 +51,8 @@ pub enum UserPermission {
     ChangeMetadata,
     /// Allows user to invite user and assign them permissions that they have
     InviteUsers,
+    /// Allows user to manage uploads of other users and perform library maintenance
+    Admin,
 }
 
 /// Creates a short-lived PASETO access token
@@ -118,7 +120,7 @@ impl axum::extract::FromRequestParts<AppState> for AuthenticatedUser {
 
         if let Some(uid) = uid {
             let bson = BsonId::from_uuid_1(uid);
-            state.db.accounts.find_one(doc! { "user_id": &bson }, None).await?
+            state.db.accounts.find_one(doc! { "user_id": &bson }).await?
                 .map(Self)
                 .ok_or_else(|| AstralError::BadRequest(String::from("Couldn't find user with this id.")))
         } else {
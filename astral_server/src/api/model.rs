@@ -1,9 +1,13 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use utoipa::{ToResponse, ToSchema};
 use uuid::Uuid;
-use crate::data::model::{SyncedLyricLine, TrackFormat};
+use crate::data::model::{AlbumType, CoverPalette, SyncedLyricLine, TrackFormat};
+use crate::metadata::merged::{MergePolicy, MetadataProvenance};
+use crate::metadata::preview::{MetadataProposal, ProposalAttachments};
+use crate::metadata::provider::ManualOverrides;
+use crate::search::engine::SearchKind;
 
 //#region Responses
 
@@ -75,6 +79,76 @@ pub struct UploadTrackResponse {
     pub track_id: Uuid
 }
 
+/// Successfully guessed and assigned track metadata
+#[derive(Debug, Clone, Serialize, ToResponse)]
+pub struct GuessMetadataResponse {
+    /// UUID of the track
+    #[response(example = "4e4002e9-712f-405d-bb63-f48677e80522")]
+    pub track_id: Uuid,
+    /// The assigned metadata
+    pub metadata: FullTrackMetadata,
+    /// Whether this track is loved by this user
+    pub loved: bool,
+    /// Sources each field of the metadata came from
+    pub provenance: MetadataProvenance,
+}
+
+/// Preview of guessed track metadata that was not committed yet
+#[derive(Debug, Clone, Serialize, ToResponse)]
+pub struct MetadataPreviewResponse {
+    /// UUID of the track
+    #[response(example = "4e4002e9-712f-405d-bb63-f48677e80522")]
+    pub track_id: Uuid,
+    /// The proposed metadata
+    pub proposal: MetadataProposal,
+    /// Sources each field of the proposal came from
+    pub provenance: MetadataProvenance,
+    /// Existing entries the proposal would attach to and entries that would be created
+    pub attachments: ProposalAttachments,
+}
+
+/// A single uploaded track that does not have metadata assigned yet
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PendingUpload {
+    /// UUID of the track
+    pub track_id: Uuid,
+    /// UUID of the user who uploaded this track
+    pub uploaded_by: Uuid,
+    /// UTC date of the upload
+    #[schema(example = example_date)]
+    pub uploaded_at: DateTime<Utc>,
+    /// Format of this track
+    pub format: TrackFormat,
+    /// Size of the uploaded file in bytes. Absent if the file is missing
+    pub size: Option<u64>,
+    /// Preview of the tags embedded in the file. Absent if the tags could not be read
+    pub preview: Option<PendingTagPreview>,
+}
+
+/// Preview of the tags embedded in an uploaded file
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PendingTagPreview {
+    /// Track name
+    #[schema(example = "AMPM Truck")]
+    pub name: String,
+    /// Name of the album
+    #[schema(example = "Kiss My Super Bowl Ring")]
+    pub album_name: String,
+    /// Artists who worked on this track
+    pub artists: Vec<String>,
+    /// Artists who worked on this album
+    pub album_artists: Vec<String>,
+    /// Duration of this track in seconds
+    #[schema(example = 340)]
+    pub duration: f64,
+    /// Index of this track in the album
+    pub number: u16,
+    /// Number of the disc this track appears on
+    pub disc_number: u16,
+    /// Whether the file contains cover art
+    pub has_cover: bool,
+}
+
 //#endregion
 
 //#region Lyrics
@@ -104,6 +178,18 @@ pub enum LyricsResponse {
 
 //#region Indexation + Search
 
+/// A single page of indexed entries
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[aliases(AlbumIndexPage = IndexPage<IndexedAlbum>, ArtistIndexPage = IndexPage<IndexedArtist>, TrackIndexPage = IndexPage<IndexedTrack>)]
+pub struct IndexPage<T> {
+    /// Entries on this page
+    pub items: Vec<T>,
+    /// Total amount of entries matching the filters
+    pub total: u64,
+    /// Cursor to fetch the next page with. Absent on the last page
+    pub next_cursor: Option<String>,
+}
+
 /// A single indexed album data
 #[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct IndexedAlbum {
@@ -119,6 +205,12 @@ pub struct IndexedAlbum {
     pub release_date: DateTime<Utc>,
     /// Prevalent genres of this album
     pub genres: Vec<String>,
+    /// Colour palette of the album cover
+    pub palette: Option<CoverPalette>,
+    /// Type of this release
+    pub album_type: AlbumType,
+    /// Edition or version of this release
+    pub edition: Option<String>,
     /// Whether this album is loved by this user
     pub loved: bool,
 }
@@ -132,6 +224,74 @@ pub struct IndexedArtist {
     pub name: String,
 }
 
+/// A single indexed genre data
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct IndexedGenre {
+    /// Normalised name of the genre
+    #[schema(example = "punk")]
+    pub name: String,
+    /// Broader genre this genre belongs to
+    #[schema(example = "rock")]
+    pub parent: Option<String>,
+    /// Amount of tracks of this genre
+    pub track_count: u32,
+    /// Amount of albums this genre is prominent in
+    pub album_count: u32,
+    /// Amount of artists that have tracks of this genre
+    pub artist_count: u32,
+}
+
+/// Results of searching across the whole library, grouped by their type
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SearchResponse {
+    /// Matching tracks, most relevant first
+    pub tracks: Vec<TrackSearchHit>,
+    /// Matching albums, most relevant first
+    pub albums: Vec<AlbumSearchHit>,
+    /// Matching artists, most relevant first
+    pub artists: Vec<ArtistSearchHit>,
+    /// Matching lyric lines, at most one per track, most relevant first
+    pub lyrics: Vec<LyricsSearchHit>,
+}
+
+/// A single search result along with its relevance
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[aliases(TrackSearchHit = SearchHit<IndexedTrack>, AlbumSearchHit = SearchHit<IndexedAlbum>, ArtistSearchHit = SearchHit<IndexedArtist>, LyricsSearchHit = SearchHit<LyricsLineHit>)]
+pub struct SearchHit<T> {
+    /// Relevance of this result relative to the best result of the same group, from 0 to 1.
+    /// Higher is more relevant, scores of different groups are not comparable
+    #[schema(example = 0.8)]
+    pub score: f32,
+    /// The found entry
+    pub item: T,
+}
+
+/// A single search-as-you-type suggestion
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SearchSuggestion {
+    /// UUID of the suggested track, album or artist
+    pub id: Uuid,
+    /// Kind of the suggested entry
+    pub kind: SearchKind,
+    /// Name of the suggested entry
+    #[schema(example = "Kiss My Super Bowl Ring")]
+    pub name: String,
+}
+
+/// A single lyric line matching the search
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LyricsLineHit {
+    /// UUID of the track these lyrics belong to
+    pub track_id: Uuid,
+    /// Name of the track
+    #[schema(example = "AMPM Truck")]
+    pub track_name: String,
+    /// The matching line
+    pub line: String,
+    /// Time in milliseconds when this line starts, if lyrics are synced
+    pub start_time_ms: Option<u32>,
+}
+
 /// A single indexed track data
 #[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct IndexedTrack {
@@ -200,7 +360,10 @@ pub struct PatchTrackMetadata {
     /// Number of the disc this track is on
     pub disc_number: Option<u16>,
     /// Artists to be changed for this track
-    pub artists: Option<Vec<Uuid>>
+    pub artists: Option<Vec<Uuid>>,
+    /// Genres of this track. Will be normalised.
+    #[schema(example = example_genres)]
+    pub genres: Option<Vec<String>>,
 }
 
 /// Request to change assigned artist metadata
@@ -215,6 +378,34 @@ pub struct PatchArtistMetadata {
     pub about_artist: Option<String>,
 }
 
+/// Request to merge duplicate artists or albums into a single one
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct MergeEntriesRequest {
+    /// UUIDs of the duplicates that will be merged and removed
+    pub duplicates: Vec<Uuid>,
+}
+
+/// Request to keep a single track out of a group of duplicates
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct ResolveDuplicatesRequest {
+    /// UUID of the track to keep
+    pub keep: Uuid,
+    /// UUIDs of the duplicate tracks to delete
+    pub remove: Vec<Uuid>,
+}
+
+/// Request to move some tracks of an album into a new album
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct SplitAlbumRequest {
+    /// UUIDs of the tracks to move into the new album
+    pub tracks: Vec<Uuid>,
+    /// Name of the new album
+    #[schema(example = "Kiss My Super Bowl Ring")]
+    pub album_name: String,
+    /// Artists of the new album. Artists of the original album are used if not provided.
+    pub artists: Option<Vec<Uuid>>,
+}
+
 /// Request to change assigned artist metadata
 #[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct PatchAlbumMetadata {
@@ -227,9 +418,24 @@ pub struct PatchAlbumMetadata {
     pub artists: Option<Vec<Uuid>>,
     /// Unix timestamp in millis for the release date of this album
     pub release_date: Option<u64>,
-    /// Most prominent genres in this album.
+    /// Most prominent genres in this album. These are kept when tracks change,
+    /// an empty list makes them calculated from genres of the tracks again.
     #[schema(example = example_genres)]
     pub genres: Option<Vec<String>>,
+    /// Type of this release
+    pub album_type: Option<AlbumType>,
+    /// Edition or version of this release. Empty string removes the edition.
+    #[schema(example = "Deluxe Edition")]
+    pub edition: Option<String>,
+}
+
+/// Optional configuration for guessing track metadata
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+pub struct GuessMetadataRequest {
+    /// Per-field priority of metadata sources. Manual overrides win every field that is not listed.
+    pub policy: Option<MergePolicy>,
+    /// Values that were manually provided by the uploader
+    pub overrides: Option<ManualOverrides>,
 }
 
 /// Request to fetch track metadata from musixmatch with minimal track info
@@ -243,6 +449,13 @@ pub struct FetchMusixmatchMetadata {
     pub album: Option<String>
 }
 
+/// Request to change personal preferences of the user
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct PatchUserPreferences {
+    /// Whether explicit tracks and albums should be hidden from suggestions
+    pub hide_explicit: Option<bool>,
+}
+
 //#endregion
 
 //#region Object parts
@@ -253,14 +466,46 @@ pub struct FullAlbumMetadata {
     pub album_name: String,
     /// Minified metadata for artists who made this album
     pub artists: Vec<MinifiedArtistMetadata>,
-    /// Minified metadata for all tracks inside this album
+    /// Type of this release
+    pub album_type: AlbumType,
+    /// Edition or version of this release
+    #[schema(example = "Deluxe Edition")]
+    pub edition: Option<String>,
+    /// Minified metadata for all tracks inside this album, ordered by disc and track number
     pub tracks: Vec<MinifiedTrackMetadata>,
+    /// Tracks of this album grouped by discs, ordered by disc number
+    pub discs: Vec<AlbumDiscMetadata>,
+    /// Total amount of discs in this album
+    #[schema(example = 1)]
+    pub disc_total: u16,
+    /// Total amount of tracks in this album
+    #[schema(example = 10)]
+    pub track_total: u32,
     /// UTC release date of this album
     #[schema(example = example_date)]
     pub release_date: DateTime<Utc>,
     /// Top 3 most prominent genres in this album.
     #[schema(example = example_genres)]
     pub genres: Vec<String>,
+    /// Colour palette of the album cover
+    pub palette: Option<CoverPalette>,
+    /// Other names this album is known under
+    pub aliases: Vec<String>,
+}
+
+/// A single disc of an album along with its tracks
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AlbumDiscMetadata {
+    /// Number of this disc, starting from 1
+    #[schema(example = 1)]
+    pub disc_number: u16,
+    /// Subtitle of this disc
+    pub subtitle: Option<String>,
+    /// Total amount of tracks on this disc. Can be larger than the amount of present tracks.
+    #[schema(example = 10)]
+    pub track_total: u16,
+    /// Minified metadata for tracks on this disc, ordered by track number
+    pub tracks: Vec<MinifiedTrackMetadata>,
 }
 
 #[derive(Debug, Clone, Serialize, ToSchema)]
@@ -270,6 +515,8 @@ pub struct FullArtistMetadata {
     pub artist_name: String,
     /// Albums by this artist
     pub albums: Vec<MinifiedAlbumMetadata>,
+    /// Albums by this artist grouped by their type, in the order they should be shown
+    pub album_groups: Vec<AlbumTypeGroup>,
     /// Genres most prominent in this artist's discography. Returns top 3 genres.
     ///
     /// You can do a GET request to `/stats/artist/{id}/genres` to get all genres and their statistics.
@@ -277,10 +524,22 @@ pub struct FullArtistMetadata {
     pub genres: Vec<String>,
     /// String containing description for this artist. Can contain markdown.
     pub about_artist: String,
+    /// Other names this artist is known under
+    #[schema(example = json!(["AC-DC"]))]
+    pub aliases: Vec<String>,
     /// All tracks by this artist
     pub tracks: Vec<Uuid>
 }
 
+/// Albums of a single type
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AlbumTypeGroup {
+    /// Type of the albums in this group
+    pub album_type: AlbumType,
+    /// Albums of this type, newest first
+    pub albums: Vec<MinifiedAlbumMetadata>,
+}
+
 /// The full aggregated metadata of a track
 #[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct FullTrackMetadata {
@@ -302,6 +561,9 @@ pub struct FullTrackMetadata {
     pub number: u16,
     /// Number of the disc this track is on
     pub disc_number: u16,
+    /// Genres of this track
+    #[schema(example = example_genres)]
+    pub genres: Vec<String>,
 }
 
 /// Essential, but minified track metadata
@@ -355,6 +617,12 @@ pub struct MinifiedAlbumMetadata {
     /// Most prominent genres in this album.
     #[schema(example = example_genres)]
     pub genres: Vec<String>,
+    /// Colour palette of the album cover
+    pub palette: Option<CoverPalette>,
+    /// Type of this release
+    pub album_type: AlbumType,
+    /// Edition or version of this release
+    pub edition: Option<String>,
 }
 
 /// Essential, but minified artist metadata
@@ -395,7 +663,7 @@ const fn example_track_ids() -> [&'static str; 4] {
 }
 
 fn example_date() -> DateTime<Utc> {
-    NaiveDateTime::from_timestamp_millis(1584046800000).unwrap().and_utc()
+    DateTime::from_timestamp_millis(1584046800000).unwrap()
 }
 
 //#endregion Object parts
\ No newline at end of file
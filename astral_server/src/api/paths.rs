@@ -8,7 +8,13 @@ pub mod upload;
 pub mod lyrics;
 /// Handles track streaming
 pub mod stream;
+/// Handles downloading tracks and albums as files
+pub mod download;
 /// Handles indexation and discovery
 pub mod index;
+/// Handles searching across the whole library
+pub mod search;
 /// User account related and other personal methods
-pub mod user;
\ No newline at end of file
+pub mod user;
+/// Handles library maintenance, such as merging duplicates
+pub mod library;
\ No newline at end of file
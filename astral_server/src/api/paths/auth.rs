@@ -30,13 +30,13 @@ use crate::Res;
 )]
 #[axum_macros::debug_handler]
 pub async fn register_with_token(
-    State(AppState { db, paseto_key }): State<AppState>,
+    State(AppState { db, paseto_key, .. }): State<AppState>,
     Json(req): Json<RegisterRequest>
 ) -> Res<Json<AuthenticationResponse>> {
     let code = req.invite_code;
-    let invite_code = db.invite_codes.find_one(doc! { "code": &code }, None).await?.ok_or_else(|| AstralError::BadRequest(String::from("Invalid invite code")))?;
+    let invite_code = db.invite_codes.find_one(doc! { "code": &code }).await?.ok_or_else(|| AstralError::BadRequest(String::from("Invalid invite code")))?;
 
-    db.invite_codes.delete_one(doc! { "code": &code }, None).await?;
+    db.invite_codes.delete_one(doc! { "code": &code }).await?;
 
     if invite_code.expires_at < Utc::now().timestamp_millis() as u64 {
         return Err(AstralError::BadRequest(String::from("This invite code has expired!")))
@@ -50,9 +50,10 @@ pub async fn register_with_token(
         permissions: invite_code.permissions,
         loved_albums: vec![],
         loved_tracks: vec![],
+        hide_explicit: false,
     };
 
-    db.accounts.insert_one(&new_user, None).await?;
+    db.accounts.insert_one(&new_user).await?;
 
     let refresh_key = create_user_refresh_key(&paseto_key, new_user.user_id.clone().to_uuid_1())?;
 
@@ -96,11 +97,11 @@ pub async fn verify(
     tag = "auth"
 )]
 pub async fn login(
-    State(AppState { db, paseto_key }): State<AppState>,
+    State(AppState { db, paseto_key, .. }): State<AppState>,
     jar: CookieJar,
     Json(req): Json<AuthenticationRequest>,
 ) -> Res<(CookieJar, String)> {
-    let user = db.accounts.find_one(doc! { "username": &req.username }, None).await?
+    let user = db.accounts.find_one(doc! { "username": &req.username }).await?
         .ok_or_else(|| AstralError::BadRequest(String::from("Invalid username. Couldn't find a user with this username.")))?;
     if !validate_password(req.password, user.password_hash) {
         return Err(AstralError::Unauthorized(String::from("Invalid password or username.")))
@@ -0,0 +1,319 @@
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::HeaderValue;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Datelike};
+use futures_util::{stream, TryStreamExt};
+use mongodb::bson::doc;
+use serde::Deserialize;
+use tempfile::TempPath;
+use tokio::fs::File;
+use tokio::sync::mpsc;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+use crate::api::AppState;
+use crate::api::extensions::AuthenticatedUser;
+use crate::api::paths::stream::{StreamQuality, transcode_track};
+use crate::data::AstralDatabase;
+use crate::data::model::{AlbumMetadata, ArtistMetadata, BsonId, TrackFormat, TrackMetadata};
+use crate::err::AstralError;
+use crate::metadata::artwork::{read_artwork, ArtworkKind};
+use crate::metadata::writer::{collect_tag_payload, write_tags_to_file};
+use crate::Res;
+
+/// Filename template used when none is provided
+const DEFAULT_TEMPLATE: &str = "{disc}-{number} {artist} - {title}";
+
+/// Version of the file to download
+#[derive(Debug, Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadProfile {
+    /// The originally uploaded file
+    #[default]
+    Original,
+    /// MP3 transcoded to 128kb/s
+    Low,
+    /// MP3 transcoded to 256kb/s
+    Medium,
+}
+
+#[derive(Deserialize)]
+pub struct DownloadProps {
+    profile: Option<DownloadProfile>,
+    template: Option<String>,
+    retag: Option<bool>,
+}
+
+/// Downloads a single track as a named file
+#[utoipa::path(
+    get,
+    path = "/download/track/{uuid}",
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, body = BinaryFile, description = "Obtained track file")
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the track to download"),
+        ("profile" = inline(Option<String>), Query, description = "Version of the file. Either `original` (default), `low` for 128kb/s MP3 or `medium` for 256kb/s MP3"),
+        ("template" = inline(Option<String>), Query, description = "Filename template without extension. Supports `{disc}`, `{number}`, `{artist}`, `{title}`, `{album}` and `{year}`. Defaults to `{disc}-{number} {artist} - {title}`"),
+        ("retag" = inline(Option<bool>), Query, description = "Whether to write current metadata into the file tags before downloading"),
+    ),
+    tag = "stream"
+)]
+pub async fn download_track(
+    State(AppState { db, .. }): State<AppState>,
+    Path(track_id): Path<Uuid>,
+    Query(DownloadProps { profile, template, retag }): Query<DownloadProps>,
+    AuthenticatedUser(_): AuthenticatedUser
+) -> Res<impl IntoResponse> {
+    let uid = BsonId::from_uuid_1(track_id);
+    let track = db.tracks_metadata.find_one(doc! { "track_id": &uid }).await?
+        .ok_or_else(|| AstralError::NotFound("Couldn't find a track with this ID".to_string()))?;
+    let album = match track.albums.first() {
+        Some(album_id) => db.albums_metadata.find_one(doc! { "album_id": album_id }).await?,
+        None => None
+    };
+    let artists = find_artists(&db, &track.artists).await?;
+
+    let profile = profile.unwrap_or_default();
+    let filename = render_filename(template.as_deref().unwrap_or(DEFAULT_TEMPLATE), &track, album.as_ref(), &artists, profile);
+    let mime: String = profile_format(track.format, profile).into();
+    let prepared = prepare_track_file(&db, track, profile, retag.unwrap_or(false)).await?;
+
+    let PreparedTrackFile { path, temporary } = prepared;
+    let file = File::open(&path).await?;
+    // the temporary copy is owned by the stream and removed once the response body is dropped
+    let body = ReaderStream::new(file).map_ok(move |chunk| {
+        let _ = &temporary;
+        chunk
+    });
+
+    attachment_response(Body::from_stream(body), &mime, &filename)
+}
+
+/// Downloads all tracks of an album along with its cover as a ZIP archive
+#[utoipa::path(
+    get,
+    path = "/download/album/{uuid}",
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, body = BinaryFile, description = "Obtained album archive")
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the album to download"),
+        ("profile" = inline(Option<String>), Query, description = "Version of the files. Either `original` (default), `low` for 128kb/s MP3 or `medium` for 256kb/s MP3"),
+        ("template" = inline(Option<String>), Query, description = "Filename template without extension for each track. Supports `{disc}`, `{number}`, `{artist}`, `{title}`, `{album}` and `{year}`. Defaults to `{disc}-{number} {artist} - {title}`"),
+        ("retag" = inline(Option<bool>), Query, description = "Whether to write current metadata into the file tags before downloading"),
+    ),
+    tag = "stream"
+)]
+pub async fn download_album(
+    State(AppState { db, .. }): State<AppState>,
+    Path(album_id): Path<Uuid>,
+    Query(DownloadProps { profile, template, retag }): Query<DownloadProps>,
+    AuthenticatedUser(_): AuthenticatedUser
+) -> Res<impl IntoResponse> {
+    let uid = BsonId::from_uuid_1(album_id);
+    let album = db.albums_metadata.find_one(doc! { "album_id": &uid }).await?
+        .ok_or_else(|| AstralError::NotFound("Couldn't find an album with this ID".to_string()))?;
+    let mut tracks = db.tracks_metadata.find(doc! { "track_id": { "$in": &album.tracks } }).await?
+        .try_collect::<Vec<TrackMetadata>>().await?;
+    tracks.sort_by_key(|it| (it.disc_number, it.number));
+    let artists = find_artists(&db, &tracks.iter().flat_map(|it| it.artists.clone()).collect::<Vec<_>>()).await?;
+
+    let profile = profile.unwrap_or_default();
+    let retag = retag.unwrap_or(false);
+    let template = template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+    let mut used_names = HashSet::new();
+    let mut entries = Vec::with_capacity(tracks.len());
+    for track in tracks {
+        let mut filename = render_filename(template, &track, Some(&album), &artists, profile);
+        let mut duplicate = 1;
+        while !used_names.insert(filename.clone()) {
+            duplicate += 1;
+            filename = format!("({duplicate}) {}", render_filename(template, &track, Some(&album), &artists, profile));
+        }
+        entries.push((filename, track));
+    }
+    let cover = read_artwork(&db, ArtworkKind::AlbumCover, &uid).await?;
+
+    // entries are sent to the client as soon as they are written, a failure aborts the response
+    let (sender, receiver) = mpsc::channel(ARCHIVE_CHANNEL_CAPACITY);
+    let runtime = tokio::runtime::Handle::current();
+    let error_sender = sender.clone();
+    tokio::task::spawn_blocking(move || {
+        let written = write_album_archive(ChannelWriter(sender), cover, entries, |track| {
+            runtime.block_on(prepare_track_file(&db, track, profile, retag))
+        });
+        if let Err(err) = written {
+            let _ = error_sender.blocking_send(Err(std::io::Error::other(err.to_string())));
+        }
+    });
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    let filename = sanitize_filename(&format!("{}.zip", album.name));
+    attachment_response(Body::from_stream(body), "application/zip", &filename)
+}
+
+/// Number of archive chunks buffered before the writer waits for the client
+const ARCHIVE_CHANNEL_CAPACITY: usize = 4;
+
+/// Size of the chunks sent to the client while writing an archive
+const ARCHIVE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Blocking writer that forwards everything written into it to the response body
+struct ChannelWriter(mpsc::Sender<std::io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client closed the connection"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A track file ready to be downloaded
+struct PreparedTrackFile {
+    /// Path to the file
+    path: PathBuf,
+    /// Temporary copy with written tags, removed from disk when dropped
+    temporary: Option<TempPath>,
+}
+
+/// Transcodes track and writes tags into a temporary copy if needed
+async fn prepare_track_file(db: &AstralDatabase, track: TrackMetadata, profile: DownloadProfile, retag: bool) -> Res<PreparedTrackFile> {
+    let path = match profile {
+        DownloadProfile::Original => PathBuf::from("astral_tracks").join(format!("{}.bin", track.track_id)),
+        DownloadProfile::Low => transcode_track(track.track_id.to_uuid_1(), &StreamQuality::Low).await?,
+        DownloadProfile::Medium => transcode_track(track.track_id.to_uuid_1(), &StreamQuality::Medium).await?,
+    };
+    if !retag {
+        return Ok(PreparedTrackFile { path, temporary: None })
+    }
+
+    let format = profile_format(track.format, profile);
+    let temp_dir = PathBuf::from("astral_tracks").join("tmp");
+    tokio::fs::create_dir_all(&temp_dir).await?;
+    let temp_path = tempfile::Builder::new().suffix(".bin").tempfile_in(&temp_dir)?.into_temp_path();
+    tokio::fs::copy(&path, &temp_path).await?;
+
+    let payload = collect_tag_payload(db, track).await?;
+    let tagged_path = temp_path.to_path_buf();
+    tokio::task::spawn_blocking(move || write_tags_to_file(&tagged_path, format, &payload)).await
+        .map_err(anyhow::Error::from)??;
+
+    Ok(PreparedTrackFile { path: temp_path.to_path_buf(), temporary: Some(temp_path) })
+}
+
+/// Writes the cover and all tracks into a ZIP archive. Each track is prepared right before it is written,
+/// so only one temporary copy exists at a time.
+fn write_album_archive(
+    writer: impl Write,
+    cover: Option<(Vec<u8>, String)>,
+    entries: Vec<(String, TrackMetadata)>,
+    mut prepare: impl FnMut(TrackMetadata) -> Res<PreparedTrackFile>,
+) -> Res<()> {
+    // audio and images are already compressed, so they are stored as is
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut zip = ZipWriter::new_stream(BufWriter::with_capacity(ARCHIVE_CHUNK_SIZE, writer));
+    if let Some((data, mime)) = cover {
+        zip.start_file(format!("cover.{}", cover_extension(&mime)), options)?;
+        zip.write_all(&data)?;
+    }
+    for (filename, track) in entries {
+        let prepared = prepare(track)?;
+        let written = zip.start_file(filename.as_str(), options).map_err(AstralError::from)
+            .and_then(|_| Ok(std::io::copy(&mut std::fs::File::open(&prepared.path)?, &mut zip)?));
+        // removes the temporary copy before the next track is prepared
+        drop(prepared);
+        written?;
+    }
+    zip.finish()?.into_inner().flush()?;
+    Ok(())
+}
+
+/// Finds metadata of all provided artists
+async fn find_artists(db: &AstralDatabase, artists: &[BsonId]) -> Res<Vec<ArtistMetadata>> {
+    db.artists_metadata.find(doc! { "artist_id": { "$in": artists } }).await?
+        .try_collect::<Vec<_>>().await
+}
+
+/// Format of the downloaded file for the profile
+fn profile_format(format: TrackFormat, profile: DownloadProfile) -> TrackFormat {
+    match profile {
+        DownloadProfile::Original => format,
+        DownloadProfile::Low | DownloadProfile::Medium => TrackFormat::Mp3,
+    }
+}
+
+/// File extension of the album cover with the provided mime type
+fn cover_extension(mime: &str) -> &'static str {
+    match mime {
+        "image/png" => "png",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        _ => "jpg",
+    }
+}
+
+/// Renders filename template for a track, appending the file extension
+fn render_filename(template: &str, track: &TrackMetadata, album: Option<&AlbumMetadata>, artists: &[ArtistMetadata], profile: DownloadProfile) -> String {
+    let artist = track.artists.iter()
+        .filter_map(|id| artists.iter().find(|it| &it.artist_id == id))
+        .map(|it| it.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let year = album
+        .filter(|it| it.release_date != 0)
+        .and_then(|it| DateTime::from_timestamp_millis(it.release_date as i64))
+        .map(|it| it.year().to_string())
+        .unwrap_or_default();
+    let extension = match profile_format(track.format, profile) {
+        TrackFormat::Flac => "flac",
+        TrackFormat::M4a => "m4a",
+        TrackFormat::Mp3 => "mp3",
+    };
+
+    let rendered = template
+        .replace("{disc}", &track.disc_number.to_string())
+        .replace("{number}", &format!("{:02}", track.number))
+        .replace("{artist}", &artist)
+        .replace("{title}", &track.name)
+        .replace("{album}", album.map(|it| it.name.as_str()).unwrap_or_default())
+        .replace("{year}", &year);
+    sanitize_filename(&format!("{}.{extension}", rendered.trim()))
+}
+
+/// Replaces characters that are not allowed in filenames
+fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_control() || "/\\:*?\"<>|".contains(c) { '_' } else { c })
+        .collect()
+}
+
+/// Creates a response that will be saved by the client as a file with the provided name
+fn attachment_response(body: Body, mime: &str, filename: &str) -> Res<Response> {
+    let ascii_name = filename.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect::<String>();
+    let encoded_name = filename.bytes()
+        .map(|b| if b.is_ascii_alphanumeric() || b"-._~".contains(&b) { (b as char).to_string() } else { format!("%{b:02X}") })
+        .collect::<String>();
+    let disposition = format!("attachment; filename=\"{ascii_name}\"; filename*=UTF-8''{encoded_name}");
+
+    let mut response = body.into_response();
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_str(mime).map_err(anyhow::Error::from)?);
+    headers.insert(CONTENT_DISPOSITION, HeaderValue::from_str(&disposition).map_err(anyhow::Error::from)?);
+    Ok(response)
+}
@@ -1,62 +1,160 @@
+use std::collections::HashSet;
 use std::str::FromStr;
 use axum::extract::{Query, State};
 use axum::Json;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use futures_util::StreamExt;
-use mongodb::bson::{doc, Document, from_bson};
-use serde::Deserialize;
+use mongodb::bson::{bson, Bson, doc, Document, from_bson, to_bson};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
 use crate::api::AppState;
 use crate::api::extensions::AuthenticatedUser;
-use crate::api::model::{IndexedAlbum, IndexedArtist, IndexedTrack};
-use crate::data::model::{AlbumMetadata, BsonId, TrackFormat, UserAccount};
+use crate::api::model::{IndexedAlbum, IndexedArtist, IndexedGenre, IndexedTrack, IndexPage};
+use crate::data::model::{AlbumMetadata, AlbumType, BsonId, TrackFormat, UserAccount};
+use crate::data::repository::Repo;
 use crate::err::AstralError;
+use crate::metadata::genres::{count_album_genres, count_artist_genres, count_track_genres, normalize_genre, parent_genre};
+use crate::search::engine::{SearchIndex, SearchKind};
 use crate::Res;
 
 /// Parameters used for indexation
 #[derive(Deserialize)]
 pub struct IndexParameters {
-    /// Amount of indices to skip. Used for pagination
+    /// Amount of indices to skip. Used for pagination, ignored when a cursor is provided
+    #[serde(default)]
     pub skip: u32,
     /// Count of indices to provide
     pub count: u32,
+    /// Opaque cursor returned by the previous page. Used for keyset pagination
+    pub cursor: Option<String>,
     /// Optional search query
     pub search: Option<String>,
+    /// Optional genre to filter by
+    pub genre: Option<String>,
+    /// Only include entries made by this artist
+    pub artist: Option<Uuid>,
+    /// Only include entries released in this year or later
+    pub year_from: Option<i32>,
+    /// Only include entries released in this year or earlier
+    pub year_to: Option<i32>,
+    /// Only include entries with tracks in this format
+    pub format: Option<TrackFormat>,
+    /// Only include explicit entries if true, or only clean entries if false
+    pub explicit: Option<bool>,
+    /// Only include entries loved by this user
+    pub loved_only: Option<bool>,
+    /// Only include albums of this type, or tracks and artists of albums of this type
+    pub album_type: Option<AlbumType>,
+    /// Only include entries added within this amount of days
+    pub added_within: Option<u32>,
+    /// Key to sort by. Defaults to name, or to search relevance when searching
+    pub sort: Option<IndexSort>,
+    /// Direction to sort in
+    #[serde(default)]
+    pub order: SortOrder,
 }
 
-/// Fetches all albums based on the skip and count parameters
+/// Key index entries can be sorted by
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexSort {
+    /// Name of the entry
+    Name,
+    /// Release date of the album (or the track's album)
+    ReleaseDate,
+    /// Date the entry was added to the library
+    DateAdded,
+    /// Duration of the track, or total duration of all tracks
+    Duration,
+    /// Play count of the track, or total play count of all tracks
+    PlayCount,
+}
+
+/// Direction of sorting
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    /// From the lowest to the highest
+    #[default]
+    Asc,
+    /// From the highest to the lowest
+    Desc,
+}
+
+impl SortOrder {
+    fn direction(self) -> i32 {
+        match self {
+            SortOrder::Asc => 1,
+            SortOrder::Desc => -1,
+        }
+    }
+}
+
+/// Kind of entries being indexed, as filters and sort keys apply to them differently
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum IndexKind {
+    Albums,
+    Artists,
+    Tracks,
+}
+
+impl IndexKind {
+    fn id_field(self) -> &'static str {
+        match self {
+            IndexKind::Albums => "album_id",
+            IndexKind::Artists => "artist_id",
+            IndexKind::Tracks => "track_id",
+        }
+    }
+
+    fn search_kind(self) -> SearchKind {
+        match self {
+            IndexKind::Albums => SearchKind::Album,
+            IndexKind::Artists => SearchKind::Artist,
+            IndexKind::Tracks => SearchKind::Track,
+        }
+    }
+}
+
+/// Maximal amount of search index matches considered by index endpoints
+const MAX_SEARCH_MATCHES: usize = 1000;
+
+/// Fetches a page of albums based on the parameters, along with the total amount of matching albums
 #[utoipa::path(
     get,
-    path = "/index/albums",
+    path = "/v2/index/albums",
     params(
-        ("skip" = u32, Query, description = "Amount of album indices to skip"),
+        ("skip" = Option<u32>, Query, description = "Amount of album indices to skip. Ignored when a cursor is provided"),
         ("count" = u32, Query, description = "Amount of albums to provide"),
-        ("search" = Option<String>, Query, description = "Optional search query"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned by the previous page as `next_cursor`"),
+        ("search" = Option<String>, Query, description = "Optional search query. Only the 1000 most relevant entries are matched"),
+        ("genre" = Option<String>, Query, description = "Optional genre to filter by"),
+        ("artist" = Option<Uuid>, Query, description = "Only include albums by this artist"),
+        ("year_from" = Option<i32>, Query, description = "Only include albums released in this year or later"),
+        ("year_to" = Option<i32>, Query, description = "Only include albums released in this year or earlier"),
+        ("format" = Option<TrackFormat>, Query, description = "Only include albums containing tracks in this format"),
+        ("explicit" = Option<bool>, Query, description = "Only include albums with explicit tracks if true, or only albums without them if false"),
+        ("loved_only" = Option<bool>, Query, description = "Only include albums loved by this user"),
+        ("album_type" = Option<AlbumType>, Query, description = "Only include albums of this type"),
+        ("added_within" = Option<u32>, Query, description = "Only include albums added within this amount of days"),
+        ("sort" = Option<IndexSort>, Query, description = "Key to sort albums by"),
+        ("order" = Option<SortOrder>, Query, description = "Direction to sort albums in"),
     ),
     responses(
-        (status = 200, body = [IndexedAlbum], description = "Successfully fetched album index"),
+        (status = 200, body = AlbumIndexPage, description = "Successfully fetched album index"),
         (status = 400, response = AstralError)
     ),
     tag = "index"
 )]
-pub async fn index_albums(
-    State(AppState { db, .. }): State<AppState>,
-    Query(IndexParameters { skip, count, search }): Query<IndexParameters>,
+pub async fn index_albums_page(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Query(params): Query<IndexParameters>,
     AuthenticatedUser(user): AuthenticatedUser
-) -> Res<Json<Vec<IndexedAlbum>>> {
-    let (first_doc, second_doc) = if let Some(search) = search {
-        (doc! { "$or": [{"$text": { "$search": &search }}, { "name": { "$regex": format!("(?i){search}") } }] }, doc! { "score": {"$meta": "textScore"}, "name": 1, "_id": 1 })
-    } else {
-        (doc! { }, doc! { "name": 1, "_id": 1 })
-    };
-    let found = db.albums_metadata.aggregate(vec![
-        doc! { "$match": first_doc },
-        doc! { "$sort": second_doc },
-        doc! {
-            "$skip": skip,
-        },
-        doc!{
-            "$limit": count,
-        },
+) -> Res<Json<IndexPage<IndexedAlbum>>> {
+    let (filter, ranking) = build_index_filter(&search_index, IndexKind::Albums, &params, &user).await?;
+    let total = count_index_entries(&db.albums_metadata, filter.clone()).await?;
+    let (found, next_cursor) = fetch_index_page(&db.albums_metadata, IndexKind::Albums, &params, filter, ranking, vec![
         doc! {
             "$lookup": {
                 "from": "artists_metadata",
@@ -65,23 +163,114 @@ pub async fn index_albums(
                 "as": "artist_objects"
             },
         },
-    ], None).await?;
-    let mapped = found
-        .filter_map(|each| async { each.ok() })
-        .map(|each| extract_indexed_album(each, &user))
-        .filter_map(|each| async { each.ok() });
+    ]).await?;
+
+    Ok(Json(IndexPage {
+        items: found.into_iter().filter_map(|each| extract_indexed_album(each, &user).ok()).collect(),
+        total,
+        next_cursor,
+    }))
+}
+
+/// Fetches albums like [index_albums_page], responding only with the entries of the page.
+/// Kept for clients of the original array response.
+#[utoipa::path(
+    get,
+    path = "/index/albums",
+    params(
+        ("skip" = Option<u32>, Query, description = "Amount of album indices to skip. Ignored when a cursor is provided"),
+        ("count" = u32, Query, description = "Amount of albums to provide"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned by the previous page as `next_cursor`"),
+        ("search" = Option<String>, Query, description = "Optional search query. Only the 1000 most relevant entries are matched"),
+        ("genre" = Option<String>, Query, description = "Optional genre to filter by"),
+        ("artist" = Option<Uuid>, Query, description = "Only include albums by this artist"),
+        ("year_from" = Option<i32>, Query, description = "Only include albums released in this year or later"),
+        ("year_to" = Option<i32>, Query, description = "Only include albums released in this year or earlier"),
+        ("format" = Option<TrackFormat>, Query, description = "Only include albums containing tracks in this format"),
+        ("explicit" = Option<bool>, Query, description = "Only include albums with explicit tracks if true, or only albums without them if false"),
+        ("loved_only" = Option<bool>, Query, description = "Only include albums loved by this user"),
+        ("album_type" = Option<AlbumType>, Query, description = "Only include albums of this type"),
+        ("added_within" = Option<u32>, Query, description = "Only include albums added within this amount of days"),
+        ("sort" = Option<IndexSort>, Query, description = "Key to sort albums by"),
+        ("order" = Option<SortOrder>, Query, description = "Direction to sort albums in"),
+    ),
+    responses(
+        (status = 200, body = [IndexedAlbum], description = "Successfully fetched album index"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "index"
+)]
+pub async fn index_albums(
+    state: State<AppState>,
+    params: Query<IndexParameters>,
+    user: AuthenticatedUser
+) -> Res<Json<Vec<IndexedAlbum>>> {
+    let Json(page) = index_albums_page(state, params, user).await?;
+    Ok(Json(page.items))
+}
+
+/// Fetches a page of artists based on the parameters, along with the total amount of matching artists
+#[utoipa::path(
+    get,
+    path = "/v2/index/artists",
+    params(
+        ("skip" = Option<u32>, Query, description = "Amount of artist indices to skip. Ignored when a cursor is provided"),
+        ("count" = u32, Query, description = "Amount of artists to provide"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned by the previous page as `next_cursor`"),
+        ("search" = Option<String>, Query, description = "Optional search query. Only the 1000 most relevant entries are matched"),
+        ("genre" = Option<String>, Query, description = "Optional genre to filter by"),
+        ("year_from" = Option<i32>, Query, description = "Only include artists with albums released in this year or later"),
+        ("year_to" = Option<i32>, Query, description = "Only include artists with albums released in this year or earlier"),
+        ("format" = Option<TrackFormat>, Query, description = "Only include artists with tracks in this format"),
+        ("explicit" = Option<bool>, Query, description = "Only include artists with explicit tracks if true, or only artists without them if false"),
+        ("loved_only" = Option<bool>, Query, description = "Only include artists with tracks loved by this user"),
+        ("album_type" = Option<AlbumType>, Query, description = "Only include artists with albums of this type"),
+        ("added_within" = Option<u32>, Query, description = "Only include artists added within this amount of days"),
+        ("sort" = Option<IndexSort>, Query, description = "Key to sort artists by. Artists can not be sorted by release date"),
+        ("order" = Option<SortOrder>, Query, description = "Direction to sort artists in"),
+    ),
+    responses(
+        (status = 200, body = ArtistIndexPage, description = "Successfully fetched artist index"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "index"
+)]
+pub async fn index_artists_page(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Query(params): Query<IndexParameters>,
+    AuthenticatedUser(user): AuthenticatedUser
+) -> Res<Json<IndexPage<IndexedArtist>>> {
+    let (filter, ranking) = build_index_filter(&search_index, IndexKind::Artists, &params, &user).await?;
+    let total = count_index_entries(&db.artists_metadata, filter.clone()).await?;
+    let (found, next_cursor) = fetch_index_page(&db.artists_metadata, IndexKind::Artists, &params, filter, ranking, vec![]).await?;
 
-    Ok(Json(mapped.collect::<Vec<_>>().await))
+    Ok(Json(IndexPage {
+        items: found.into_iter().filter_map(|each| extract_indexed_artist(each).ok()).collect(),
+        total,
+        next_cursor,
+    }))
 }
 
-/// Fetches all artists based on the skip and count parameters
+/// Fetches artists like [index_artists_page], responding only with the entries of the page.
+/// Kept for clients of the original array response.
 #[utoipa::path(
     get,
     path = "/index/artists",
     params(
-        ("skip" = u32, Query, description = "Amount of artist indices to skip"),
+        ("skip" = Option<u32>, Query, description = "Amount of artist indices to skip. Ignored when a cursor is provided"),
         ("count" = u32, Query, description = "Amount of artists to provide"),
-        ("search" = Option<String>, Query, description = "Optional search query"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned by the previous page as `next_cursor`"),
+        ("search" = Option<String>, Query, description = "Optional search query. Only the 1000 most relevant entries are matched"),
+        ("genre" = Option<String>, Query, description = "Optional genre to filter by"),
+        ("year_from" = Option<i32>, Query, description = "Only include artists with albums released in this year or later"),
+        ("year_to" = Option<i32>, Query, description = "Only include artists with albums released in this year or earlier"),
+        ("format" = Option<TrackFormat>, Query, description = "Only include artists with tracks in this format"),
+        ("explicit" = Option<bool>, Query, description = "Only include artists with explicit tracks if true, or only artists without them if false"),
+        ("loved_only" = Option<bool>, Query, description = "Only include artists with tracks loved by this user"),
+        ("album_type" = Option<AlbumType>, Query, description = "Only include artists with albums of this type"),
+        ("added_within" = Option<u32>, Query, description = "Only include artists added within this amount of days"),
+        ("sort" = Option<IndexSort>, Query, description = "Key to sort artists by. Artists can not be sorted by release date"),
+        ("order" = Option<SortOrder>, Query, description = "Direction to sort artists in"),
     ),
     responses(
         (status = 200, body = [IndexedArtist], description = "Successfully fetched artist index"),
@@ -90,63 +279,49 @@ pub async fn index_albums(
     tag = "index"
 )]
 pub async fn index_artists(
-    State(AppState { db, .. }): State<AppState>,
-    Query(IndexParameters { skip, count, search }): Query<IndexParameters>,
-    AuthenticatedUser(_): AuthenticatedUser
+    state: State<AppState>,
+    params: Query<IndexParameters>,
+    user: AuthenticatedUser
 ) -> Res<Json<Vec<IndexedArtist>>> {
-    let (first_doc, second_doc) = if let Some(search) = search {
-        (doc! { "$or": [{"$text": { "$search": &search }}, { "name": { "$regex": format!("(?i){search}") } }] }, doc! { "score": {"$meta": "textScore"}, "name": 1, "_id": 1 })
-    } else {
-        (doc! { }, doc! { "name": 1, "_id": 1 })
-    };
-    let found = db.artists_metadata.aggregate(vec![
-        doc! { "$match": first_doc },
-        doc! { "$sort": second_doc },
-        doc! {
-            "$skip": skip,
-        },
-        doc!{
-            "$limit": count,
-        },
-    ], None).await?;
-    let mapped = found
-        .filter_map(|each| async { each.ok() })
-        .map(extract_indexed_artist)
-        .filter_map(|each| async { each.ok() });
-
-    Ok(Json(mapped.collect::<Vec<_>>().await))
+    let Json(page) = index_artists_page(state, params, user).await?;
+    Ok(Json(page.items))
 }
 
-/// Fetches all albums based on the skip and count parameters
+/// Fetches a page of tracks based on the parameters, along with the total amount of matching tracks
 #[utoipa::path(
     get,
-    path = "/index/tracks",
+    path = "/v2/index/tracks",
     params(
-        ("skip" = u32, Query, description = "Amount of track indices to skip"),
+        ("skip" = Option<u32>, Query, description = "Amount of track indices to skip. Ignored when a cursor is provided"),
         ("count" = u32, Query, description = "Amount of tracks to provide"),
-        ("search" = Option<String>, Query, description = "Optional search query"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned by the previous page as `next_cursor`"),
+        ("search" = Option<String>, Query, description = "Optional search query. Only the 1000 most relevant entries are matched"),
+        ("genre" = Option<String>, Query, description = "Optional genre to filter by"),
+        ("artist" = Option<Uuid>, Query, description = "Only include tracks by this artist"),
+        ("year_from" = Option<i32>, Query, description = "Only include tracks released in this year or later"),
+        ("year_to" = Option<i32>, Query, description = "Only include tracks released in this year or earlier"),
+        ("format" = Option<TrackFormat>, Query, description = "Only include tracks in this format"),
+        ("explicit" = Option<bool>, Query, description = "Only include explicit tracks if true, or only clean tracks if false"),
+        ("loved_only" = Option<bool>, Query, description = "Only include tracks loved by this user"),
+        ("album_type" = Option<AlbumType>, Query, description = "Only include tracks from albums of this type"),
+        ("added_within" = Option<u32>, Query, description = "Only include tracks added within this amount of days"),
+        ("sort" = Option<IndexSort>, Query, description = "Key to sort tracks by"),
+        ("order" = Option<SortOrder>, Query, description = "Direction to sort tracks in"),
     ),
     responses(
-        (status = 200, body = [IndexedTrack], description = "Successfully fetched track index"),
+        (status = 200, body = TrackIndexPage, description = "Successfully fetched track index"),
         (status = 400, response = AstralError)
     ),
     tag = "index"
 )]
-pub async fn index_tracks(
-    State(AppState { db, .. }): State<AppState>,
-    Query(IndexParameters { skip, count, search }): Query<IndexParameters>,
+pub async fn index_tracks_page(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Query(params): Query<IndexParameters>,
     AuthenticatedUser(user): AuthenticatedUser
-) -> Res<Json<Vec<IndexedTrack>>> {
-    let (first_doc, second_doc) = if let Some(search) = search {
-        (doc! { "$or": [{"$text": { "$search": &search }}, { "name": { "$regex": format!("(?i){search}") } }] }, doc! { "score": {"$meta": "textScore"}, "name": 1, "_id": 1 })
-    } else {
-        (doc! { }, doc! { "name": 1, "_id": 1 })
-    };
-    let found = db.tracks_metadata.aggregate(vec![
-        doc! { "$match": first_doc },
-        doc! { "$sort": second_doc },
-        doc! { "$skip": skip },
-        doc! { "$limit": count },
+) -> Res<Json<IndexPage<IndexedTrack>>> {
+    let (filter, ranking) = build_index_filter(&search_index, IndexKind::Tracks, &params, &user).await?;
+    let total = count_index_entries(&db.tracks_metadata, filter.clone()).await?;
+    let (found, next_cursor) = fetch_index_page(&db.tracks_metadata, IndexKind::Tracks, &params, filter, ranking, vec![
         doc! {
             "$lookup": {
                 "from": "artists_metadata",
@@ -163,17 +338,398 @@ pub async fn index_tracks(
                 "as": "album_objects"
             }
         }
-    ], None).await?;
-    let mapped = found
+    ]).await?;
+
+    Ok(Json(IndexPage {
+        items: found.into_iter().filter_map(|each| extract_indexed_track(each, &user).ok()).collect(),
+        total,
+        next_cursor,
+    }))
+}
+
+/// Fetches tracks like [index_tracks_page], responding only with the entries of the page.
+/// Kept for clients of the original array response.
+#[utoipa::path(
+    get,
+    path = "/index/tracks",
+    params(
+        ("skip" = Option<u32>, Query, description = "Amount of track indices to skip. Ignored when a cursor is provided"),
+        ("count" = u32, Query, description = "Amount of tracks to provide"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned by the previous page as `next_cursor`"),
+        ("search" = Option<String>, Query, description = "Optional search query. Only the 1000 most relevant entries are matched"),
+        ("genre" = Option<String>, Query, description = "Optional genre to filter by"),
+        ("artist" = Option<Uuid>, Query, description = "Only include tracks by this artist"),
+        ("year_from" = Option<i32>, Query, description = "Only include tracks released in this year or later"),
+        ("year_to" = Option<i32>, Query, description = "Only include tracks released in this year or earlier"),
+        ("format" = Option<TrackFormat>, Query, description = "Only include tracks in this format"),
+        ("explicit" = Option<bool>, Query, description = "Only include explicit tracks if true, or only clean tracks if false"),
+        ("loved_only" = Option<bool>, Query, description = "Only include tracks loved by this user"),
+        ("album_type" = Option<AlbumType>, Query, description = "Only include tracks from albums of this type"),
+        ("added_within" = Option<u32>, Query, description = "Only include tracks added within this amount of days"),
+        ("sort" = Option<IndexSort>, Query, description = "Key to sort tracks by"),
+        ("order" = Option<SortOrder>, Query, description = "Direction to sort tracks in"),
+    ),
+    responses(
+        (status = 200, body = [IndexedTrack], description = "Successfully fetched track index"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "index"
+)]
+pub async fn index_tracks(
+    state: State<AppState>,
+    params: Query<IndexParameters>,
+    user: AuthenticatedUser
+) -> Res<Json<Vec<IndexedTrack>>> {
+    let Json(page) = index_tracks_page(state, params, user).await?;
+    Ok(Json(page.items))
+}
+
+/// Condition on albums or tracks related to an index entry
+struct RelatedCondition {
+    /// Collection of the related entries
+    from: &'static str,
+    /// Field of the index entry referencing the related entries
+    local_field: &'static str,
+    /// Field of the related entries the references point to
+    foreign_field: &'static str,
+    /// Condition the related entries have to match
+    condition: Document,
+    /// Whether any related entry has to match, or none of them may match
+    any: bool,
+}
+
+impl RelatedCondition {
+    fn albums(condition: Document) -> Self {
+        Self { from: "albums_metadata", local_field: "albums", foreign_field: "album_id", condition, any: true }
+    }
+
+    fn tracks(condition: Document, any: bool) -> Self {
+        Self { from: "tracks_metadata", local_field: "tracks", foreign_field: "track_id", condition, any }
+    }
+
+    /// Stages looking up the first matching related entry and filtering by its presence
+    fn stages(self, idx: usize) -> [Document; 3] {
+        let field = format!("related_{idx}");
+        [
+            doc! {
+                "$lookup": {
+                    "from": self.from,
+                    "localField": self.local_field,
+                    "foreignField": self.foreign_field,
+                    "pipeline": [{ "$match": self.condition }, { "$limit": 1 }, { "$project": { "_id": 1 } }],
+                    "as": &field,
+                }
+            },
+            doc! { "$match": { format!("{field}.0"): { "$exists": self.any } } },
+            doc! { "$unset": field },
+        ]
+    }
+}
+
+/// Builds the stages filtering index entries from index parameters, along with IDs of entries matching the search query
+/// ordered by relevance.
+///
+/// Filters that depend on other collections (e.g. release year for tracks) look up the related entries
+/// inside the pipeline, so the same stages can be used to count all matching entries.
+async fn build_index_filter(search_index: &SearchIndex, kind: IndexKind, params: &IndexParameters, user: &UserAccount) -> Res<(Vec<Document>, Option<Vec<Bson>>)> {
+    let mut filter = doc! { };
+    let mut ranking = None;
+    if let Some(search) = params.search.clone() {
+        // searching the index blocks, so it runs outside of the async runtime
+        let search_index = search_index.clone();
+        let found = tokio::task::spawn_blocking(move || search_index.search(&search, Some(kind.search_kind()), MAX_SEARCH_MATCHES)).await
+            .map_err(|err| AstralError::Unknown(err.into()))??;
+        let ids = found.into_iter()
+            .map(|each| Bson::from(BsonId::from_uuid_1(each.id)))
+            .collect::<Vec<_>>();
+        filter.insert(kind.id_field(), doc! { "$in": &ids });
+        ranking = Some(ids);
+    }
+    let mut conditions: Vec<Document> = vec![];
+    let mut related: Vec<RelatedCondition> = vec![];
+
+    if let Some(genre) = params.genre.as_deref().and_then(normalize_genre) {
+        match kind {
+            IndexKind::Artists => {
+                let key = format!("genres.{genre}");
+                conditions.push(doc! { key: { "$exists": true } })
+            }
+            _ => conditions.push(doc! { "genres": genre }),
+        }
+    }
+    if let Some(artist) = params.artist {
+        if kind != IndexKind::Artists {
+            conditions.push(doc! { "artists": BsonId::from_uuid_1(artist) });
+        }
+    }
+    if params.year_from.is_some() || params.year_to.is_some() {
+        let mut range = doc! { };
+        if let Some(from) = params.year_from {
+            range.insert("$gte", year_start_millis(from)?);
+        }
+        if let Some(to) = params.year_to {
+            range.insert("$lt", year_start_millis(to + 1)?);
+        }
+        match kind {
+            IndexKind::Albums => conditions.push(doc! { "release_date": range }),
+            _ => related.push(RelatedCondition::albums(doc! { "release_date": range })),
+        }
+    }
+    if let Some(format) = params.format {
+        let format = to_bson(&format).map_err(anyhow::Error::from)?;
+        match kind {
+            IndexKind::Tracks => conditions.push(doc! { "format": format }),
+            _ => related.push(RelatedCondition::tracks(doc! { "format": format }, true)),
+        }
+    }
+    if let Some(explicit) = params.explicit {
+        match kind {
+            IndexKind::Tracks => conditions.push(doc! { "is_explicit": explicit }),
+            _ => related.push(RelatedCondition::tracks(doc! { "is_explicit": true }, explicit)),
+        }
+    }
+    if params.loved_only.unwrap_or(false) {
+        match kind {
+            IndexKind::Albums => conditions.push(doc! { "album_id": { "$in": &user.loved_albums } }),
+            IndexKind::Artists => conditions.push(doc! { "tracks": { "$in": &user.loved_tracks } }),
+            IndexKind::Tracks => conditions.push(doc! { "track_id": { "$in": &user.loved_tracks } }),
+        }
+    }
+    if let Some(album_type) = params.album_type {
+        let mut types = vec![to_bson(&album_type).map_err(anyhow::Error::from)?];
+        if album_type == AlbumType::default() {
+            // albums created before album types were introduced do not have the field and are regular albums
+            types.push(Bson::Null);
+        }
+        let matching = doc! { "album_type": { "$in": types } };
+        match kind {
+            IndexKind::Albums => conditions.push(matching),
+            _ => related.push(RelatedCondition::albums(matching)),
+        }
+    }
+    if let Some(days) = params.added_within {
+        let since = Utc::now() - Duration::days(days as i64);
+        conditions.push(doc! { "added_at": { "$gte": since.timestamp_millis() } });
+    }
+
+    if !conditions.is_empty() {
+        filter.insert("$and", conditions);
+    }
+    let mut stages = vec![doc! { "$match": filter }];
+    for (idx, condition) in related.into_iter().enumerate() {
+        stages.extend(condition.stages(idx));
+    }
+    Ok((stages, ranking))
+}
+
+/// Counts all index entries passing the filter stages
+async fn count_index_entries<T: Send + Sync>(collection: &Repo<T>, mut filter: Vec<Document>) -> Res<u64> {
+    filter.push(doc! { "$count": "total" });
+    let counted = collection.aggregate(filter).await?.next().await.transpose()?;
+    Ok(match counted.as_ref().and_then(|it| it.get("total")) {
+        Some(Bson::Int32(total)) => *total as u64,
+        Some(Bson::Int64(total)) => *total as u64,
+        _ => 0,
+    })
+}
+
+/// Sorting of index entries
+struct SortPlan {
+    /// Stages calculating the `sort_key` field
+    stages: Vec<Document>,
+    /// Fields to sort by along with their direction, ending with a unique field
+    keys: Vec<(&'static str, i32)>,
+}
+
+/// Builds the sort plan for index entries, calculating aggregated sort keys if needed.
+///
+/// Sort keys are always calculated into a separate `sort_key` field that is never null,
+/// as keyset comparisons do not match across null and numeric values.
+fn build_sort_plan(kind: IndexKind, params: &IndexParameters, ranking: Option<Vec<Bson>>) -> Res<SortPlan> {
+    let direction = params.order.direction();
+    let sort = match (params.sort, ranking) {
+        (None, Some(ranking)) => {
+            let relevance = doc! { "$indexOfArray": [ranking, format!("${}", kind.id_field())] };
+            return Ok(SortPlan {
+                stages: vec![doc! { "$addFields": { "sort_key": relevance } }],
+                keys: vec![("sort_key", direction), ("_id", 1)],
+            })
+        }
+        (None, None) => IndexSort::Name,
+        (Some(sort), _) => sort,
+    };
+
+    let mut stages = vec![];
+    let sort_key = match (sort, kind) {
+        (IndexSort::Name, _) => return Ok(SortPlan { stages, keys: vec![("name", direction), ("_id", 1)] }),
+        (IndexSort::DateAdded, _) => bson!({ "$ifNull": ["$added_at", 0] }),
+        (IndexSort::ReleaseDate, IndexKind::Albums) => bson!({ "$ifNull": ["$release_date", 0] }),
+        (IndexSort::ReleaseDate, IndexKind::Artists) => return Err(AstralError::BadRequest(String::from("Artists can not be sorted by release date"))),
+        (IndexSort::ReleaseDate, IndexKind::Tracks) => {
+            stages.push(doc! {
+                "$lookup": {
+                    "from": "albums_metadata",
+                    "localField": "albums",
+                    "foreignField": "album_id",
+                    "as": "sort_albums"
+                }
+            });
+            bson!({ "$ifNull": [{ "$max": "$sort_albums.release_date" }, 0] })
+        }
+        (IndexSort::Duration, IndexKind::Tracks) => bson!({ "$ifNull": ["$length", 0] }),
+        (IndexSort::PlayCount, IndexKind::Tracks) => bson!({ "$ifNull": ["$play_count", 0] }),
+        (IndexSort::Duration | IndexSort::PlayCount, _) => {
+            stages.push(doc! {
+                "$lookup": {
+                    "from": "tracks_metadata",
+                    "localField": "tracks",
+                    "foreignField": "track_id",
+                    "as": "sort_tracks"
+                }
+            });
+            if sort == IndexSort::Duration {
+                bson!({ "$sum": "$sort_tracks.length" })
+            } else {
+                bson!({ "$sum": "$sort_tracks.play_count" })
+            }
+        }
+    };
+    stages.push(doc! { "$addFields": { "sort_key": sort_key } });
+    stages.push(doc! { "$project": { "sort_albums": 0, "sort_tracks": 0 } });
+    Ok(SortPlan { stages, keys: vec![("sort_key", direction), ("name", 1), ("_id", 1)] })
+}
+
+/// Opaque position in the index, handed out to clients as a hex encoded token
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexCursor {
+    /// Names of sort keys the cursor was created for
+    keys: Vec<String>,
+    /// Values of sort keys of the last returned entry
+    values: Vec<Bson>,
+}
+
+impl IndexCursor {
+    fn encode(&self) -> Res<String> {
+        let bytes = mongodb::bson::to_vec(self).map_err(anyhow::Error::from)?;
+        Ok(hex::encode(bytes))
+    }
+
+    fn decode(token: &str) -> Res<Self> {
+        hex::decode(token).ok()
+            .and_then(|bytes| mongodb::bson::from_slice(&bytes).ok())
+            .ok_or_else(|| AstralError::BadRequest(String::from("Invalid cursor")))
+    }
+}
+
+/// Builds a filter matching entries placed after the cursor values in the sort order
+fn keyset_filter(keys: &[(&'static str, i32)], values: &[Bson]) -> Document {
+    let branches = (0..keys.len()).map(|idx| {
+        let mut branch = doc! { };
+        for ((key, _), value) in keys[..idx].iter().zip(values) {
+            branch.insert(*key, value.clone());
+        }
+        let (key, direction) = keys[idx];
+        let operator = if direction > 0 { "$gt" } else { "$lt" };
+        branch.insert(key, doc! { operator: values[idx].clone() });
+        branch
+    }).collect::<Vec<_>>();
+    doc! { "$or": branches }
+}
+
+/// Fetches a single page of index entries along with the cursor to the next page.
+///
+/// Additional stages are only run on entries of the page, so they should be used for lookups.
+async fn fetch_index_page<T: Send + Sync>(
+    collection: &Repo<T>,
+    kind: IndexKind,
+    params: &IndexParameters,
+    filter: Vec<Document>,
+    ranking: Option<Vec<Bson>>,
+    additional_stages: Vec<Document>,
+) -> Res<(Vec<Document>, Option<String>)> {
+    let plan = build_sort_plan(kind, params, ranking)?;
+    let cursor = params.cursor.as_deref().map(IndexCursor::decode).transpose()?;
+
+    let mut pipeline = filter;
+    pipeline.extend(plan.stages);
+    match cursor {
+        Some(IndexCursor { keys, values }) => {
+            if plan.keys.len() != keys.len() || plan.keys.iter().zip(&keys).any(|((expected, _), actual)| expected != actual) || keys.len() != values.len() {
+                return Err(AstralError::BadRequest(String::from("Cursor does not match the requested sorting")))
+            }
+            pipeline.push(doc! { "$match": keyset_filter(&plan.keys, &values) });
+            pipeline.push(doc! { "$sort": sort_document(&plan.keys) });
+        }
+        None => {
+            pipeline.push(doc! { "$sort": sort_document(&plan.keys) });
+            pipeline.push(doc! { "$skip": params.skip });
+        }
+    }
+    pipeline.push(doc! { "$limit": params.count });
+    pipeline.extend(additional_stages);
+
+    let found = collection.aggregate(pipeline).await?
         .filter_map(|each| async { each.ok() })
-        .map(|each| extract_indexed_track(each, &user))
-        .filter_map(|each| async { each.ok() });
+        .collect::<Vec<_>>().await;
+
+    let next_cursor = match found.last() {
+        Some(last) if found.len() >= params.count as usize => Some(IndexCursor {
+            keys: plan.keys.iter().map(|(key, _)| key.to_string()).collect(),
+            values: plan.keys.iter().map(|(key, _)| last.get(*key).cloned().unwrap_or(Bson::Null)).collect(),
+        }.encode()?),
+        _ => None,
+    };
+    Ok((found, next_cursor))
+}
+
+fn sort_document(keys: &[(&'static str, i32)]) -> Document {
+    keys.iter().map(|(key, direction)| (key.to_string(), Bson::Int32(*direction))).collect()
+}
+
+/// Millisecond timestamp of the first moment of the year
+fn year_start_millis(year: i32) -> Res<i64> {
+    NaiveDate::from_ymd_opt(year, 1, 1)
+        .and_then(|it| it.and_hms_opt(0, 0, 0))
+        .map(|it| it.and_utc().timestamp_millis())
+        .ok_or_else(|| AstralError::BadRequest(format!("Invalid year: {year}")))
+}
+
+/// Fetches all genres present in the library along with their usage statistics
+#[utoipa::path(
+    get,
+    path = "/index/genres",
+    responses(
+        (status = 200, body = [IndexedGenre], description = "Successfully fetched genre index"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "index"
+)]
+pub async fn index_genres(
+    State(AppState { db, .. }): State<AppState>,
+    AuthenticatedUser(_): AuthenticatedUser
+) -> Res<Json<Vec<IndexedGenre>>> {
+    let tracks = count_track_genres(&db, doc! { }).await?;
+    let albums = count_album_genres(&db).await?;
+    let artists = count_artist_genres(&db).await?;
+
+    // albums can have manually assigned genres none of their tracks have
+    let names = tracks.keys().chain(albums.keys()).collect::<HashSet<_>>();
+    let mut genres = names.into_iter()
+        .map(|name| IndexedGenre {
+            name: name.clone(),
+            parent: parent_genre(name).map(String::from),
+            track_count: tracks.get(name).copied().unwrap_or(0),
+            album_count: albums.get(name).copied().unwrap_or(0),
+            artist_count: artists.get(name).copied().unwrap_or(0),
+        })
+        .collect::<Vec<_>>();
+    genres.sort_by(|a, b| b.track_count.cmp(&a.track_count).then(a.name.cmp(&b.name)));
 
-    Ok(Json(mapped.collect::<Vec<_>>().await))
+    Ok(Json(genres))
 }
 
 
-fn extract_indexed_track(doc: Document, user: &UserAccount) -> Res<IndexedTrack> {
+pub(crate) fn extract_indexed_track(doc: Document, user: &UserAccount) -> Res<IndexedTrack> {
     let id = from_bson::<BsonId>(doc.get("track_id").unwrap().to_owned())?;
     if doc.get_array("albums").unwrap().is_empty() {
         return Err(AstralError::NotFound("Invalid track data".to_owned()))
@@ -196,14 +752,14 @@ fn extract_indexed_track(doc: Document, user: &UserAccount) -> Res<IndexedTrack>
     })
 }
 
-fn extract_indexed_artist(doc: Document) -> Res<IndexedArtist> {
+pub(crate) fn extract_indexed_artist(doc: Document) -> Res<IndexedArtist> {
     Ok(IndexedArtist {
         id: from_bson::<BsonId>(doc.get("artist_id").unwrap().to_owned())?.to_uuid_1(),
         name: doc.get_str("name")?.to_owned(),
     })
 }
 
-fn extract_indexed_album(doc: Document, user: &UserAccount) -> Res<IndexedAlbum> {
+pub(crate) fn extract_indexed_album(doc: Document, user: &UserAccount) -> Res<IndexedAlbum> {
     let id = from_bson::<BsonId>(doc.get("album_id").unwrap().to_owned())?;
     Ok(IndexedAlbum {
         id: id.to_uuid_1(),
@@ -216,8 +772,11 @@ fn extract_indexed_album(doc: Document, user: &UserAccount) -> Res<IndexedAlbum>
             )
             .collect(),
         tracks: from_bson::<Vec<BsonId>>(doc.get("tracks").unwrap().to_owned())?.into_iter().map(BsonId::to_uuid_1).collect(),
-        release_date: NaiveDateTime::from_timestamp_millis(doc.get_i64("release_date")?).unwrap().and_utc(),
+        release_date: DateTime::from_timestamp_millis(doc.get_i64("release_date")?).unwrap_or_default(),
         genres: from_bson(doc.get("genres").unwrap().to_owned())?,
+        palette: doc.get("palette").map(|it| from_bson(it.to_owned())).transpose()?.flatten(),
+        album_type: doc.get("album_type").map(|it| from_bson(it.to_owned())).transpose()?.unwrap_or_default(),
+        edition: doc.get("edition").map(|it| from_bson(it.to_owned())).transpose()?.flatten(),
         loved: user.loved_albums.contains(&id)
     })
 }
\ No newline at end of file
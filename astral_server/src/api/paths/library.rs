@@ -0,0 +1,202 @@
+use axum::extract::{Path, Query, State};
+use axum::Json;
+use serde::Deserialize;
+use uuid::Uuid;
+use crate::api::AppState;
+use crate::api::extensions::{AuthenticatedUser, UserPermission};
+use crate::api::model::{AlbumMetadataResponse, ArtistMetadataResponse, MergeEntriesRequest, ResolveDuplicatesRequest, SplitAlbumRequest};
+use crate::api::paths::metadata::{extract_album_metadata, extract_artist_metadata};
+use crate::data::integrity::{check_integrity, IntegrityIssue};
+use crate::data::model::{BsonId, UserAccount};
+use crate::err::AstralError;
+use crate::metadata::duplicates::{find_duplicates, resolve_duplicates, DuplicateGroup};
+use crate::metadata::merge::{merge_albums, merge_artists, split_album};
+use crate::Res;
+
+fn require_admin(user: &UserAccount) -> Res<()> {
+    if !user.permissions.contains(&UserPermission::Admin) {
+        return Err(AstralError::Unauthorized(String::from("Only admins can perform library maintenance.")))
+    }
+    Ok(())
+}
+
+/// Merges duplicate artists into this one. Tracks and albums of the duplicates are moved to this artist,
+/// their names are kept as aliases so future uploads are assigned to this artist, and the duplicates are removed.
+/// Only available to admins.
+#[utoipa::path(
+    post,
+    path = "/library/artist/{uuid}/merge",
+    request_body = MergeEntriesRequest,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, response = ArtistMetadataResponse)
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the artist that the duplicates are merged into"),
+    ),
+    tag = "library"
+)]
+pub async fn merge_artist(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Path(artist_id): Path<Uuid>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    Json(MergeEntriesRequest { duplicates }): Json<MergeEntriesRequest>
+) -> Res<Json<ArtistMetadataResponse>> {
+    require_admin(&user)?;
+
+    let id = BsonId::from_uuid_1(artist_id);
+    let duplicates = duplicates.into_iter().map(BsonId::from_uuid_1).collect::<Vec<_>>();
+    merge_artists(&db, &search_index, &id, &duplicates).await?;
+
+    Ok(Json(ArtistMetadataResponse {
+        artist_id,
+        metadata: extract_artist_metadata(&db, id).await?
+    }))
+}
+
+/// Merges duplicate albums into this one. Tracks of the duplicates are moved to this album,
+/// their names are kept as aliases so future uploads are assigned to this album, and the duplicates are removed.
+/// Only available to admins.
+#[utoipa::path(
+    post,
+    path = "/library/album/{uuid}/merge",
+    request_body = MergeEntriesRequest,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, response = AlbumMetadataResponse)
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the album that the duplicates are merged into"),
+    ),
+    tag = "library"
+)]
+pub async fn merge_album(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Path(album_id): Path<Uuid>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    Json(MergeEntriesRequest { duplicates }): Json<MergeEntriesRequest>
+) -> Res<Json<AlbumMetadataResponse>> {
+    require_admin(&user)?;
+
+    let id = BsonId::from_uuid_1(album_id);
+    let duplicates = duplicates.into_iter().map(BsonId::from_uuid_1).collect::<Vec<_>>();
+    merge_albums(&db, &search_index, &id, &duplicates).await?;
+
+    // loved duplicates are now loved through the merged album
+    let loved = user.loved_albums.contains(&id) || user.loved_albums.iter().any(|it| duplicates.contains(it));
+    Ok(Json(AlbumMetadataResponse {
+        album_id,
+        metadata: extract_album_metadata(&db, id, &user).await?,
+        loved
+    }))
+}
+
+/// Moves some tracks of a wrongly merged album into a new album. Returns metadata of the new album.
+/// Only available to admins.
+#[utoipa::path(
+    post,
+    path = "/library/album/{uuid}/split",
+    request_body = SplitAlbumRequest,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, response = AlbumMetadataResponse)
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the album to split"),
+    ),
+    tag = "library"
+)]
+pub async fn split_album_tracks(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Path(album_id): Path<Uuid>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    Json(SplitAlbumRequest { tracks, album_name, artists }): Json<SplitAlbumRequest>
+) -> Res<Json<AlbumMetadataResponse>> {
+    require_admin(&user)?;
+
+    let tracks = tracks.into_iter().map(BsonId::from_uuid_1).collect::<Vec<_>>();
+    let artists = artists.map(|it| it.into_iter().map(BsonId::from_uuid_1).collect());
+    let new_id = split_album(&db, &search_index, &BsonId::from_uuid_1(album_id), &tracks, album_name, artists).await?;
+
+    Ok(Json(AlbumMetadataResponse {
+        album_id: new_id.to_uuid_1(),
+        metadata: extract_album_metadata(&db, new_id, &user).await?,
+        loved: false
+    }))
+}
+
+/// Finds tracks that are likely duplicates of each other: identical files, tracks with matching acoustic
+/// fingerprints, or tracks with the same title and artists and close lengths, possibly in different formats.
+/// Only available to admins.
+#[utoipa::path(
+    get,
+    path = "/library/duplicates",
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, body = Vec<DuplicateGroup>, description = "Groups of likely duplicate tracks, best quality track first")
+    ),
+    tag = "library"
+)]
+pub async fn duplicate_report(
+    State(AppState { db, .. }): State<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Res<Json<Vec<DuplicateGroup>>> {
+    require_admin(&user)?;
+    Ok(Json(find_duplicates(&db).await?))
+}
+
+/// Keeps one track out of a group of duplicates and deletes the rest. Play counts, likes, lyrics
+/// and artwork of the deleted tracks are carried over to the kept one, which also takes their place in albums.
+/// Only available to admins.
+#[utoipa::path(
+    post,
+    path = "/library/duplicates/resolve",
+    request_body = ResolveDuplicatesRequest,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, body = (), description = "Successfully deleted duplicate tracks")
+    ),
+    tag = "library"
+)]
+pub async fn resolve_duplicate_tracks(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    Json(ResolveDuplicatesRequest { keep, remove }): Json<ResolveDuplicatesRequest>
+) -> Res<()> {
+    require_admin(&user)?;
+
+    let remove = remove.into_iter().map(BsonId::from_uuid_1).collect::<Vec<_>>();
+    resolve_duplicates(&db, &search_index, &BsonId::from_uuid_1(keep), &remove).await
+}
+
+#[derive(Deserialize)]
+pub struct IntegrityProps {
+    repair: Option<bool>,
+    remove_missing_tracks: Option<bool>,
+}
+
+/// Scans the library for inconsistencies, such as tracks with missing audio files, audio files without tracks,
+/// empty albums, references to deleted entries, and lyrics or artwork of deleted entries.
+/// Found issues are fixed if `repair` is set, tracks with missing audio files are only removed if `remove_missing_tracks`
+/// is set as well. Only available to admins.
+#[utoipa::path(
+    post,
+    path = "/library/integrity",
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, body = Vec<IntegrityIssue>, description = "All found issues, already fixed if `repair` was set")
+    ),
+    params(
+        ("repair" = inline(Option<bool>), Query, description = "Whether to also fix found issues"),
+        ("remove_missing_tracks" = inline(Option<bool>), Query, description = "Whether repairing also removes tracks with missing audio files"),
+    ),
+    tag = "library"
+)]
+pub async fn integrity_check(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Query(IntegrityProps { repair, remove_missing_tracks }): Query<IntegrityProps>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Res<Json<Vec<IntegrityIssue>>> {
+    require_admin(&user)?;
+    Ok(Json(check_integrity(&db, &search_index, repair.unwrap_or(false), remove_missing_tracks.unwrap_or(false)).await?))
+}
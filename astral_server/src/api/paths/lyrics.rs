@@ -10,6 +10,7 @@ use crate::api::model::LyricsResponse;
 use crate::data::model::{BsonId, LyricsStatus, SyncedLyricLine, TrackLyrics};
 use crate::err::AstralError;
 use crate::metadata::musix::musix_request;
+use crate::search::engine::reindex_entries;
 use crate::Res;
 
 /// Fetches lyrics (internally from MusixMatch)
@@ -27,12 +28,12 @@ use crate::Res;
 )]
 
 pub async fn get_lyrics(
-    State(AppState { db, .. }): State<AppState>,
+    State(AppState { db, search_index, .. }): State<AppState>,
     Path(uuid): Path<Uuid>,
     AuthenticatedUser(_): AuthenticatedUser
 ) -> Res<Json<LyricsResponse>> {
     let uuid = BsonId::from_uuid_1(uuid);
-    let found_lyrics = db.lyrics.find_one(doc! { "track_id": uuid }, None).await?;
+    let found_lyrics = db.lyrics.find_one(doc! { "track_id": uuid }).await?;
     if let Some(found_lyrics) = found_lyrics {
         return match found_lyrics.status {
             LyricsStatus::NoLyrics { .. } => Ok(Json(LyricsResponse::NoLyrics)),
@@ -41,14 +42,15 @@ pub async fn get_lyrics(
         };
     }
 
-    let track = db.tracks_metadata.find_one(doc! { "track_id": uuid }, None).await?
+    let track = db.tracks_metadata.find_one(doc! { "track_id": uuid }).await?
         .ok_or_else(|| AstralError::NotFound(String::from("Could not find a track with this UUID")))?;
-    let artist = db.artists_metadata.find_one(doc! { "artist_id": { "$in": &track.artists } }, None).await?.map(|it| it.name).unwrap_or(String::new());
-    let album = db.albums_metadata.find_one(doc! { "album_id": { "$in": &track.albums } }, None).await?.map(|it| it.name).unwrap_or(String::new());
+    let artist = db.artists_metadata.find_one(doc! { "artist_id": { "$in": &track.artists } }).await?.map(|it| it.name).unwrap_or(String::new());
+    let album = db.albums_metadata.find_one(doc! { "album_id": { "$in": &track.albums } }).await?.map(|it| it.name).unwrap_or(String::new());
 
     let lyrics = fetch_musixmatch_lyrics(track.name, artist, Some(album), None).await?;
     let lyrics = TrackLyrics { track_id: uuid, status: lyrics };
-    db.lyrics.insert_one(&lyrics, None).await?;
+    db.lyrics.insert_one(&lyrics).await?;
+    reindex_entries(&db, &search_index, &[uuid], &[], &[]).await?;
 
     return match lyrics.status {
         LyricsStatus::NoLyrics { .. } => Ok(Json(LyricsResponse::NoLyrics)),
@@ -1,30 +1,32 @@
-use std::str::FromStr;
+use std::cmp::Reverse;
 use axum::extract::{Path, Query, State};
 use axum::{Json};
 use axum::body::Body;
-use axum::response::IntoResponse;
-use axum_extra::headers::ContentType;
-use axum_extra::TypedHeader;
-use chrono::NaiveDateTime;
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap as HttpHeaders, HeaderValue as HttpHeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::DateTime;
 use futures_util::{StreamExt};
 use mongodb::bson::doc;
-use mongodb::GridFsDownloadStream;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Url;
 use serde::Deserialize;
 use serde_json::Value;
-use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
 use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 use crate::api::AppState;
 use crate::api::extensions::AuthenticatedUser;
-use crate::api::model::{TrackMetadataResponse, AlbumMetadataResponse, ArtistMetadataResponse, FullTrackMetadata, MinifiedArtistMetadata, MinifiedAlbumMetadata, MinifiedTrackMetadata, FullArtistMetadata, FullAlbumMetadata};
+use crate::api::model::{AlbumDiscMetadata, AlbumTypeGroup, TrackMetadataResponse, AlbumMetadataResponse, ArtistMetadataResponse, FullTrackMetadata, MinifiedArtistMetadata, MinifiedAlbumMetadata, MinifiedTrackMetadata, FullArtistMetadata, FullAlbumMetadata};
 use crate::data::AstralDatabase;
-use crate::data::model::{AlbumMetadata, ArtistMetadata, BsonId, TrackMetadata, UserAccount};
+use crate::data::model::{AlbumDisc, AlbumMetadata, ArtistMetadata, BsonId, TrackMetadata, UserAccount};
 use crate::err::AstralError;
-use crate::metadata::ExtractedTrackMetadata;
+use crate::metadata::artwork::{ArtworkFile, ArtworkKind, CoverFormat, find_artwork};
+use crate::metadata::genres::{top_genre_limit, top_genres};
 use crate::Res;
 
+/// Cache policy of artwork. Artwork rarely changes, and clients revalidate them with the `ETag` once stale.
+const ARTWORK_CACHE_CONTROL: &str = "public, max-age=86400";
+
 /// Gets full metadata of a single track
 #[utoipa::path(
     get,
@@ -109,15 +111,31 @@ pub async fn get_album_metadata(
     ))
 }
 
+/// Parameters used for fetching cover art
+#[derive(Debug, Deserialize)]
+pub struct CoverParameters {
+    /// Size of the square the cover should fit in. Original cover is returned if not provided.
+    pub size: Option<u32>,
+    /// Format of the resized cover
+    #[serde(default)]
+    pub format: CoverFormat,
+}
+
 /// Gets cover art of an album
+///
+/// If `size` is provided, a resized variant is returned. Variants are generated on first request and cached.
+/// Responses carry an `ETag`, so clients can revalidate covers with `If-None-Match`.
 #[utoipa::path(
     get,
     path = "/metadata/album/{id}/cover",
     params(
-        ("id" = Uuid, Path, description = "UUID of the album")
+        ("id" = Uuid, Path, description = "UUID of the album"),
+        ("size" = Option<u32>, Query, description = "Size of the square the cover should fit in, rounded up to one of 64, 128, 256, 512 or 1024"),
+        ("format" = Option<CoverFormat>, Query, description = "Format of the resized cover. Defaults to `jpeg`"),
     ),
     responses(
         (status = 200, body = BinaryFile, description = "Found the album cover art"),
+        (status = 304, description = "Cover art did not change since it was last fetched"),
         (status = 400, response = AstralError)
     ),
     tag = "metadata"
@@ -125,58 +143,129 @@ pub async fn get_album_metadata(
 pub async fn get_album_cover_art(
     State(AppState { db, .. }): State<AppState>,
     Path(uuid): Path<Uuid>,
+    Query(params): Query<CoverParameters>,
+    headers: HttpHeaders,
 //    AuthenticatedUser(_): AuthenticatedUser,
-) -> Res<(TypedHeader<ContentType>, impl IntoResponse)> {
-    let metadata = db.gridfs_album_arts.find(doc! { "filename": uuid.to_string() }, None).await?.next().await
-        .ok_or_else(|| AstralError::NotFound(String::from("Couldn't find album cover for this UUID")))??.metadata;
-    let metadata = metadata.unwrap();
-    let mime_type = metadata.get("mime_type").unwrap();
-    let mime_type = mime_type.as_str().unwrap();
-    let download_stream = db.gridfs_album_arts.open_download_stream_by_name(uuid.to_string(), None).await?;
-    let stream_body = Body::from_stream(ReaderStream::new(download_stream.compat()));
-    Ok(
-        (
-            TypedHeader(ContentType::from_str(mime_type).unwrap()),
-            stream_body
-        )
-    )
+) -> Res<Response> {
+    let cover = find_artwork(&db, ArtworkKind::AlbumCover, &BsonId::from_uuid_1(uuid), params.size, params.format).await?
+        .ok_or_else(|| AstralError::NotFound(String::from("Couldn't find album cover for this UUID")))?;
+    artwork_response(&db, cover, &headers).await
 }
 
-/// Gets cover art of a track (by querying it's album)
+/// Gets cover art of a track. Falls back to the cover of its album if the track does not have its own artwork.
 #[utoipa::path(
     get,
     path = "/metadata/track/{id}/cover",
     params(
-        ("id" = Uuid, Path, description = "UUID of the track")
+        ("id" = Uuid, Path, description = "UUID of the track"),
+        ("size" = Option<u32>, Query, description = "Size of the square the cover should fit in, rounded up to one of 64, 128, 256, 512 or 1024"),
+        ("format" = Option<CoverFormat>, Query, description = "Format of the resized cover. Defaults to `jpeg`"),
     ),
     responses(
-    (status = 200, body = BinaryFile, description = "Found the album cover art"),
-    (status = 400, response = AstralError)
+        (status = 200, body = BinaryFile, description = "Found the album cover art"),
+        (status = 304, description = "Cover art did not change since it was last fetched"),
+        (status = 400, response = AstralError)
     ),
     tag = "metadata"
 )]
 pub async fn get_track_cover_art(
     State(AppState { db, .. }): State<AppState>,
     Path(uuid): Path<Uuid>,
+    Query(params): Query<CoverParameters>,
+    headers: HttpHeaders,
 //    AuthenticatedUser(_): AuthenticatedUser,
-) -> Res<(TypedHeader<ContentType>, impl IntoResponse)> {
-    let track = db.tracks_metadata.find_one(doc! { "track_id": uuid }, None).await?
+) -> Res<Response> {
+    let track = db.tracks_metadata.find_one(doc! { "track_id": uuid }).await?
         .ok_or_else(|| AstralError::NotFound(String::from("Couldn't find track with this UUID")))?;
+    if let Some(art) = find_artwork(&db, ArtworkKind::TrackArt, &track.track_id, params.size, params.format).await? {
+        return artwork_response(&db, art, &headers).await
+    }
+
     let album_id = track.albums.first().map(ToOwned::to_owned)
         .ok_or_else(|| AstralError::NotFound(String::from("This track does not have any albums associated with it")))?;
-    let metadata = db.gridfs_album_arts.find(doc! { "filename": album_id.to_string() }, None).await?.next().await
-        .ok_or_else(|| AstralError::NotFound(String::from("Album for this track does not have a cover")))??.metadata;
-    let metadata = metadata.unwrap();
-    let mime_type = metadata.get("mime_type").unwrap();
-    let mime_type = mime_type.as_str().unwrap();
-    let download_stream = db.gridfs_album_arts.open_download_stream_by_name(album_id.to_string(), None).await?;
-    let stream_body = Body::from_stream(ReaderStream::new(download_stream.compat()));
-    Ok(
-        (
-            TypedHeader(ContentType::from_str(mime_type).unwrap()),
-            stream_body
-        )
-    )
+    let cover = find_artwork(&db, ArtworkKind::AlbumCover, &album_id, params.size, params.format).await?
+        .ok_or_else(|| AstralError::NotFound(String::from("Album for this track does not have a cover")))?;
+    artwork_response(&db, cover, &headers).await
+}
+
+/// Gets photo of an artist
+#[utoipa::path(
+    get,
+    path = "/metadata/artist/{id}/photo",
+    params(
+        ("id" = Uuid, Path, description = "UUID of the artist"),
+        ("size" = Option<u32>, Query, description = "Size of the square the photo should fit in, rounded up to one of 64, 128, 256, 512 or 1024"),
+        ("format" = Option<CoverFormat>, Query, description = "Format of the resized photo. Defaults to `jpeg`"),
+    ),
+    responses(
+        (status = 200, body = BinaryFile, description = "Found the artist photo"),
+        (status = 304, description = "Photo did not change since it was last fetched"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "metadata"
+)]
+pub async fn get_artist_photo(
+    State(AppState { db, .. }): State<AppState>,
+    Path(uuid): Path<Uuid>,
+    Query(params): Query<CoverParameters>,
+    headers: HttpHeaders,
+) -> Res<Response> {
+    let photo = find_artwork(&db, ArtworkKind::ArtistPhoto, &BsonId::from_uuid_1(uuid), params.size, params.format).await?
+        .ok_or_else(|| AstralError::NotFound(String::from("Couldn't find artist photo for this UUID")))?;
+    artwork_response(&db, photo, &headers).await
+}
+
+/// Gets banner of an artist
+#[utoipa::path(
+    get,
+    path = "/metadata/artist/{id}/banner",
+    params(
+        ("id" = Uuid, Path, description = "UUID of the artist"),
+        ("size" = Option<u32>, Query, description = "Size of the square the banner should fit in, rounded up to one of 64, 128, 256, 512 or 1024"),
+        ("format" = Option<CoverFormat>, Query, description = "Format of the resized banner. Defaults to `jpeg`"),
+    ),
+    responses(
+        (status = 200, body = BinaryFile, description = "Found the artist banner"),
+        (status = 304, description = "Banner did not change since it was last fetched"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "metadata"
+)]
+pub async fn get_artist_banner(
+    State(AppState { db, .. }): State<AppState>,
+    Path(uuid): Path<Uuid>,
+    Query(params): Query<CoverParameters>,
+    headers: HttpHeaders,
+) -> Res<Response> {
+    let banner = find_artwork(&db, ArtworkKind::ArtistBanner, &BsonId::from_uuid_1(uuid), params.size, params.format).await?
+        .ok_or_else(|| AstralError::NotFound(String::from("Couldn't find artist banner for this UUID")))?;
+    artwork_response(&db, banner, &headers).await
+}
+
+/// Serves the artwork with caching headers, or responds with `304 Not Modified` if the client already has it
+async fn artwork_response(db: &AstralDatabase, artwork: ArtworkFile, headers: &HttpHeaders) -> Res<Response> {
+    let etag = artwork.etag();
+    let cached = headers.get(IF_NONE_MATCH)
+        .and_then(|it| it.to_str().ok())
+        .is_some_and(|it| it.split(',').any(|each| each.trim() == etag || each.trim() == "*"));
+
+    let mut response = if cached {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let bucket = artwork.bucket(db);
+        let mime_type = HttpHeaderValue::from_str(&artwork.mime_type).unwrap_or(HttpHeaderValue::from_static("application/octet-stream"));
+        let body = match artwork.data {
+            Some(data) => Body::from(data),
+            None => Body::from_stream(ReaderStream::new(bucket.open(&artwork.file_id).await?)),
+        };
+        let mut response = body.into_response();
+        response.headers_mut().insert(CONTENT_TYPE, mime_type);
+        response
+    };
+    let headers = response.headers_mut();
+    headers.insert(ETAG, HttpHeaderValue::from_str(&etag).unwrap());
+    headers.insert(CACHE_CONTROL, HttpHeaderValue::from_static(ARTWORK_CACHE_CONTROL));
+    Ok(response)
 }
 
 #[derive(Deserialize, Debug)]
@@ -220,7 +309,7 @@ pub async fn pass_to_musixmatch(
 }
 
 pub async fn extract_track_metadata(db: &AstralDatabase, track_id: BsonId) -> Res<FullTrackMetadata> {
-    let track = db.tracks_metadata.find_one(doc! { "track_id": track_id }, None).await?
+    let track = db.tracks_metadata.find_one(doc! { "track_id": track_id }).await?
         .ok_or_else(|| AstralError::NotFound(format!("Could not find track with UUID: {track_id}")))?;
     Ok(FullTrackMetadata {
         track_name: track.name,
@@ -230,48 +319,106 @@ pub async fn extract_track_metadata(db: &AstralDatabase, track_id: BsonId) -> Re
         is_explicit: track.is_explicit,
         disc_number: track.disc_number,
         number: track.number,
-        format: track.format
+        format: track.format,
+        genres: track.genres,
     })
 }
 
 pub async fn extract_album_metadata(db: &AstralDatabase, album_id: BsonId, user: &UserAccount) -> Res<FullAlbumMetadata> {
-    let album = db.albums_metadata.find_one(doc! { "album_id": album_id }, None).await?
+    let album = db.albums_metadata.find_one(doc! { "album_id": album_id }).await?
         .ok_or_else(|| AstralError::NotFound(format!("Could not find album with UUID: {album_id}")))?;
+    let tracks = extract_minified_tracks(&db, album.tracks, user).await?;
+    let discs = group_discs(&tracks, &album.discs);
+    // discs without any present tracks still count towards the total
+    let missing_discs = album.discs.iter()
+        .filter(|known| !discs.iter().any(|it| it.disc_number == known.number))
+        .map(|known| known.track_total.unwrap_or(0) as u32);
+    let track_total = discs.iter().map(|it| it.track_total as u32).chain(missing_discs).sum::<u32>();
     Ok(FullAlbumMetadata {
         album_name: album.name,
+        album_type: album.album_type,
+        edition: album.edition,
         artists: extract_minified_artists(&db, album.artists).await?,
-        tracks: extract_minified_tracks(&db, album.tracks, user).await?,
-        release_date: NaiveDateTime::from_timestamp_millis(album.release_date as i64).unwrap().and_utc(),
-        genres: album.genres
+        disc_total: album.disc_total.unwrap_or(0).max(discs.last().map(|it| it.disc_number).unwrap_or(0)),
+        track_total: track_total.max(tracks.len() as u32),
+        tracks,
+        discs,
+        release_date: DateTime::from_timestamp_millis(album.release_date as i64).unwrap_or_default(),
+        genres: album.genres,
+        palette: album.palette,
+        aliases: album.aliases,
     })
 }
 
+/// Groups ordered tracks of an album into discs. Tracks without a disc number are placed on the first disc.
+fn group_discs(tracks: &[MinifiedTrackMetadata], known: &[AlbumDisc]) -> Vec<AlbumDiscMetadata> {
+    let mut discs: Vec<AlbumDiscMetadata> = vec![];
+    for track in tracks {
+        let number = track.disc_number.max(1);
+        match discs.last_mut() {
+            Some(disc) if disc.disc_number == number => disc.tracks.push(track.clone()),
+            _ => {
+                let info = known.iter().find(|it| it.number == number);
+                discs.push(AlbumDiscMetadata {
+                    disc_number: number,
+                    subtitle: info.and_then(|it| it.subtitle.clone()),
+                    track_total: info.and_then(|it| it.track_total).unwrap_or(0),
+                    tracks: vec![track.clone()],
+                });
+            }
+        }
+    }
+    for disc in &mut discs {
+        disc.track_total = disc.track_total.max(disc.tracks.len() as u16);
+    }
+    discs
+}
+
 pub async fn extract_artist_metadata(db: &AstralDatabase, artist_id: BsonId) -> Res<FullArtistMetadata> {
-    let artist = db.artists_metadata.find_one(doc! { "artist_id": artist_id }, None).await?
+    let artist = db.artists_metadata.find_one(doc! { "artist_id": artist_id }).await?
         .ok_or_else(|| AstralError::NotFound(format!("Could not find album with UUID: {artist_id}")))?;
+    let albums = extract_minified_albums(&db, artist.albums).await?;
     Ok(FullArtistMetadata {
         artist_name: artist.name,
-        albums: extract_minified_albums(&db, artist.albums).await?,
-        genres: artist.genres.into_keys().collect(),
+        album_groups: group_album_types(&albums),
+        albums,
+        genres: top_genres(&artist.genres, top_genre_limit()),
         about_artist: artist.about,
+        aliases: artist.aliases,
         tracks: artist.tracks.into_iter().map(BsonId::to_uuid_1).collect()
     })
 }
 
 
+/// Groups albums by their type, ordering albums in every group from the newest one
+fn group_album_types(albums: &[MinifiedAlbumMetadata]) -> Vec<AlbumTypeGroup> {
+    let mut groups: Vec<AlbumTypeGroup> = vec![];
+    for album in albums {
+        match groups.iter_mut().find(|it| it.album_type == album.album_type) {
+            Some(group) => group.albums.push(album.clone()),
+            None => groups.push(AlbumTypeGroup { album_type: album.album_type, albums: vec![album.clone()] }),
+        }
+    }
+    groups.sort_by_key(|it| it.album_type);
+    for group in &mut groups {
+        group.albums.sort_by_key(|it| Reverse(it.release_date));
+    }
+    groups
+}
+
 async fn extract_minified_artists(db: &AstralDatabase, artists: Vec<BsonId>) -> Res<Vec<MinifiedArtistMetadata>> {
-    let all = db.artists_metadata.find(doc! { "artist_id": { "$in": &artists } }, None).await?
+    let all = db.artists_metadata.find(doc! { "artist_id": { "$in": &artists } }).await?
         .map(|it| it.unwrap()).collect::<Vec<ArtistMetadata>>().await;
     Ok(all.into_iter().map(|each| MinifiedArtistMetadata {
         artist_id: each.artist_id.to_uuid_1(),
         artist_name: each.name.clone(),
         album_ids: each.albums.into_iter().map(BsonId::to_uuid_1).collect(),
-        genres: each.genres.into_keys().collect(),
+        genres: top_genres(&each.genres, top_genre_limit()),
     }).collect())
 }
 
 async fn extract_minified_albums(db: &AstralDatabase, albums: Vec<BsonId>) -> Res<Vec<MinifiedAlbumMetadata>> {
-    let all = db.albums_metadata.find(doc! { "album_id": { "$in": &albums } }, None).await?
+    let all = db.albums_metadata.find(doc! { "album_id": { "$in": &albums } }).await?
         .map(|it| it.unwrap()).collect::<Vec<AlbumMetadata>>().await;
     Ok(all.into_iter().map(|each| MinifiedAlbumMetadata {
         album_id: each.album_id.to_uuid_1(),
@@ -279,13 +426,17 @@ async fn extract_minified_albums(db: &AstralDatabase, albums: Vec<BsonId>) -> Re
         artist_ids: each.artists.into_iter().map(BsonId::to_uuid_1).collect(),
         track_ids: each.tracks.into_iter().map(BsonId::to_uuid_1).collect(),
         genres: each.genres.clone(),
-        release_date: NaiveDateTime::from_timestamp_millis(each.release_date as i64).unwrap().and_utc(),
+        release_date: DateTime::from_timestamp_millis(each.release_date as i64).unwrap_or_default(),
+        palette: each.palette,
+        album_type: each.album_type,
+        edition: each.edition,
     }).collect())
 }
 
 async fn extract_minified_tracks(db: &AstralDatabase, tracks: Vec<BsonId>, user: &UserAccount) -> Res<Vec<MinifiedTrackMetadata>> {
-    let all = db.tracks_metadata.find(doc! { "track_id": { "$in": &tracks } }, None).await?
+    let mut all = db.tracks_metadata.find(doc! { "track_id": { "$in": &tracks } }).await?
         .map(|it| it.unwrap()).collect::<Vec<TrackMetadata>>().await;
+    all.sort_by(|a, b| (a.disc_number.max(1), a.number).cmp(&(b.disc_number.max(1), b.number)).then_with(|| a.name.cmp(&b.name)));
     Ok(all.into_iter().map(|each| MinifiedTrackMetadata {
         track_id: each.track_id.to_uuid_1(),
         track_name: each.name,
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use axum::extract::{Query, State};
+use axum::Json;
+use futures_util::StreamExt;
+use mongodb::bson::{Bson, doc, Document};
+use serde::Deserialize;
+use crate::api::AppState;
+use crate::api::extensions::{AuthenticatedUser, UserPermission};
+use crate::api::model::{LyricsLineHit, SearchHit, SearchResponse, SearchSuggestion};
+use crate::api::paths::index::{extract_indexed_album, extract_indexed_artist, extract_indexed_track};
+use crate::data::AstralDatabase;
+use crate::data::model::BsonId;
+use crate::data::repository::Repo;
+use crate::err::AstralError;
+use crate::search::tokenize;
+use crate::search::engine::{rebuild_search_index, LyricsMatch, SearchIndex, SearchKind};
+use crate::Res;
+
+/// Default amount of hits returned per group
+const DEFAULT_LIMIT: u32 = 10;
+/// Maximal amount of hits returned per group
+const MAX_LIMIT: u32 = 50;
+/// Default amount of suggestions returned
+const DEFAULT_SUGGEST_LIMIT: u32 = 5;
+/// Maximal amount of suggestions returned
+const MAX_SUGGEST_LIMIT: u32 = 10;
+/// Time budget for finding suggestions. Suggestions are requested on every keystroke, so stale ones are dropped instead of waited for.
+const SUGGEST_BUDGET: Duration = Duration::from_millis(150);
+
+/// Maximal amount of suggestion lookups running at once. Lookups that ran out of time still finish their current step,
+/// so new ones are turned away while too many of them are running.
+const MAX_RUNNING_SUGGESTIONS: usize = 4;
+/// Amount of suggestion lookups currently running
+static RUNNING_SUGGESTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Permit to run a single suggestion lookup, released when dropped
+struct SuggestionSlot;
+
+impl SuggestionSlot {
+    fn acquire() -> Option<Self> {
+        if RUNNING_SUGGESTIONS.fetch_add(1, Ordering::AcqRel) >= MAX_RUNNING_SUGGESTIONS {
+            RUNNING_SUGGESTIONS.fetch_sub(1, Ordering::AcqRel);
+            return None
+        }
+        Some(Self)
+    }
+}
+
+impl Drop for SuggestionSlot {
+    fn drop(&mut self) {
+        RUNNING_SUGGESTIONS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Parameters used for search
+#[derive(Deserialize)]
+pub struct SearchParameters {
+    /// The search query
+    pub query: String,
+    /// Maximal amount of hits per group
+    pub limit: Option<u32>,
+}
+
+/// Searches tracks, albums, artists and lyrics at once.
+///
+/// Results are grouped by their type and ranked by relevance. Search is case and diacritics
+/// insensitive, matches word prefixes and tolerates small typos. Popular entries are ranked higher.
+/// Scores are relative to the best hit of their group, so they can not be compared across groups.
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(
+        ("query" = String, Query, description = "The search query"),
+        ("limit" = Option<u32>, Query, description = "Maximal amount of hits per group. Defaults to 10, at most 50"),
+    ),
+    responses(
+        (status = 200, body = SearchResponse, description = "Successfully searched the library"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "index"
+)]
+pub async fn search(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Query(SearchParameters { query, limit }): Query<SearchParameters>,
+    AuthenticatedUser(user): AuthenticatedUser
+) -> Res<Json<SearchResponse>> {
+    let tokens = tokenize(&query);
+    if tokens.is_empty() {
+        return Err(AstralError::BadRequest(String::from("Search query can not be empty")))
+    }
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+
+    let (tracks, albums, artists, lyrics) = tokio::task::spawn_blocking(move || -> Res<_> {
+        Ok((
+            rank_names(&search_index, &query, SearchKind::Track, limit)?,
+            rank_names(&search_index, &query, SearchKind::Album, limit)?,
+            rank_names(&search_index, &query, SearchKind::Artist, limit)?,
+            search_index.search_lyrics(&query, limit)?,
+        ))
+    }).await.map_err(|err| AstralError::Unknown(err.into()))??;
+    let lyrics = name_lyrics(&db, lyrics).await?;
+
+    let tracks = hydrate(&db.tracks_metadata, "track_id", tracks, vec![
+        doc! { "$lookup": { "from": "artists_metadata", "localField": "artists", "foreignField": "artist_id", "as": "artist_objects" } },
+        doc! { "$lookup": { "from": "albums_metadata", "localField": "albums", "foreignField": "album_id", "as": "album_objects" } },
+    ]).await?
+        .into_iter()
+        .filter_map(|(score, each)| extract_indexed_track(each, &user).ok().map(|item| SearchHit { score, item }))
+        .collect();
+    let albums = hydrate(&db.albums_metadata, "album_id", albums, vec![
+        doc! { "$lookup": { "from": "artists_metadata", "localField": "artists", "foreignField": "artist_id", "as": "artist_objects" } },
+    ]).await?
+        .into_iter()
+        .filter_map(|(score, each)| extract_indexed_album(each, &user).ok().map(|item| SearchHit { score, item }))
+        .collect();
+    let artists = hydrate(&db.artists_metadata, "artist_id", artists, vec![]).await?
+        .into_iter()
+        .filter_map(|(score, each)| extract_indexed_artist(each).ok().map(|item| SearchHit { score, item }))
+        .collect();
+
+    Ok(Json(SearchResponse { tracks, albums, artists, lyrics }))
+}
+
+/// Suggests track, album and artist names starting with the query, for search-as-you-type.
+///
+/// Only word prefixes are matched and no other data is loaded, so this is much cheaper than `/search`.
+/// Explicit tracks and albums are left out if the user chose to hide them.
+/// If suggestions can not be found in time, or too many lookups are already running, an empty list is returned.
+#[utoipa::path(
+    get,
+    path = "/search/suggest",
+    params(
+        ("query" = String, Query, description = "The partially typed query"),
+        ("limit" = Option<u32>, Query, description = "Maximal amount of suggestions. Defaults to 5, at most 10"),
+    ),
+    responses(
+        (status = 200, body = Vec<SearchSuggestion>, description = "Successfully found suggestions, most relevant first"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "index"
+)]
+pub async fn suggest(
+    State(AppState { search_index, .. }): State<AppState>,
+    Query(SearchParameters { query, limit }): Query<SearchParameters>,
+    AuthenticatedUser(user): AuthenticatedUser
+) -> Res<Json<Vec<SearchSuggestion>>> {
+    let limit = limit.unwrap_or(DEFAULT_SUGGEST_LIMIT).clamp(1, MAX_SUGGEST_LIMIT) as usize;
+    let Some(slot) = SuggestionSlot::acquire() else {
+        return Ok(Json(vec![]))
+    };
+    let deadline = Instant::now() + SUGGEST_BUDGET;
+    let task = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        search_index.suggest(&query, user.hide_explicit, limit, deadline)
+    });
+    let found = match tokio::time::timeout(SUGGEST_BUDGET, task).await {
+        Ok(found) => found.map_err(|err| AstralError::Unknown(err.into()))??,
+        Err(_) => vec![],
+    };
+    Ok(Json(found.into_iter()
+        .map(|each| SearchSuggestion { id: each.id, kind: each.kind, name: each.name })
+        .collect()))
+}
+
+/// Finds entries of the kind in the search index, returning their IDs along with the relevance relative to the best entry
+fn rank_names(search_index: &SearchIndex, query: &str, kind: SearchKind, limit: usize) -> Res<Vec<(f32, Bson)>> {
+    let found = search_index.search(query, Some(kind), limit)?;
+    let best = found.first().map(|it| it.score).unwrap_or_default();
+    Ok(found.into_iter()
+        .map(|each| (relative_score(each.score, best), Bson::from(BsonId::from_uuid_1(each.id))))
+        .collect())
+}
+
+/// Scales the score into `0..=1` relative to the best score of its group. Index scores are unbounded
+/// and differ between groups, so they are only meaningful next to the scores of the same group.
+fn relative_score(score: f32, best: f32) -> f32 {
+    if best > 0.0 { score / best } else { 0.0 }
+}
+
+/// Fetches full documents of ranked entries, keeping their order
+async fn hydrate<T: Send + Sync>(collection: &Repo<T>, id_field: &str, ranked: Vec<(f32, Bson)>, lookups: Vec<Document>) -> Res<Vec<(f32, Document)>> {
+    if ranked.is_empty() {
+        return Ok(vec![])
+    }
+    let ids = ranked.iter().map(|(_, id)| id.clone()).collect::<Vec<_>>();
+    let mut pipeline = vec![doc! { "$match": { id_field: { "$in": ids } } }];
+    pipeline.extend(lookups);
+    let mut found = collection.aggregate(pipeline).await?
+        .filter_map(|each| async { each.ok() })
+        .collect::<Vec<_>>().await;
+    Ok(ranked.into_iter()
+        .filter_map(|(score, id)| {
+            let idx = found.iter().position(|each| each.get(id_field) == Some(&id))?;
+            Some((score, found.swap_remove(idx)))
+        })
+        .collect())
+}
+
+/// Adds names of the tracks to lyric lines found in the search index, scoring them relative to the best line
+async fn name_lyrics(db: &AstralDatabase, found: Vec<LyricsMatch>) -> Res<Vec<SearchHit<LyricsLineHit>>> {
+    let best = found.first().map(|it| it.score).unwrap_or_default();
+    let track_ids = found.iter().map(|each| BsonId::from_uuid_1(each.track_id)).collect::<Vec<_>>();
+    let names = db.tracks_metadata.find(doc! { "track_id": { "$in": &track_ids } }).await?
+        .filter_map(|each| async { each.ok() })
+        .map(|each| (each.track_id.to_uuid_1(), each.name))
+        .collect::<HashMap<_, _>>().await;
+
+    Ok(found.into_iter()
+        .filter_map(|each| Some(SearchHit {
+            score: relative_score(each.score, best),
+            item: LyricsLineHit {
+                track_id: each.track_id,
+                track_name: names.get(&each.track_id)?.clone(),
+                line: each.line,
+                start_time_ms: each.start_time_ms,
+            },
+        }))
+        .collect())
+}
+
+/// Rebuilds the whole search index from the database. Only available to admins.
+#[utoipa::path(
+    post,
+    path = "/search/rebuild",
+    responses(
+        (status = 200, body = u64, description = "Successfully rebuilt the search index, returns amount of indexed entries"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "index"
+)]
+pub async fn rebuild_index(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser
+) -> Res<Json<u64>> {
+    if !user.permissions.contains(&UserPermission::Admin) {
+        return Err(AstralError::Unauthorized(String::from("Only admins can rebuild the search index.")))
+    }
+    let indexed = rebuild_search_index(&db, &search_index).await?;
+    Ok(Json(indexed as u64))
+}
@@ -46,8 +46,9 @@ pub async fn stream_track(
     AuthenticatedUser(_): AuthenticatedUser
 ) -> Res<impl IntoResponse> {
     let uid = BsonId::from_uuid_1(track_id);
-    let metadata = db.tracks_metadata.find_one(doc! { "track_id": &uid }, None).await?
+    let metadata = db.tracks_metadata.find_one(doc! { "track_id": &uid }).await?
         .ok_or_else(|| AstralError::NotFound("Couldn't find a track with this ID".to_string()))?;
+    db.tracks_metadata.update_one(doc! { "track_id": &uid }, doc! { "$inc": { "play_count": 1 } }).await?;
 
     let mut req = axum::extract::Request::new(Body::empty());
     ServeFile::new_with_mime(
@@ -56,7 +57,7 @@ pub async fn stream_track(
     ).try_call(req).await.map_err(AstralError::from)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Copy, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum StreamQuality {
     Low,
@@ -88,10 +89,22 @@ pub async fn stream_track_transcoded(
     Path(PathParams { track_id, quality }): Path<PathParams>,
     AuthenticatedUser(_): AuthenticatedUser
 ) -> Res<impl IntoResponse> {
-    let track_exists = db.tracks_metadata.find_one(doc! { "track_id": BsonId::from_uuid_1(track_id) }, None).await?.is_some();
+    let uid = BsonId::from_uuid_1(track_id);
+    let track_exists = db.tracks_metadata.find_one(doc! { "track_id": &uid }).await?.is_some();
     if !track_exists {
         return Err(AstralError::NotFound("Couldn't find a track with this UUID".to_string()))
     }
+    db.tracks_metadata.update_one(doc! { "track_id": &uid }, doc! { "$inc": { "play_count": 1 } }).await?;
+    let path = transcode_track(track_id, &quality).await?;
+
+    let req = axum::extract::Request::new(Body::empty());
+    ServeFile::new_with_mime(path, &Mime::from_str("audio/mpeg").unwrap())
+        .try_call(req).await.map_err(AstralError::from)
+}
+
+/// Transcodes the track into MP3 of the provided quality if it was not transcoded yet.
+/// Returns path to the transcoded file.
+pub async fn transcode_track(track_id: Uuid, quality: &StreamQuality) -> Res<PathBuf> {
     let base_path = PathBuf::from("astral_tracks")
         .join(format!("transcoded_{}", match quality { StreamQuality::Low => "low", StreamQuality::Medium => "medium" }));
     let path = base_path
@@ -125,14 +138,7 @@ pub async fn stream_track_transcoded(
             .unwrap();
 
         command.wait().await?;
-
-        let req = axum::extract::Request::new(Body::empty());
-        ServeFile::new_with_mime(path, &Mime::from_str("audio/mpeg").unwrap())
-            .try_call(req).await.map_err(AstralError::from)
-    } else {
-        let req = axum::extract::Request::new(Body::empty());
-        ServeFile::new_with_mime(path, &Mime::from_str("audio/mpeg").unwrap())
-            .try_call(req).await.map_err(AstralError::from)
     }
+    Ok(path)
 }
 
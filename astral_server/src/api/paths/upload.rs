@@ -3,9 +3,9 @@ use std::collections::HashSet;
 use std::path::PathBuf;
 use axum::extract::{Path, Query, State};
 use axum::Json;
-use futures_util::{AsyncReadExt as FutReadExt, AsyncWriteExt as FutWriteExt, StreamExt};
-use mongodb::bson::{bson, doc};
-use mongodb::GridFsUploadStream;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use mongodb::bson::{bson, doc, to_bson};
 use serde::Deserialize;
 use sha2::{Digest, Sha256};
 use tokio::fs::File;
@@ -15,13 +15,25 @@ use axum::body::Body;
 use tokio::io::AsyncReadExt;
 use crate::api::AppState;
 use crate::api::extensions::{AuthenticatedUser, UserPermission};
-use crate::api::model::{AlbumMetadataResponse, ArtistMetadataResponse, PatchAlbumMetadata, PatchArtistMetadata, PatchTrackMetadata, TrackMetadataResponse, UploadTrackResponse};
+use crate::api::model::{AlbumMetadataResponse, ArtistMetadataResponse, GuessMetadataRequest, GuessMetadataResponse, MetadataPreviewResponse, PatchAlbumMetadata, PatchArtistMetadata, PatchTrackMetadata, PendingTagPreview, PendingUpload, TrackMetadataResponse, UploadTrackResponse};
 use crate::api::paths::metadata::{extract_album_metadata, extract_artist_metadata, extract_track_metadata};
-use crate::data::model::{BsonId, TrackFormat, UndefinedTrack};
+use crate::data::{AstralDatabase, Transaction};
+use crate::data::model::{AlbumMetadata, BsonId, TrackFormat, UndefinedTrack, UserAccount};
 use crate::err::AstralError;
-use crate::metadata::binary::extract_metadata_from_bytes;
-use crate::metadata::classify_insert_metadata;
-use crate::metadata::merged::extract_merged_metadata;
+use crate::metadata::binary::{EmbeddedTagsProvider, extract_metadata_from_file};
+use crate::metadata::{classify_insert_metadata, remove_track, remove_track_files, ExtractedTrackMetadata};
+use crate::metadata::merged::{extract_merged_metadata, MergePolicy, MetadataProvenance};
+use crate::metadata::musicbrainz::MusicBrainzProvider;
+use crate::metadata::musix::MusixmatchProvider;
+use crate::jobs::fingerprinter::spawn_fingerprinter;
+use crate::jobs::tag_writer::{spawn_tag_writer, write_tags_matching};
+use crate::metadata::preview::{MetadataProposal, preview_attachments};
+use crate::metadata::writer::write_track_tags;
+use crate::metadata::artwork::{delete_artwork, store_artwork, ArtworkKind};
+use crate::metadata::palette::refresh_album_palette;
+use crate::metadata::genres::{normalize_genres, refresh_genres};
+use crate::search::engine::reindex_entries;
+use crate::metadata::provider::{ManualOverrideProvider, ManualOverrides, MetadataProvider, MetadataSource};
 use crate::Res;
 
 /// Uploads a track to the servers with zero metadata assigned. Returned UUID can be used to update metadata.
@@ -67,7 +79,7 @@ pub async fn upload_track(
     }
     let hash = hex::encode(&hasher.finalize()[..]);
 
-    if let Some(track) = db.undefined_tracks.find_one(doc! { "hash": &hash }, None).await? {
+    if let Some(track) = db.undefined_tracks.find_one(doc! { "hash": &hash }).await? {
         tokio::fs::remove_file(&path).await?;
         return Ok(Json(UploadTrackResponse {
             track_id: track.track_id.to_uuid_1(),
@@ -79,9 +91,10 @@ pub async fn upload_track(
         track_id,
         hash,
         uploaded_by: user.user_id.clone(),
-        format: track_format
+        format: track_format,
+        uploaded_at: Utc::now().timestamp_millis() as u64,
     };
-    db.undefined_tracks.insert_one(&new_track, None).await?;
+    db.undefined_tracks.insert_one(&new_track).await?;
 
     Ok(Json(UploadTrackResponse {
         track_id: new_track.track_id.to_uuid_1()
@@ -92,23 +105,26 @@ pub async fn upload_track(
 pub struct MetadataProps {
     musix_priority: Option<bool>,
     skip_musix: Option<bool>,
+    musicbrainz: Option<bool>,
     musix_artist_override: Option<String>,
     musix_album_override: Option<String>,
     musix_name_override: Option<String>
 }
 
-/// Attempts to guess track data from audio metadata and musixmatch metadata
+/// Attempts to guess track data from audio metadata and external metadata sources
 #[utoipa::path(
     post,
     path = "/upload/guess_metadata/{uuid}",
+    request_body = GuessMetadataRequest,
     responses(
         (status = 400, response = AstralError),
-        (status = 200, response = TrackMetadataResponse)
+        (status = 200, response = GuessMetadataResponse)
     ),
     params(
         ("uuid" = Uuid, Path, description = "UUID of the track to use for metadata guessing"),
-        ("musix_priority" = inline(Option<bool>), Query, description = "Whether to prioritize Musixmatch metadata over bundled metadaata"),
+        ("musix_priority" = inline(Option<bool>), Query, description = "Whether to prioritize Musixmatch metadata over bundled metadaata. Ignored if a policy is provided in the body"),
         ("skip_musix" = inline(Option<bool>), Query, description = "Whether to fully skip Musixmatch metadata fetching"),
+        ("musicbrainz" = inline(Option<bool>), Query, description = "Whether to also fetch metadata from MusicBrainz"),
         ("musix_artist_override" = inline(Option<String>), Query, description = "Custom override for track artist when fetching Musixmatch"),
         ("musix_album_override" = inline(Option<String>), Query, description = "Custom override for track album when fetching Musixmatch"),
         ("musix_name_override" = inline(Option<String>), Query, description = "Custom override for track name when fetching Musixmatch"),
@@ -117,47 +133,279 @@ pub struct MetadataProps {
     tag = "upload"
 )]
 pub async fn guess_metadata(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Path(track_id): Path<Uuid>,
+    Query(props): Query<MetadataProps>,
+    AuthenticatedUser(_): AuthenticatedUser,
+    body: Option<Json<GuessMetadataRequest>>,
+) -> Res<Json<GuessMetadataResponse>> {
+    let uid = BsonId::from_uuid_1(track_id);
+    let (track, track_audio_bytes) = read_undefined_track(&db, uid).await?;
+
+    let (extracted, provenance) = guess_extracted_metadata(&track_audio_bytes, track.format, props, body.unwrap_or_default().0).await?;
+    drop(track_audio_bytes);
+
+    let uid = classify_insert_metadata(&db, &search_index, extracted, uid, track.hash).await?;
+    spawn_fingerprinter(db.clone(), uid);
+
+    let metadata = extract_track_metadata(&db, uid.clone()).await?;
+
+    Ok(Json(GuessMetadataResponse {
+        track_id: uid.to_uuid_1(),
+        metadata,
+        loved: false,
+        provenance,
+    }))
+}
+
+/// Guesses track metadata same as `/upload/guess_metadata/{uuid}`, but does not modify anything.
+/// Returned proposal can be edited and then committed with `/upload/commit_metadata/{uuid}`.
+#[utoipa::path(
+    post,
+    path = "/upload/guess_metadata/{uuid}/preview",
+    request_body = GuessMetadataRequest,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, response = MetadataPreviewResponse)
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the track to use for metadata guessing"),
+        ("musix_priority" = inline(Option<bool>), Query, description = "Whether to prioritize Musixmatch metadata over bundled metadaata. Ignored if a policy is provided in the body"),
+        ("skip_musix" = inline(Option<bool>), Query, description = "Whether to fully skip Musixmatch metadata fetching"),
+        ("musicbrainz" = inline(Option<bool>), Query, description = "Whether to also fetch metadata from MusicBrainz"),
+        ("musix_artist_override" = inline(Option<String>), Query, description = "Custom override for track artist when fetching Musixmatch"),
+        ("musix_album_override" = inline(Option<String>), Query, description = "Custom override for track album when fetching Musixmatch"),
+        ("musix_name_override" = inline(Option<String>), Query, description = "Custom override for track name when fetching Musixmatch"),
+    ),
+    tag = "upload"
+)]
+pub async fn preview_metadata(
     State(AppState { db, .. }): State<AppState>,
     Path(track_id): Path<Uuid>,
-    Query(MetadataProps { musix_priority, skip_musix, musix_album_override, musix_artist_override, musix_name_override }): Query<MetadataProps>,
+    Query(props): Query<MetadataProps>,
     AuthenticatedUser(_): AuthenticatedUser,
+    body: Option<Json<GuessMetadataRequest>>,
+) -> Res<Json<MetadataPreviewResponse>> {
+    let uid = BsonId::from_uuid_1(track_id);
+    let (track, track_audio_bytes) = read_undefined_track(&db, uid).await?;
+
+    let (extracted, provenance) = guess_extracted_metadata(&track_audio_bytes, track.format, props, body.unwrap_or_default().0).await?;
+    drop(track_audio_bytes);
+
+    let attachments = preview_attachments(&db, &extracted).await?;
+
+    Ok(Json(MetadataPreviewResponse {
+        track_id,
+        proposal: extracted.into(),
+        provenance,
+        attachments,
+    }))
+}
+
+/// Commits (possibly edited) proposed metadata to a track without metadata
+#[utoipa::path(
+    post,
+    path = "/upload/commit_metadata/{uuid}",
+    request_body = MetadataProposal,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, response = TrackMetadataResponse)
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the track to assign metadata to"),
+    ),
+    tag = "upload"
+)]
+pub async fn commit_metadata(
+    State(AppState { db, search_index, .. }): State<AppState>,
+    Path(track_id): Path<Uuid>,
+    AuthenticatedUser(_): AuthenticatedUser,
+    Json(proposal): Json<MetadataProposal>,
 ) -> Res<Json<TrackMetadataResponse>> {
     let uid = BsonId::from_uuid_1(track_id);
-    let track = db.undefined_tracks.find_one(doc! {"track_id": &uid }, None).await?
+    let (track, track_audio_bytes) = read_undefined_track(&db, uid).await?;
+
+    let extracted = proposal.into_extracted(&track_audio_bytes, track.format)?;
+    drop(track_audio_bytes);
+
+    let uid = classify_insert_metadata(&db, &search_index, extracted, uid, track.hash).await?;
+    spawn_fingerprinter(db.clone(), uid);
+
+    let metadata = extract_track_metadata(&db, uid.clone()).await?;
+
+    Ok(Json(TrackMetadataResponse {
+        track_id: uid.to_uuid_1(),
+        metadata,
+        loved: false,
+    }))
+}
+
+/// Finds a track without metadata and reads its audio file. The upload date of the track is refreshed,
+/// so the pending upload sweeper does not discard it while its metadata is being guessed.
+async fn read_undefined_track(db: &AstralDatabase, uid: BsonId) -> Res<(UndefinedTrack, Vec<u8>)> {
+    let now = Utc::now().timestamp_millis();
+    let track = db.undefined_tracks.find_one_and_update(doc! {"track_id": &uid }, doc! { "$set": { "uploaded_at": now } }).await?
         .ok_or_else(|| AstralError::BadRequest(String::from("Track with this UUID does not exist")))?;
 
     let mut track_audio_bytes = vec![];
     let mut stream = BufReader::new(File::open(PathBuf::from("astral_tracks").join(format!("{uid}.bin"))).await?);
     stream.read_to_end(&mut track_audio_bytes).await?;
-    drop(stream);
-
-    let extracted = if skip_musix.unwrap_or(false) {
-        let mut extracted = extract_metadata_from_bytes(&track_audio_bytes, track.format)?;
-        if(musix_priority.unwrap_or(false)) {
-            extracted.artists = musix_artist_override.clone().map(|it| it.split(", ").map(String::from).collect::<Vec<_>>()).unwrap_or(extracted.artists);
-            extracted.album_artists = musix_artist_override.map(|it| it.split(", ").map(String::from).collect::<Vec<_>>()).unwrap_or(extracted.album_artists);
-            extracted.name = musix_name_override.unwrap_or(extracted.name);
-            extracted.album_name = musix_album_override.unwrap_or(extracted.album_name);
-            extracted
-        } else {
-            extracted
+
+    Ok((track, track_audio_bytes))
+}
+
+/// Sets up metadata providers and merge policy from guessing parameters and extracts merged metadata
+async fn guess_extracted_metadata(
+    bytes: &[u8],
+    format: TrackFormat,
+    MetadataProps { musix_priority, skip_musix, musicbrainz, musix_album_override, musix_artist_override, musix_name_override }: MetadataProps,
+    GuessMetadataRequest { policy, overrides }: GuessMetadataRequest,
+) -> Res<(ExtractedTrackMetadata, MetadataProvenance)> {
+    let musix_priority = musix_priority.unwrap_or(false);
+    let skip_musix = skip_musix.unwrap_or(false);
+    let mut providers: Vec<Box<dyn MetadataProvider>> = vec![Box::new(EmbeddedTagsProvider)];
+    if skip_musix && musix_priority {
+        // musixmatch overrides are treated as manual values when it is skipped
+        let artists = musix_artist_override.map(|it| it.split(", ").map(String::from).collect::<Vec<_>>());
+        let overrides = overrides.unwrap_or_default();
+        providers.push(Box::new(ManualOverrideProvider(ManualOverrides {
+            name: overrides.name.or(musix_name_override),
+            album_name: overrides.album_name.or(musix_album_override),
+            artists: overrides.artists.or(artists),
+            ..overrides
+        })));
+    } else {
+        if let Some(overrides) = overrides {
+            providers.push(Box::new(ManualOverrideProvider(overrides)));
+        }
+        if !skip_musix {
+            providers.push(Box::new(MusixmatchProvider {
+                artist_override: musix_artist_override,
+                album_override: musix_album_override,
+                name_override: musix_name_override,
+            }));
+        }
+    }
+    if musicbrainz.unwrap_or(false) {
+        providers.push(Box::new(MusicBrainzProvider));
+    }
+
+    let policy = policy.unwrap_or_else(|| if skip_musix && musix_priority {
+        MergePolicy::preferring(MetadataSource::Manual)
+    } else if musix_priority {
+        MergePolicy::preferring(MetadataSource::Musixmatch)
+    } else {
+        MergePolicy::default()
+    });
+
+    extract_merged_metadata(bytes, format, &providers, &policy).await
+}
+
+#[derive(Deserialize)]
+pub struct PendingUploadsProps {
+    all: Option<bool>,
+}
+
+/// Lists uploaded tracks that do not have metadata assigned yet
+#[utoipa::path(
+    get,
+    path = "/upload/pending",
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, body = [PendingUpload], description = "Successfully listed pending uploads")
+    ),
+    params(
+        ("all" = inline(Option<bool>), Query, description = "Whether to list pending uploads of all users. Requires admin permission"),
+    ),
+    tag = "upload"
+)]
+pub async fn list_pending_uploads(
+    State(AppState { db, .. }): State<AppState>,
+    Query(PendingUploadsProps { all }): Query<PendingUploadsProps>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Res<Json<Vec<PendingUpload>>> {
+    let filter = if all.unwrap_or(false) {
+        if !user.permissions.contains(&UserPermission::Admin) {
+            return Err(AstralError::Unauthorized(String::from("You are not authorized to view uploads of other users")))
         }
+        doc! { }
     } else {
-        extract_merged_metadata(&track_audio_bytes, track.format, musix_priority.unwrap_or(false), musix_artist_override, musix_album_override, musix_name_override).await?
+        doc! { "uploaded_by": &user.user_id }
     };
-    drop(track_audio_bytes);
 
-    db.undefined_tracks.delete_one(doc! { "track_id": &uid }, None).await?;
+    let tracks = db.undefined_tracks.find(filter).await?
+        .filter_map(|each| async { each.ok() })
+        .collect::<Vec<_>>().await;
+
+    let mut pending = Vec::with_capacity(tracks.len());
+    for track in tracks {
+        let path = std::path::Path::new("astral_tracks").join(format!("{}.bin", track.track_id));
+        let size = tokio::fs::metadata(&path).await.ok().map(|it| it.len());
+        // only the tags are read, the audio itself stays on disk
+        let format = track.format;
+        let preview = tokio::task::spawn_blocking(move || extract_metadata_from_file(&path, format)).await
+            .map_err(|err| AstralError::Unknown(err.into()))?
+            .ok()
+            .map(|extracted| PendingTagPreview {
+                name: extracted.name,
+                album_name: extracted.album_name,
+                artists: extracted.artists,
+                album_artists: extracted.album_artists,
+                duration: extracted.duration,
+                number: extracted.number,
+                disc_number: extracted.disc_number,
+                has_cover: extracted.cover_art.is_some(),
+            });
+        pending.push(PendingUpload {
+            track_id: track.track_id.to_uuid_1(),
+            uploaded_by: track.uploaded_by.to_uuid_1(),
+            // uploads stored before their date was recorded are listed as uploaded at the unix epoch
+            uploaded_at: DateTime::from_timestamp_millis(track.uploaded_at as i64).unwrap_or_default(),
+            format: track.format,
+            size,
+            preview,
+        });
+    }
 
-    let uid = classify_insert_metadata(&db, extracted, uid).await?;
+    Ok(Json(pending))
+}
 
-    let metadata = extract_track_metadata(&db, uid.clone()).await?;
+/// Discards an uploaded track that does not have metadata assigned yet, deleting its file
+#[utoipa::path(
+    post,
+    path = "/upload/pending/{uuid}/discard",
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, body = (), description = "Successfully discarded pending upload")
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the pending track to discard"),
+    ),
+    tag = "upload"
+)]
+pub async fn discard_pending_upload(
+    State(AppState { db, .. }): State<AppState>,
+    Path(track_id): Path<Uuid>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Res<()> {
+    let uid = BsonId::from_uuid_1(track_id);
+    let track = db.undefined_tracks.find_one(doc! { "track_id": &uid }).await?
+        .ok_or_else(|| AstralError::NotFound(String::from("Could not find a pending upload with this UUID")))?;
+    if track.uploaded_by != user.user_id && !user.permissions.contains(&UserPermission::Admin) {
+        return Err(AstralError::Unauthorized(String::from("You are not authorized to discard uploads of other users")))
+    }
 
-    Ok(Json(TrackMetadataResponse {
-        track_id: uid.to_uuid_1(),
-        metadata,
-        loved: false,
-    }))
+    discard_undefined_track(&db, &track).await
+}
+
+/// Deletes a track without metadata along with its file
+pub async fn discard_undefined_track(db: &AstralDatabase, track: &UndefinedTrack) -> Res<()> {
+    db.undefined_tracks.delete_one(doc! { "track_id": &track.track_id }).await?;
+    let path = std::path::Path::new("astral_tracks").join(format!("{}.bin", track.track_id));
+    if path.exists() {
+        tokio::fs::remove_file(&path).await?;
+    }
+    Ok(())
 }
 
 /// Completely deletes an album and all tracks in it
@@ -174,7 +422,7 @@ pub async fn guess_metadata(
     tag = "upload"
 )]
 pub async fn delete_album(
-    State(AppState { db, .. }): State<AppState>,
+    State(AppState { db, search_index, .. }): State<AppState>,
     Path(album_id): Path<Uuid>,
     AuthenticatedUser(user): AuthenticatedUser,
 ) -> Res<()> {
@@ -183,38 +431,45 @@ pub async fn delete_album(
     }
 
     let id = BsonId::from_uuid_1(album_id);
-    let album = db.albums_metadata.find_one_and_delete(doc! { "album_id": id }, None).await?;
-
-    let files_dir = std::path::Path::new("astral_tracks");
-    let transcoded_low_dir = files_dir.join("transcoded_low");
-    let transcoded_med_dir = files_dir.join("transcoded_medium");
-    if let Some(album) = album {
-        let found_art = db.gridfs_album_arts.find(doc! { "filename": id.to_string() }, None).await?.next().await.unwrap()?;
-        db.gridfs_album_arts.delete(found_art.id).await?;
-        db.artists_metadata.update_many(doc! { "artist_id": {"$in": &album.artists} }, doc! {
-            "$pull": {
-                "albums": &id,
-                "tracks": { "$in": &album.tracks }
-            }
-        }, None).await?;
-        for track_id in &album.tracks {
-            db.tracks_metadata.delete_one(doc!{ "track_id": &track_id }, None).await?;
-            db.lyrics.delete_one(doc! {"track_id": &track_id}, None).await?;
-            let filename = format!("{track_id}.bin");
-            tokio::fs::remove_file(&files_dir.join(&filename)).await?;
-            let low = transcoded_low_dir.join(&filename);
-            if low.exists() {
-                tokio::fs::remove_file(&low).await?;
-            }
-            let medium = transcoded_med_dir.join(&filename);
-            if medium.exists() {
-                tokio::fs::remove_file(&medium).await?;
-            }
-        }
-        return Ok(())
-    } else {
+    let removed = db.with_transaction((&db, id), |session, (db, id)| Box::pin(delete_album_entries(db, *id, session))).await?;
+    let Some(album) = removed else {
         return Ok(())
+    };
+
+    // files are only removed once the metadata is gone, so a failed write never leaves metadata without audio
+    delete_artwork(&db, ArtworkKind::AlbumCover, &id).await?;
+    for track_id in &album.tracks {
+        delete_artwork(&db, ArtworkKind::TrackArt, track_id).await?;
+        remove_track_files(track_id).await?;
     }
+    refresh_genres(&db, &album.artists, &[]).await?;
+    reindex_entries(&db, &search_index, &album.tracks, &[id], &album.artists).await?;
+    Ok(())
+}
+
+/// Removes metadata of an album, its tracks and all references to them as part of the transaction. Returns the removed album, if it existed.
+async fn delete_album_entries(db: &AstralDatabase, id: BsonId, session: &mut Transaction) -> Res<Option<AlbumMetadata>> {
+    let Some(album) = db.albums_metadata.find_one_and_delete_with_session(doc! { "album_id": id }, session).await? else {
+        return Ok(None)
+    };
+    // references are looked up on both sides, so stale ones are cleaned up as well
+    db.artists_metadata.update_many_with_session(doc! { "$or": [{ "albums": &id }, { "tracks": { "$in": &album.tracks } }] }, doc! {
+        "$pull": {
+            "albums": &id,
+            "tracks": { "$in": &album.tracks }
+        }
+    }, session).await?;
+    db.albums_metadata.update_many_with_session(doc! { "tracks": { "$in": &album.tracks } }, doc! { "$pull": { "tracks": { "$in": &album.tracks } } }, session).await?;
+    db.tracks_metadata.delete_many_with_session(doc! { "track_id": { "$in": &album.tracks } }, session).await?;
+    db.lyrics.delete_many_with_session(doc! { "track_id": { "$in": &album.tracks } }, session).await?;
+    db.fingerprints.delete_many_with_session(doc! { "track_id": { "$in": &album.tracks } }, session).await?;
+    db.accounts.update_many_with_session(doc! { }, doc! {
+        "$pull": {
+            "loved_albums": &id,
+            "loved_tracks": { "$in": &album.tracks }
+        }
+    }, session).await?;
+    Ok(Some(album))
 }
 
 /// Completely deletes a single track
@@ -231,7 +486,7 @@ pub async fn delete_album(
     tag = "upload"
 )]
 pub async fn delete_track(
-    State(AppState { db, .. }): State<AppState>,
+    State(AppState { db, search_index, .. }): State<AppState>,
     Path(track_id): Path<Uuid>,
     AuthenticatedUser(user): AuthenticatedUser,
 ) -> Res<()> {
@@ -239,39 +494,13 @@ pub async fn delete_track(
         return Err(AstralError::Unauthorized(String::from("You are not authorized to change metadata.")))
     }
 
-    let id = BsonId::from_uuid_1(track_id);
-    let track = db.tracks_metadata.find_one_and_delete(doc! { "track_id": id }, None).await?;
-
-    let files_dir = std::path::Path::new("astral_tracks");
-    let transcoded_low_dir = files_dir.join("transcoded_low");
-    let transcoded_med_dir = files_dir.join("transcoded_medium");
-    if let Some(track) = track {
-        db.tracks_metadata.delete_one(doc!{ "track_id": &id }, None).await?;
-        db.lyrics.delete_one(doc! {"track_id": &id}, None).await?;
-        let filename = format!("{track_id}.bin");
-        tokio::fs::remove_file(&files_dir.join(&filename)).await?;
-        let low = transcoded_low_dir.join(&filename);
-        if low.exists() {
-            tokio::fs::remove_file(&low).await?;
-        }
-        let medium = transcoded_med_dir.join(&filename);
-        if medium.exists() {
-            tokio::fs::remove_file(&medium).await?;
-        }
-        db.artists_metadata.update_many(doc! { "artist_id": {"$in": &track.artists} }, doc! {
-            "$pull": {
-                "tracks": &id
-            }
-        }, None).await?;
-        db.albums_metadata.update_many(doc! { "album_id": {"$in": &track.albums} }, doc! {
-            "$pull": {
-                "tracks": &id
-            }
-        }, None).await?;
-        return Ok(())
-    } else {
-        return Ok(())
-    }
+    remove_track(&db, &search_index, &BsonId::from_uuid_1(track_id)).await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct WriteTagsProps {
+    write_tags: Option<bool>,
 }
 
 /// Updates metadata for a single track
@@ -285,14 +514,16 @@ pub async fn delete_track(
     ),
     params(
         ("uuid" = Uuid, Path, description = "UUID of the track to patch"),
+        ("write_tags" = inline(Option<bool>), Query, description = "Whether to also write new metadata into the stored file tags"),
     ),
     tag = "upload"
 )]
 pub async fn patch_track_metadata(
-    State(AppState { db, .. }): State<AppState>,
+    State(AppState { db, search_index, .. }): State<AppState>,
     Path(track_id): Path<Uuid>,
+    Query(WriteTagsProps { write_tags }): Query<WriteTagsProps>,
     AuthenticatedUser(user): AuthenticatedUser,
-    Json(PatchTrackMetadata { track_name, track_length, is_explicit, number, disc_number, artists }): Json<PatchTrackMetadata>
+    Json(PatchTrackMetadata { track_name, track_length, is_explicit, number, disc_number, artists, genres }): Json<PatchTrackMetadata>
 ) -> Res<Json<TrackMetadataResponse>> {
     if !user.permissions.contains(&UserPermission::ChangeMetadata) {
         return Err(AstralError::Unauthorized(String::from("You are not authorized to change metadata.")))
@@ -314,11 +545,30 @@ pub async fn patch_track_metadata(
     if let Some(disc_number) = disc_number {
         doc_object.insert("disc_number", disc_number as i32);
     }
+    let refresh_artists = artists.is_some() || genres.is_some();
     if let Some(artists) = artists {
         doc_object.insert("artists", bson!(artists.into_iter().map(BsonId::from_uuid_1).collect::<Vec<_>>()));
     }
+    if let Some(genres) = genres {
+        doc_object.insert("genres", normalize_genres(&genres));
+    }
     let uid = BsonId::from_uuid_1(track_id.clone());
-    db.tracks_metadata.update_one(doc! { "track_id": &uid }, doc! { "$set": doc_object }, None).await?;
+    let old_data = db.tracks_metadata.find_one_and_update(doc! { "track_id": &uid }, doc! { "$set": doc_object }).await?
+        .ok_or_else(|| AstralError::NotFound(String::from("Could not find a track with this UUID")))?;
+
+    if refresh_artists {
+        let new_data = db.tracks_metadata.find_one(doc! { "track_id": &uid }).await?
+            .ok_or_else(|| AstralError::NotFound(String::from("Could not find a track with this UUID")))?;
+        let artists = old_data.artists.into_iter().chain(new_data.artists).collect::<HashSet<_>>().into_iter().collect::<Vec<_>>();
+        refresh_genres(&db, &artists, &new_data.albums).await?;
+        reindex_entries(&db, &search_index, &[uid], &[], &artists).await?;
+    } else {
+        reindex_entries(&db, &search_index, &[uid], &[], &[]).await?;
+    }
+
+    if write_tags.unwrap_or(false) {
+        write_track_tags(&db, uid).await?;
+    }
 
     let metadata = extract_track_metadata(&db, uid.clone()).await?;
 
@@ -340,34 +590,68 @@ pub async fn patch_track_metadata(
     ),
     params(
         ("uuid" = Uuid, Path, description = "UUID of the album to patch"),
+        ("write_tags" = inline(Option<bool>), Query, description = "Whether to also write new metadata into the file tags of all tracks in this album"),
     ),
     tag = "upload"
 )]
 pub async fn patch_album_metadata(
-    State(AppState { db, .. }): State<AppState>,
+    State(AppState { db, search_index, .. }): State<AppState>,
     Path(album_id): Path<Uuid>,
+    Query(WriteTagsProps { write_tags }): Query<WriteTagsProps>,
     AuthenticatedUser(user): AuthenticatedUser,
-    Json(PatchAlbumMetadata { album_name, tracks, artists, release_date, genres }): Json<PatchAlbumMetadata>
+    Json(patch): Json<PatchAlbumMetadata>
 ) -> Res<Json<AlbumMetadataResponse>> {
     if !user.permissions.contains(&UserPermission::ChangeMetadata) {
         return Err(AstralError::Unauthorized(String::from("You are not authorized to change metadata.")))
     }
 
     let album_id = BsonId::from_uuid_1(album_id);
-    let old_data = db.albums_metadata.find_one(doc! { "album_id": &album_id }, None).await?
+    let refresh_album = patch.genres.as_ref().is_some_and(|it| it.is_empty()) || patch.tracks.is_some();
+    let refresh_artists = db.with_transaction((&db, album_id, &patch), |session, (db, album_id, patch)| {
+        Box::pin(patch_album_entries(db, *album_id, patch, session))
+    }).await?;
+    refresh_genres(&db, &refresh_artists, if refresh_album { std::slice::from_ref(&album_id) } else { &[] }).await?;
+    reindex_entries(&db, &search_index, &[], &[album_id], &refresh_artists).await?;
+
+    if write_tags.unwrap_or(false) {
+        write_tags_matching(&db, doc! { "albums": &album_id }).await?;
+    }
+
+    let metadata = extract_album_metadata(&db, album_id, &user).await?;
+
+    Ok(Json(AlbumMetadataResponse {
+        album_id: album_id.to_uuid_1(),
+        metadata,
+        loved: user.loved_albums.contains(&album_id)
+    }))
+}
+
+/// Applies the album patch and updates references of changed tracks and artists as part of the transaction.
+/// Returns artists whose genres have to be refreshed.
+async fn patch_album_entries(db: &AstralDatabase, album_id: BsonId, patch: &PatchAlbumMetadata, session: &mut Transaction) -> Res<Vec<BsonId>> {
+    let old_data = db.albums_metadata.find_one_with_session(doc! { "album_id": &album_id }, session).await?
         .ok_or_else(|| AstralError::NotFound(String::from("Could not find an album with this UUID")))?;
     let mut doc_object = doc!();
-    if let Some(album_name) = album_name {
+    if let Some(album_name) = &patch.album_name {
         doc_object.insert("name", album_name);
     }
-    if let Some(release_date) = release_date {
+    if let Some(release_date) = patch.release_date {
         doc_object.insert("release_date", release_date as i64);
     }
-    if let Some(genres) = genres {
+    if let Some(album_type) = patch.album_type {
+        doc_object.insert("album_type", to_bson(&album_type).map_err(anyhow::Error::from)?);
+    }
+    if let Some(edition) = &patch.edition {
+        doc_object.insert("edition", Some(edition).filter(|it| !it.is_empty()));
+    }
+    let mut refresh_artists = old_data.artists.clone();
+    if let Some(genres) = &patch.genres {
+        let genres = normalize_genres(genres);
+        doc_object.insert("manual_genres", !genres.is_empty());
         doc_object.insert("genres", genres);
     }
-    if let Some(tracks) = tracks {
-        let tracks = tracks.into_iter().map(BsonId::from_uuid_1);
+    if let Some(tracks) = &patch.tracks {
+        let tracks = tracks.iter().copied().map(BsonId::from_uuid_1);
         let old_tracks: HashSet<BsonId, RandomState> = HashSet::from_iter(old_data.tracks.clone().into_iter());
         let new_tracks: HashSet<BsonId, RandomState> = HashSet::from_iter(tracks.clone());
         // these are the tracks that we will have to remove album reference from
@@ -376,12 +660,12 @@ pub async fn patch_album_metadata(
         let add_album: Vec<&BsonId> = new_tracks.difference(&old_tracks).collect();
 
         // adding album data
-        db.tracks_metadata.update_many(doc! { "track_id": { "$in": &add_album } }, doc! { "$addToSet": { "albums": &album_id } }, None).await?;
-        db.tracks_metadata.update_many(doc! { "track_id": { "$in": &remove_album} }, doc! { "$pull": { "albums": &album_id } }, None).await?;
+        db.tracks_metadata.update_many_with_session(doc! { "track_id": { "$in": &add_album } }, doc! { "$addToSet": { "albums": &album_id } }, session).await?;
+        db.tracks_metadata.update_many_with_session(doc! { "track_id": { "$in": &remove_album} }, doc! { "$pull": { "albums": &album_id } }, session).await?;
         doc_object.insert("tracks", tracks.collect::<Vec<_>>());
     }
-    if let Some(artists) = artists {
-        let artists = artists.into_iter().map(BsonId::from_uuid_1);
+    if let Some(artists) = &patch.artists {
+        let artists = artists.iter().copied().map(BsonId::from_uuid_1);
         let old_artists: HashSet<BsonId, RandomState> = HashSet::from_iter(old_data.artists.into_iter());
         let new_artists: HashSet<BsonId, RandomState> = HashSet::from_iter(artists.clone());
 
@@ -396,19 +680,14 @@ pub async fn patch_album_metadata(
             bson!(old_data.tracks)
         };
 
-        db.artists_metadata.update_many(doc! { "artist_id": { "$in": &add_album } }, doc! { "$addToSet": { "albums": &album_id, "tracks": { "$each": &tracks } }}, None).await?;
-        db.artists_metadata.update_many(doc! { "artist_id": { "$in": &remove_album } }, doc! { "$pull": { "albums": &album_id }, "$pullAll": { "tracks": &tracks } }, None).await?;
+        db.artists_metadata.update_many_with_session(doc! { "artist_id": { "$in": &add_album } }, doc! { "$addToSet": { "albums": &album_id, "tracks": { "$each": &tracks } }}, session).await?;
+        db.artists_metadata.update_many_with_session(doc! { "artist_id": { "$in": &remove_album } }, doc! { "$pull": { "albums": &album_id }, "$pullAll": { "tracks": &tracks } }, session).await?;
+        refresh_artists.extend(add_album.into_iter().copied());
         doc_object.insert("artists", artists.collect::<Vec<_>>());
     }
-    db.albums_metadata.update_one(doc! { "album_id": &album_id }, doc! { "$set": doc_object }, None).await?;
-
-    let metadata = extract_album_metadata(&db, album_id, &user).await?;
-
-    Ok(Json(AlbumMetadataResponse {
-        album_id: album_id.to_uuid_1(),
-        metadata,
-        loved: user.loved_albums.contains(&album_id)
-    }))
+    let refresh_artists = if doc_object.contains_key("tracks") || doc_object.contains_key("artists") { refresh_artists } else { vec![] };
+    db.albums_metadata.update_one_with_session(doc! { "album_id": &album_id }, doc! { "$set": doc_object }, session).await?;
+    Ok(refresh_artists)
 }
 
 /// Updates metadata for a single artist
@@ -426,7 +705,7 @@ pub async fn patch_album_metadata(
     tag = "upload"
 )]
 pub async fn patch_artist_metadata(
-    State(AppState { db, .. }): State<AppState>,
+    State(AppState { db, search_index, .. }): State<AppState>,
     Path(artist_id): Path<Uuid>,
     AuthenticatedUser(user): AuthenticatedUser,
     Json(PatchArtistMetadata { artist_name, albums, about_artist }): Json<PatchArtistMetadata>
@@ -436,7 +715,7 @@ pub async fn patch_artist_metadata(
     }
 
     let artist_id = BsonId::from_uuid_1(artist_id);
-    let old_data = db.artists_metadata.find_one(doc! { "artist_id": &artist_id }, None).await?
+    let old_data = db.artists_metadata.find_one(doc! { "artist_id": &artist_id }).await?
         .ok_or_else(|| AstralError::NotFound(String::from("Could not find an artist with this UUID")))?;
     let mut doc_object = doc!();
 
@@ -446,6 +725,7 @@ pub async fn patch_artist_metadata(
     if let Some(about) = about_artist {
         doc_object.insert("about", about);
     }
+    let refresh_artist = albums.is_some();
     if let Some(albums) = albums {
         let albums = albums.into_iter().map(BsonId::from_uuid_1);
         let old_albums: HashSet<BsonId, RandomState> = HashSet::from_iter(old_data.albums.clone().into_iter());
@@ -456,13 +736,17 @@ pub async fn patch_artist_metadata(
         // these are the albums that we will have to add artist reference to
         let add_artist: Vec<&BsonId> = new_albums.difference(&old_albums).collect();
 
-        db.albums_metadata.update_many(doc! { "album_id": { "$in": add_artist } }, doc! { "$addToSet": { "artists": &artist_id } }, None).await?;
-        db.albums_metadata.update_many(doc! { "album_id": { "$in": remove_artist } }, doc! { "$pull": { "artists": &artist_id } }, None).await?;
+        db.albums_metadata.update_many(doc! { "album_id": { "$in": add_artist } }, doc! { "$addToSet": { "artists": &artist_id } }).await?;
+        db.albums_metadata.update_many(doc! { "album_id": { "$in": remove_artist } }, doc! { "$pull": { "artists": &artist_id } }).await?;
 
         doc_object.insert("albums", albums.collect::<Vec<_>>());
     }
 
-    db.artists_metadata.update_one(doc! { "artist_id": &artist_id }, doc! { "$set": doc_object }, None).await?;
+    db.artists_metadata.update_one(doc! { "artist_id": &artist_id }, doc! { "$set": doc_object }).await?;
+    if refresh_artist {
+        refresh_genres(&db, &[artist_id], &[]).await?;
+    }
+    reindex_entries(&db, &search_index, &[], &[], &[artist_id]).await?;
 
     let metadata = extract_artist_metadata(&db, artist_id).await?;
 
@@ -482,32 +766,175 @@ pub async fn patch_artist_metadata(
     ),
     params(
         ("uuid" = Uuid, Path, description = "UUID of the album"),
+        ("write_tags" = inline(Option<bool>), Query, description = "Whether to also write new cover into the file tags of all tracks in this album"),
     ),
     tag = "upload"
 )]
 pub async fn change_cover(
     State(AppState { db, .. }): State<AppState>,
     Path(id): Path<Uuid>,
+    Query(WriteTagsProps { write_tags }): Query<WriteTagsProps>,
     AuthenticatedUser(user): AuthenticatedUser,
-    mut stream: Body
+    stream: Body
 ) -> Res<()> {
     if !user.permissions.contains(&UserPermission::ChangeMetadata) {
         return Err(AstralError::Unauthorized(String::from("You are not authorized to change metadata")))
     }
 
-    let found = db.gridfs_album_arts.find(doc! { "filename": id.to_string() }, None).await?.next().await;
-    if let Some(Ok(found)) = found {
-        db.gridfs_album_arts.delete(found.id).await?;
+    let album_id = BsonId::from_uuid_1(id);
+    let data = read_body(stream).await?;
+    store_artwork(&db, ArtworkKind::AlbumCover, &album_id, &data).await?;
+    refresh_album_palette(&db, &album_id, data).await?;
+
+    if write_tags.unwrap_or(false) {
+        write_tags_matching(&db, doc! { "albums": album_id }).await?;
     }
 
-    let mut u_stream = db.gridfs_album_arts.open_upload_stream(id.to_string(), None);
-    let mut stream = stream.into_data_stream();
-    while let Some(Ok(chunk)) = stream.next().await {
-        u_stream.write(&chunk).await?;
+    Ok(())
+}
+
+/// Changes the photo of an artist
+#[utoipa::path(
+    post,
+    path = "/upload/artist/{uuid}/photo",
+    request_body = BinaryFile,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, description = "Successfully changed artist photo")
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the artist"),
+    ),
+    tag = "upload"
+)]
+pub async fn change_artist_photo(
+    State(AppState { db, .. }): State<AppState>,
+    Path(id): Path<Uuid>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    stream: Body
+) -> Res<()> {
+    change_artist_artwork(&db, &user, ArtworkKind::ArtistPhoto, id, stream).await
+}
+
+/// Changes the banner of an artist, shown on top of the artist page
+#[utoipa::path(
+    post,
+    path = "/upload/artist/{uuid}/banner",
+    request_body = BinaryFile,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, description = "Successfully changed artist banner")
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the artist"),
+    ),
+    tag = "upload"
+)]
+pub async fn change_artist_banner(
+    State(AppState { db, .. }): State<AppState>,
+    Path(id): Path<Uuid>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    stream: Body
+) -> Res<()> {
+    change_artist_artwork(&db, &user, ArtworkKind::ArtistBanner, id, stream).await
+}
+
+async fn change_artist_artwork(db: &AstralDatabase, user: &UserAccount, kind: ArtworkKind, id: Uuid, stream: Body) -> Res<()> {
+    if !user.permissions.contains(&UserPermission::ChangeMetadata) {
+        return Err(AstralError::Unauthorized(String::from("You are not authorized to change metadata")))
+    }
+    let id = BsonId::from_uuid_1(id);
+    if db.artists_metadata.find_one(doc! { "artist_id": &id }).await?.is_none() {
+        return Err(AstralError::NotFound(String::from("Couldn't find an artist with this UUID")))
     }
-    u_stream.flush().await?;
-    GridFsUploadStream::close(&mut u_stream).await?;
 
+    let data = read_body(stream).await?;
+    store_artwork(db, kind, &id, &data).await
+}
+
+/// Changes artwork of a single track. Tracks without their own artwork use the cover of their album.
+#[utoipa::path(
+    post,
+    path = "/upload/track/{uuid}/cover",
+    request_body = BinaryFile,
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, description = "Successfully changed track artwork")
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the track"),
+        ("write_tags" = inline(Option<bool>), Query, description = "Whether to also write new artwork into the file tags of this track"),
+    ),
+    tag = "upload"
+)]
+pub async fn change_track_cover(
+    State(AppState { db, .. }): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(WriteTagsProps { write_tags }): Query<WriteTagsProps>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    stream: Body
+) -> Res<()> {
+    if !user.permissions.contains(&UserPermission::ChangeMetadata) {
+        return Err(AstralError::Unauthorized(String::from("You are not authorized to change metadata")))
+    }
+    let id = BsonId::from_uuid_1(id);
+    if db.tracks_metadata.find_one(doc! { "track_id": &id }).await?.is_none() {
+        return Err(AstralError::NotFound(String::from("Couldn't find a track with this UUID")))
+    }
+
+    let data = read_body(stream).await?;
+    store_artwork(&db, ArtworkKind::TrackArt, &id, &data).await?;
+
+    if write_tags.unwrap_or(false) {
+        write_track_tags(&db, id).await?;
+    }
+
+    Ok(())
+}
+
+/// Reads the whole request body into memory
+async fn read_body(body: Body) -> Res<Vec<u8>> {
+    let mut data = vec![];
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|err| AstralError::BadRequest(format!("Failed to read request body: {err}")))?;
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+#[derive(Deserialize)]
+pub struct BatchWriteTagsProps {
+    album: Option<Uuid>,
+}
+
+/// Starts a background job that writes current metadata into the stored file tags of all tracks, or tracks of a single album
+#[utoipa::path(
+    post,
+    path = "/upload/write_tags",
+    responses(
+        (status = 400, response = AstralError),
+        (status = 200, description = "Successfully started writing tags")
+    ),
+    params(
+        ("album" = inline(Option<Uuid>), Query, description = "UUID of the album to write tags for. Writes tags for the whole library if absent"),
+    ),
+    tag = "upload"
+)]
+pub async fn batch_write_tags(
+    State(AppState { db, .. }): State<AppState>,
+    Query(BatchWriteTagsProps { album }): Query<BatchWriteTagsProps>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Res<()> {
+    if !user.permissions.contains(&UserPermission::ChangeMetadata) {
+        return Err(AstralError::Unauthorized(String::from("You are not authorized to change metadata")))
+    }
+
+    let filter = match album {
+        Some(album) => doc! { "albums": BsonId::from_uuid_1(album) },
+        None => doc! { }
+    };
+    spawn_tag_writer(db, filter);
 
     Ok(())
 }
\ No newline at end of file
@@ -1,8 +1,10 @@
 use axum::extract::{Path, State};
+use axum::Json;
 use mongodb::bson::doc;
 use uuid::Uuid;
 use crate::api::AppState;
 use crate::api::extensions::AuthenticatedUser;
+use crate::api::model::PatchUserPreferences;
 use crate::data::model::BsonId;
 use crate::err::AstralError;
 use crate::Res;
@@ -26,7 +28,7 @@ pub async fn love_track(
     AuthenticatedUser(user): AuthenticatedUser
 ) -> Res<()> {
     let track = BsonId::from_uuid_1(track);
-    db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$addToSet": { "loved_tracks": &track }}, None).await?;
+    db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$addToSet": { "loved_tracks": &track }}).await?;
     Ok(())
 }
 
@@ -49,7 +51,7 @@ pub async fn unlove_track(
     AuthenticatedUser(user): AuthenticatedUser
 ) -> Res<()> {
     let track = BsonId::from_uuid_1(track);
-    db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$pull": { "loved_tracks": &track }}, None).await?;
+    db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$pull": { "loved_tracks": &track }}).await?;
     Ok(())
 }
 
@@ -72,7 +74,7 @@ pub async fn love_album(
     AuthenticatedUser(user): AuthenticatedUser
 ) -> Res<()> {
     let album = BsonId::from_uuid_1(album);
-    db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$addToSet": { "loved_albums": &album }}, None).await?;
+    db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$addToSet": { "loved_albums": &album }}).await?;
     Ok(())
 }
 
@@ -95,6 +97,32 @@ pub async fn unlove_album(
     AuthenticatedUser(user): AuthenticatedUser
 ) -> Res<()> {
     let album = BsonId::from_uuid_1(album);
-    db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$pull": { "loved_albums": &album }}, None).await?;
+    db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$pull": { "loved_albums": &album }}).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Change personal preferences of the user. Only provided preferences are changed.
+#[utoipa::path(
+    patch,
+    path = "/user/preferences",
+    request_body = PatchUserPreferences,
+    responses(
+        (status = 200, description = "Successfully changed the preferences"),
+        (status = 400, response = AstralError)
+    ),
+    tag = "user"
+)]
+pub async fn patch_preferences(
+    State(AppState { db, .. }): State<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    Json(req): Json<PatchUserPreferences>
+) -> Res<()> {
+    let mut update = doc! { };
+    if let Some(hide_explicit) = req.hide_explicit {
+        update.insert("hide_explicit", hide_explicit);
+    }
+    if !update.is_empty() {
+        db.accounts.update_one(doc! { "user_id": &user.user_id }, doc! { "$set": update }).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,882 @@
+use std::collections::HashMap;
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Method, Request, StatusCode};
+use axum::response::Response;
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson, Bson};
+use mongodb::bson::oid::ObjectId;
+use pasetors::keys::{Generate, SymmetricKey};
+use pasetors::version4::V4;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use uuid::Uuid;
+use crate::api::extensions::{create_user_access_key, create_user_refresh_key, validate_access_key, validate_key, UserPermission};
+use crate::api::paths::auth::hash_password;
+use crate::api::{router, AppState};
+use crate::data::AstralDatabase;
+use crate::data::backup::{export_library, import_library};
+use crate::data::migrations::{latest_version, run_migrations, schema_state};
+use crate::data::model::{AlbumMetadata, AlbumType, ArtistMetadata, BsonId, InviteCode, LyricsStatus, TrackFingerprint, TrackFormat, TrackLyrics, TrackMetadata, UndefinedTrack, UserAccount};
+use crate::jobs::fingerprinter::backfill_fingerprints;
+use crate::metadata::ExtractedTrackMetadata;
+use crate::metadata::genres::refresh_genres;
+use crate::metadata::preview::preview_attachments;
+use crate::search::engine::SearchIndex;
+
+/// Held by tests that write into `astral_tracks`, which is shared by all tests
+static TRACK_FILES: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Creates app state with an in-memory database and a search index in a fresh temporary directory
+fn test_state() -> AppState {
+    let index_dir = std::env::temp_dir().join(format!("astral_test_{}", Uuid::new_v4()));
+    AppState {
+        paseto_key: SymmetricKey::<V4>::generate().unwrap(),
+        db: AstralDatabase::in_memory().unwrap(),
+        search_index: SearchIndex::open_in(&index_dir).unwrap(),
+    }
+}
+
+/// Creates app state with a database in a fresh temporary SQLite file
+fn sqlite_state() -> AppState {
+    let path = std::env::temp_dir().join(format!("astral_test_{}.sqlite3", Uuid::new_v4()));
+    AppState { db: AstralDatabase::open_sqlite(path).unwrap(), ..test_state() }
+}
+
+/// Stores a new account with the password and returns it along with an access token
+async fn create_account(state: &AppState, username: &str, password: &str) -> (UserAccount, String) {
+    let account = UserAccount {
+        user_id: BsonId::new(),
+        username: username.to_owned(),
+        password_hash: hash_password(password.to_owned()),
+        register_date: Utc::now().timestamp_millis() as u64,
+        permissions: vec![],
+        loved_tracks: vec![],
+        loved_albums: vec![],
+        hide_explicit: false,
+    };
+    state.db.accounts.insert_one(&account).await.unwrap();
+    let token = create_user_access_key(&state.paseto_key, account.user_id.to_uuid_1()).unwrap();
+    (account, token)
+}
+
+async fn stored_account(state: &AppState, account: &UserAccount) -> UserAccount {
+    state.db.accounts.find_one(doc! { "user_id": &account.user_id }).await.unwrap().unwrap()
+}
+
+/// Sends the request through the router, returning the whole response
+async fn respond(state: &AppState, method: Method, uri: &str, token: Option<&str>, body: Option<Value>) -> Response {
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    let request = match body {
+        Some(body) => request.header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }.unwrap();
+
+    router(state.clone()).oneshot(request).await.unwrap()
+}
+
+/// Sends raw bytes as the request body, returning the status of the response
+async fn upload(state: &AppState, uri: &str, token: &str, data: Vec<u8>) -> StatusCode {
+    let request = Request::builder().method(Method::POST).uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .body(Body::from(data))
+        .unwrap();
+    router(state.clone()).oneshot(request).await.unwrap().status()
+}
+
+/// Encodes a square PNG image of the size
+fn test_image(size: u32) -> Vec<u8> {
+    let mut data = std::io::Cursor::new(vec![]);
+    image::DynamicImage::new_rgb8(size, size).write_to(&mut data, image::ImageFormat::Png).unwrap();
+    data.into_inner()
+}
+
+/// Sends the request through the router, returning the status and the response body
+async fn send(state: &AppState, method: Method, uri: &str, token: Option<&str>, body: Option<Value>) -> (StatusCode, Vec<u8>) {
+    let response = respond(state, method, uri, token, body).await;
+    let status = response.status();
+    (status, to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+}
+
+/// Replaces permissions of the stored account
+async fn grant(state: &AppState, account: &UserAccount, permissions: &[UserPermission]) {
+    state.db.accounts.update_one(doc! { "user_id": &account.user_id }, doc! {
+        "$set": { "permissions": to_bson(permissions).unwrap() }
+    }).await.unwrap();
+}
+
+/// Stores an artist without any albums or tracks
+async fn seed_artist(state: &AppState, name: &str) -> ArtistMetadata {
+    let artist = ArtistMetadata {
+        artist_id: BsonId::new(),
+        name: name.to_owned(),
+        albums: vec![],
+        tracks: vec![],
+        genres: HashMap::new(),
+        about: String::new(),
+        added_at: 0,
+        aliases: vec![],
+    };
+    state.db.artists_metadata.insert_one(&artist).await.unwrap();
+    artist
+}
+
+/// Stores an album of a new artist, containing tracks with the provided names
+async fn seed_album(state: &AppState, artist_name: &str, album_name: &str, track_names: &[&str]) -> (ArtistMetadata, AlbumMetadata, Vec<TrackMetadata>) {
+    let artist_id = BsonId::new();
+    let album_id = BsonId::new();
+    let tracks = track_names.iter().enumerate().map(|(idx, name)| TrackMetadata {
+        track_id: BsonId::new(),
+        name: name.to_string(),
+        length: 200,
+        artists: vec![artist_id],
+        albums: vec![album_id],
+        is_explicit: false,
+        format: TrackFormat::Flac,
+        number: idx as u16 + 1,
+        disc_number: 1,
+        genres: vec![],
+        added_at: 0,
+        play_count: 0,
+        hash: None,
+    }).collect::<Vec<_>>();
+    let track_ids = tracks.iter().map(|it| it.track_id).collect::<Vec<_>>();
+    let artist = ArtistMetadata {
+        artist_id,
+        name: artist_name.to_owned(),
+        albums: vec![album_id],
+        tracks: track_ids.clone(),
+        genres: HashMap::new(),
+        about: String::new(),
+        added_at: 0,
+        aliases: vec![],
+    };
+    let album = AlbumMetadata {
+        album_id,
+        name: album_name.to_owned(),
+        artists: vec![artist_id],
+        tracks: track_ids,
+        release_date: 0,
+        genres: vec![],
+        manual_genres: false,
+        added_at: 0,
+        palette: None,
+        discs: vec![],
+        disc_total: None,
+        album_type: AlbumType::Album,
+        edition: None,
+        aliases: vec![],
+    };
+    state.db.artists_metadata.insert_one(&artist).await.unwrap();
+    state.db.albums_metadata.insert_one(&album).await.unwrap();
+    for track in &tracks {
+        state.db.tracks_metadata.insert_one(track).await.unwrap();
+    }
+    (artist, album, tracks)
+}
+
+async fn stored_album(state: &AppState, album_id: &BsonId) -> AlbumMetadata {
+    state.db.albums_metadata.find_one(doc! { "album_id": album_id }).await.unwrap().unwrap()
+}
+
+async fn stored_artist(state: &AppState, artist_id: &BsonId) -> ArtistMetadata {
+    state.db.artists_metadata.find_one(doc! { "artist_id": artist_id }).await.unwrap().unwrap()
+}
+
+async fn stored_track(state: &AppState, track_id: &BsonId) -> TrackMetadata {
+    state.db.tracks_metadata.find_one(doc! { "track_id": track_id }).await.unwrap().unwrap()
+}
+
+fn pending_upload(account: &UserAccount) -> UndefinedTrack {
+    UndefinedTrack {
+        track_id: BsonId::new(),
+        hash: Uuid::new_v4().to_string(),
+        uploaded_by: account.user_id,
+        format: TrackFormat::Mp3,
+        uploaded_at: Utc::now().timestamp_millis() as u64,
+    }
+}
+
+fn invite_code(code: &str, expires_at: u64) -> InviteCode {
+    InviteCode {
+        code: code.to_owned(),
+        issued_by: BsonId::new(),
+        expires_at,
+        permissions: vec![UserPermission::UploadTracks],
+    }
+}
+
+#[tokio::test]
+async fn register_consumes_invite_code() {
+    let state = test_state();
+    let code = invite_code("A53gBf7A", Utc::now().timestamp_millis() as u64 + 60_000);
+    state.db.invite_codes.insert_one(&code).await.unwrap();
+
+    let (status, body) = send(&state, Method::POST, "/auth/register", None, Some(json!({
+        "username": "maxus",
+        "password": "hunter2",
+        "invite_code": "A53gBf7A",
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["invited_by"], json!(code.issued_by.to_uuid_1()));
+    let account = state.db.accounts.find_one(doc! { "username": "maxus" }).await.unwrap().unwrap();
+    assert_eq!(account.permissions, vec![UserPermission::UploadTracks]);
+    assert_eq!(validate_key(&state.paseto_key, body["refresh_token"].as_str().unwrap()).unwrap(), account.user_id.to_uuid_1());
+    assert_eq!(state.db.invite_codes.count(doc! { }).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn register_rejects_invalid_invite_codes() {
+    let state = test_state();
+    state.db.invite_codes.insert_one(&invite_code("expired", 1)).await.unwrap();
+
+    for code in ["expired", "unknown"] {
+        let (status, _) = send(&state, Method::POST, "/auth/register", None, Some(json!({
+            "username": "maxus",
+            "password": "hunter2",
+            "invite_code": code,
+        }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(state.db.accounts.count(doc! { }).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn login_checks_password() {
+    let state = test_state();
+    let (account, _) = create_account(&state, "maxus", "hunter2").await;
+
+    let (status, body) = send(&state, Method::POST, "/auth/login", None, Some(json!({ "username": "maxus", "password": "hunter2" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let refresh_token = String::from_utf8(body).unwrap();
+    assert_eq!(validate_key(&state.paseto_key, &refresh_token).unwrap(), account.user_id.to_uuid_1());
+
+    let (status, _) = send(&state, Method::POST, "/auth/login", None, Some(json!({ "username": "maxus", "password": "wrong" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&state, Method::POST, "/auth/login", None, Some(json!({ "username": "nobody", "password": "hunter2" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn refresh_token_obtains_access_token() {
+    let state = test_state();
+    let (account, _) = create_account(&state, "maxus", "hunter2").await;
+    let refresh_token = create_user_refresh_key(&state.paseto_key, account.user_id.to_uuid_1()).unwrap();
+
+    let (status, body) = send(&state, Method::GET, "/auth/token", Some(&refresh_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let access_token = String::from_utf8(body).unwrap();
+    assert_eq!(validate_access_key(&state.paseto_key, &access_token).unwrap(), account.user_id.to_uuid_1());
+
+    // refresh tokens can not be used as access tokens
+    let (status, _) = send(&state, Method::POST, &format!("/user/love/track/{}", Uuid::new_v4()), Some(&refresh_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn user_endpoints_require_authentication() {
+    let state = test_state();
+    let (status, _) = send(&state, Method::POST, &format!("/user/love/track/{}", Uuid::new_v4()), None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&state, Method::PATCH, "/user/preferences", Some("garbage"), Some(json!({ "hide_explicit": true }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn love_and_unlove_tracks_and_albums() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let track = Uuid::new_v4();
+    let album = Uuid::new_v4();
+
+    // loving twice keeps a single entry
+    for _ in 0..2 {
+        let (status, _) = send(&state, Method::POST, &format!("/user/love/track/{track}"), Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, _) = send(&state, Method::POST, &format!("/user/love/album/{album}"), Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let stored = stored_account(&state, &account).await;
+    assert_eq!(stored.loved_tracks, vec![BsonId::from_uuid_1(track)]);
+    assert_eq!(stored.loved_albums, vec![BsonId::from_uuid_1(album)]);
+
+    send(&state, Method::POST, &format!("/user/unlove/track/{track}"), Some(&token), None).await;
+    send(&state, Method::POST, &format!("/user/unlove/album/{album}"), Some(&token), None).await;
+    let stored = stored_account(&state, &account).await;
+    assert!(stored.loved_tracks.is_empty());
+    assert!(stored.loved_albums.is_empty());
+}
+
+#[tokio::test]
+async fn patch_preferences_changes_only_provided_fields() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+
+    let (status, _) = send(&state, Method::PATCH, "/user/preferences", Some(&token), Some(json!({ "hide_explicit": true }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(stored_account(&state, &account).await.hide_explicit);
+
+    let (status, _) = send(&state, Method::PATCH, "/user/preferences", Some(&token), Some(json!({ }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(stored_account(&state, &account).await.hide_explicit);
+}
+
+#[tokio::test]
+async fn get_lyrics_returns_stored_lyrics() {
+    let state = test_state();
+    let (_, token) = create_account(&state, "maxus", "hunter2").await;
+    let track = Uuid::new_v4();
+    state.db.lyrics.insert_one(&TrackLyrics {
+        track_id: BsonId::from_uuid_1(track),
+        status: LyricsStatus::Unsynced { lines: vec![String::from("abc"), String::from("def")] },
+    }).await.unwrap();
+
+    let (status, body) = send(&state, Method::GET, &format!("/lyrics/{track}"), Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body, json!({ "lyrics_status": "unsynced", "lines": ["abc", "def"] }));
+}
+
+#[tokio::test]
+async fn list_pending_uploads_of_the_user() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (other, _) = create_account(&state, "other", "hunter2").await;
+    let own = pending_upload(&account);
+    state.db.undefined_tracks.insert_one(&own).await.unwrap();
+    state.db.undefined_tracks.insert_one(&pending_upload(&other)).await.unwrap();
+
+    let (status, body) = send(&state, Method::GET, "/upload/pending", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["track_id"], json!(own.track_id.to_uuid_1()));
+    assert_eq!(body[0]["preview"], Value::Null);
+
+    let (status, _) = send(&state, Method::GET, "/upload/pending?all=true", Some(&token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    grant(&state, &account, &[UserPermission::Admin]).await;
+    let (status, body) = send(&state, Method::GET, "/upload/pending?all=true", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn discard_pending_upload_checks_uploader() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (_, other_token) = create_account(&state, "other", "hunter2").await;
+    let upload = pending_upload(&account);
+    state.db.undefined_tracks.insert_one(&upload).await.unwrap();
+    let uri = format!("/upload/pending/{}/discard", upload.track_id.to_uuid_1());
+
+    let (status, _) = send(&state, Method::POST, &uri, Some(&other_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(state.db.undefined_tracks.count(doc! { }).await.unwrap(), 1);
+
+    let (status, _) = send(&state, Method::POST, &uri, Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(state.db.undefined_tracks.count(doc! { }).await.unwrap(), 0);
+    let (status, _) = send(&state, Method::POST, &uri, Some(&token), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn patch_album_moves_artist_references() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (old_artist, album, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay"]).await;
+    let new_artist = seed_artist(&state, "Grimes").await;
+    let uri = format!("/upload/album/{}/patch", album.album_id.to_uuid_1());
+    let patch = json!({ "album_name": "Pills", "artists": [new_artist.artist_id.to_uuid_1()] });
+
+    let (status, _) = send(&state, Method::PATCH, &uri, Some(&token), Some(patch.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    grant(&state, &account, &[UserPermission::ChangeMetadata]).await;
+    let (status, body) = send(&state, Method::PATCH, &uri, Some(&token), Some(patch)).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["metadata"]["album_name"], json!("Pills"));
+
+    let stored = stored_album(&state, &album.album_id).await;
+    assert_eq!(stored.name, "Pills");
+    assert_eq!(stored.artists, vec![new_artist.artist_id]);
+    let old_artist = stored_artist(&state, &old_artist.artist_id).await;
+    assert!(old_artist.albums.is_empty());
+    assert!(old_artist.tracks.is_empty());
+    let new_artist = stored_artist(&state, &new_artist.artist_id).await;
+    assert_eq!(new_artist.albums, vec![album.album_id]);
+    assert_eq!(new_artist.tracks, vec![tracks[0].track_id]);
+}
+
+#[tokio::test]
+async fn manual_album_genres_survive_track_changes() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    grant(&state, &account, &[UserPermission::ChangeMetadata]).await;
+    let (_, album, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay"]).await;
+    let album_uri = format!("/upload/album/{}/patch", album.album_id.to_uuid_1());
+    let track_uri = format!("/upload/track/{}/patch", tracks[0].track_id.to_uuid_1());
+
+    let (status, _) = send(&state, Method::PATCH, &album_uri, Some(&token), Some(json!({ "genres": ["Shoegaze"] }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&state, Method::PATCH, &track_uri, Some(&token), Some(json!({ "genres": ["Techno"] }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let stored = stored_album(&state, &album.album_id).await;
+    assert_eq!(stored.genres, vec!["shoegaze"]);
+    assert!(stored.manual_genres);
+
+    let (status, _) = send(&state, Method::PATCH, &album_uri, Some(&token), Some(json!({ "genres": [] }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let stored = stored_album(&state, &album.album_id).await;
+    assert_eq!(stored.genres, vec!["techno"]);
+    assert!(!stored.manual_genres);
+}
+
+#[tokio::test]
+async fn index_validates_parameters() {
+    let state = test_state();
+    let (_, token) = create_account(&state, "maxus", "hunter2").await;
+
+    let (status, _) = send(&state, Method::GET, "/v2/index/albums?count=10", None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&state, Method::GET, "/v2/index/albums", Some(&token), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    for kind in ["albums", "artists", "tracks"] {
+        let (status, _) = send(&state, Method::GET, &format!("/v2/index/{kind}?count=10&year_from=300000"), Some(&token), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
+
+#[tokio::test]
+async fn search_finds_rebuilt_entries() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    seed_album(&state, "Meltdown", "Drugs", &["Clay"]).await;
+
+    let (status, _) = send(&state, Method::POST, "/search/rebuild", Some(&token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    grant(&state, &account, &[UserPermission::Admin]).await;
+    let (status, body) = send(&state, Method::POST, "/search/rebuild", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(serde_json::from_slice::<u64>(&body).unwrap(), 3);
+
+    let (status, body) = send(&state, Method::GET, "/search/suggest?query=cla", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0]["kind"], json!("track"));
+    assert_eq!(body[0]["name"], json!("Clay"));
+    let (status, body) = send(&state, Method::GET, "/v2/index/tracks?count=10&search=clay", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["total"], json!(1));
+    assert_eq!(body["items"][0]["name"], json!("Clay"));
+
+    let (status, _) = send(&state, Method::GET, "/search?query=%20", Some(&token), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, body) = send(&state, Method::GET, "/search?query=qwxzqwxz", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body, json!({ "tracks": [], "albums": [], "artists": [], "lyrics": [] }));
+}
+
+#[tokio::test]
+async fn suggest_matches_words_longer_than_indexed_prefixes() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    grant(&state, &account, &[UserPermission::Admin]).await;
+    seed_album(&state, "Meltdown", "Drugs", &["Supercalifragilisticexpialidocious"]).await;
+    let (status, _) = send(&state, Method::POST, "/search/rebuild", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = send(&state, Method::GET, "/search/suggest?query=supercalifragilisticexpiali", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0]["name"], json!("Supercalifragilisticexpialidocious"));
+}
+
+#[tokio::test]
+async fn split_album_moves_tracks_to_new_album() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (old_artist, album, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay", "Pills"]).await;
+    let new_artist = seed_artist(&state, "Grimes").await;
+    let uri = format!("/library/album/{}/split", album.album_id.to_uuid_1());
+    let request = json!({
+        "tracks": [tracks[1].track_id.to_uuid_1()],
+        "album_name": "Pills",
+        "artists": [new_artist.artist_id.to_uuid_1()],
+    });
+
+    let (status, _) = send(&state, Method::POST, &uri, Some(&token), Some(request.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    grant(&state, &account, &[UserPermission::Admin]).await;
+    let (status, _) = send(&state, Method::POST, &uri, Some(&token), Some(json!({
+        "tracks": [tracks[0].track_id.to_uuid_1(), tracks[1].track_id.to_uuid_1()],
+        "album_name": "Pills",
+    }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, body) = send(&state, Method::POST, &uri, Some(&token), Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    let new_id = BsonId::from_uuid_1(serde_json::from_value(body["album_id"].clone()).unwrap());
+
+    assert_eq!(stored_album(&state, &album.album_id).await.tracks, vec![tracks[0].track_id]);
+    let new_album = stored_album(&state, &new_id).await;
+    assert_eq!(new_album.tracks, vec![tracks[1].track_id]);
+    assert_eq!(new_album.artists, vec![new_artist.artist_id]);
+    let moved = stored_track(&state, &tracks[1].track_id).await;
+    assert_eq!(moved.albums, vec![new_id]);
+    assert_eq!(moved.artists, vec![new_artist.artist_id]);
+    assert_eq!(stored_artist(&state, &old_artist.artist_id).await.tracks, vec![tracks[0].track_id]);
+    let new_artist = stored_artist(&state, &new_artist.artist_id).await;
+    assert_eq!(new_artist.albums, vec![new_id]);
+    assert_eq!(new_artist.tracks, vec![tracks[1].track_id]);
+}
+
+#[tokio::test]
+async fn duplicate_report_groups_same_titles() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (_, _, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay", "Clay", "Pills"]).await;
+
+    let (status, _) = send(&state, Method::GET, "/library/duplicates", Some(&token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&state, Method::POST, "/library/integrity", Some(&token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    grant(&state, &account, &[UserPermission::Admin]).await;
+    let (status, body) = send(&state, Method::GET, "/library/duplicates", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["reason"], json!("same_title"));
+    let mut grouped = body[0]["tracks"].as_array().unwrap().iter()
+        .map(|it| serde_json::from_value::<Uuid>(it["track_id"].clone()).unwrap())
+        .collect::<Vec<_>>();
+    grouped.sort();
+    let mut expected = vec![tracks[0].track_id.to_uuid_1(), tracks[1].track_id.to_uuid_1()];
+    expected.sort();
+    assert_eq!(grouped, expected);
+}
+
+#[tokio::test]
+async fn download_track_names_file_by_template() {
+    let _files = TRACK_FILES.lock().await;
+    let state = test_state();
+    let (_, token) = create_account(&state, "maxus", "hunter2").await;
+    let (_, _, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay"]).await;
+    let path = std::path::Path::new("astral_tracks").join(format!("{}.bin", tracks[0].track_id));
+    std::fs::create_dir_all("astral_tracks").unwrap();
+    std::fs::write(&path, b"audio").unwrap();
+
+    let uri = format!("/download/track/{}?template=%7Bartist%7D%20-%20%7Btitle%7D", tracks[0].track_id.to_uuid_1());
+    let response = respond(&state, Method::GET, &uri, Some(&token), None).await;
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(parts.status, StatusCode::OK);
+    assert_eq!(parts.headers[header::CONTENT_TYPE], "audio/flac");
+    assert_eq!(parts.headers[header::CONTENT_DISPOSITION], "attachment; filename=\"Meltdown - Clay.flac\"; filename*=UTF-8''Meltdown%20-%20Clay.flac");
+    assert_eq!(body.as_ref(), b"audio");
+
+    let (status, _) = send(&state, Method::GET, &format!("/download/track/{}", Uuid::new_v4()), Some(&token), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(&state, Method::GET, &format!("/download/album/{}", Uuid::new_v4()), Some(&token), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn artwork_is_resized_and_cached() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (artist, album, _) = seed_album(&state, "Meltdown", "Drugs", &["Clay"]).await;
+    let cover_uri = format!("/upload/cover/{}", album.album_id.to_uuid_1());
+
+    assert_eq!(upload(&state, &cover_uri, &token, test_image(300)).await, StatusCode::UNAUTHORIZED);
+    grant(&state, &account, &[UserPermission::ChangeMetadata]).await;
+    assert_eq!(upload(&state, &cover_uri, &token, test_image(300)).await, StatusCode::OK);
+    let photo_uri = format!("/upload/artist/{}/photo", Uuid::new_v4());
+    assert_eq!(upload(&state, &photo_uri, &token, test_image(100)).await, StatusCode::NOT_FOUND);
+    let photo_uri = format!("/upload/artist/{}/photo", artist.artist_id.to_uuid_1());
+    assert_eq!(upload(&state, &photo_uri, &token, test_image(100)).await, StatusCode::OK);
+
+    let uri = format!("/metadata/album/{}/cover", album.album_id.to_uuid_1());
+    let (status, body) = send(&state, Method::GET, &uri, Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, test_image(300));
+
+    let uri = format!("{uri}?size=100&format=webp");
+    let response = respond(&state, Method::GET, &uri, Some(&token), None).await;
+    let (parts, body) = response.into_parts();
+    assert_eq!(parts.status, StatusCode::OK);
+    assert_eq!(parts.headers[header::CONTENT_TYPE], "image/webp");
+    let resized = image::load_from_memory(&to_bytes(body, usize::MAX).await.unwrap()).unwrap();
+    assert_eq!((resized.width(), resized.height()), (128, 128));
+    let etag = parts.headers[header::ETAG].clone();
+
+    // the cached variant is served again, so its entity tag stays the same
+    let request = Request::builder().method(Method::GET).uri(&uri)
+        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .header(header::IF_NONE_MATCH, etag.clone())
+        .body(Body::empty())
+        .unwrap();
+    let response = router(state.clone()).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[header::ETAG], etag);
+
+    // photos are never upscaled
+    let uri = format!("/metadata/artist/{}/photo?size=1000", artist.artist_id.to_uuid_1());
+    let (status, body) = send(&state, Method::GET, &uri, Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(image::load_from_memory(&body).unwrap().width(), 100);
+
+    // artwork too large to decode is refused instead of being resized
+    let mut wide = std::io::Cursor::new(vec![]);
+    image::DynamicImage::new_rgb8(10_000, 1).write_to(&mut wide, image::ImageFormat::Png).unwrap();
+    assert_eq!(upload(&state, &photo_uri, &token, wide.into_inner()).await, StatusCode::OK);
+    let (status, _) = send(&state, Method::GET, &uri.replace("size=1000", "size=64"), Some(&token), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn merge_album_moves_tracks_and_cover() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (artist, album, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay"]).await;
+    let (_, duplicate, duplicate_tracks) = seed_album(&state, "Meltdown", "Drugs (Deluxe)", &["Pills"]).await;
+    grant(&state, &account, &[UserPermission::ChangeMetadata]).await;
+    let cover_uri = format!("/upload/cover/{}", duplicate.album_id.to_uuid_1());
+    assert_eq!(upload(&state, &cover_uri, &token, test_image(64)).await, StatusCode::OK);
+
+    let uri = format!("/library/album/{}/merge", album.album_id.to_uuid_1());
+    let request = json!({ "duplicates": [duplicate.album_id.to_uuid_1()] });
+    let (status, _) = send(&state, Method::POST, &uri, Some(&token), Some(request.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    grant(&state, &account, &[UserPermission::Admin]).await;
+    let (status, _) = send(&state, Method::POST, &uri, Some(&token), Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let merged = stored_album(&state, &album.album_id).await;
+    assert_eq!(merged.tracks, vec![tracks[0].track_id, duplicate_tracks[0].track_id]);
+    assert_eq!(merged.aliases, vec![String::from("Drugs (Deluxe)")]);
+    assert!(state.db.albums_metadata.find_one(doc! { "album_id": duplicate.album_id }).await.unwrap().is_none());
+    assert_eq!(stored_track(&state, &duplicate_tracks[0].track_id).await.albums, vec![album.album_id]);
+    assert!(stored_artist(&state, &artist.artist_id).await.albums.contains(&album.album_id));
+
+    let (status, body) = send(&state, Method::GET, &format!("/metadata/album/{}/cover", album.album_id.to_uuid_1()), Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, test_image(64));
+    let (status, _) = send(&state, Method::GET, &format!("/metadata/album/{}/cover", duplicate.album_id.to_uuid_1()), Some(&token), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn resolve_duplicates_keeps_album_positions() {
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (_, album, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay", "Pills", "Clay"]).await;
+    state.db.tracks_metadata.update_one(doc! { "track_id": tracks[0].track_id }, doc! { "$set": { "play_count": 3 } }).await.unwrap();
+    grant(&state, &account, &[UserPermission::Admin]).await;
+
+    let (status, _) = send(&state, Method::POST, "/library/duplicates/resolve", Some(&token), Some(json!({
+        "keep": tracks[2].track_id.to_uuid_1(),
+        "remove": [tracks[0].track_id.to_uuid_1()],
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // the kept track moves into the place of the removed one instead of staying at the end
+    assert_eq!(stored_album(&state, &album.album_id).await.tracks, vec![tracks[2].track_id, tracks[1].track_id]);
+    assert_eq!(stored_track(&state, &tracks[2].track_id).await.play_count, 3);
+    assert!(state.db.tracks_metadata.find_one(doc! { "track_id": tracks[0].track_id }).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn genre_index_counts_tracks_albums_and_artists() {
+    let state = test_state();
+    let (_, token) = create_account(&state, "maxus", "hunter2").await;
+    let (artist, album, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay", "Pills"]).await;
+    state.db.tracks_metadata.update_one(doc! { "track_id": tracks[0].track_id }, doc! { "$set": { "genres": ["punk"] } }).await.unwrap();
+    state.db.tracks_metadata.update_one(doc! { "track_id": tracks[1].track_id }, doc! { "$set": { "genres": ["punk", "techno"] } }).await.unwrap();
+    refresh_genres(&state.db, &[artist.artist_id], &[album.album_id]).await.unwrap();
+
+    let (status, body) = send(&state, Method::GET, "/index/genres", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0], json!({ "name": "punk", "parent": "rock", "track_count": 2, "album_count": 1, "artist_count": 1 }));
+    assert_eq!(body[1]["name"], json!("techno"));
+    assert_eq!(body[1]["track_count"], json!(1));
+    assert_eq!(body[1]["artist_count"], json!(1));
+}
+
+#[tokio::test]
+async fn migrations_update_old_documents_once() {
+    let state = test_state();
+    let album_id = ObjectId::new();
+    state.db.documents("albums_metadata").unwrap().insert_one(&doc! {
+        "_id": album_id,
+        "album_id": BsonId::new(),
+        "name": "Drugs",
+        "added_at": 0,
+    }).await.unwrap();
+
+    let outcomes = run_migrations(&state.db, true).await.unwrap();
+    assert_eq!(outcomes.iter().map(|it| it.affected).collect::<Vec<_>>(), vec![1, 1]);
+    assert_eq!(schema_state(&state.db).await.unwrap().version, 0);
+
+    let outcomes = run_migrations(&state.db, false).await.unwrap();
+    assert_eq!(outcomes.len(), 2);
+    let album = state.db.documents("albums_metadata").unwrap().find_one(doc! { "_id": album_id }).await.unwrap().unwrap();
+    assert_eq!(album.get_str("album_type").unwrap(), "album");
+    assert_eq!(album.get("added_at"), Some(&Bson::Int64(album_id.timestamp().timestamp_millis())));
+    let schema = schema_state(&state.db).await.unwrap();
+    assert_eq!(schema.version, latest_version());
+    assert_eq!(schema.applied.len(), 2);
+
+    assert!(run_migrations(&state.db, false).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn backup_restores_library_into_empty_instance() {
+    let _files = TRACK_FILES.lock().await;
+    let state = test_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (_, album, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay", "Pills"]).await;
+    grant(&state, &account, &[UserPermission::ChangeMetadata]).await;
+    assert_eq!(upload(&state, &format!("/upload/cover/{}", album.album_id.to_uuid_1()), &token, test_image(64)).await, StatusCode::OK);
+    let audio = std::path::Path::new("astral_tracks").join(format!("{}.bin", tracks[0].track_id));
+    std::fs::create_dir_all("astral_tracks").unwrap();
+    std::fs::write(&audio, b"audio").unwrap();
+
+    let archive = std::env::temp_dir().join(format!("astral_backup_{}.zip", Uuid::new_v4()));
+    let exported = export_library(&state.db, &archive).await;
+    std::fs::remove_file(&audio).unwrap();
+    let exported = exported.unwrap();
+    assert_eq!(exported.collections["tracks_metadata"], 2);
+    assert_eq!(exported.missing_audio, vec![tracks[1].track_id.to_string()]);
+
+    let restored = test_state();
+    let imported = import_library(&restored.db, &archive).await;
+    let restored_audio = std::fs::read(&audio);
+    // importing again is refused, as the instance is no longer empty
+    let repeated = import_library(&restored.db, &archive).await;
+    let _ = std::fs::remove_file(&audio);
+    std::fs::remove_file(&archive).unwrap();
+    imported.unwrap();
+    assert!(repeated.is_err());
+    assert_eq!(restored_audio.unwrap(), b"audio");
+    assert_eq!(stored_album(&restored, &album.album_id).await.tracks, album.tracks);
+    assert_eq!(stored_account(&restored, &account).await.username, "maxus");
+    let cover = restored.db.album_arts.find_by_name(&album.album_id.to_string()).await.unwrap().unwrap();
+    assert_eq!(restored.db.album_arts.read(&cover.id).await.unwrap(), test_image(64));
+}
+
+#[tokio::test]
+async fn fingerprint_backfill_skips_stored_attempts() {
+    let state = test_state();
+    let (_, _, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay", "Pills", "Ribs"]).await;
+    for (track, failed) in [(&tracks[0], false), (&tracks[1], true)] {
+        state.db.fingerprints.insert_one(&TrackFingerprint {
+            track_id: track.track_id,
+            fingerprint: vec![],
+            computed_at: 1,
+            failed,
+            acoustid: None,
+            recording_ids: vec![],
+            looked_up_at: None,
+        }).await.unwrap();
+    }
+
+    // the remaining track has no audio file, so its attempt fails and is stored as such
+    assert_eq!(backfill_fingerprints(&state.db).await.unwrap(), 0);
+    let stored = |track: &TrackMetadata| state.db.fingerprints.find_one(doc! { "track_id": track.track_id });
+    assert_eq!(stored(&tracks[0]).await.unwrap().unwrap().computed_at, 1);
+    assert_eq!(stored(&tracks[1]).await.unwrap().unwrap().computed_at, 1);
+    assert!(stored(&tracks[2]).await.unwrap().unwrap().failed);
+
+    let computed_at = stored(&tracks[2]).await.unwrap().unwrap().computed_at;
+    assert_eq!(backfill_fingerprints(&state.db).await.unwrap(), 0);
+    assert_eq!(stored(&tracks[2]).await.unwrap().unwrap().computed_at, computed_at);
+}
+
+#[tokio::test]
+async fn sqlite_backend_serves_library() {
+    let state = sqlite_state();
+    let (account, token) = create_account(&state, "maxus", "hunter2").await;
+    let (_, album, tracks) = seed_album(&state, "Meltdown", "Drugs", &["Clay", "Pills", "Clay"]).await;
+    let (_, duplicate, duplicate_tracks) = seed_album(&state, "Meltdown", "Drugs (Deluxe)", &["Ribs"]).await;
+    state.db.tracks_metadata.update_many(doc! { }, doc! { "$set": { "genres": ["punk"] } }).await.unwrap();
+    grant(&state, &account, &[UserPermission::Admin, UserPermission::ChangeMetadata]).await;
+
+    // covers are stored in the database along with everything else
+    assert_eq!(upload(&state, &format!("/upload/cover/{}", duplicate.album_id.to_uuid_1()), &token, test_image(64)).await, StatusCode::OK);
+    let (status, _) = send(&state, Method::POST, &format!("/library/album/{}/merge", album.album_id.to_uuid_1()), Some(&token), Some(json!({
+        "duplicates": [duplicate.album_id.to_uuid_1()],
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = send(&state, Method::GET, &format!("/metadata/album/{}/cover?size=64", album.album_id.to_uuid_1()), Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(image::load_from_memory(&body).unwrap().width(), 64);
+
+    let (status, _) = send(&state, Method::POST, "/library/duplicates/resolve", Some(&token), Some(json!({
+        "keep": tracks[2].track_id.to_uuid_1(),
+        "remove": [tracks[0].track_id.to_uuid_1()],
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stored_album(&state, &album.album_id).await.tracks, vec![tracks[2].track_id, tracks[1].track_id, duplicate_tracks[0].track_id]);
+
+    let (status, body) = send(&state, Method::GET, "/index/genres", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0]["track_count"], json!(3));
+    let (status, body) = send(&state, Method::GET, "/v2/index/tracks?count=2&sort=name", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["total"], json!(3));
+    assert_eq!(body["items"][0]["name"], json!("Clay"));
+    assert_eq!(body["items"][0]["album_name"], json!("Drugs"));
+    assert!(body["next_cursor"].is_string());
+
+    let (status, body) = send(&state, Method::POST, "/search/rebuild", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    // both seeded albums are by their own artist
+    assert_eq!(serde_json::from_slice::<u64>(&body).unwrap(), 6);
+    assert_eq!(run_migrations(&state.db, false).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn preview_attaches_only_to_the_same_edition() {
+    let state = test_state();
+    let (_, album, _) = seed_album(&state, "Meltdown", "Drugs", &["Clay"]).await;
+    let mut metadata = ExtractedTrackMetadata {
+        name: String::from("Pills"),
+        album_name: String::from("Drugs"),
+        artists: vec![String::from("Meltdown")],
+        album_artists: vec![],
+        cover_art: None,
+        duration: 180.0,
+        format: TrackFormat::Flac,
+        number: 2,
+        disc_number: 1,
+        track_total: 0,
+        disc_total: 0,
+        disc_subtitle: None,
+        album_type: None,
+        edition: None,
+        release_date: 0,
+        is_explicit: false,
+        lyrics: None,
+        genres: vec![],
+    };
+
+    let attachments = preview_attachments(&state.db, &metadata).await.unwrap();
+    assert_eq!(attachments.existing_album, Some((album.album_id.to_uuid_1(), String::from("Drugs"))));
+    metadata.edition = Some(String::from("Deluxe"));
+    let attachments = preview_attachments(&state.db, &metadata).await.unwrap();
+    assert_eq!(attachments.existing_album, None);
+    assert_eq!(attachments.new_album, Some(String::from("Drugs")));
+}
@@ -1,78 +1,253 @@
+use std::env;
 use std::fs::create_dir_all;
 use std::time::Instant;
-use mongodb::{Client, Collection, Database, GridFsBucket, IndexModel};
-use mongodb::bson::doc;
-use mongodb::options::GridFsBucketOptions;
+use futures_util::future::BoxFuture;
+use mongodb::{Client, ClientSession, Collection, IndexModel};
+use mongodb::bson::{doc, Document};
+use mongodb::error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
 use crate::api::extensions::UserPermission;
-use crate::data::model::{AlbumMetadata, ArtistMetadata, InviteCode, TrackLyrics, TrackMetadata, UndefinedTrack, UserAccount};
+use crate::data::migrations::run_migrations;
+use crate::data::bucket::Bucket;
+#[cfg(test)]
+use crate::data::repository::memory::{MemoryStore, MemoryTransaction};
+use crate::data::repository::{Backend, MongoBackend, Repo};
+use crate::data::repository::sqlite::{SqliteDatabase, SqliteTransaction};
+use crate::data::model::{AlbumMetadata, ArtistMetadata, InviteCode, TrackFingerprint, TrackLyrics, TrackMetadata, UndefinedTrack, UserAccount};
+use crate::err::AstralError;
+use crate::Res;
 
 /// Contains all database models
 pub mod model;
+/// Finds and repairs inconsistencies in the library
+pub mod integrity;
+/// Schema versioning and data migrations
+pub mod migrations;
+/// Full library export and import
+pub mod backup;
+/// Storage abstraction over collections
+pub mod repository;
+/// Storage of binary files such as artwork
+pub mod bucket;
 
-/// MongoDB database wrapper
+/// Maximum amount of attempts of a transaction failing with transient errors
+pub const MAX_TRANSACTION_ATTEMPTS: usize = 5;
+
+/// Writes grouped into a single transaction with [AstralDatabase::start_transaction].
+/// Holds no session if the deployment does not support transactions, as writes are applied one by one then.
+#[derive(Default)]
+pub struct Transaction {
+    session: Option<ClientSession>,
+    /// Open transaction of the SQLite database, rolled back if it is dropped
+    sqlite: Option<SqliteTransaction>,
+    /// Writes to in-memory collections, applied once the transaction commits
+    #[cfg(test)]
+    memory: Option<MemoryTransaction>,
+}
+
+impl Transaction {
+    /// Session the writes are made with, if the deployment supports transactions
+    pub fn session(&mut self) -> Option<&mut ClientSession> {
+        self.session.as_mut()
+    }
+
+    /// Copies of in-memory collections changed by the transaction
+    #[cfg(test)]
+    pub fn memory(&mut self) -> Option<&mut MemoryTransaction> {
+        self.memory.as_mut()
+    }
+}
+
+/// Database wrapper, storing collections and files in the selected [Backend]
 #[derive(Debug, Clone)]
 pub struct AstralDatabase {
     /// Metadata for tracks
-    pub tracks_metadata: Collection<TrackMetadata>,
+    pub tracks_metadata: Repo<TrackMetadata>,
     /// Metadata for artists
-    pub artists_metadata: Collection<ArtistMetadata>,
+    pub artists_metadata: Repo<ArtistMetadata>,
     /// Metadata for albums
-    pub albums_metadata: Collection<AlbumMetadata>,
+    pub albums_metadata: Repo<AlbumMetadata>,
     /// User accounts
-    pub accounts: Collection<UserAccount>,
+    pub accounts: Repo<UserAccount>,
     /// Invite codes used to register
-    pub invite_codes: Collection<InviteCode>,
+    pub invite_codes: Repo<InviteCode>,
     /// Undefined tracks
-    pub undefined_tracks: Collection<UndefinedTrack>,
+    pub undefined_tracks: Repo<UndefinedTrack>,
     /// Track lyrics
-    pub lyrics: Collection<TrackLyrics>,
-    /// GridFS bucket for all the album arts
-    pub gridfs_album_arts: GridFsBucket,
-    /// Access to the inner database
-    pub inner: Database
+    pub lyrics: Repo<TrackLyrics>,
+    /// Acoustic fingerprints of tracks
+    pub fingerprints: Repo<TrackFingerprint>,
+    /// Bucket for all the album arts
+    pub album_arts: Bucket,
+    /// Bucket for artist photos, artist banners and per-track artwork
+    pub artwork: Bucket,
+    /// Storage of the collections and files
+    pub backend: Backend,
 }
 
 impl AstralDatabase {
-    /// Connects to database using MongoDB connection uri
-    pub async fn connect(url: String) -> anyhow::Result<Self> {
+    /// Opens the database selected in the environment and applies pending migrations
+    pub async fn connect() -> anyhow::Result<Self> {
+        let db = Self::open().await?;
+        // applied migrations are recorded in the schema history, see the `migration-status` command
+        run_migrations(&db, false).await?;
+        Ok(db)
+    }
+
+    /// Opens the database selected by the `DATABASE_BACKEND` environment variable without applying migrations.
+    /// `mongodb` (the default) connects to the deployment at `MONGODB_URI`, `sqlite` opens the database file
+    /// at `SQLITE_PATH`, `astral.sqlite3` by default.
+    pub async fn open() -> anyhow::Result<Self> {
         // creating the tracks directory
         let tracks = std::path::Path::new("astral_tracks");
         if !tracks.exists() {
             tokio::fs::create_dir_all(&tracks).await?;
         }
 
+        match env::var("DATABASE_BACKEND").as_deref() {
+            Err(_) | Ok("mongodb") => Self::open_mongo(env::var("MONGODB_URI")?).await,
+            Ok("sqlite") => Ok(Self::open_sqlite(env::var("SQLITE_PATH").unwrap_or_else(|_| String::from("astral.sqlite3")))?),
+            Ok(other) => anyhow::bail!("Unknown database backend: {other}"),
+        }
+    }
+
+    /// Connects to MongoDB using the connection uri, creating indexes of the collections
+    async fn open_mongo(url: String) -> anyhow::Result<Self> {
         let client = Client::with_uri_str(url).await?;
         let inner = client.database("astral");
-        let tracks_metadata = inner.collection("tracks_metadata");
+        let hello = inner.run_command(doc! { "hello": 1 }, None).await?;
+        let supports_transactions = hello.contains_key("setName") || hello.get_str("msg").is_ok_and(|it| it == "isdbgrid");
+        if !supports_transactions {
+            eprintln!("WARNING: MongoDB is running as a standalone server, which does not support transactions.");
+            eprintln!("WARNING: Writes spanning several documents are applied one by one, and a failure halfway leaves them partially applied.");
+            eprintln!("WARNING: Run MongoDB as a replica set, a single node one is enough, to make them atomic.");
+        }
+        let tracks_metadata: Collection<TrackMetadata> = inner.collection("tracks_metadata");
         tracks_metadata.create_index(IndexModel::builder().keys(doc! { "name": "text" }).build(), None).await?;
         tracks_metadata.create_index(IndexModel::builder().keys(doc! { "name": 1 }).build(), None).await?;
+        tracks_metadata.create_index(IndexModel::builder().keys(doc! { "added_at": 1 }).build(), None).await?;
 
-        let artists_metadata = inner.collection("artists_metadata");
+        let artists_metadata: Collection<ArtistMetadata> = inner.collection("artists_metadata");
         artists_metadata.create_index(IndexModel::builder().keys(doc! { "name": "text", "about": "text" }).build(), None).await?;
         artists_metadata.create_index(IndexModel::builder().keys(doc! { "name": 1 }).build(), None).await?;
+        artists_metadata.create_index(IndexModel::builder().keys(doc! { "aliases": 1 }).build(), None).await?;
+        artists_metadata.create_index(IndexModel::builder().keys(doc! { "added_at": 1 }).build(), None).await?;
 
-        let albums_metadata = inner.collection("albums_metadata");
+        let albums_metadata: Collection<AlbumMetadata> = inner.collection("albums_metadata");
         albums_metadata.create_index(IndexModel::builder().keys(doc! { "name": "text" }).build(), None).await?;
         albums_metadata.create_index(IndexModel::builder().keys(doc! { "name": 1 }).build(), None).await?;
+        albums_metadata.create_index(IndexModel::builder().keys(doc! { "aliases": 1 }).build(), None).await?;
+        albums_metadata.create_index(IndexModel::builder().keys(doc! { "added_at": 1 }).build(), None).await?;
 
-        let accounts = inner.collection("accounts");
+        let accounts: Collection<UserAccount> = inner.collection("accounts");
         accounts.create_index(IndexModel::builder().keys(doc! { "username": 1 }).build(), None).await?;
         
-        let invite_codes = inner.collection("invite_codes");
-        let undefined_tracks = inner.collection("undefined_tracks");
-        let lyrics = inner.collection("lyrics");
-        let gridfs_album_arts = inner.gridfs_bucket(GridFsBucketOptions::builder().bucket_name(String::from("album_arts")).build());
+        let undefined_tracks: Collection<UndefinedTrack> = inner.collection("undefined_tracks");
+        undefined_tracks.create_index(IndexModel::builder().keys(doc! { "uploaded_by": 1 }).build(), None).await?;
+        undefined_tracks.create_index(IndexModel::builder().keys(doc! { "uploaded_at": 1 }).build(), None).await?;
+        let fingerprints: Collection<TrackFingerprint> = inner.collection("fingerprints");
+        fingerprints.create_index(IndexModel::builder().keys(doc! { "track_id": 1 }).build(), None).await?;
+
+        Ok(Self::with_backend(Backend::Mongo(MongoBackend { client, database: inner, supports_transactions }))?)
+    }
+
+    /// Opens the SQLite database file at the path, creating it if it does not exist yet
+    pub fn open_sqlite(path: impl AsRef<std::path::Path>) -> Res<Self> {
+        Self::with_backend(Backend::Sqlite(SqliteDatabase::open(path)?))
+    }
 
+    /// Creates a database keeping all collections and files in memory, used by the test harness
+    #[cfg(test)]
+    pub fn in_memory() -> Res<Self> {
+        Self::with_backend(Backend::Memory(MemoryStore::default()))
+    }
+
+    /// Opens repositories and buckets of the database in the backend
+    fn with_backend(backend: Backend) -> Res<Self> {
         Ok(Self {
-            inner,
-            tracks_metadata,
-            artists_metadata,
-            albums_metadata,
-            invite_codes,
-            undefined_tracks,
-            lyrics,
-            accounts,
-            gridfs_album_arts,
+            tracks_metadata: backend.repository("tracks_metadata")?,
+            artists_metadata: backend.repository("artists_metadata")?,
+            albums_metadata: backend.repository("albums_metadata")?,
+            accounts: backend.repository("accounts")?,
+            invite_codes: backend.repository("invite_codes")?,
+            undefined_tracks: backend.repository("undefined_tracks")?,
+            lyrics: backend.repository("lyrics")?,
+            fingerprints: backend.repository("fingerprints")?,
+            album_arts: backend.bucket("album_arts")?,
+            artwork: backend.bucket("artwork")?,
+            backend,
         })
     }
+
+    /// Untyped repository for the collection, stored in the same backend as the typed one.
+    /// Used by code working with every collection, such as backups and migrations.
+    pub fn documents(&self, name: &str) -> Res<Repo<Document>> {
+        self.backend.repository(name)
+    }
+
+    /// Whether writes made with [AstralDatabase::start_transaction] are applied atomically.
+    /// MongoDB requires a replica set or a sharded cluster for that.
+    pub fn supports_transactions(&self) -> bool {
+        match &self.backend {
+            Backend::Mongo(mongo) => mongo.supports_transactions,
+            Backend::Sqlite(_) => true,
+            #[cfg(test)]
+            Backend::Memory(_) => true,
+        }
+    }
+
+    /// Starts a transaction for a multi-document write. Writes made with the transaction are applied atomically
+    /// if the deployment supports transactions, otherwise they are applied one by one.
+    pub async fn start_transaction(&self) -> Res<Transaction> {
+        match &self.backend {
+            Backend::Mongo(mongo) if mongo.supports_transactions => {
+                let mut session = mongo.client.start_session(None).await?;
+                session.start_transaction(None).await?;
+                Ok(Transaction { session: Some(session), ..Default::default() })
+            }
+            Backend::Mongo(_) => Ok(Transaction::default()),
+            Backend::Sqlite(sqlite) => Ok(Transaction { sqlite: Some(sqlite.begin().await?), ..Default::default() }),
+            #[cfg(test)]
+            Backend::Memory(_) => Ok(Transaction { memory: Some(MemoryTransaction::default()), ..Default::default() }),
+        }
+    }
+
+    /// Commits writes made with the transaction. Dropping the transaction without committing aborts them.
+    pub async fn commit_transaction(&self, session: &mut Transaction) -> Res<()> {
+        if let Some(mongo) = session.session() {
+            mongo.commit_transaction().await?;
+        }
+        if let Some(sqlite) = session.sqlite.take() {
+            sqlite.commit().await?;
+        }
+        #[cfg(test)]
+        if let (Backend::Memory(store), Some(memory)) = (&self.backend, session.memory.take()) {
+            store.commit(memory);
+        }
+        Ok(())
+    }
+
+    /// Runs the writes in a transaction and commits it, same as `withTransaction` of the MongoDB drivers.
+    /// Writes failing with a transient error are retried in a new transaction, and a commit with an unknown result is retried,
+    /// at most [MAX_TRANSACTION_ATTEMPTS] times. The context is passed to the writes on every attempt.
+    pub async fn with_transaction<C, R, F>(&self, mut context: C, mut writes: F) -> Res<R>
+    where F: for<'s> FnMut(&'s mut Transaction, &'s mut C) -> BoxFuture<'s, Res<R>> {
+        let mut attempts = 0;
+        'transaction: loop {
+            attempts += 1;
+            let mut session = self.start_transaction().await?;
+            let value = match writes(&mut session, &mut context).await {
+                Ok(value) => value,
+                Err(AstralError::Database(err)) if attempts < MAX_TRANSACTION_ATTEMPTS && err.contains_label(TRANSIENT_TRANSACTION_ERROR) => continue,
+                Err(err) => return Err(err),
+            };
+            loop {
+                match self.commit_transaction(&mut session).await {
+                    Ok(()) => return Ok(value),
+                    Err(AstralError::Database(err)) if attempts < MAX_TRANSACTION_ATTEMPTS && err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) => attempts += 1,
+                    Err(AstralError::Database(err)) if attempts < MAX_TRANSACTION_ATTEMPTS && err.contains_label(TRANSIENT_TRANSACTION_ERROR) => continue 'transaction,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use crate::data::AstralDatabase;
+use crate::data::bucket::Bucket;
+use crate::data::migrations::{latest_version, run_migrations, schema_state, SCHEMA_COLLECTION, SCHEMA_DOCUMENT_ID};
+use crate::data::model::BsonId;
+use crate::err::AstralError;
+use crate::metadata::artwork::ArtworkKind;
+use crate::Res;
+
+/// Version of the archive layout, increased whenever it changes incompatibly
+const ARCHIVE_FORMAT_VERSION: u32 = 1;
+/// Name of the manifest inside the archive
+const MANIFEST_NAME: &str = "manifest.json";
+/// All collections included in the archive
+const COLLECTIONS: [&str; 9] = [
+    "tracks_metadata", "albums_metadata", "artists_metadata", "accounts", "invite_codes",
+    "undefined_tracks", "lyrics", "fingerprints", "schema",
+];
+/// Amount of documents inserted at once when importing
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Description of the archive contents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Version of the archive layout
+    pub format_version: u32,
+    /// Milliseconds unix timestamp for when the archive was created
+    pub exported_at: u64,
+    /// Schema version of the exported database
+    pub schema_version: u32,
+    /// Amount of exported documents in every collection
+    pub collections: BTreeMap<String, u64>,
+    /// All files in the archive except the manifest itself
+    pub files: Vec<BackupFile>,
+    /// UUIDs of tracks whose audio files were missing when exporting
+    #[serde(default)]
+    pub missing_audio: Vec<String>,
+}
+
+/// A single file inside the archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupFile {
+    /// Path of the file inside the archive
+    pub path: String,
+    /// Hex encoded SHA-256 hash of the file contents
+    pub sha256: String,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Metadata of the artwork file, in canonical extended JSON
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// Buckets included in the archive along with their names
+fn buckets(db: &AstralDatabase) -> [(&'static str, &Bucket); 2] {
+    [("album_arts", &db.album_arts), ("artwork", &db.artwork)]
+}
+
+/// Writes data into the archive entry while hashing it
+struct HashingWriter<'a, W: Write> {
+    inner: &'a mut W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<'a, W: Write> HashingWriter<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        Self { inner, hasher: Sha256::new(), size: 0 }
+    }
+
+    fn finish(self, path: String, metadata: Option<serde_json::Value>) -> BackupFile {
+        BackupFile { path, sha256: hex::encode(&self.hasher.finalize()[..]), size: self.size, metadata }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Exports all collections, original artwork and original audio files into a ZIP archive.
+/// Transcoded copies and resized artwork are skipped, as they are regenerated on demand.
+/// The server should be stopped, so the archive is consistent.
+pub async fn export_library(db: &AstralDatabase, path: &Path) -> Res<BackupManifest> {
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let text = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated).large_file(true);
+    // audio and images are already compressed, so they are stored as is
+    let binary = SimpleFileOptions::default().compression_method(CompressionMethod::Stored).large_file(true);
+    let mut manifest = BackupManifest {
+        format_version: ARCHIVE_FORMAT_VERSION,
+        exported_at: Utc::now().timestamp_millis() as u64,
+        schema_version: schema_state(db).await?.version,
+        collections: BTreeMap::new(),
+        files: vec![],
+        missing_audio: vec![],
+    };
+
+    for name in COLLECTIONS {
+        let entry = format!("collections/{name}.jsonl");
+        zip.start_file(entry.as_str(), text)?;
+        let mut writer = HashingWriter::new(&mut zip);
+        let mut count = 0;
+        // the schema collection also holds the migration lock, which belongs to this instance only
+        let filter = if name == SCHEMA_COLLECTION { doc! { "_id": SCHEMA_DOCUMENT_ID } } else { doc! { } };
+        let mut documents = db.documents(name)?.find(filter).await?;
+        while let Some(document) = documents.try_next().await? {
+            serde_json::to_writer(&mut writer, &Bson::Document(document).into_canonical_extjson())?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        manifest.files.push(writer.finish(entry, None));
+        manifest.collections.insert(name.to_owned(), count);
+    }
+
+    for (bucket_name, bucket) in buckets(db) {
+        for file in bucket.find(doc! { "metadata.variant_of": { "$exists": false } }).await? {
+            if file.filename.is_empty() {
+                continue
+            }
+            let data = bucket.read(&file.id).await?;
+
+            // artwork is stored under `gridfs/` regardless of the backend, keeping the archive layout unchanged
+            let entry = format!("gridfs/{bucket_name}/{}", file.filename);
+            zip.start_file(entry.as_str(), binary)?;
+            let mut writer = HashingWriter::new(&mut zip);
+            writer.write_all(&data)?;
+            let metadata = file.metadata.map(|it| Bson::Document(it).into_canonical_extjson());
+            manifest.files.push(writer.finish(entry, metadata));
+        }
+    }
+
+    let mut track_ids: Vec<BsonId> = db.tracks_metadata.find(doc! { }).await?.map_ok(|it| it.track_id).try_collect().await?;
+    track_ids.extend(db.undefined_tracks.find(doc! { }).await?.map_ok(|it| it.track_id).try_collect::<Vec<_>>().await?);
+    for track_id in track_ids {
+        let source = Path::new("astral_tracks").join(format!("{track_id}.bin"));
+        if !source.exists() {
+            manifest.missing_audio.push(track_id.to_string());
+            continue
+        }
+        let entry = format!("tracks/{track_id}.bin");
+        zip.start_file(entry.as_str(), binary)?;
+        let mut writer = HashingWriter::new(&mut zip);
+        std::io::copy(&mut File::open(&source)?, &mut writer)?;
+        manifest.files.push(writer.finish(entry, None));
+    }
+
+    zip.start_file(MANIFEST_NAME, text)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest)?;
+    zip.finish()?.flush()?;
+    Ok(manifest)
+}
+
+/// Reads the manifest from the archive and checks that the archive can be imported by this server
+fn read_manifest(archive: &mut ZipArchive<File>) -> Res<BackupManifest> {
+    let manifest: BackupManifest = serde_json::from_reader(archive.by_name(MANIFEST_NAME)?)?;
+    if manifest.format_version != ARCHIVE_FORMAT_VERSION {
+        return Err(AstralError::BadRequest(format!("Unsupported archive format version {}", manifest.format_version)))
+    }
+    if manifest.schema_version > latest_version() {
+        return Err(AstralError::BadRequest(format!(
+            "Archive schema version {} is newer than version {} supported by this server", manifest.schema_version, latest_version()
+        )))
+    }
+    Ok(manifest)
+}
+
+/// Checks hashes of all files in the archive against the manifest
+fn verify_archive(archive: &mut ZipArchive<File>, manifest: &BackupManifest) -> Res<()> {
+    for file in &manifest.files {
+        let mut entry = archive.by_name(&file.path)?;
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut entry, &mut hasher)?;
+        if size != file.size || hex::encode(&hasher.finalize()[..]) != file.sha256 {
+            return Err(AstralError::BadRequest(format!("File {} in the archive is corrupted", file.path)))
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the instance does not contain any data yet
+async fn ensure_empty(db: &AstralDatabase) -> Res<()> {
+    // the schema collection is filled by connecting to an empty instance, its version is replaced by the archived one
+    for name in COLLECTIONS.into_iter().filter(|it| *it != SCHEMA_COLLECTION) {
+        if db.documents(name)?.count(doc! { }).await? > 0 {
+            return Err(AstralError::BadRequest(format!("Collection {name} is not empty, import requires an empty instance")))
+        }
+    }
+    for (name, bucket) in buckets(db) {
+        if !bucket.find(doc! { }).await?.is_empty() {
+            return Err(AstralError::BadRequest(format!("Bucket {name} is not empty, import requires an empty instance")))
+        }
+    }
+    let mut entries = tokio::fs::read_dir("astral_tracks").await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.path().extension().is_some_and(|it| it == "bin") {
+            return Err(AstralError::BadRequest(String::from("Directory astral_tracks is not empty, import requires an empty instance")))
+        }
+    }
+    Ok(())
+}
+
+/// Parses a single line of an exported collection
+fn parse_document(line: &str) -> Res<Document> {
+    let value: serde_json::Value = serde_json::from_str(line)?;
+    match Bson::try_from(value).map_err(anyhow::Error::from)? {
+        Bson::Document(document) => Ok(document),
+        _ => Err(AstralError::BadRequest(String::from("Exported collections can only contain documents"))),
+    }
+}
+
+/// Checks that every entry of the manifest is a known collection, a known bucket with an artwork filename,
+/// or an audio file named by a track UUID, so nothing outside of the instance is ever written
+fn validate_manifest(manifest: &BackupManifest) -> Res<()> {
+    if let Some(name) = manifest.collections.keys().find(|it| !COLLECTIONS.contains(&it.as_str())) {
+        return Err(AstralError::BadRequest(format!("Archive contains unknown collection {name}")))
+    }
+    for file in &manifest.files {
+        let valid = match archive_entry(&file.path) {
+            Some(ArchiveEntry::Collection(name)) => manifest.collections.contains_key(name),
+            Some(ArchiveEntry::Artwork(bucket_name, filename)) => !filename.contains('@') && ArtworkKind::parse_filename(filename)
+                .is_some_and(|(kind, _)| (kind == ArtworkKind::AlbumCover) == (bucket_name == "album_arts")),
+            Some(ArchiveEntry::Track(_)) => true,
+            None => false,
+        };
+        if !valid {
+            return Err(AstralError::BadRequest(format!("Archive contains unexpected file {}", file.path)))
+        }
+    }
+    Ok(())
+}
+
+/// Kind of a file inside the archive
+enum ArchiveEntry<'a> {
+    /// Exported collection with the name
+    Collection(&'a str),
+    /// Original artwork in a known bucket, along with the bucket name and the filename
+    Artwork(&'static str, &'a str),
+    /// Audio file of the track
+    Track(BsonId),
+}
+
+/// Parses path of a file inside the archive. Audio files are only accepted if they are named `{uuid}.bin`.
+fn archive_entry(path: &str) -> Option<ArchiveEntry<'_>> {
+    if let Some(name) = path.strip_prefix("collections/").and_then(|it| it.strip_suffix(".jsonl")) {
+        COLLECTIONS.contains(&name).then_some(ArchiveEntry::Collection(name))
+    } else if let Some((bucket_name, filename)) = path.strip_prefix("gridfs/").and_then(|it| it.split_once('/')) {
+        let bucket_name = ["album_arts", "artwork"].into_iter().find(|it| *it == bucket_name)?;
+        Some(ArchiveEntry::Artwork(bucket_name, filename))
+    } else {
+        let uuid = path.strip_prefix("tracks/")?.strip_suffix(".bin")?;
+        Some(ArchiveEntry::Track(BsonId::from_uuid_1(Uuid::parse_str(uuid).ok()?)))
+    }
+}
+
+/// Restores an archive created by [export_library] into an empty instance. All file hashes are verified before
+/// anything is written, and pending migrations are applied once the data is restored.
+///
+/// If the import fails halfway, everything it wrote is removed again, so the instance is left empty and the import
+/// can be retried. To import into an instance that already contains data, wipe its database and `astral_tracks` first.
+pub async fn import_library(db: &AstralDatabase, path: &Path) -> Res<BackupManifest> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let manifest = read_manifest(&mut archive)?;
+    validate_manifest(&manifest)?;
+    verify_archive(&mut archive, &manifest)?;
+    ensure_empty(db).await?;
+
+    let mut written_tracks = vec![];
+    match restore_archive(db, &mut archive, &manifest, &mut written_tracks).await {
+        Ok(()) => Ok(manifest),
+        Err(err) => {
+            // the original error is more useful than a failure of the cleanup
+            let _ = wipe_import(db, &written_tracks).await;
+            Err(err)
+        }
+    }
+}
+
+/// Writes collections, artwork and audio files of the archive into the instance.
+/// Audio files are recorded as they are written, so they can be removed if the import fails.
+async fn restore_archive(db: &AstralDatabase, archive: &mut ZipArchive<File>, manifest: &BackupManifest, written_tracks: &mut Vec<BsonId>) -> Res<()> {
+    // version of the empty instance is replaced by the archived one, so the archived data is migrated
+    db.documents(SCHEMA_COLLECTION)?.delete_many(doc! { "_id": SCHEMA_DOCUMENT_ID }).await?;
+
+    for (name, expected) in &manifest.collections {
+        let collection = db.documents(name)?;
+        let reader = BufReader::new(archive.by_name(&format!("collections/{name}.jsonl"))?);
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        let mut imported = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue
+            }
+            batch.push(parse_document(&line)?);
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                imported += batch.len() as u64;
+                collection.insert_many(&batch).await?;
+                batch.clear();
+            }
+        }
+        if !batch.is_empty() {
+            imported += batch.len() as u64;
+            collection.insert_many(&batch).await?;
+        }
+        if imported != *expected {
+            return Err(AstralError::BadRequest(format!("Collection {name} contains {imported} documents, expected {expected}")))
+        }
+    }
+
+    for file in &manifest.files {
+        match archive_entry(&file.path) {
+            Some(ArchiveEntry::Artwork(bucket_name, filename)) => {
+                let Some((_, bucket)) = buckets(db).into_iter().find(|(name, _)| *name == bucket_name) else {
+                    continue
+                };
+                let mut data = vec![];
+                archive.by_name(&file.path)?.read_to_end(&mut data)?;
+                let metadata = match file.metadata.clone().map(Bson::try_from).transpose().map_err(anyhow::Error::from)? {
+                    Some(Bson::Document(metadata)) => Some(metadata),
+                    _ => None,
+                };
+                bucket.upload(filename, metadata, &data).await?;
+            }
+            Some(ArchiveEntry::Track(track_id)) => {
+                // the path is built from the parsed UUID, so it always points into `astral_tracks`
+                written_tracks.push(track_id);
+                let mut target = File::create(Path::new("astral_tracks").join(format!("{track_id}.bin")))?;
+                std::io::copy(&mut archive.by_name(&file.path)?, &mut target)?;
+            }
+            Some(ArchiveEntry::Collection(_)) | None => {}
+        }
+    }
+
+    run_migrations(db, false).await?;
+    Ok(())
+}
+
+/// Removes everything written by a failed import. The instance was empty before, so all collections and buckets are cleared.
+async fn wipe_import(db: &AstralDatabase, written_tracks: &[BsonId]) -> Res<()> {
+    for name in COLLECTIONS {
+        db.documents(name)?.delete_many(doc! { }).await?;
+    }
+    for (_, bucket) in buckets(db) {
+        bucket.clear().await?;
+    }
+    for track_id in written_tracks {
+        let path = Path::new("astral_tracks").join(format!("{track_id}.bin"));
+        if path.exists() {
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
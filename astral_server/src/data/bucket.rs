@@ -0,0 +1,163 @@
+use std::fmt::Debug;
+use std::io::Cursor;
+use std::sync::Arc;
+use futures_util::{AsyncWriteExt, TryStreamExt};
+use mongodb::bson::{doc, Binary, Bson, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::GridFsBucket;
+use mongodb::options::GridFsUploadOptions;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use crate::data::repository::Repo;
+use crate::err::AstralError;
+use crate::Res;
+
+/// Shared handle to a bucket of files
+pub type Bucket = Arc<dyn FileBucket>;
+
+/// Contents of a stored file, read as they arrive
+pub type FileReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A file stored in a bucket
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    /// ID of the file. Files are never changed, so a replaced file always gets a new ID.
+    pub id: Bson,
+    /// Name the file is looked up by, not necessarily unique
+    pub filename: String,
+    /// Metadata stored along with the file
+    pub metadata: Option<Document>,
+}
+
+/// Storage of binary files such as artwork, modelled after GridFS buckets.
+/// Files are found with filters on their `filename` and `metadata` fields.
+#[axum::async_trait]
+pub trait FileBucket: Debug + Send + Sync {
+    /// Finds all files matching the filter, oldest first
+    async fn find(&self, filter: Document) -> Res<Vec<StoredFile>>;
+    /// Opens contents of the file for reading
+    async fn open(&self, id: &Bson) -> Res<FileReader>;
+    /// Stores a new file, returning its ID
+    async fn upload(&self, filename: &str, metadata: Option<Document>, data: &[u8]) -> Res<Bson>;
+    /// Removes the file
+    async fn delete(&self, id: &Bson) -> Res<()>;
+    /// Changes the name of the file
+    async fn rename(&self, id: &Bson, filename: &str) -> Res<()>;
+    /// Removes all files of the bucket
+    async fn clear(&self) -> Res<()>;
+
+    /// Reads whole contents of the file
+    async fn read(&self, id: &Bson) -> Res<Vec<u8>> {
+        let mut data = vec![];
+        self.open(id).await?.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Finds the first file with the name
+    async fn find_by_name(&self, filename: &str) -> Res<Option<StoredFile>> {
+        Ok(self.find(doc! { "filename": filename }).await?.into_iter().next())
+    }
+}
+
+/// Bucket backed by GridFS of a MongoDB database
+#[derive(Debug, Clone)]
+pub struct GridFsFiles(pub GridFsBucket);
+
+#[axum::async_trait]
+impl FileBucket for GridFsFiles {
+    async fn find(&self, filter: Document) -> Res<Vec<StoredFile>> {
+        Ok(self.0.find(filter, None).await?
+            .map_ok(|file| StoredFile { id: file.id, filename: file.filename.unwrap_or_default(), metadata: file.metadata })
+            .try_collect().await?)
+    }
+
+    async fn open(&self, id: &Bson) -> Res<FileReader> {
+        Ok(Box::new(self.0.open_download_stream(id.clone()).await?.compat()))
+    }
+
+    async fn upload(&self, filename: &str, metadata: Option<Document>, data: &[u8]) -> Res<Bson> {
+        let mut upload_stream = self.0.open_upload_stream(filename, GridFsUploadOptions::builder().metadata(metadata).build());
+        upload_stream.write_all(data).await?;
+        upload_stream.close().await?;
+        Ok(upload_stream.id().clone())
+    }
+
+    async fn delete(&self, id: &Bson) -> Res<()> {
+        Ok(self.0.delete(id.clone()).await?)
+    }
+
+    async fn rename(&self, id: &Bson, filename: &str) -> Res<()> {
+        Ok(self.0.rename(id.clone(), filename).await?)
+    }
+
+    async fn clear(&self) -> Res<()> {
+        Ok(self.0.drop().await?)
+    }
+}
+
+/// Bucket storing files in two collections of a repository backend, same as GridFS does:
+/// `{bucket}_files` with names and metadata, and `{bucket}_chunks` with contents of every file in a single chunk.
+#[derive(Debug, Clone)]
+pub struct DocumentFiles {
+    files: Repo<Document>,
+    chunks: Repo<Document>,
+}
+
+impl DocumentFiles {
+    pub fn new(files: Repo<Document>, chunks: Repo<Document>) -> Self {
+        Self { files, chunks }
+    }
+}
+
+#[axum::async_trait]
+impl FileBucket for DocumentFiles {
+    async fn find(&self, filter: Document) -> Res<Vec<StoredFile>> {
+        self.files.find(filter).await?
+            .map_ok(|file| StoredFile {
+                filename: file.get_str("filename").unwrap_or_default().to_owned(),
+                metadata: file.get_document("metadata").ok().cloned(),
+                id: file.get("_id").cloned().unwrap_or(Bson::Null),
+            })
+            .try_collect().await
+    }
+
+    async fn open(&self, id: &Bson) -> Res<FileReader> {
+        let chunk = self.chunks.find_one(doc! { "files_id": id }).await?
+            .ok_or_else(|| AstralError::NotFound(format!("File {id} does not exist")))?;
+        let data = chunk.get_binary_generic("data")?.clone();
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    async fn upload(&self, filename: &str, metadata: Option<Document>, data: &[u8]) -> Res<Bson> {
+        let id = Bson::ObjectId(ObjectId::new());
+        // contents are stored first, so a file that can be found can always be read
+        self.chunks.insert_one(&doc! {
+            "files_id": &id,
+            "data": Binary { subtype: BinarySubtype::Generic, bytes: data.to_vec() },
+        }).await?;
+        let mut file = doc! { "_id": &id, "filename": filename, "length": data.len() as i64, "uploadDate": DateTime::now() };
+        if let Some(metadata) = metadata {
+            file.insert("metadata", metadata);
+        }
+        self.files.insert_one(&file).await?;
+        Ok(id)
+    }
+
+    async fn delete(&self, id: &Bson) -> Res<()> {
+        self.files.delete_one(doc! { "_id": id }).await?;
+        self.chunks.delete_many(doc! { "files_id": id }).await?;
+        Ok(())
+    }
+
+    async fn rename(&self, id: &Bson, filename: &str) -> Res<()> {
+        self.files.update_one(doc! { "_id": id }, doc! { "$set": { "filename": filename } }).await?;
+        Ok(())
+    }
+
+    async fn clear(&self) -> Res<()> {
+        self.files.delete_many(doc! { }).await?;
+        self.chunks.delete_many(doc! { }).await?;
+        Ok(())
+    }
+}
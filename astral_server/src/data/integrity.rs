@@ -0,0 +1,335 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use crate::data::AstralDatabase;
+use crate::data::model::BsonId;
+use crate::jobs::sweeper::pending_ttl;
+use crate::metadata::artwork::{delete_artwork, ArtworkKind};
+use crate::metadata::genres::refresh_genres;
+use crate::metadata::remove_track;
+use crate::search::engine::{reindex_entries, SearchIndex};
+use crate::Res;
+
+/// Directories of `astral_tracks` containing transcoded copies of tracks
+const TRANSCODED_DIRS: [&str; 2] = ["transcoded_low", "transcoded_medium"];
+
+/// A single inconsistency found in the library
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum IntegrityIssue {
+    /// Entry references an entry that does not exist anymore. Repaired by removing the reference.
+    DanglingReference {
+        /// Collection containing the entry
+        collection: String,
+        /// ID of the entry
+        id: Uuid,
+        /// Field containing the reference
+        field: String,
+        /// The missing referenced ID
+        reference: Uuid,
+    },
+    /// Track metadata exists, but its audio file is missing. Repaired by removing the track,
+    /// only if removing such tracks was explicitly requested.
+    MissingAudioFile {
+        /// UUID of the track
+        track_id: Uuid,
+    },
+    /// Pending upload exists, but its audio file is missing. Repaired by discarding the upload.
+    MissingPendingFile {
+        /// UUID of the pending upload
+        track_id: Uuid,
+    },
+    /// Audio file does not belong to any track or pending upload. Repaired by removing the file.
+    /// Files younger than the pending upload ttl are skipped, as they can belong to uploads still in progress.
+    OrphanedAudioFile {
+        /// Path of the file
+        path: String,
+    },
+    /// Album does not contain any tracks. Repaired by removing the album.
+    EmptyAlbum {
+        /// UUID of the album
+        album_id: Uuid,
+    },
+    /// Lyrics or another per-track entry belongs to a missing track. Repaired by removing the entry.
+    OrphanedEntry {
+        /// Collection containing the entry
+        collection: String,
+        /// UUID of the missing track
+        track_id: Uuid,
+    },
+    /// Stored artwork belongs to a missing album, artist or track. Repaired by removing the artwork.
+    OrphanedArtwork {
+        /// Name of the artwork file
+        filename: String,
+    },
+}
+
+impl Display for IntegrityIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityIssue::DanglingReference { collection, id, field, reference } =>
+                write!(f, "{collection}/{id}: `{field}` references missing entry {reference}"),
+            IntegrityIssue::MissingAudioFile { track_id } => write!(f, "tracks_metadata/{track_id}: audio file is missing"),
+            IntegrityIssue::MissingPendingFile { track_id } => write!(f, "undefined_tracks/{track_id}: audio file is missing"),
+            IntegrityIssue::OrphanedAudioFile { path } => write!(f, "{path}: file does not belong to any track"),
+            IntegrityIssue::EmptyAlbum { album_id } => write!(f, "albums_metadata/{album_id}: album does not contain any tracks"),
+            IntegrityIssue::OrphanedEntry { collection, track_id } => write!(f, "{collection}/{track_id}: track does not exist"),
+            IntegrityIssue::OrphanedArtwork { filename } => write!(f, "artwork {filename}: owner does not exist"),
+        }
+    }
+}
+
+/// IDs of all tracks, albums, artists and pending uploads in the library
+struct ExistingIds {
+    tracks: HashSet<BsonId>,
+    albums: HashSet<BsonId>,
+    artists: HashSet<BsonId>,
+    pending: HashSet<BsonId>,
+}
+
+impl ExistingIds {
+    async fn load(db: &AstralDatabase) -> Res<Self> {
+        Ok(Self {
+            tracks: db.tracks_metadata.find(doc! { }).await?.map_ok(|it| it.track_id).try_collect().await?,
+            albums: db.albums_metadata.find(doc! { }).await?.map_ok(|it| it.album_id).try_collect().await?,
+            artists: db.artists_metadata.find(doc! { }).await?.map_ok(|it| it.artist_id).try_collect().await?,
+            pending: db.undefined_tracks.find(doc! { }).await?.map_ok(|it| it.track_id).try_collect().await?,
+        })
+    }
+
+    fn contains(&self, kind: ArtworkKind, owner: &BsonId) -> bool {
+        match kind {
+            ArtworkKind::AlbumCover => self.albums.contains(owner),
+            ArtworkKind::ArtistPhoto | ArtworkKind::ArtistBanner => self.artists.contains(owner),
+            ArtworkKind::TrackArt => self.tracks.contains(owner),
+        }
+    }
+}
+
+/// Finds references that point to missing entries
+fn dangling_references(ids: &[BsonId], existing: &HashSet<BsonId>) -> Vec<BsonId> {
+    ids.iter().filter(|it| !existing.contains(it)).copied().collect()
+}
+
+fn dangling_issues<'a>(collection: &'a str, id: &'a BsonId, field: &'a str, references: &'a [BsonId]) -> impl Iterator<Item = IntegrityIssue> + 'a {
+    references.iter().map(move |reference| IntegrityIssue::DanglingReference {
+        collection: collection.to_owned(),
+        id: id.to_uuid_1(),
+        field: field.to_owned(),
+        reference: reference.to_uuid_1(),
+    })
+}
+
+/// Path of the audio file of a track or pending upload
+fn track_file(id: &BsonId) -> PathBuf {
+    Path::new("astral_tracks").join(format!("{id}.bin"))
+}
+
+/// Scans the library for inconsistencies and returns all of them. If `repair` is set, found issues are also fixed,
+/// see [IntegrityIssue] for how each of them is repaired. Tracks with missing audio files are only removed if
+/// `remove_missing_tracks` is set as well, as a file can be missing because of an unmounted disk rather than a broken library.
+/// Genres and search entries of affected albums and artists are refreshed afterwards.
+pub async fn check_integrity(db: &AstralDatabase, search: &SearchIndex, repair: bool, remove_missing_tracks: bool) -> Res<Vec<IntegrityIssue>> {
+    let mut issues = vec![];
+    check_audio_files(db, search, repair, repair && remove_missing_tracks, &mut issues).await?;
+    // tracks could have been removed, so the rest of the checks see the repaired library
+    let existing = ExistingIds::load(db).await?;
+    check_references(db, search, &existing, repair, &mut issues).await?;
+    check_empty_albums(db, search, repair, &mut issues).await?;
+    check_track_entries(db, search, &existing, repair, &mut issues).await?;
+    check_orphaned_files(&existing, repair, &mut issues).await?;
+    check_artwork(db, &existing, repair, &mut issues).await?;
+    Ok(issues)
+}
+
+/// Finds tracks and pending uploads without audio files
+async fn check_audio_files(db: &AstralDatabase, search: &SearchIndex, repair: bool, remove_missing_tracks: bool, issues: &mut Vec<IntegrityIssue>) -> Res<()> {
+    let tracks: Vec<BsonId> = db.tracks_metadata.find(doc! { }).await?.map_ok(|it| it.track_id).try_collect().await?;
+    for track_id in tracks.iter().filter(|it| !track_file(it).exists()) {
+        if remove_missing_tracks {
+            remove_track(db, search, track_id).await?;
+        }
+        issues.push(IntegrityIssue::MissingAudioFile { track_id: track_id.to_uuid_1() });
+    }
+
+    let pending: Vec<BsonId> = db.undefined_tracks.find(doc! { }).await?.map_ok(|it| it.track_id).try_collect().await?;
+    for track_id in pending.iter().filter(|it| !track_file(it).exists()) {
+        if repair {
+            db.undefined_tracks.delete_one(doc! { "track_id": track_id }).await?;
+        }
+        issues.push(IntegrityIssue::MissingPendingFile { track_id: track_id.to_uuid_1() });
+    }
+    Ok(())
+}
+
+/// Finds references to missing tracks, albums and artists
+async fn check_references(db: &AstralDatabase, search: &SearchIndex, existing: &ExistingIds, repair: bool, issues: &mut Vec<IntegrityIssue>) -> Res<()> {
+    let mut refresh_tracks = vec![];
+    let mut refresh_albums = vec![];
+    let mut refresh_artists = vec![];
+
+    let mut tracks = db.tracks_metadata.find(doc! { }).await?;
+    while let Some(track) = tracks.try_next().await? {
+        let artists = dangling_references(&track.artists, &existing.artists);
+        let albums = dangling_references(&track.albums, &existing.albums);
+        if artists.is_empty() && albums.is_empty() {
+            continue
+        }
+        if repair {
+            db.tracks_metadata.update_one(doc! { "track_id": &track.track_id }, doc! { "$pullAll": { "artists": &artists, "albums": &albums } }).await?;
+        }
+        refresh_tracks.push(track.track_id);
+        issues.extend(dangling_issues("tracks_metadata", &track.track_id, "artists", &artists));
+        issues.extend(dangling_issues("tracks_metadata", &track.track_id, "albums", &albums));
+    }
+
+    let mut albums = db.albums_metadata.find(doc! { }).await?;
+    while let Some(album) = albums.try_next().await? {
+        let artists = dangling_references(&album.artists, &existing.artists);
+        let tracks = dangling_references(&album.tracks, &existing.tracks);
+        if artists.is_empty() && tracks.is_empty() {
+            continue
+        }
+        if repair {
+            db.albums_metadata.update_one(doc! { "album_id": &album.album_id }, doc! { "$pullAll": { "artists": &artists, "tracks": &tracks } }).await?;
+        }
+        refresh_albums.push(album.album_id);
+        issues.extend(dangling_issues("albums_metadata", &album.album_id, "artists", &artists));
+        issues.extend(dangling_issues("albums_metadata", &album.album_id, "tracks", &tracks));
+    }
+
+    let mut artists = db.artists_metadata.find(doc! { }).await?;
+    while let Some(artist) = artists.try_next().await? {
+        let albums = dangling_references(&artist.albums, &existing.albums);
+        let tracks = dangling_references(&artist.tracks, &existing.tracks);
+        if albums.is_empty() && tracks.is_empty() {
+            continue
+        }
+        if repair {
+            db.artists_metadata.update_one(doc! { "artist_id": &artist.artist_id }, doc! { "$pullAll": { "albums": &albums, "tracks": &tracks } }).await?;
+        }
+        refresh_artists.push(artist.artist_id);
+        issues.extend(dangling_issues("artists_metadata", &artist.artist_id, "albums", &albums));
+        issues.extend(dangling_issues("artists_metadata", &artist.artist_id, "tracks", &tracks));
+    }
+
+    let mut accounts = db.accounts.find(doc! { }).await?;
+    while let Some(account) = accounts.try_next().await? {
+        let tracks = dangling_references(&account.loved_tracks, &existing.tracks);
+        let albums = dangling_references(&account.loved_albums, &existing.albums);
+        if repair && !(tracks.is_empty() && albums.is_empty()) {
+            db.accounts.update_one(doc! { "user_id": &account.user_id }, doc! { "$pullAll": { "loved_tracks": &tracks, "loved_albums": &albums } }).await?;
+        }
+        issues.extend(dangling_issues("accounts", &account.user_id, "loved_tracks", &tracks));
+        issues.extend(dangling_issues("accounts", &account.user_id, "loved_albums", &albums));
+    }
+
+    if repair {
+        refresh_genres(db, &refresh_artists, &refresh_albums).await?;
+        reindex_entries(db, search, &refresh_tracks, &refresh_albums, &refresh_artists).await?;
+    }
+    Ok(())
+}
+
+/// Finds albums without any tracks
+async fn check_empty_albums(db: &AstralDatabase, search: &SearchIndex, repair: bool, issues: &mut Vec<IntegrityIssue>) -> Res<()> {
+    let empty: Vec<BsonId> = db.albums_metadata.find(doc! { "tracks": { "$size": 0 } }).await?
+        .map_ok(|it| it.album_id)
+        .try_collect().await?;
+    if repair && !empty.is_empty() {
+        let artists: Vec<BsonId> = db.artists_metadata.find(doc! { "albums": { "$in": &empty } }).await?
+            .map_ok(|it| it.artist_id)
+            .try_collect().await?;
+        let mut session = db.start_transaction().await?;
+        db.albums_metadata.delete_many_with_session(doc! { "album_id": { "$in": &empty } }, &mut session).await?;
+        db.artists_metadata.update_many_with_session(doc! { "albums": { "$in": &empty } }, doc! { "$pullAll": { "albums": &empty } }, &mut session).await?;
+        db.tracks_metadata.update_many_with_session(doc! { "albums": { "$in": &empty } }, doc! { "$pullAll": { "albums": &empty } }, &mut session).await?;
+        db.accounts.update_many_with_session(doc! { "loved_albums": { "$in": &empty } }, doc! { "$pullAll": { "loved_albums": &empty } }, &mut session).await?;
+        db.commit_transaction(&mut session).await?;
+        for album_id in &empty {
+            delete_artwork(db, ArtworkKind::AlbumCover, album_id).await?;
+        }
+        reindex_entries(db, search, &[], &empty, &artists).await?;
+    }
+    issues.extend(empty.iter().map(|it| IntegrityIssue::EmptyAlbum { album_id: it.to_uuid_1() }));
+    Ok(())
+}
+
+/// Finds lyrics and fingerprints of missing tracks
+async fn check_track_entries(db: &AstralDatabase, search: &SearchIndex, existing: &ExistingIds, repair: bool, issues: &mut Vec<IntegrityIssue>) -> Res<()> {
+    let lyrics: Vec<BsonId> = db.lyrics.find(doc! { }).await?.map_ok(|it| it.track_id).try_collect().await?;
+    let fingerprints: Vec<BsonId> = db.fingerprints.find(doc! { }).await?.map_ok(|it| it.track_id).try_collect().await?;
+    let lyrics = dangling_references(&lyrics, &existing.tracks);
+    let fingerprints = dangling_references(&fingerprints, &existing.tracks);
+    if repair {
+        db.lyrics.delete_many(doc! { "track_id": { "$in": &lyrics } }).await?;
+        db.fingerprints.delete_many(doc! { "track_id": { "$in": &fingerprints } }).await?;
+        // removes indexed lines of the orphaned lyrics
+        reindex_entries(db, search, &lyrics, &[], &[]).await?;
+    }
+    issues.extend(lyrics.iter().map(|it| IntegrityIssue::OrphanedEntry { collection: String::from("lyrics"), track_id: it.to_uuid_1() }));
+    issues.extend(fingerprints.iter().map(|it| IntegrityIssue::OrphanedEntry { collection: String::from("fingerprints"), track_id: it.to_uuid_1() }));
+    Ok(())
+}
+
+/// Finds audio files and transcoded copies in `astral_tracks` that do not belong to any track or pending upload.
+/// Uploads store their file before the pending upload is inserted, so recently modified files are skipped.
+async fn check_orphaned_files(existing: &ExistingIds, repair: bool, issues: &mut Vec<IntegrityIssue>) -> Res<()> {
+    let grace = pending_ttl();
+    let root = Path::new("astral_tracks");
+    let dirs = std::iter::once((root.to_path_buf(), true))
+        .chain(TRANSCODED_DIRS.iter().map(|it| (root.join(it), false)));
+    for (dir, is_original) in dirs {
+        if !dir.exists() {
+            continue
+        }
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if !entry.file_type().await?.is_file() || path.extension().and_then(|it| it.to_str()) != Some("bin") {
+                continue
+            }
+            let owner = path.file_stem()
+                .and_then(|it| it.to_str())
+                .and_then(|it| Uuid::parse_str(it).ok())
+                .map(BsonId::from_uuid_1);
+            // pending uploads are never transcoded, so only originals can belong to them
+            let owned = owner.is_some_and(|it| existing.tracks.contains(&it) || (is_original && existing.pending.contains(&it)));
+            if owned {
+                continue
+            }
+            let age = entry.metadata().await?.modified()?.elapsed().unwrap_or_default();
+            if age < grace {
+                continue
+            }
+            if repair {
+                tokio::fs::remove_file(&path).await?;
+            }
+            issues.push(IntegrityIssue::OrphanedAudioFile { path: path.to_string_lossy().into_owned() });
+        }
+    }
+    Ok(())
+}
+
+/// Finds stored artwork whose album, artist or track does not exist
+async fn check_artwork(db: &AstralDatabase, existing: &ExistingIds, repair: bool, issues: &mut Vec<IntegrityIssue>) -> Res<()> {
+    for bucket in [&db.album_arts, &db.artwork] {
+        for file in bucket.find(doc! { }).await? {
+            let filename = file.filename;
+            let owned = ArtworkKind::parse_filename(&filename).is_some_and(|(kind, owner)| existing.contains(kind, &owner));
+            if owned {
+                continue
+            }
+            if repair {
+                bucket.delete(&file.id).await?;
+            }
+            issues.push(IntegrityIssue::OrphanedArtwork { filename });
+        }
+    }
+    Ok(())
+}
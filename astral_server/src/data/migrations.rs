@@ -0,0 +1,226 @@
+use std::time::Duration;
+use chrono::Utc;
+use mongodb::bson::{doc, from_document, to_document, Bson, Document};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::data::AstralDatabase;
+use crate::err::AstralError;
+use crate::Res;
+
+/// Collection storing the schema version of the database
+pub const SCHEMA_COLLECTION: &str = "schema";
+/// ID of the document in the schema collection storing the schema version
+pub const SCHEMA_DOCUMENT_ID: &str = "schema_version";
+/// ID of the document in the schema collection locking it while migrations are applied
+const LOCK_DOCUMENT_ID: &str = "migration_lock";
+/// How long the migration lock is held at most, after which it is considered abandoned by a crashed process
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// How often a held migration lock is checked again
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A single update applied to all documents of a collection matching the filter.
+/// Filters only match documents that were not migrated yet, so running a step twice changes nothing.
+pub struct MigrationStep {
+    /// Name of the collection to update
+    pub collection: &'static str,
+    /// Documents that need to be migrated
+    pub filter: Document,
+    /// Update applied to every matched document
+    pub update: StepUpdate,
+}
+
+/// Update applied by a [MigrationStep]
+pub enum StepUpdate {
+    /// Update document, supported by all repositories
+    Document(Document),
+    /// Aggregation pipeline, see [crate::data::repository::Repository::update_many_pipeline]
+    Pipeline(Vec<Document>),
+}
+
+impl MigrationStep {
+    /// Sets the field to its default value in all documents that do not have it
+    fn default_field(collection: &'static str, field: &str, value: impl Into<Bson>) -> Self {
+        Self {
+            collection,
+            filter: doc! { field: { "$exists": false } },
+            update: StepUpdate::Document(doc! { "$set": { field: value.into() } }),
+        }
+    }
+
+    /// Sets the date an entry was added at from the creation time of its object ID, for entries stored without one
+    fn backfill_added_at(collection: &'static str) -> Self {
+        Self {
+            collection,
+            filter: doc! { "added_at": { "$in": [0, Bson::Null] }, "_id": { "$type": "objectId" } },
+            update: StepUpdate::Pipeline(vec![
+                doc! { "$set": { "added_at": { "$toLong": { "$toDate": "$_id" } } } },
+            ]),
+        }
+    }
+}
+
+/// A versioned change to the stored data
+pub struct Migration {
+    /// Version the schema is at after this migration is applied. Versions start at 1 and increase by one.
+    pub version: u32,
+    /// Short name of this migration
+    pub name: &'static str,
+    /// Updates this migration consists of
+    pub steps: fn() -> Vec<MigrationStep>,
+}
+
+/// All migrations in the order they are applied. New migrations are only ever appended to the end.
+///
+/// Documents missing a field with `#[serde(default)]` are already read with the default, so defaults are only
+/// stored for fields the database itself filters on.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "album_defaults",
+        steps: || vec![
+            // albums are filtered by type, which does not match documents without one
+            MigrationStep::default_field("albums_metadata", "album_type", "album"),
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "backfill_added_at",
+        steps: || vec![
+            MigrationStep::backfill_added_at("tracks_metadata"),
+            MigrationStep::backfill_added_at("albums_metadata"),
+            MigrationStep::backfill_added_at("artists_metadata"),
+        ],
+    },
+];
+
+/// Schema version the current code expects the database to be at
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|it| it.version).unwrap_or(0)
+}
+
+/// Stored schema version along with the history of applied migrations
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SchemaState {
+    /// Current schema version, `0` for databases created before versioning
+    pub version: u32,
+    /// Migrations applied to this database, oldest first
+    #[serde(default)]
+    pub applied: Vec<AppliedMigration>,
+}
+
+/// A migration that was applied to the database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    /// Version of the migration
+    pub version: u32,
+    /// Name of the migration
+    pub name: String,
+    /// Milliseconds unix timestamp for when the migration was applied
+    pub applied_at: u64,
+    /// Amount of documents changed by the migration
+    pub affected: u64,
+}
+
+/// Outcome of running a single migration
+#[derive(Debug, Clone)]
+pub struct MigrationOutcome {
+    /// Version of the migration
+    pub version: u32,
+    /// Name of the migration
+    pub name: &'static str,
+    /// Amount of documents that were changed, or would be changed in a dry run
+    pub affected: u64,
+}
+
+/// Reads the stored schema version and migration history
+pub async fn schema_state(db: &AstralDatabase) -> Res<SchemaState> {
+    let state = db.documents(SCHEMA_COLLECTION)?.find_one(doc! { "_id": SCHEMA_DOCUMENT_ID }).await?;
+    Ok(state.map(from_document).transpose()?.unwrap_or_default())
+}
+
+/// Migrations that were not applied to the database yet
+pub async fn pending_migrations(db: &AstralDatabase) -> Res<Vec<&'static Migration>> {
+    let state = schema_state(db).await?;
+    if state.version > latest_version() {
+        return Err(AstralError::Unknown(anyhow::anyhow!(
+            "Database schema version {} is newer than version {} supported by this server", state.version, latest_version()
+        )))
+    }
+    Ok(MIGRATIONS.iter().filter(|it| it.version > state.version).collect())
+}
+
+/// Takes the migration lock, waiting while another process holds it. Returns the owner token the lock was taken with.
+///
+/// The lock is a document in the schema collection. An expired lock is taken over by updating it, and a missing one
+/// is inserted, which fails if another process inserted it first.
+async fn acquire_migration_lock(db: &AstralDatabase) -> Res<String> {
+    let owner = Uuid::new_v4().to_string();
+    let schema = db.documents(SCHEMA_COLLECTION)?;
+    loop {
+        let now = Utc::now().timestamp_millis();
+        let locked_until = now + LOCK_TIMEOUT.as_millis() as i64;
+        let taken = schema.update_one(
+            doc! { "_id": LOCK_DOCUMENT_ID, "locked_until": { "$lt": now } },
+            doc! { "$set": { "locked_until": locked_until, "owner": &owner } },
+        ).await? > 0;
+        if taken || schema.try_insert_one(&doc! { "_id": LOCK_DOCUMENT_ID, "locked_until": locked_until, "owner": &owner }).await? {
+            return Ok(owner)
+        }
+        tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+    }
+}
+
+/// Releases the migration lock, unless it expired and was taken by another process since
+async fn release_migration_lock(db: &AstralDatabase, owner: &str) -> Res<()> {
+    db.documents(SCHEMA_COLLECTION)?.delete_one(doc! { "_id": LOCK_DOCUMENT_ID, "owner": owner }).await?;
+    Ok(())
+}
+
+/// Applies all pending migrations in order, storing the new schema version after each of them.
+/// In a dry run nothing is changed, and the outcomes contain amounts of documents that would be changed.
+/// Migrations are applied under a lock, so processes starting at the same time do not apply them twice.
+/// Steps go through the repositories of the database, so they also apply to collections stored outside of MongoDB.
+pub async fn run_migrations(db: &AstralDatabase, dry_run: bool) -> Res<Vec<MigrationOutcome>> {
+    if dry_run {
+        return apply_migrations(db, true).await
+    }
+    let owner = acquire_migration_lock(db).await?;
+    let outcomes = apply_migrations(db, false).await;
+    release_migration_lock(db, &owner).await?;
+    outcomes
+}
+
+/// Applies pending migrations, see [run_migrations]
+async fn apply_migrations(db: &AstralDatabase, dry_run: bool) -> Res<Vec<MigrationOutcome>> {
+    let mut outcomes = vec![];
+    // pending migrations are read under the lock, so migrations applied by another process are skipped
+    for migration in pending_migrations(db).await? {
+        let mut affected = 0;
+        for step in (migration.steps)() {
+            let collection = db.documents(step.collection)?;
+            affected += match step.update {
+                _ if dry_run => collection.count(step.filter).await?,
+                StepUpdate::Document(update) => collection.update_many(step.filter, update).await?,
+                StepUpdate::Pipeline(pipeline) => collection.update_many_pipeline(step.filter, pipeline).await?,
+            };
+        }
+
+        if !dry_run {
+            let applied = AppliedMigration {
+                version: migration.version,
+                name: migration.name.to_owned(),
+                applied_at: Utc::now().timestamp_millis() as u64,
+                affected,
+            };
+            // the state is only written under the lock, so reading and replacing it can not lose applied migrations
+            let mut state = schema_state(db).await?;
+            state.version = migration.version;
+            state.applied.push(applied);
+            let mut document = doc! { "_id": SCHEMA_DOCUMENT_ID };
+            document.extend(to_document(&state).map_err(anyhow::Error::from)?);
+            db.documents(SCHEMA_COLLECTION)?.upsert_one(doc! { "_id": SCHEMA_DOCUMENT_ID }, &document).await?;
+        }
+        outcomes.push(MigrationOutcome { version: migration.version, name: migration.name, affected });
+    }
+    Ok(outcomes)
+}
@@ -26,6 +26,18 @@ pub struct TrackMetadata {
     pub number: u16,
     /// Number of the disc this track is in
     pub disc_number: u16,
+    /// Normalised genres of this track
+    #[serde(default)]
+    pub genres: Vec<String>,
+    /// Milliseconds unix timestamp for when this track was added to the library
+    #[serde(default)]
+    pub added_at: u64,
+    /// Amount of times this track was streamed
+    #[serde(default)]
+    pub play_count: u32,
+    /// Hex encoded SHA-256 hash of the uploaded file
+    #[serde(default)]
+    pub hash: Option<String>,
 }
 
 /// Artist metadata representation in the DB
@@ -43,6 +55,13 @@ pub struct ArtistMetadata {
     pub genres: HashMap<String, u32>,
     /// Some description for this artist. Can contain markdown.
     pub about: String,
+    /// Milliseconds unix timestamp for when this artist was added to the library
+    #[serde(default)]
+    pub added_at: u64,
+    /// Other names of this artist, e.g. names of artists merged into this one.
+    /// Ingested tracks credited to one of these names are assigned to this artist.
+    #[serde(default)]
+    pub aliases: Vec<String>,
 }
 
 /// Album metadata representation in the DB
@@ -58,9 +77,104 @@ pub struct AlbumMetadata {
     pub tracks: Vec<BsonId>,
     /// Milliseconds unix timestamp for the release date
     pub release_date: u64,
-    /// Most prominent genres for this album.
-    // fetch from last.fm?
+    /// Most prominent genres for this album, calculated from genres of its tracks unless set manually.
     pub genres: Vec<String>,
+    /// Whether the genres were set manually, in which case they are not recalculated from the tracks
+    #[serde(default)]
+    pub manual_genres: bool,
+    /// Milliseconds unix timestamp for when this album was added to the library
+    #[serde(default)]
+    pub added_at: u64,
+    /// Colour palette of the album cover, if it has one
+    #[serde(default)]
+    pub palette: Option<CoverPalette>,
+    /// Discs of this album that have a subtitle or a known amount of tracks
+    #[serde(default)]
+    pub discs: Vec<AlbumDisc>,
+    /// Total amount of discs in this album, as declared by track tags
+    #[serde(default)]
+    pub disc_total: Option<u16>,
+    /// Type of this release
+    #[serde(default)]
+    pub album_type: AlbumType,
+    /// Edition or version of this release, e.g. `Deluxe Edition` or `2011 Remaster`
+    #[serde(default)]
+    pub edition: Option<String>,
+    /// Other names of this album, e.g. names of albums merged into this one.
+    /// Ingested tracks from an album with one of these names are assigned to this album.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+/// Type of an album release. Variants are ordered in the way they are shown on artist pages.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlbumType {
+    /// A regular full-length album (LP)
+    #[default]
+    Album,
+    /// An extended play
+    Ep,
+    /// A single
+    Single,
+    /// A compilation of previously released tracks
+    Compilation,
+    /// A live recording
+    Live,
+    /// A soundtrack to a movie, show or game
+    Soundtrack,
+    /// A remix album
+    Remix,
+    /// Any other release type
+    Other,
+}
+
+impl AlbumType {
+    /// Parses a release type tag, such as MusicBrainz `album; live`.
+    /// Secondary types (compilation, live, soundtrack, remix) describe a release better, so they win over primary ones.
+    pub fn parse_release_type(value: &str) -> Option<Self> {
+        let types = value.split([';', '/', ',', '\0'])
+            .filter_map(|it| match it.trim().to_lowercase().as_str() {
+                "album" | "lp" => Some(AlbumType::Album),
+                "ep" => Some(AlbumType::Ep),
+                "single" => Some(AlbumType::Single),
+                "compilation" | "dj-mix" => Some(AlbumType::Compilation),
+                "live" => Some(AlbumType::Live),
+                "soundtrack" => Some(AlbumType::Soundtrack),
+                "remix" => Some(AlbumType::Remix),
+                "" => None,
+                _ => Some(AlbumType::Other),
+            })
+            .collect::<Vec<_>>();
+        types.iter().copied()
+            .find(|it| matches!(it, AlbumType::Compilation | AlbumType::Live | AlbumType::Soundtrack | AlbumType::Remix))
+            .or_else(|| types.first().copied())
+    }
+}
+
+/// A single disc of a multi-disc album or a box set
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlbumDisc {
+    /// Number of this disc
+    pub number: u16,
+    /// Subtitle of this disc
+    pub subtitle: Option<String>,
+    /// Total amount of tracks on this disc, as declared by track tags
+    pub track_total: Option<u16>,
+}
+
+/// Colour palette extracted from a cover, with colours as `#rrggbb` hex strings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct CoverPalette {
+    /// The most common colour
+    #[schema(example = "#1f2a38")]
+    pub dominant: String,
+    /// A bright and saturated colour, suitable for accents
+    #[schema(example = "#e0493c")]
+    pub vibrant: String,
+    /// A desaturated colour, suitable for backgrounds
+    #[schema(example = "#6b7480")]
+    pub muted: String,
 }
 
 /// A single user account
@@ -80,6 +194,9 @@ pub struct UserAccount {
     pub loved_tracks: Vec<BsonId>,
     /// List of albums loved by this user
     pub loved_albums: Vec<BsonId>,
+    /// Whether explicit tracks and albums should be hidden from suggestions for this user
+    #[serde(default)]
+    pub hide_explicit: bool,
 }
 
 /// A single invite code record
@@ -124,7 +241,10 @@ pub struct UndefinedTrack {
     /// UUID of the user who uploaded this track
     pub uploaded_by: BsonId,
     /// Format of the track
-    pub format: TrackFormat
+    pub format: TrackFormat,
+    /// Milliseconds unix timestamp for when this track was uploaded
+    #[serde(default)]
+    pub uploaded_at: u64,
 }
 
 /// Lyrics container for a single track
@@ -137,6 +257,29 @@ pub struct TrackLyrics {
     pub status: LyricsStatus,
 }
 
+/// Acoustic fingerprint of a single track
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackFingerprint {
+    /// ID of the track
+    pub track_id: BsonId,
+    /// Chromaprint fingerprint of the beginning of the track
+    pub fingerprint: Vec<u32>,
+    /// Milliseconds unix timestamp for when the fingerprint was computed
+    pub computed_at: u64,
+    /// Whether the fingerprint could not be computed. Such tracks are not fingerprinted again on startup.
+    #[serde(default)]
+    pub failed: bool,
+    /// AcoustID of the recording, if it was found
+    #[serde(default)]
+    pub acoustid: Option<String>,
+    /// MusicBrainz IDs of the recordings linked to the AcoustID
+    #[serde(default)]
+    pub recording_ids: Vec<String>,
+    /// Milliseconds unix timestamp for when the fingerprint was looked up in AcoustID
+    #[serde(default)]
+    pub looked_up_at: Option<u64>,
+}
+
 /// Lyrics status for track lyrics
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "status")]
@@ -0,0 +1,295 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use mongodb::bson::{doc, Document};
+use mongodb::{Client, Collection, Database};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{GridFsBucketOptions, ReplaceOptions};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::data::Transaction;
+use crate::data::bucket::{Bucket, DocumentFiles, GridFsFiles};
+use crate::data::model::BsonId;
+#[cfg(test)]
+use crate::data::repository::memory::MemoryStore;
+use crate::data::repository::sqlite::SqliteDatabase;
+use crate::err::AstralError;
+use crate::metadata::merge;
+use crate::Res;
+
+/// Evaluation of MongoDB style filters and updates for repositories outside of MongoDB
+pub mod query;
+/// Evaluation of aggregation pipelines for repositories outside of MongoDB
+pub mod pipeline;
+/// In-memory implementation used by the test harness
+#[cfg(test)]
+pub mod memory;
+/// Implementation backed by an embedded SQLite database
+pub mod sqlite;
+
+/// Shared handle to a repository of entries of a single type
+pub type Repo<T> = Arc<dyn Repository<T>>;
+
+/// Entries read from a repository, streamed as they arrive
+pub type EntryStream<T> = BoxStream<'static, Res<T>>;
+
+/// Storage of entries of a single type, queried with MongoDB style filters and updates.
+/// Methods taking a transaction are part of a transaction started with [crate::data::AstralDatabase::start_transaction].
+/// Reads of the transaction see its own writes, and writes are discarded if the transaction is dropped without committing.
+#[axum::async_trait]
+pub trait Repository<T: Send + Sync>: Debug + Send + Sync {
+    /// Finds the first entry matching the filter
+    async fn find_one(&self, filter: Document) -> Res<Option<T>>;
+    /// Streams all entries matching the filter
+    async fn find(&self, filter: Document) -> Res<EntryStream<T>>;
+    /// Counts entries matching the filter
+    async fn count(&self, filter: Document) -> Res<u64>;
+    /// Inserts a new entry
+    async fn insert_one(&self, entry: &T) -> Res<()>;
+    /// Inserts all entries at once
+    async fn insert_many(&self, entries: &[T]) -> Res<()>;
+    /// Inserts the entry unless an entry with the same `_id` already exists. Returns whether the entry was inserted.
+    async fn try_insert_one(&self, entry: &T) -> Res<bool>;
+    /// Replaces the first entry matching the filter, or inserts the entry if nothing matches
+    async fn upsert_one(&self, filter: Document, entry: &T) -> Res<()>;
+    /// Applies the update to the first entry matching the filter. Returns amount of changed entries.
+    async fn update_one(&self, filter: Document, update: Document) -> Res<u64>;
+    /// Applies the update to all entries matching the filter. Returns amount of changed entries.
+    async fn update_many(&self, filter: Document, update: Document) -> Res<u64>;
+    /// Applies the update to the first entry matching the filter. Returns the entry as it was before the update.
+    async fn find_one_and_update(&self, filter: Document, update: Document) -> Res<Option<T>>;
+    /// Replaces references to any of the duplicates inside the array field with the survivor in entries matching the filter,
+    /// keeping order of the array and removing repeated references. Returns amount of changed entries.
+    async fn replace_references(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId) -> Res<u64>;
+    /// Removes the first entry matching the filter. Returns amount of removed entries.
+    async fn delete_one(&self, filter: Document) -> Res<u64>;
+    /// Removes all entries matching the filter. Returns amount of removed entries.
+    async fn delete_many(&self, filter: Document) -> Res<u64>;
+    /// Removes the first entry matching the filter and returns it
+    async fn find_one_and_delete(&self, filter: Document) -> Res<Option<T>>;
+    /// Runs an aggregation pipeline over the entries. Implementations outside of MongoDB evaluate the stages
+    /// supported by [pipeline::aggregate].
+    async fn aggregate(&self, pipeline: Vec<Document>) -> Res<EntryStream<Document>>;
+    /// Applies the update pipeline to all entries matching the filter. Returns amount of changed entries.
+    /// Implementations outside of MongoDB evaluate the stages supported by [pipeline::apply_pipeline].
+    async fn update_many_pipeline(&self, filter: Document, pipeline: Vec<Document>) -> Res<u64>;
+
+    /// Finds the first entry matching the filter as part of the transaction
+    async fn find_one_with_session(&self, filter: Document, session: &mut Transaction) -> Res<Option<T>>;
+    /// Inserts a new entry as part of the transaction
+    async fn insert_one_with_session(&self, entry: &T, session: &mut Transaction) -> Res<()>;
+    /// Applies the update to the first entry matching the filter as part of the transaction
+    async fn update_one_with_session(&self, filter: Document, update: Document, session: &mut Transaction) -> Res<u64>;
+    /// Applies the update to all entries matching the filter as part of the transaction
+    async fn update_many_with_session(&self, filter: Document, update: Document, session: &mut Transaction) -> Res<u64>;
+    /// Replaces references to any of the duplicates inside the array field with the survivor as part of the transaction
+    async fn replace_references_with_session(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId, session: &mut Transaction) -> Res<u64>;
+    /// Removes the first entry matching the filter as part of the transaction
+    async fn delete_one_with_session(&self, filter: Document, session: &mut Transaction) -> Res<u64>;
+    /// Removes all entries matching the filter as part of the transaction
+    async fn delete_many_with_session(&self, filter: Document, session: &mut Transaction) -> Res<u64>;
+    /// Removes the first entry matching the filter as part of the transaction and returns it
+    async fn find_one_and_delete_with_session(&self, filter: Document, session: &mut Transaction) -> Res<Option<T>>;
+}
+
+/// Storage of all collections and files of the database
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// Collections and GridFS buckets of a MongoDB database
+    Mongo(MongoBackend),
+    /// Tables of an embedded SQLite database. Files are stored in tables as well.
+    Sqlite(SqliteDatabase),
+    /// Collections kept in memory, used by the test harness. Files are stored in collections as well.
+    #[cfg(test)]
+    Memory(MemoryStore),
+}
+
+/// MongoDB database along with the client it was opened with
+#[derive(Debug, Clone)]
+pub struct MongoBackend {
+    /// Client the database was opened with, used to start sessions
+    pub client: Client,
+    /// The MongoDB database
+    pub database: Database,
+    /// Whether the deployment supports multi-document transactions, which requires a replica set or a sharded cluster
+    pub supports_transactions: bool,
+}
+
+impl Backend {
+    /// Creates a repository for the collection with the name
+    pub fn repository<T>(&self, name: &str) -> Res<Repo<T>>
+    where T: Serialize + DeserializeOwned + Debug + Unpin + Send + Sync + 'static {
+        Ok(match self {
+            Backend::Mongo(mongo) => Arc::new(MongoRepository(mongo.database.collection::<T>(name))),
+            Backend::Sqlite(sqlite) => Arc::new(sqlite.repository::<T>(name)?),
+            #[cfg(test)]
+            Backend::Memory(store) => Arc::new(store.repository::<T>(name)),
+        })
+    }
+
+    /// Creates a bucket of files with the name. MongoDB stores them in GridFS, other backends in the `{name}_files`
+    /// and `{name}_chunks` collections.
+    pub fn bucket(&self, name: &str) -> Res<Bucket> {
+        Ok(match self {
+            Backend::Mongo(mongo) => {
+                Arc::new(GridFsFiles(mongo.database.gridfs_bucket(GridFsBucketOptions::builder().bucket_name(name.to_owned()).build())))
+            }
+            _ => Arc::new(DocumentFiles::new(self.repository(&format!("{name}_files"))?, self.repository(&format!("{name}_chunks"))?)),
+        })
+    }
+}
+
+/// Server error code for a write violating a unique index
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+/// Whether the error was caused by inserting a document with an already existing unique key
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Command(err) => err.code == DUPLICATE_KEY_CODE,
+        ErrorKind::Write(WriteFailure::WriteError(err)) => err.code == DUPLICATE_KEY_CODE,
+        _ => false,
+    }
+}
+
+/// Repository backed by a MongoDB collection
+#[derive(Debug, Clone)]
+pub struct MongoRepository<T: Send + Sync>(pub Collection<T>);
+
+#[axum::async_trait]
+impl<T> Repository<T> for MongoRepository<T>
+where T: Serialize + DeserializeOwned + Debug + Unpin + Send + Sync + 'static {
+    async fn find_one(&self, filter: Document) -> Res<Option<T>> {
+        Ok(self.0.find_one(filter, None).await?)
+    }
+
+    async fn find(&self, filter: Document) -> Res<EntryStream<T>> {
+        Ok(self.0.find(filter, None).await?.map_err(AstralError::from).boxed())
+    }
+
+    async fn count(&self, filter: Document) -> Res<u64> {
+        Ok(self.0.count_documents(filter, None).await?)
+    }
+
+    async fn insert_one(&self, entry: &T) -> Res<()> {
+        self.0.insert_one(entry, None).await?;
+        Ok(())
+    }
+
+    async fn insert_many(&self, entries: &[T]) -> Res<()> {
+        // MongoDB rejects inserting no documents
+        if !entries.is_empty() {
+            self.0.insert_many(entries, None).await?;
+        }
+        Ok(())
+    }
+
+    async fn try_insert_one(&self, entry: &T) -> Res<bool> {
+        match self.0.insert_one(entry, None).await {
+            Ok(_) => Ok(true),
+            Err(err) if is_duplicate_key(&err) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn upsert_one(&self, filter: Document, entry: &T) -> Res<()> {
+        self.0.replace_one(filter, entry, ReplaceOptions::builder().upsert(true).build()).await?;
+        Ok(())
+    }
+
+    async fn update_one(&self, filter: Document, update: Document) -> Res<u64> {
+        Ok(self.0.update_one(filter, update, None).await?.modified_count)
+    }
+
+    async fn update_many(&self, filter: Document, update: Document) -> Res<u64> {
+        Ok(self.0.update_many(filter, update, None).await?.modified_count)
+    }
+
+    async fn find_one_and_update(&self, filter: Document, update: Document) -> Res<Option<T>> {
+        Ok(self.0.find_one_and_update(filter, update, None).await?)
+    }
+
+    async fn replace_references(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId) -> Res<u64> {
+        let filter = query::references_filter(filter, field, duplicates);
+        Ok(self.0.update_many(filter, merge::replace_references(field, duplicates, survivor), None).await?.modified_count)
+    }
+
+    async fn delete_one(&self, filter: Document) -> Res<u64> {
+        Ok(self.0.delete_one(filter, None).await?.deleted_count)
+    }
+
+    async fn delete_many(&self, filter: Document) -> Res<u64> {
+        Ok(self.0.delete_many(filter, None).await?.deleted_count)
+    }
+
+    async fn find_one_and_delete(&self, filter: Document) -> Res<Option<T>> {
+        Ok(self.0.find_one_and_delete(filter, None).await?)
+    }
+
+    async fn aggregate(&self, pipeline: Vec<Document>) -> Res<EntryStream<Document>> {
+        Ok(self.0.aggregate(pipeline, None).await?.map_err(AstralError::from).boxed())
+    }
+
+    async fn update_many_pipeline(&self, filter: Document, pipeline: Vec<Document>) -> Res<u64> {
+        Ok(self.0.update_many(filter, pipeline, None).await?.modified_count)
+    }
+
+    async fn find_one_with_session(&self, filter: Document, session: &mut Transaction) -> Res<Option<T>> {
+        Ok(match session.session() {
+            Some(session) => self.0.find_one_with_session(filter, None, session).await?,
+            None => self.0.find_one(filter, None).await?,
+        })
+    }
+
+    async fn insert_one_with_session(&self, entry: &T, session: &mut Transaction) -> Res<()> {
+        match session.session() {
+            Some(session) => self.0.insert_one_with_session(entry, None, session).await?,
+            None => self.0.insert_one(entry, None).await?,
+        };
+        Ok(())
+    }
+
+    async fn update_one_with_session(&self, filter: Document, update: Document, session: &mut Transaction) -> Res<u64> {
+        Ok(match session.session() {
+            Some(session) => self.0.update_one_with_session(filter, update, None, session).await?,
+            None => self.0.update_one(filter, update, None).await?,
+        }.modified_count)
+    }
+
+    async fn update_many_with_session(&self, filter: Document, update: Document, session: &mut Transaction) -> Res<u64> {
+        Ok(match session.session() {
+            Some(session) => self.0.update_many_with_session(filter, update, None, session).await?,
+            None => self.0.update_many(filter, update, None).await?,
+        }.modified_count)
+    }
+
+    async fn replace_references_with_session(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId, session: &mut Transaction) -> Res<u64> {
+        let filter = query::references_filter(filter, field, duplicates);
+        let update = merge::replace_references(field, duplicates, survivor);
+        Ok(match session.session() {
+            Some(session) => self.0.update_many_with_session(filter, update, None, session).await?,
+            None => self.0.update_many(filter, update, None).await?,
+        }.modified_count)
+    }
+
+    async fn delete_one_with_session(&self, filter: Document, session: &mut Transaction) -> Res<u64> {
+        Ok(match session.session() {
+            Some(session) => self.0.delete_one_with_session(filter, None, session).await?,
+            None => self.0.delete_one(filter, None).await?,
+        }.deleted_count)
+    }
+
+    async fn delete_many_with_session(&self, filter: Document, session: &mut Transaction) -> Res<u64> {
+        Ok(match session.session() {
+            Some(session) => self.0.delete_many_with_session(filter, None, session).await?,
+            None => self.0.delete_many(filter, None).await?,
+        }.deleted_count)
+    }
+
+    async fn find_one_and_delete_with_session(&self, filter: Document, session: &mut Transaction) -> Res<Option<T>> {
+        Ok(match session.session() {
+            Some(session) => self.0.find_one_and_delete_with_session(filter, None, session).await?,
+            None => self.0.find_one_and_delete(filter, None).await?,
+        })
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use futures_util::stream::{self, StreamExt};
+use mongodb::bson::{from_document, to_document, Bson, Document};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::data::Transaction;
+use crate::data::model::BsonId;
+use crate::data::repository::pipeline::{aggregate, apply_pipeline};
+use crate::data::repository::query::{self, apply_update, ensure_id, matches, references_filter};
+use crate::data::repository::{EntryStream, Repository};
+use crate::err::AstralError;
+use crate::Res;
+
+/// Collections of documents kept in memory, shared by all repositories of a database
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore(Arc<RwLock<HashMap<String, Vec<Document>>>>);
+
+/// Copies of the collections changed by a transaction. Reads and writes made with the transaction use the copies,
+/// which replace the stored collections once it commits and are discarded if it is dropped.
+#[derive(Debug, Default)]
+pub struct MemoryTransaction(HashMap<String, Vec<Document>>);
+
+impl MemoryStore {
+    /// Repository of the collection with the name
+    pub fn repository<T>(&self, name: &str) -> MemoryRepository<T> {
+        MemoryRepository { store: self.clone(), name: name.to_owned(), _entry: PhantomData }
+    }
+
+    /// Replaces the collections with the copies changed by the transaction.
+    /// Writes made to the same collections outside of the transaction while it was open are lost.
+    pub fn commit(&self, transaction: MemoryTransaction) {
+        self.0.write().unwrap().extend(transaction.0);
+    }
+
+    /// All documents of the collection
+    fn snapshot(&self, name: &str) -> Vec<Document> {
+        self.0.read().unwrap().get(name).cloned().unwrap_or_default()
+    }
+
+    /// Finds documents of the collection whose field matches any of the values, used for lookups
+    fn lookup(&self, name: &str, field: &str, values: &[Bson]) -> Res<Vec<Document>> {
+        let filter = mongodb::bson::doc! { field: { "$in": values } };
+        let mut found = vec![];
+        for document in self.0.read().unwrap().get(name).into_iter().flatten() {
+            if matches(document, &filter)? {
+                found.push(document.clone());
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// Repository keeping entries in a [MemoryStore] as BSON documents. Filters and updates are evaluated with
+/// [crate::data::repository::query], aggregations with [crate::data::repository::pipeline].
+/// Writes made as part of a transaction are buffered in a [MemoryTransaction].
+#[derive(Debug)]
+pub struct MemoryRepository<T> {
+    store: MemoryStore,
+    name: String,
+    _entry: PhantomData<fn() -> T>,
+}
+
+impl<T> MemoryRepository<T> {
+    /// Runs the work on the entries of the collection, or on the copy of the transaction if it buffers writes
+    fn entries<R>(&self, session: Option<&mut Transaction>, work: impl FnOnce(&mut Vec<Document>) -> Res<R>) -> Res<R> {
+        match session.and_then(Transaction::memory) {
+            Some(transaction) => {
+                let entries = transaction.0.entry(self.name.clone()).or_insert_with(|| self.store.snapshot(&self.name));
+                work(entries)
+            }
+            None => work(self.store.0.write().unwrap().entry(self.name.clone()).or_default()),
+        }
+    }
+
+    /// Finds at most `limit` entries matching the filter
+    fn select(&self, filter: &Document, limit: usize, session: Option<&mut Transaction>) -> Res<Vec<Document>> {
+        self.entries(session, |entries| {
+            let mut found = vec![];
+            for entry in entries.iter() {
+                if found.len() == limit {
+                    break
+                }
+                if matches(entry, filter)? {
+                    found.push(entry.clone());
+                }
+            }
+            Ok(found)
+        })
+    }
+
+    /// Inserts the documents, failing if any of their IDs is already taken, same as the unique `_id` index of MongoDB
+    fn insert(&self, mut documents: Vec<Document>, session: Option<&mut Transaction>) -> Res<()> {
+        documents.iter_mut().for_each(ensure_id);
+        self.entries(session, |entries| {
+            for document in &documents {
+                if entries.iter().any(|it| it.get("_id") == document.get("_id")) {
+                    return Err(AstralError::Unknown(anyhow::anyhow!("Duplicate ID {} in {}", document.get("_id").unwrap(), self.name)))
+                }
+            }
+            entries.extend(documents);
+            Ok(())
+        })
+    }
+
+    /// Applies the change to at most `limit` entries matching the filter, returning them as they were before the change
+    fn modify(&self, filter: &Document, limit: usize, session: Option<&mut Transaction>, mut change: impl FnMut(&mut Document) -> Res<()>) -> Res<Vec<Document>> {
+        self.entries(session, |entries| {
+            let mut changed = vec![];
+            let mut matched = 0;
+            for entry in entries.iter_mut() {
+                if matched == limit {
+                    break
+                }
+                if matches(entry, filter)? {
+                    matched += 1;
+                    let before = entry.clone();
+                    change(entry)?;
+                    if *entry != before {
+                        changed.push(before);
+                    }
+                }
+            }
+            Ok(changed)
+        })
+    }
+
+    fn update(&self, filter: &Document, update: &Document, limit: usize, session: Option<&mut Transaction>) -> Res<u64> {
+        Ok(self.modify(filter, limit, session, |entry| apply_update(entry, update))?.len() as u64)
+    }
+
+    fn replace(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId, session: Option<&mut Transaction>) -> Res<u64> {
+        let filter = references_filter(filter, field, duplicates);
+        let duplicates: Vec<Bson> = duplicates.iter().map(|it| Bson::from(*it)).collect();
+        let survivor = Bson::from(*survivor);
+        let changed = self.modify(&filter, usize::MAX, session, |entry| {
+            query::replace_references(entry, field, &duplicates, &survivor);
+            Ok(())
+        })?;
+        Ok(changed.len() as u64)
+    }
+
+    /// Removes at most `limit` entries matching the filter and returns them
+    fn delete(&self, filter: &Document, limit: usize, session: Option<&mut Transaction>) -> Res<Vec<Document>> {
+        self.entries(session, |entries| {
+            let mut removed = vec![];
+            let mut idx = 0;
+            while idx < entries.len() {
+                if removed.len() < limit && matches(&entries[idx], filter)? {
+                    removed.push(entries.remove(idx));
+                } else {
+                    idx += 1;
+                }
+            }
+            Ok(removed)
+        })
+    }
+}
+
+fn decode<T: DeserializeOwned>(found: Vec<Document>) -> Res<Option<T>> {
+    Ok(found.into_iter().next().map(from_document).transpose()?)
+}
+
+#[axum::async_trait]
+impl<T> Repository<T> for MemoryRepository<T>
+where T: Serialize + DeserializeOwned + Debug + Send + Sync + 'static {
+    async fn find_one(&self, filter: Document) -> Res<Option<T>> {
+        decode(self.select(&filter, 1, None)?)
+    }
+
+    async fn find(&self, filter: Document) -> Res<EntryStream<T>> {
+        let found = self.select(&filter, usize::MAX, None)?;
+        Ok(stream::iter(found).map(|entry| from_document(entry).map_err(AstralError::from)).boxed())
+    }
+
+    async fn count(&self, filter: Document) -> Res<u64> {
+        Ok(self.select(&filter, usize::MAX, None)?.len() as u64)
+    }
+
+    async fn insert_one(&self, entry: &T) -> Res<()> {
+        self.insert(vec![to_document(entry).map_err(anyhow::Error::from)?], None)
+    }
+
+    async fn insert_many(&self, entries: &[T]) -> Res<()> {
+        let entries = entries.iter().map(to_document).collect::<Result<Vec<_>, _>>().map_err(anyhow::Error::from)?;
+        self.insert(entries, None)
+    }
+
+    async fn try_insert_one(&self, entry: &T) -> Res<bool> {
+        let mut entry = to_document(entry).map_err(anyhow::Error::from)?;
+        ensure_id(&mut entry);
+        self.entries(None, |entries| {
+            if entries.iter().any(|it| it.get("_id") == entry.get("_id")) {
+                return Ok(false)
+            }
+            entries.push(entry);
+            Ok(true)
+        })
+    }
+
+    async fn upsert_one(&self, filter: Document, entry: &T) -> Res<()> {
+        let mut replacement = to_document(entry).map_err(anyhow::Error::from)?;
+        self.entries(None, |entries| {
+            for entry in entries.iter_mut() {
+                if matches(entry, &filter)? {
+                    // the replaced entry keeps its ID, same as in MongoDB
+                    if let Some(id) = entry.get("_id") {
+                        replacement.insert("_id", id.clone());
+                    }
+                    *entry = replacement;
+                    return Ok(())
+                }
+            }
+            ensure_id(&mut replacement);
+            entries.push(replacement);
+            Ok(())
+        })
+    }
+
+    async fn update_one(&self, filter: Document, update: Document) -> Res<u64> {
+        self.update(&filter, &update, 1, None)
+    }
+
+    async fn update_many(&self, filter: Document, update: Document) -> Res<u64> {
+        self.update(&filter, &update, usize::MAX, None)
+    }
+
+    async fn find_one_and_update(&self, filter: Document, update: Document) -> Res<Option<T>> {
+        let mut found = None;
+        self.modify(&filter, 1, None, |entry| {
+            found = Some(entry.clone());
+            apply_update(entry, &update)
+        })?;
+        Ok(found.map(from_document).transpose()?)
+    }
+
+    async fn replace_references(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId) -> Res<u64> {
+        self.replace(filter, field, duplicates, survivor, None)
+    }
+
+    async fn delete_one(&self, filter: Document) -> Res<u64> {
+        Ok(self.delete(&filter, 1, None)?.len() as u64)
+    }
+
+    async fn delete_many(&self, filter: Document) -> Res<u64> {
+        Ok(self.delete(&filter, usize::MAX, None)?.len() as u64)
+    }
+
+    async fn find_one_and_delete(&self, filter: Document) -> Res<Option<T>> {
+        decode(self.delete(&filter, 1, None)?)
+    }
+
+    async fn aggregate(&self, pipeline: Vec<Document>) -> Res<EntryStream<Document>> {
+        let entries = self.store.snapshot(&self.name);
+        let found = aggregate(entries, &pipeline, &|name, field, values| self.store.lookup(name, field, values))?;
+        Ok(stream::iter(found).map(Ok).boxed())
+    }
+
+    async fn update_many_pipeline(&self, filter: Document, pipeline: Vec<Document>) -> Res<u64> {
+        Ok(self.modify(&filter, usize::MAX, None, |entry| apply_pipeline(entry, &pipeline))?.len() as u64)
+    }
+
+    async fn find_one_with_session(&self, filter: Document, session: &mut Transaction) -> Res<Option<T>> {
+        decode(self.select(&filter, 1, Some(session))?)
+    }
+
+    async fn insert_one_with_session(&self, entry: &T, session: &mut Transaction) -> Res<()> {
+        self.insert(vec![to_document(entry).map_err(anyhow::Error::from)?], Some(session))
+    }
+
+    async fn update_one_with_session(&self, filter: Document, update: Document, session: &mut Transaction) -> Res<u64> {
+        self.update(&filter, &update, 1, Some(session))
+    }
+
+    async fn update_many_with_session(&self, filter: Document, update: Document, session: &mut Transaction) -> Res<u64> {
+        self.update(&filter, &update, usize::MAX, Some(session))
+    }
+
+    async fn replace_references_with_session(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId, session: &mut Transaction) -> Res<u64> {
+        self.replace(filter, field, duplicates, survivor, Some(session))
+    }
+
+    async fn delete_one_with_session(&self, filter: Document, session: &mut Transaction) -> Res<u64> {
+        Ok(self.delete(&filter, 1, Some(session))?.len() as u64)
+    }
+
+    async fn delete_many_with_session(&self, filter: Document, session: &mut Transaction) -> Res<u64> {
+        Ok(self.delete(&filter, usize::MAX, Some(session))?.len() as u64)
+    }
+
+    async fn find_one_and_delete_with_session(&self, filter: Document, session: &mut Transaction) -> Res<Option<T>> {
+        decode(self.delete(&filter, 1, Some(session))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use crate::data::AstralDatabase;
+    use crate::data::model::{LyricsStatus, TrackLyrics};
+    use super::*;
+
+    fn lyrics(track_id: BsonId) -> TrackLyrics {
+        TrackLyrics { track_id, status: LyricsStatus::Unsynced { lines: vec![String::from("abc")] } }
+    }
+
+    #[tokio::test]
+    async fn transactions_buffer_writes_until_commit() {
+        let db = AstralDatabase::in_memory().unwrap();
+        let kept = BsonId::new();
+        db.lyrics.insert_one(&lyrics(kept)).await.unwrap();
+
+        let mut aborted = db.start_transaction().await.unwrap();
+        db.lyrics.delete_one_with_session(doc! { "track_id": kept }, &mut aborted).await.unwrap();
+        db.lyrics.insert_one_with_session(&lyrics(BsonId::new()), &mut aborted).await.unwrap();
+        // the transaction reads its own writes, which are not visible outside of it
+        assert!(db.lyrics.find_one_with_session(doc! { "track_id": kept }, &mut aborted).await.unwrap().is_none());
+        assert_eq!(db.lyrics.count(doc! { }).await.unwrap(), 1);
+        drop(aborted);
+        assert_eq!(db.lyrics.count(doc! { "track_id": kept }).await.unwrap(), 1);
+        assert_eq!(db.lyrics.count(doc! { }).await.unwrap(), 1);
+
+        let added = BsonId::new();
+        let mut session = db.start_transaction().await.unwrap();
+        db.lyrics.insert_one_with_session(&lyrics(added), &mut session).await.unwrap();
+        db.lyrics.delete_one_with_session(doc! { "track_id": kept }, &mut session).await.unwrap();
+        db.commit_transaction(&mut session).await.unwrap();
+        let found = db.lyrics.find_one(doc! { }).await.unwrap().unwrap();
+        assert_eq!(found.track_id, added);
+        assert_eq!(db.lyrics.count(doc! { }).await.unwrap(), 1);
+    }
+}
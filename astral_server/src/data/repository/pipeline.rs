@@ -0,0 +1,439 @@
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use crate::data::repository::query::{field, matches, number, order, unsupported};
+use crate::Res;
+
+/// Reads entries of another collection for `$lookup`. Receives name of the collection, the field of its entries
+/// and the values the field has to match, and returns the matching entries in the order they are stored in.
+pub type Lookup<'a> = dyn Fn(&str, &str, &[Bson]) -> Res<Vec<Document>> + 'a;
+
+/// Runs the aggregation pipeline over the entries. Only the stages and expressions used by the handlers
+/// are supported, anything else fails.
+pub fn aggregate(mut entries: Vec<Document>, pipeline: &[Document], lookup: &Lookup) -> Res<Vec<Document>> {
+    for stage in pipeline {
+        let Some((name, argument)) = stage.iter().next() else {
+            continue
+        };
+        entries = match name.as_str() {
+            "$match" => {
+                let filter = argument.as_document().ok_or_else(|| unsupported("stage", name))?;
+                let mut matched = vec![];
+                for entry in entries {
+                    if matches(&entry, filter)? {
+                        matched.push(entry);
+                    }
+                }
+                matched
+            }
+            "$lookup" => {
+                let spec = argument.as_document().ok_or_else(|| unsupported("stage", name))?;
+                join(entries, spec, lookup)?
+            }
+            "$unwind" => {
+                let path = match argument {
+                    Bson::Document(spec) => spec.get_str("path").ok(),
+                    other => other.as_str(),
+                };
+                let path = path.and_then(|it| it.strip_prefix('$')).ok_or_else(|| unsupported("stage", name))?;
+                unwind(entries, path)
+            }
+            "$group" => {
+                let spec = argument.as_document().ok_or_else(|| unsupported("stage", name))?;
+                group(&entries, spec)?
+            }
+            "$project" => {
+                let spec = argument.as_document().ok_or_else(|| unsupported("stage", name))?;
+                entries.iter().map(|entry| project(entry, spec)).collect::<Res<_>>()?
+            }
+            "$addFields" | "$set" => {
+                let spec = argument.as_document().ok_or_else(|| unsupported("stage", name))?;
+                for entry in &mut entries {
+                    add_fields(entry, spec)?;
+                }
+                entries
+            }
+            "$unset" => {
+                let fields = match argument {
+                    Bson::Array(fields) => fields.iter().filter_map(Bson::as_str).collect(),
+                    other => other.as_str().into_iter().collect::<Vec<_>>(),
+                };
+                for entry in &mut entries {
+                    for field in &fields {
+                        entry.remove(*field);
+                    }
+                }
+                entries
+            }
+            "$sort" => {
+                let keys = argument.as_document().ok_or_else(|| unsupported("stage", name))?;
+                // the sort is stable, so entries equal in all keys keep their order
+                entries.sort_by(|a, b| keys.iter()
+                    .map(|(key, direction)| {
+                        let ordering = order(field(a, key).unwrap_or(&Bson::Null), field(b, key).unwrap_or(&Bson::Null));
+                        if number(direction).is_some_and(|it| it < 0.0) { ordering.reverse() } else { ordering }
+                    })
+                    .find(|it| it.is_ne())
+                    .unwrap_or(std::cmp::Ordering::Equal));
+                entries
+            }
+            "$skip" => {
+                let skip = number(argument).ok_or_else(|| unsupported("stage", name))? as usize;
+                entries.into_iter().skip(skip).collect()
+            }
+            "$limit" => {
+                let limit = number(argument).ok_or_else(|| unsupported("stage", name))? as usize;
+                entries.truncate(limit);
+                entries
+            }
+            "$count" => {
+                let field = argument.as_str().ok_or_else(|| unsupported("stage", name))?;
+                // same as in MongoDB, nothing is returned if there is nothing to count
+                match entries.len() {
+                    0 => vec![],
+                    count => vec![doc! { field: count as i32 }],
+                }
+            }
+            other => return Err(unsupported("stage", other)),
+        };
+    }
+    Ok(entries)
+}
+
+/// Applies the update pipeline to the entry. Only stages changing fields of the entry are allowed.
+pub fn apply_pipeline(entry: &mut Document, pipeline: &[Document]) -> Res<()> {
+    for stage in pipeline {
+        let name = stage.keys().next().map(String::as_str).unwrap_or_default();
+        if !["$set", "$addFields", "$unset", "$project"].contains(&name) {
+            return Err(unsupported("update pipeline", name))
+        }
+    }
+    let no_lookup = |_: &str, _: &str, _: &[Bson]| Err(unsupported("update pipeline", "$lookup"));
+    if let Some(updated) = aggregate(vec![entry.clone()], pipeline, &no_lookup)?.pop() {
+        *entry = updated;
+    }
+    Ok(())
+}
+
+/// Adds entries of another collection whose field matches the local field to every entry, see [Lookup]
+fn join(entries: Vec<Document>, spec: &Document, lookup: &Lookup) -> Res<Vec<Document>> {
+    let (Ok(from), Ok(local_field), Ok(foreign_field), Ok(output)) =
+        (spec.get_str("from"), spec.get_str("localField"), spec.get_str("foreignField"), spec.get_str("as")) else {
+        return Err(unsupported("stage", "$lookup"))
+    };
+    let pipeline = match spec.get("pipeline") {
+        Some(Bson::Array(stages)) => stages.iter()
+            .map(|it| it.as_document().cloned().ok_or_else(|| unsupported("stage", "$lookup")))
+            .collect::<Res<Vec<_>>>()?,
+        Some(_) => return Err(unsupported("stage", "$lookup")),
+        None => vec![],
+    };
+
+    let mut joined = Vec::with_capacity(entries.len());
+    for mut entry in entries {
+        // references stored in an array match any of their elements, and missing references match null
+        let local = match resolve(&entry, local_field) {
+            Some(Bson::Array(items)) => items,
+            Some(value) => vec![value],
+            None => vec![Bson::Null],
+        };
+        let found = aggregate(lookup(from, foreign_field, &local)?, &pipeline, lookup)?;
+        entry.insert(output, found.into_iter().map(Bson::Document).collect::<Vec<_>>());
+        joined.push(entry);
+    }
+    Ok(joined)
+}
+
+/// Outputs an entry for every element of the array field, replacing the array with the element.
+/// Entries without the field or with an empty array are dropped.
+fn unwind(entries: Vec<Document>, path: &str) -> Vec<Document> {
+    let mut unwound = vec![];
+    for entry in entries {
+        match entry.get(path) {
+            Some(Bson::Array(items)) => for item in items {
+                let mut each = entry.clone();
+                each.insert(path, item.clone());
+                unwound.push(each);
+            },
+            None | Some(Bson::Null) => {}
+            Some(_) => unwound.push(entry),
+        }
+    }
+    unwound
+}
+
+/// Groups entries by the `_id` expression, calculating the accumulators of every group in the order the groups were found
+fn group(entries: &[Document], spec: &Document) -> Res<Vec<Document>> {
+    let key = spec.get("_id").ok_or_else(|| unsupported("stage", "$group"))?;
+    let mut groups: Vec<(Bson, Vec<&Document>)> = vec![];
+    for entry in entries {
+        let id = evaluate(entry, key)?.unwrap_or(Bson::Null);
+        match groups.iter_mut().find(|(existing, _)| *existing == id) {
+            Some((_, members)) => members.push(entry),
+            None => groups.push((id, vec![entry])),
+        }
+    }
+
+    let mut grouped = vec![];
+    for (id, members) in groups {
+        let mut output = doc! { "_id": id };
+        for (name, accumulator) in spec.iter().filter(|(name, _)| *name != "_id") {
+            let Some(("$sum", expression)) = accumulator.as_document().and_then(|it| it.iter().next()).map(|(op, it)| (op.as_str(), it)) else {
+                return Err(unsupported("accumulator", name))
+            };
+            let mut values = vec![];
+            for member in &members {
+                values.extend(evaluate(member, expression)?);
+            }
+            output.insert(name, sum(&values));
+        }
+        grouped.push(output);
+    }
+    Ok(grouped)
+}
+
+/// Keeps the fields set to `1`, removes the fields set to `0`, or sets the fields to the expressions.
+/// The `_id` field is kept unless it is removed explicitly.
+fn project(entry: &Document, spec: &Document) -> Res<Document> {
+    let is_flag = |value: &Bson, flag: bool| value.as_bool() == Some(flag) || number(value) == Some(flag as i32 as f64);
+    let removes_id = spec.get("_id").is_some_and(|it| is_flag(it, false));
+    let mut fields = spec.iter().filter(|(name, _)| *name != "_id").peekable();
+    let excluding = match fields.peek() {
+        Some(_) => fields.all(|(_, value)| is_flag(value, false)),
+        None => removes_id,
+    };
+
+    if excluding {
+        let mut projected = entry.clone();
+        for (name, _) in spec.iter().filter(|(name, _)| *name != "_id") {
+            projected.remove(name);
+        }
+        if removes_id {
+            projected.remove("_id");
+        }
+        return Ok(projected)
+    }
+
+    let mut projected = doc! { };
+    if let (Some(id), false) = (entry.get("_id"), removes_id) {
+        projected.insert("_id", id.clone());
+    }
+    for (name, value) in spec.iter().filter(|(name, _)| *name != "_id") {
+        let value = if is_flag(value, true) { entry.get(name).cloned() } else { evaluate(entry, value)? };
+        if let Some(value) = value {
+            projected.insert(name, value);
+        }
+    }
+    Ok(projected)
+}
+
+/// Sets the fields to the values of the expressions. Fields whose expression resolves to nothing are not added.
+fn add_fields(entry: &mut Document, spec: &Document) -> Res<()> {
+    for (name, expression) in spec {
+        if let Some(value) = evaluate(entry, expression)? {
+            entry.insert(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Finds the value of the dotted path. Paths going through arrays of documents resolve to an array of the values
+/// found in every element, same as field paths of MongoDB expressions.
+fn resolve(entry: &Document, path: &str) -> Option<Bson> {
+    fn descend(value: &Bson, parts: &[&str]) -> Option<Bson> {
+        let Some((part, rest)) = parts.split_first() else {
+            return Some(value.clone())
+        };
+        match value {
+            Bson::Document(document) => descend(document.get(*part)?, rest),
+            Bson::Array(items) => Some(Bson::Array(items.iter().filter_map(|it| descend(it, parts)).collect())),
+            _ => None,
+        }
+    }
+    let parts = path.split('.').collect::<Vec<_>>();
+    let (first, rest) = parts.split_first()?;
+    descend(entry.get(*first)?, rest)
+}
+
+/// Sums the numbers, ignoring other values. Arrays are summed element by element.
+fn sum(values: &[Bson]) -> Bson {
+    let values = values.iter()
+        .flat_map(|it| match it {
+            Bson::Array(items) => items.iter().collect(),
+            other => vec![other],
+        })
+        .filter(|it| number(it).is_some())
+        .collect::<Vec<_>>();
+    let total: f64 = values.iter().filter_map(|it| number(it)).sum();
+    if values.iter().any(|it| matches!(it, Bson::Double(_))) {
+        Bson::Double(total)
+    } else if values.iter().all(|it| matches!(it, Bson::Int32(_))) && total.abs() <= i32::MAX as f64 {
+        Bson::Int32(total as i32)
+    } else {
+        Bson::Int64(total as i64)
+    }
+}
+
+/// Arguments of an operator, evaluated. Operators taking a single argument also accept it without an array.
+fn arguments(entry: &Document, argument: &Bson) -> Res<Vec<Option<Bson>>> {
+    match argument {
+        Bson::Array(items) => items.iter().map(|it| evaluate(entry, it)).collect(),
+        other => Ok(vec![evaluate(entry, other)?]),
+    }
+}
+
+/// Evaluates the aggregation expression against the entry. Returns `None` if it resolves to a missing field.
+fn evaluate(entry: &Document, expression: &Bson) -> Res<Option<Bson>> {
+    match expression {
+        Bson::String(path) if path.starts_with("$$") => Err(unsupported("variable", path)),
+        Bson::String(path) if path.starts_with('$') => Ok(resolve(entry, &path[1..])),
+        Bson::Array(items) => Ok(Some(Bson::Array(arguments(entry, &Bson::Array(items.clone()))?
+            .into_iter()
+            .map(|it| it.unwrap_or(Bson::Null))
+            .collect()))),
+        Bson::Document(document) if document.keys().next().is_some_and(|it| it.starts_with('$')) => {
+            let (operator, argument) = document.iter().next().unwrap();
+            operate(entry, operator, argument)
+        }
+        Bson::Document(document) => {
+            let mut evaluated = doc! { };
+            for (name, value) in document {
+                if let Some(value) = evaluate(entry, value)? {
+                    evaluated.insert(name, value);
+                }
+            }
+            Ok(Some(Bson::Document(evaluated)))
+        }
+        literal => Ok(Some(literal.clone())),
+    }
+}
+
+/// Evaluates the expression operator with its argument
+fn operate(entry: &Document, operator: &str, argument: &Bson) -> Res<Option<Bson>> {
+    let args = arguments(entry, argument)?;
+    let value = match (operator, args.as_slice()) {
+        ("$literal", _) => Some(argument.clone()),
+        ("$ifNull", [values @ .., replacement]) => values.iter()
+            .flatten()
+            .find(|it| !matches!(it, Bson::Null))
+            .or(replacement.as_ref())
+            .cloned(),
+        ("$indexOfArray", [array, value, ..]) => {
+            let position = match array {
+                Some(Bson::Array(items)) => items.iter().position(|it| Some(it) == value.as_ref()),
+                _ => return Ok(Some(Bson::Null)),
+            };
+            Some(Bson::Int32(position.map_or(-1, |it| it as i32)))
+        }
+        ("$sum", values) => Some(sum(&values.iter().flatten().cloned().collect::<Vec<_>>())),
+        ("$max" | "$min", values) => {
+            let values = match values {
+                [Some(Bson::Array(items))] => items.clone(),
+                values => values.iter().flatten().cloned().collect(),
+            };
+            let values = values.into_iter().filter(|it| !matches!(it, Bson::Null));
+            let found = if operator == "$max" { values.max_by(order) } else { values.min_by(order) };
+            Some(found.unwrap_or(Bson::Null))
+        }
+        ("$size", [Some(Bson::Array(items))]) => Some(Bson::Int32(items.len() as i32)),
+        ("$objectToArray", [Some(Bson::Document(document))]) => Some(Bson::Array(document.iter()
+            .map(|(name, value)| Bson::Document(doc! { "k": name, "v": value.clone() }))
+            .collect())),
+        ("$objectToArray", [None | Some(Bson::Null)]) => Some(Bson::Null),
+        ("$toDate", [value]) => match value {
+            None | Some(Bson::Null) => Some(Bson::Null),
+            Some(Bson::ObjectId(id)) => Some(Bson::DateTime(id.timestamp())),
+            Some(Bson::DateTime(date)) => Some(Bson::DateTime(*date)),
+            Some(other) => Some(Bson::DateTime(DateTime::from_millis(number(other).ok_or_else(|| unsupported("conversion", operator))? as i64))),
+        },
+        ("$toLong", [value]) => match value {
+            None | Some(Bson::Null) => Some(Bson::Null),
+            Some(Bson::DateTime(date)) => Some(Bson::Int64(date.timestamp_millis())),
+            Some(Bson::Boolean(value)) => Some(Bson::Int64(*value as i64)),
+            Some(other) => Some(Bson::Int64(number(other).ok_or_else(|| unsupported("conversion", operator))? as i64)),
+        },
+        _ => return Err(unsupported("expression", operator)),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+    use crate::data::repository::query::matches;
+    use super::*;
+
+    /// Looks up entries of the `albums` collection
+    fn albums(collection: &str, field: &str, values: &[Bson]) -> Res<Vec<Document>> {
+        assert_eq!(collection, "albums");
+        let stored = vec![
+            doc! { "album_id": 1, "release_date": 30, "genres": ["rock"] },
+            doc! { "album_id": 2, "release_date": 10, "genres": ["pop", "rock"] },
+        ];
+        let filter = doc! { field: { "$in": values } };
+        Ok(stored.into_iter().filter(|it| matches(it, &filter).unwrap()).collect())
+    }
+
+    #[test]
+    fn sorts_by_looked_up_keys() {
+        let entries = vec![
+            doc! { "name": "b", "albums": [2] },
+            doc! { "name": "a", "albums": [1, 2] },
+            doc! { "name": "c", "albums": [] },
+        ];
+        let found = aggregate(entries, &[
+            doc! { "$lookup": { "from": "albums", "localField": "albums", "foreignField": "album_id", "as": "sort_albums" } },
+            doc! { "$addFields": { "sort_key": { "$ifNull": [{ "$max": "$sort_albums.release_date" }, 0] } } },
+            doc! { "$project": { "sort_albums": 0 } },
+            doc! { "$sort": { "sort_key": -1, "name": 1 } },
+            doc! { "$skip": 1 },
+        ], &albums).unwrap();
+        assert_eq!(found, vec![
+            doc! { "name": "b", "albums": [2], "sort_key": 10 },
+            doc! { "name": "c", "albums": [], "sort_key": 0 },
+        ]);
+    }
+
+    #[test]
+    fn filters_by_looked_up_pipelines() {
+        let entries = vec![doc! { "name": "a", "albums": [1] }, doc! { "name": "b", "albums": [2] }];
+        let found = aggregate(entries, &[
+            doc! {
+                "$lookup": {
+                    "from": "albums",
+                    "localField": "albums",
+                    "foreignField": "album_id",
+                    "pipeline": [{ "$match": { "release_date": { "$lt": 20 } } }, { "$limit": 1 }, { "$project": { "_id": 1 } }],
+                    "as": "related_0",
+                }
+            },
+            doc! { "$match": { "related_0.0": { "$exists": true } } },
+            doc! { "$unset": "related_0" },
+            doc! { "$count": "total" },
+        ], &albums).unwrap();
+        assert_eq!(found, vec![doc! { "total": 1 }]);
+    }
+
+    #[test]
+    fn groups_unwound_values() {
+        let entries = vec![
+            doc! { "genres": { "rock": 2, "pop": 1 } },
+            doc! { "genres": { "rock": 1 } },
+            doc! { "genres": { } },
+        ];
+        let found = aggregate(entries, &[
+            doc! { "$project": { "genres": { "$objectToArray": "$genres" } } },
+            doc! { "$unwind": "$genres" },
+            doc! { "$group": { "_id": "$genres.k", "count": { "$sum": 1 } } },
+        ], &albums).unwrap();
+        assert_eq!(found, vec![doc! { "_id": "rock", "count": 2 }, doc! { "_id": "pop", "count": 1 }]);
+    }
+
+    #[test]
+    fn applies_update_pipelines() {
+        let id = ObjectId::new();
+        let mut entry = doc! { "_id": id, "added_at": 0 };
+        apply_pipeline(&mut entry, &[doc! { "$set": { "added_at": { "$toLong": { "$toDate": "$_id" } } } }]).unwrap();
+        assert_eq!(entry, doc! { "_id": id, "added_at": id.timestamp().timestamp_millis() });
+        assert!(apply_pipeline(&mut entry, &[doc! { "$match": { } }]).is_err());
+        assert!(aggregate(vec![], &[doc! { "$facet": { } }], &albums).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use std::cmp::Ordering;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::bson::oid::ObjectId;
+use crate::data::model::BsonId;
+use crate::err::AstralError;
+use crate::Res;
+
+pub(super) fn unsupported(kind: &str, operator: &str) -> AstralError {
+    AstralError::Unknown(anyhow::anyhow!("Unsupported {kind} operator `{operator}` outside of MongoDB"))
+}
+
+/// Restricts the filter to entries referencing any of the duplicates inside the array field
+pub fn references_filter(filter: Document, field: &str, duplicates: &[BsonId]) -> Document {
+    doc! { "$and": [filter, { field: { "$in": duplicates } }] }
+}
+
+/// Adds a new object ID to the document if it does not have one yet, same as MongoDB does when inserting
+pub fn ensure_id(document: &mut Document) {
+    if !document.contains_key("_id") {
+        let mut with_id = doc! { "_id": ObjectId::new() };
+        with_id.extend(std::mem::take(document));
+        *document = with_id;
+    }
+}
+
+/// Finds the field by its dotted path. Numeric parts of the path index into arrays.
+pub fn field<'a>(entry: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut value = entry.get(parts.next()?)?;
+    for part in parts {
+        value = match value {
+            Bson::Document(document) => document.get(part)?,
+            Bson::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Numeric value of the BSON, so numbers of different widths compare as equal
+pub(super) fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(it) => Some(*it as f64),
+        Bson::Int64(it) => Some(*it as f64),
+        Bson::Double(it) => Some(*it),
+        _ => None,
+    }
+}
+
+fn same(a: &Bson, b: &Bson) -> bool {
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Compares the field with the expected value. Arrays match if any of their elements does, same as in MongoDB.
+fn equals(value: Option<&Bson>, expected: &Bson) -> bool {
+    match value {
+        None => matches!(expected, Bson::Null),
+        Some(Bson::Array(items)) if !matches!(expected, Bson::Array(_)) => items.iter().any(|it| same(it, expected)),
+        Some(value) => same(value, expected),
+    }
+}
+
+/// Position of the type in the order MongoDB sorts values of different types in
+fn type_order(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::MaxKey => 13,
+        _ => 12,
+    }
+}
+
+/// Orders any two values the way MongoDB sorts them: by type first, then by value
+pub fn order(a: &Bson, b: &Bson) -> Ordering {
+    let by_type = type_order(a).cmp(&type_order(b));
+    if by_type.is_ne() {
+        return by_type
+    }
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => a.cmp(b),
+        (Bson::Document(a), Bson::Document(b)) => a.iter().zip(b)
+            .map(|((a_key, a), (b_key, b))| a_key.cmp(b_key).then_with(|| order(a, b)))
+            .find(|it| it.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Bson::Array(a), Bson::Array(b)) => a.iter().zip(b)
+            .map(|(a, b)| order(a, b))
+            .find(|it| it.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Bson::Binary(a), Bson::Binary(b)) => a.bytes.len().cmp(&b.bytes.len()).then_with(|| a.bytes.cmp(&b.bytes)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.cmp(b),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => (a.time, a.increment).cmp(&(b.time, b.increment)),
+        (a, b) => match (number(a), number(b)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            _ => Ordering::Equal,
+        },
+    }
+}
+
+/// Orders values of the same type, values of different types are not comparable, same as in MongoDB queries
+fn compare(value: Option<&Bson>, expected: &Bson) -> Option<Ordering> {
+    let value = value.unwrap_or(&Bson::Null);
+    (type_order(value) == type_order(expected)).then(|| order(value, expected))
+}
+
+/// Checks whether the value has the type named by the `$type` operator
+fn has_type(value: Option<&Bson>, name: &Bson) -> Res<bool> {
+    let Some(value) = value else {
+        return Ok(false)
+    };
+    let matched = match name.as_str().ok_or_else(|| unsupported("query", "$type"))? {
+        "double" => matches!(value, Bson::Double(_)),
+        "string" => matches!(value, Bson::String(_)),
+        "object" => matches!(value, Bson::Document(_)),
+        "array" => matches!(value, Bson::Array(_)),
+        "binData" => matches!(value, Bson::Binary(_)),
+        "objectId" => matches!(value, Bson::ObjectId(_)),
+        "bool" => matches!(value, Bson::Boolean(_)),
+        "date" => matches!(value, Bson::DateTime(_)),
+        "null" => matches!(value, Bson::Null),
+        "int" => matches!(value, Bson::Int32(_)),
+        "long" => matches!(value, Bson::Int64(_)),
+        "number" => number(value).is_some(),
+        other => return Err(unsupported("type", other)),
+    };
+    Ok(matched)
+}
+
+fn values(argument: &Bson) -> &[Bson] {
+    argument.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Checks the field against a value or a document of query operators
+fn matches_field(value: Option<&Bson>, condition: &Bson) -> Res<bool> {
+    let Bson::Document(operators) = condition else {
+        return Ok(equals(value, condition))
+    };
+    if !operators.keys().next().is_some_and(|it| it.starts_with('$')) {
+        return Ok(equals(value, condition))
+    }
+    for (operator, argument) in operators {
+        let matched = match operator.as_str() {
+            "$eq" => equals(value, argument),
+            "$ne" => !equals(value, argument),
+            "$in" => values(argument).iter().any(|it| equals(value, it)),
+            "$nin" => !values(argument).iter().any(|it| equals(value, it)),
+            "$lt" => compare(value, argument).is_some_and(Ordering::is_lt),
+            "$lte" => compare(value, argument).is_some_and(Ordering::is_le),
+            "$gt" => compare(value, argument).is_some_and(Ordering::is_gt),
+            "$gte" => compare(value, argument).is_some_and(Ordering::is_ge),
+            "$exists" => value.is_some() == argument.as_bool().unwrap_or(true),
+            "$type" => has_type(value, argument)?,
+            "$size" => match (value, number(argument)) {
+                (Some(Bson::Array(items)), Some(size)) => items.len() as f64 == size,
+                _ => false,
+            },
+            other => return Err(unsupported("query", other)),
+        };
+        if !matched {
+            return Ok(false)
+        }
+    }
+    Ok(true)
+}
+
+/// Checks whether the document matches the filter. Fields are found by their dotted paths, and only the operators
+/// used by the handlers are supported, anything else fails instead of silently matching.
+pub fn matches(entry: &Document, filter: &Document) -> Res<bool> {
+    for (key, condition) in filter {
+        let matched = match key.as_str() {
+            "$or" | "$and" => {
+                let mut results = vec![];
+                for each in values(condition) {
+                    let each = each.as_document().ok_or_else(|| unsupported("query", key))?;
+                    results.push(matches(entry, each)?);
+                }
+                if key == "$or" { results.contains(&true) } else { !results.contains(&false) }
+            }
+            other if other.starts_with('$') => return Err(unsupported("query", other)),
+            path => matches_field(field(entry, path), condition)?,
+        };
+        if !matched {
+            return Ok(false)
+        }
+    }
+    Ok(true)
+}
+
+/// Replaces any of the duplicates inside the array field with the survivor, keeping order of the array and removing repeated entries
+pub fn replace_references(entry: &mut Document, field: &str, duplicates: &[Bson], survivor: &Bson) {
+    let Ok(items) = entry.get_array_mut(field) else {
+        return
+    };
+    let mut replaced: Vec<Bson> = vec![];
+    for item in items.drain(..) {
+        let item = if duplicates.iter().any(|it| same(it, &item)) { survivor.clone() } else { item };
+        if !replaced.iter().any(|it| same(it, &item)) {
+            replaced.push(item);
+        }
+    }
+    *items = replaced;
+}
+
+/// Embedded document holding the last part of a dotted path, along with that part. Missing embedded documents
+/// are created if `create` is set, otherwise `None` is returned. Paths through arrays or other values are not supported.
+fn parent_mut<'a, 'p>(entry: &'a mut Document, path: &'p str, create: bool, operator: &str) -> Res<Option<(&'a mut Document, &'p str)>> {
+    let Some((head, rest)) = path.split_once('.') else {
+        return Ok(Some((entry, path)))
+    };
+    if create && !entry.contains_key(head) {
+        entry.insert(head, Document::new());
+    }
+    match entry.get_mut(head) {
+        Some(Bson::Document(inner)) => parent_mut(inner, rest, create, operator),
+        None => Ok(None),
+        Some(_) => Err(unsupported("update", operator)),
+    }
+}
+
+/// Applies the update operators to the document. Fields may be dotted paths into embedded documents.
+pub fn apply_update(entry: &mut Document, update: &Document) -> Res<()> {
+    for (operator, fields) in update {
+        let fields = fields.as_document().ok_or_else(|| unsupported("update", operator))?;
+        for (path, argument) in fields {
+            let Some((entry, field)) = parent_mut(entry, path, operator != "$unset", operator)? else {
+                // unsetting a field of a missing embedded document changes nothing
+                continue
+            };
+            match operator.as_str() {
+                "$set" => {
+                    entry.insert(field, argument.clone());
+                }
+                "$unset" => {
+                    entry.remove(field);
+                }
+                "$inc" => {
+                    let current = entry.get(field).and_then(number).unwrap_or_default();
+                    let increment = number(argument).ok_or_else(|| unsupported("update", operator))?;
+                    let value = match (entry.get(field), argument) {
+                        (Some(Bson::Double(_)), _) | (_, Bson::Double(_)) => Bson::Double(current + increment),
+                        (Some(Bson::Int32(_)), Bson::Int32(_)) => Bson::Int32((current + increment) as i32),
+                        _ => Bson::Int64((current + increment) as i64),
+                    };
+                    entry.insert(field, value);
+                }
+                "$push" | "$addToSet" | "$pull" | "$pullAll" => {
+                    let mut items = match entry.remove(field) {
+                        Some(Bson::Array(items)) => items,
+                        None => vec![],
+                        Some(_) => return Err(unsupported("update", operator)),
+                    };
+                    // several values are added at once with `$each`
+                    let added = match argument.as_document().and_then(|it| it.get("$each")) {
+                        Some(each) => values(each).to_vec(),
+                        None => vec![argument.clone()],
+                    };
+                    match operator.as_str() {
+                        "$push" => items.extend(added),
+                        "$addToSet" => for value in added {
+                            if !items.iter().any(|it| same(it, &value)) {
+                                items.push(value)
+                            }
+                        },
+                        "$pull" => {
+                            let mut kept = vec![];
+                            for item in items {
+                                if !matches_field(Some(&item), argument)? {
+                                    kept.push(item);
+                                }
+                            }
+                            items = kept;
+                        }
+                        _ => items.retain(|item| !values(argument).iter().any(|it| same(it, item))),
+                    }
+                    entry.insert(field, items);
+                }
+                other => return Err(unsupported("update", other)),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use super::*;
+
+    #[test]
+    fn matches_array_fields_by_element() {
+        let entry = doc! { "name": "a", "loved_tracks": [1, 2, 3] };
+        assert!(matches(&entry, &doc! { "loved_tracks": 2 }).unwrap());
+        assert!(matches(&entry, &doc! { "loved_tracks": { "$in": [5, 3] } }).unwrap());
+        assert!(!matches(&entry, &doc! { "loved_tracks": 4 }).unwrap());
+        assert!(matches(&entry, &doc! { "loved_tracks": { "$size": 3 } }).unwrap());
+    }
+
+    #[test]
+    fn updates_fields_of_embedded_documents() {
+        let mut entry = doc! { "name": "a", "genres": { "rock": 1 }, "tracks": [1] };
+        apply_update(&mut entry, &doc! {
+            "$set": { "genres.pop": 2, "links.site": "b" },
+            "$inc": { "genres.rock": 1 },
+            "$unset": { "missing.field": "" },
+        }).unwrap();
+        assert_eq!(entry, doc! { "name": "a", "genres": { "rock": 2, "pop": 2 }, "tracks": [1], "links": { "site": "b" } });
+        apply_update(&mut entry, &doc! { "$unset": { "genres.pop": "" } }).unwrap();
+        assert_eq!(entry.get_document("genres").unwrap(), &doc! { "rock": 2 });
+        assert!(apply_update(&mut entry, &doc! { "$set": { "tracks.0": 2 } }).is_err());
+    }
+
+    #[test]
+    fn matches_logical_and_existence_operators() {
+        let entry = doc! { "name": "a", "count": 1i64 };
+        assert!(matches(&entry, &doc! { "$or": [{ "name": "b" }, { "count": 1 }] }).unwrap());
+        assert!(!matches(&entry, &doc! { "$and": [{ "name": "a" }, { "count": 2 }] }).unwrap());
+        assert!(matches(&entry, &doc! { "missing": { "$exists": false }, "name": { "$ne": "b" } }).unwrap());
+        assert!(matches(&entry, &doc! { }).unwrap());
+        assert!(matches(&entry, &doc! { "count": { "$lt": 2, "$gte": 1.0 } }).unwrap());
+        assert!(!matches(&entry, &doc! { "count": { "$gt": 1 } }).unwrap());
+    }
+
+    #[test]
+    fn applies_array_updates() {
+        let mut entry = doc! { "tracks": [1, 2] };
+        apply_update(&mut entry, &doc! { "$addToSet": { "tracks": 2 } }).unwrap();
+        assert_eq!(entry, doc! { "tracks": [1, 2] });
+        apply_update(&mut entry, &doc! { "$push": { "tracks": 3 } }).unwrap();
+        apply_update(&mut entry, &doc! { "$pull": { "tracks": { "$in": [1] } } }).unwrap();
+        assert_eq!(entry, doc! { "tracks": [2, 3] });
+        apply_update(&mut entry, &doc! { "$pullAll": { "tracks": [3] }, "$set": { "name": "x" } }).unwrap();
+        assert_eq!(entry, doc! { "tracks": [2], "name": "x" });
+        apply_update(&mut entry, &doc! { "$addToSet": { "tracks": { "$each": [2, 4, 5] } } }).unwrap();
+        assert_eq!(entry, doc! { "tracks": [2, 4, 5], "name": "x" });
+    }
+
+    #[test]
+    fn replaces_references_keeping_order() {
+        let mut entry = doc! { "albums": [1, 2, 3, 4] };
+        replace_references(&mut entry, "albums", &[Bson::Int32(2), Bson::Int32(4)], &Bson::Int32(3));
+        assert_eq!(entry, doc! { "albums": [1, 3] });
+    }
+
+    #[test]
+    fn rejects_unsupported_operators() {
+        assert!(matches(&doc! { "name": "a" }, &doc! { "name": { "$regex": "a" } }).is_err());
+        assert!(apply_update(&mut doc! { "count": 1 }, &doc! { "$mul": { "count": 2 } }).is_err());
+    }
+}
@@ -0,0 +1,724 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures_util::stream::{self, StreamExt};
+use mongodb::bson::{doc, from_document, to_document, Bson, Document};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::OwnedMutexGuard;
+use crate::data::Transaction;
+use crate::data::model::BsonId;
+use crate::data::repository::pipeline::{aggregate, apply_pipeline};
+use crate::data::repository::query::{self, apply_update, ensure_id, matches, references_filter};
+use crate::data::repository::{EntryStream, Repository};
+use crate::err::AstralError;
+use crate::Res;
+
+/// How long a write waits for a transaction of another connection to finish before it fails
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Embedded SQLite database storing every collection in its own table.
+///
+/// Writes outside of transactions go through a shared connection. Transactions get a dedicated connection, which is
+/// held for the whole transaction, so they are applied one at a time. The database is opened in WAL mode, so reads
+/// are not blocked by an open transaction and see the data as it was before it.
+#[derive(Debug, Clone)]
+pub struct SqliteDatabase {
+    connection: Arc<Mutex<Connection>>,
+    transactions: Arc<tokio::sync::Mutex<Connection>>,
+}
+
+impl SqliteDatabase {
+    /// Opens the database at the path, creating it if it does not exist yet
+    pub fn open(path: impl AsRef<Path>) -> Res<Self> {
+        let connection = connect(path.as_ref())?;
+        let transactions = connect(path.as_ref())?;
+        Ok(Self { connection: Arc::new(Mutex::new(connection)), transactions: Arc::new(tokio::sync::Mutex::new(transactions)) })
+    }
+
+    /// Repository of the collection, creating its table if it does not exist yet
+    pub fn repository<T>(&self, name: &str) -> Res<SqliteRepository<T>> {
+        let table = Table::open(&self.connection.lock().unwrap(), name)?;
+        Ok(SqliteRepository { database: self.clone(), table, _entry: PhantomData })
+    }
+
+    /// Starts a transaction on the dedicated connection, waiting for the previous one to finish
+    pub async fn begin(&self) -> Res<SqliteTransaction> {
+        let connection = self.transactions.clone().lock_owned().await;
+        blocking(move || {
+            // the write lock is taken right away, so the transaction can not fail halfway because of another writer
+            connection.execute_batch("BEGIN IMMEDIATE")?;
+            Ok(SqliteTransaction { connection, open: true })
+        }).await
+    }
+}
+
+/// Opens a connection to the database file
+fn connect(path: &Path) -> Res<Connection> {
+    let connection = Connection::open(path)?;
+    connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(connection)
+}
+
+/// Open transaction of a [SqliteDatabase], rolled back if it is dropped without committing
+pub struct SqliteTransaction {
+    connection: OwnedMutexGuard<Connection>,
+    open: bool,
+}
+
+impl SqliteTransaction {
+    /// Commits all writes of the transaction
+    pub async fn commit(mut self) -> Res<()> {
+        blocking(move || self.finish()).await
+    }
+
+    /// Commits the transaction, which is rolled back once dropped if the commit fails
+    fn finish(&mut self) -> Res<()> {
+        self.connection.execute_batch("COMMIT")?;
+        self.open = false;
+        Ok(())
+    }
+}
+
+impl Drop for SqliteTransaction {
+    fn drop(&mut self) {
+        if self.open {
+            let _ = self.connection.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+/// Repository storing entries of a collection as BSON documents in a table of a [SqliteDatabase].
+/// Fields entries are looked up by are copied into indexed columns, filters on them only read the matching rows.
+/// Other filters and all updates are evaluated with [crate::data::repository::query],
+/// aggregations with [crate::data::repository::pipeline].
+#[derive(Debug)]
+pub struct SqliteRepository<T> {
+    database: SqliteDatabase,
+    table: Table,
+    _entry: PhantomData<fn() -> T>,
+}
+
+/// Fields of the collection stored in their own indexed columns. All tables have an `_id` column, which is unique.
+fn key_fields(table: &str) -> &'static [&'static str] {
+    match table {
+        // scalar fields index filters match on are keys as well
+        "tracks_metadata" => &["_id", "track_id", "format", "is_explicit", "added_at"],
+        "albums_metadata" => &["_id", "album_id", "album_type", "release_date", "added_at"],
+        "artists_metadata" => &["_id", "artist_id", "added_at"],
+        "accounts" => &["_id", "user_id", "username"],
+        "invite_codes" => &["_id", "code"],
+        "undefined_tracks" => &["_id", "track_id", "uploaded_by"],
+        "lyrics" => &["_id", "track_id"],
+        "fingerprints" => &["_id", "track_id"],
+        // tables of file buckets, see [crate::data::bucket::DocumentFiles]
+        name if name.ends_with("_files") => &["_id", "filename"],
+        name if name.ends_with("_chunks") => &["_id", "files_id"],
+        _ => &["_id"],
+    }
+}
+
+/// Column value of a key field. Only strings, UUIDs, object IDs, booleans and integers are stored,
+/// the column is left empty for other values.
+fn key_value(value: &Bson) -> Option<Value> {
+    match value {
+        Bson::String(value) => Some(Value::Text(value.clone())),
+        Bson::Boolean(value) => Some(Value::Integer(*value as i64)),
+        Bson::ObjectId(id) => Some(Value::Text(id.to_hex())),
+        Bson::Binary(binary) => Some(Value::Blob(binary.bytes.clone())),
+        Bson::Int32(value) => Some(Value::Integer(*value as i64)),
+        Bson::Int64(value) => Some(Value::Integer(*value)),
+        _ => None,
+    }
+}
+
+fn encode(document: &Document) -> Res<Vec<u8>> {
+    let mut bytes = vec![];
+    document.to_writer(&mut bytes).map_err(anyhow::Error::from)?;
+    Ok(bytes)
+}
+
+/// Runs the work on the blocking thread pool, as SQLite blocks the calling thread
+async fn blocking<R: Send + 'static>(work: impl FnOnce() -> Res<R> + Send + 'static) -> Res<R> {
+    tokio::task::spawn_blocking(work).await
+        .map_err(|err| AstralError::Unknown(err.into()))?
+}
+
+/// Table of a collection. Statements run on the connection they are given, so they can be part of a transaction.
+#[derive(Debug, Clone)]
+struct Table {
+    name: String,
+    keys: &'static [&'static str],
+}
+
+impl Table {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_owned(), keys: key_fields(name) }
+    }
+
+    /// Opens the table of the collection, creating it along with its key columns if it does not exist yet.
+    /// Key columns missing from a table created by an older version are added and filled in from the stored documents.
+    fn open(connection: &Connection, name: &str) -> Res<Self> {
+        let table = Self::new(name);
+        let transaction = connection.unchecked_transaction()?;
+        transaction.execute(&format!("CREATE TABLE IF NOT EXISTS {name} (id INTEGER PRIMARY KEY AUTOINCREMENT, document BLOB NOT NULL)"), [])?;
+        let columns = transaction.prepare(&format!("SELECT name FROM pragma_table_info('{name}')"))?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let missing = table.keys.iter().filter(|key| !columns.iter().any(|column| column == *key)).collect::<Vec<_>>();
+        for key in &missing {
+            transaction.execute(&format!("ALTER TABLE {name} ADD COLUMN {key}"), [])?;
+        }
+        if !missing.is_empty() {
+            for (id, mut document) in table.select(&transaction, &Document::new(), usize::MAX)? {
+                ensure_id(&mut document);
+                table.replace(&transaction, id, &document)?;
+            }
+        }
+        for key in table.keys {
+            // IDs are unique, same as the `_id` index of MongoDB
+            let unique = if *key == "_id" { "UNIQUE" } else { "" };
+            transaction.execute(&format!("CREATE {unique} INDEX IF NOT EXISTS {name}_{key} ON {name} ({key})"), [])?;
+        }
+        transaction.commit()?;
+        Ok(table)
+    }
+
+    /// Conditions on key columns of the filter and of all its `$and` conditions, added to `conditions`.
+    /// Returns whether the conditions match exactly the rows the filter matches, otherwise they only narrow them down.
+    fn key_conditions(&self, filter: &Document, conditions: &mut Vec<KeyCondition>) -> bool {
+        let mut exact = true;
+        for (field, value) in filter {
+            match value {
+                Bson::Array(items) if field == "$and" => for item in items {
+                    exact &= matches!(item, Bson::Document(item) if self.key_conditions(item, conditions));
+                },
+                _ if self.keys.contains(&field.as_str()) => match KeyCondition::new(field, value) {
+                    Some(condition) => {
+                        exact &= condition.exact;
+                        conditions.push(condition);
+                    }
+                    None => exact = false,
+                },
+                _ => exact = false,
+            }
+        }
+        exact
+    }
+
+    /// `WHERE` clause narrowing down rows that can match the filter along with its parameters,
+    /// and whether it matches exactly the rows the filter matches
+    fn where_clause(&self, filter: &Document) -> (String, Vec<Value>, bool) {
+        let mut conditions = vec![];
+        let exact = self.key_conditions(filter, &mut conditions);
+        if conditions.is_empty() {
+            return (String::new(), vec![], exact)
+        }
+        let clause = conditions.iter().map(|it| it.sql.as_str()).collect::<Vec<_>>().join(" AND ");
+        (format!("WHERE {clause}"), conditions.into_iter().flat_map(|it| it.values).collect(), exact)
+    }
+
+    /// Visits at most `limit` rows matching the filter, along with their row IDs, in insertion order.
+    /// Rows are decoded one at a time, so rows that do not match are never kept in memory.
+    fn scan(&self, connection: &Connection, filter: &Document, limit: usize, mut visit: impl FnMut(i64, Document)) -> Res<usize> {
+        let (condition, values, _) = self.where_clause(filter);
+        let mut statement = connection.prepare(&format!("SELECT id, document FROM {} {condition} ORDER BY id", self.name))?;
+        let mut rows = statement.query(params_from_iter(values))?;
+        let mut visited = 0;
+        while visited < limit {
+            let Some(row) = rows.next()? else {
+                break
+            };
+            let bytes: Vec<u8> = row.get(1)?;
+            let document = Document::from_reader(bytes.as_slice()).map_err(anyhow::Error::from)?;
+            if matches(&document, filter)? {
+                visit(row.get(0)?, document);
+                visited += 1;
+            }
+        }
+        Ok(visited)
+    }
+
+    /// Reads at most `limit` rows matching the filter, along with their row IDs, in insertion order
+    fn select(&self, connection: &Connection, filter: &Document, limit: usize) -> Res<Vec<(i64, Document)>> {
+        let mut found = vec![];
+        self.scan(connection, filter, limit, |id, document| found.push((id, document)))?;
+        Ok(found)
+    }
+
+    /// Counts rows matching the filter. Filters on key columns only are counted by SQLite without reading the rows.
+    fn count(&self, connection: &Connection, filter: &Document) -> Res<u64> {
+        let (condition, values, exact) = self.where_clause(filter);
+        if !exact {
+            return Ok(self.scan(connection, filter, usize::MAX, |_, _| {})? as u64)
+        }
+        let count: i64 = connection.query_row(&format!("SELECT COUNT(*) FROM {} {condition}", self.name), params_from_iter(values), |row| row.get(0))?;
+        Ok(count as u64)
+    }
+
+    fn find(&self, connection: &Connection, filter: &Document, limit: usize) -> Res<Vec<Document>> {
+        Ok(self.select(connection, filter, limit)?.into_iter().map(|(_, document)| document).collect())
+    }
+
+    /// Document followed by values of its key columns
+    fn row_values(&self, document: &Document) -> Res<Vec<Value>> {
+        let mut values = vec![Value::Blob(encode(document)?)];
+        values.extend(self.keys.iter().map(|key| document.get(*key).and_then(key_value).unwrap_or(Value::Null)));
+        Ok(values)
+    }
+
+    /// Inserts the document, giving it an ID if it has none. Fails if the ID is already taken.
+    fn insert(&self, connection: &Connection, mut document: Document) -> Res<()> {
+        ensure_id(&mut document);
+        let columns = ["document"].iter().chain(self.keys).copied().collect::<Vec<_>>();
+        let placeholders = (1..=columns.len()).map(|idx| format!("?{idx}")).collect::<Vec<_>>();
+        connection.execute(
+            &format!("INSERT INTO {} ({}) VALUES ({})", self.name, columns.join(", "), placeholders.join(", ")),
+            params_from_iter(self.row_values(&document)?),
+        )?;
+        Ok(())
+    }
+
+    fn replace(&self, connection: &Connection, id: i64, document: &Document) -> Res<()> {
+        let assignments = ["document"].iter().chain(self.keys).enumerate()
+            .map(|(idx, column)| format!("{column} = ?{}", idx + 1))
+            .collect::<Vec<_>>();
+        let mut values = self.row_values(document)?;
+        values.push(Value::Integer(id));
+        connection.execute(
+            &format!("UPDATE {} SET {} WHERE id = ?{}", self.name, assignments.join(", "), values.len()),
+            params_from_iter(values),
+        )?;
+        Ok(())
+    }
+
+    fn insert_many(&self, connection: &Connection, documents: Vec<Document>) -> Res<()> {
+        for document in documents {
+            self.insert(connection, document)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the change to at most `limit` rows matching the filter, returning the changed rows as they were before
+    fn modify(&self, connection: &Connection, filter: &Document, limit: usize, mut change: impl FnMut(&mut Document) -> Res<()>) -> Res<Vec<Document>> {
+        let mut changed = vec![];
+        for (id, mut document) in self.select(connection, filter, limit)? {
+            let before = document.clone();
+            change(&mut document)?;
+            if document != before {
+                self.replace(connection, id, &document)?;
+                changed.push(before);
+            }
+        }
+        Ok(changed)
+    }
+
+    fn update(&self, connection: &Connection, filter: &Document, update: &Document, limit: usize) -> Res<u64> {
+        Ok(self.modify(connection, filter, limit, |document| apply_update(document, update))?.len() as u64)
+    }
+
+    fn replace_references(&self, connection: &Connection, filter: &Document, field: &str, duplicates: &[BsonId], survivor: &BsonId) -> Res<u64> {
+        let filter = references_filter(filter.clone(), field, duplicates);
+        let duplicates: Vec<Bson> = duplicates.iter().map(|it| Bson::from(*it)).collect();
+        let survivor = Bson::from(*survivor);
+        let changed = self.modify(connection, &filter, usize::MAX, |document| {
+            query::replace_references(document, field, &duplicates, &survivor);
+            Ok(())
+        })?;
+        Ok(changed.len() as u64)
+    }
+
+    /// Inserts the document unless a row with the same `_id` exists, returning whether it was inserted
+    fn try_insert(&self, connection: &Connection, mut document: Document) -> Res<bool> {
+        ensure_id(&mut document);
+        if !self.select(connection, &doc! { "_id": document.get("_id") }, 1)?.is_empty() {
+            return Ok(false)
+        }
+        self.insert(connection, document)?;
+        Ok(true)
+    }
+
+    /// Replaces the first row matching the filter, keeping its ID, or inserts the document if nothing matches
+    fn upsert(&self, connection: &Connection, filter: &Document, mut document: Document) -> Res<()> {
+        match self.select(connection, filter, 1)?.pop() {
+            Some((id, replaced)) => {
+                if let Some(replaced_id) = replaced.get("_id") {
+                    document.insert("_id", replaced_id.clone());
+                }
+                self.replace(connection, id, &document)
+            }
+            None => self.insert(connection, document),
+        }
+    }
+
+    /// Removes at most `limit` rows matching the filter, returning their documents
+    fn delete(&self, connection: &Connection, filter: &Document, limit: usize) -> Res<Vec<Document>> {
+        let found = self.select(connection, filter, limit)?;
+        for (id, _) in &found {
+            connection.execute(&format!("DELETE FROM {} WHERE id = ?1", self.name), params![id])?;
+        }
+        Ok(found.into_iter().map(|(_, document)| document).collect())
+    }
+}
+
+/// Condition on a key column in SQL, along with its parameters
+struct KeyCondition {
+    sql: String,
+    values: Vec<Value>,
+    /// Whether the condition matches exactly the rows the filter on the field matches
+    exact: bool,
+}
+
+impl KeyCondition {
+    /// Condition on the column of the key field matching a value, one of `$in` values or an integer range.
+    /// `None` if the filter on the field can not be checked on the column.
+    fn new(key: &str, filter: &Bson) -> Option<Self> {
+        let Bson::Document(operators) = filter else {
+            let value = key_value(filter)?;
+            return Some(Self { sql: format!("{key} = ?"), values: vec![value], exact: true })
+        };
+        if let (1, Some(Bson::Array(items))) = (operators.len(), operators.get("$in")) {
+            // null also matches missing fields, which have an empty column same as values that are not stored
+            let nullable = items.iter().any(|it| it == &Bson::Null);
+            let values = items.iter().filter(|it| *it != &Bson::Null).map(key_value).collect::<Option<Vec<_>>>()?;
+            let mut sql = format!("{key} IN ({})", vec!["?"; values.len()].join(", "));
+            if nullable {
+                sql = format!("({sql} OR {key} IS NULL)");
+            }
+            return Some(Self { sql, values, exact: !nullable })
+        }
+        let mut ranges = vec![];
+        let mut values = vec![];
+        for (operator, value) in operators {
+            let comparison = match operator.as_str() {
+                "$gt" => ">",
+                "$gte" => ">=",
+                "$lt" => "<",
+                "$lte" => "<=",
+                _ => return None,
+            };
+            if !matches!(value, Bson::Int32(_) | Bson::Int64(_)) {
+                return None
+            }
+            ranges.push(format!("{key} {comparison} ?"));
+            values.push(key_value(value)?);
+        }
+        // SQLite orders values of other types after integers, while MongoDB only compares numbers with numbers
+        (!ranges.is_empty()).then(|| Self { sql: ranges.join(" AND "), values, exact: false })
+    }
+}
+
+impl<T> SqliteRepository<T> {
+    /// Runs the work on the table on the blocking thread pool. Work of a transaction runs on its connection,
+    /// other work runs on the shared connection in a transaction of its own, so it is applied whole or not at all.
+    async fn run<R: Send + 'static>(&self, session: Option<&mut Transaction>, work: impl FnOnce(&Table, &Connection) -> Res<R> + Send + 'static) -> Res<R> {
+        let table = self.table.clone();
+        if let Some(slot) = session.map(|it| &mut it.sqlite) {
+            if let Some(transaction) = slot.take() {
+                // the transaction is moved along with the work, so it is rolled back if the work is cancelled
+                let (transaction, result) = blocking(move || {
+                    let result = work(&table, &transaction.connection);
+                    Ok((transaction, result))
+                }).await?;
+                *slot = Some(transaction);
+                return result
+            }
+        }
+        let connection = self.database.connection.clone();
+        blocking(move || {
+            let connection = connection.lock().unwrap();
+            let transaction = connection.unchecked_transaction()?;
+            let result = work(&table, &transaction)?;
+            transaction.commit()?;
+            Ok(result)
+        }).await
+    }
+}
+
+fn decode<T: DeserializeOwned>(found: Vec<Document>) -> Res<Option<T>> {
+    Ok(found.into_iter().next().map(from_document).transpose()?)
+}
+
+#[axum::async_trait]
+impl<T> Repository<T> for SqliteRepository<T>
+where T: Serialize + DeserializeOwned + Debug + Send + Sync + 'static {
+    async fn find_one(&self, filter: Document) -> Res<Option<T>> {
+        decode(self.run(None, move |table, connection| table.find(connection, &filter, 1)).await?)
+    }
+
+    async fn find(&self, filter: Document) -> Res<EntryStream<T>> {
+        let found = self.run(None, move |table, connection| table.find(connection, &filter, usize::MAX)).await?;
+        Ok(stream::iter(found).map(|document| from_document(document).map_err(AstralError::from)).boxed())
+    }
+
+    async fn count(&self, filter: Document) -> Res<u64> {
+        self.run(None, move |table, connection| table.count(connection, &filter)).await
+    }
+
+    async fn insert_one(&self, entry: &T) -> Res<()> {
+        let document = to_document(entry).map_err(anyhow::Error::from)?;
+        self.run(None, move |table, connection| table.insert(connection, document)).await
+    }
+
+    async fn insert_many(&self, entries: &[T]) -> Res<()> {
+        let documents = entries.iter().map(to_document).collect::<Result<Vec<_>, _>>().map_err(anyhow::Error::from)?;
+        self.run(None, move |table, connection| table.insert_many(connection, documents)).await
+    }
+
+    async fn try_insert_one(&self, entry: &T) -> Res<bool> {
+        let document = to_document(entry).map_err(anyhow::Error::from)?;
+        self.run(None, move |table, connection| table.try_insert(connection, document)).await
+    }
+
+    async fn upsert_one(&self, filter: Document, entry: &T) -> Res<()> {
+        let document = to_document(entry).map_err(anyhow::Error::from)?;
+        self.run(None, move |table, connection| table.upsert(connection, &filter, document)).await
+    }
+
+    async fn update_one(&self, filter: Document, update: Document) -> Res<u64> {
+        self.run(None, move |table, connection| table.update(connection, &filter, &update, 1)).await
+    }
+
+    async fn update_many(&self, filter: Document, update: Document) -> Res<u64> {
+        self.run(None, move |table, connection| table.update(connection, &filter, &update, usize::MAX)).await
+    }
+
+    async fn find_one_and_update(&self, filter: Document, update: Document) -> Res<Option<T>> {
+        // unchanged entries are matched as well, so they are read separately
+        let found = self.run(None, move |table, connection| {
+            let found = table.find(connection, &filter, 1)?;
+            table.update(connection, &filter, &update, 1)?;
+            Ok(found)
+        }).await?;
+        decode(found)
+    }
+
+    async fn replace_references(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId) -> Res<u64> {
+        let (field, duplicates, survivor) = (field.to_owned(), duplicates.to_vec(), *survivor);
+        self.run(None, move |table, connection| table.replace_references(connection, &filter, &field, &duplicates, &survivor)).await
+    }
+
+    async fn delete_one(&self, filter: Document) -> Res<u64> {
+        Ok(self.run(None, move |table, connection| table.delete(connection, &filter, 1)).await?.len() as u64)
+    }
+
+    async fn delete_many(&self, filter: Document) -> Res<u64> {
+        Ok(self.run(None, move |table, connection| table.delete(connection, &filter, usize::MAX)).await?.len() as u64)
+    }
+
+    async fn find_one_and_delete(&self, filter: Document) -> Res<Option<T>> {
+        decode(self.run(None, move |table, connection| table.delete(connection, &filter, 1)).await?)
+    }
+
+    async fn aggregate(&self, mut pipeline: Vec<Document>) -> Res<EntryStream<Document>> {
+        let found = self.run(None, move |table, connection| {
+            // a leading match only reads the matching rows, narrowed down by key columns
+            let filter = match pipeline.first().map(|it| it.get_document("$match")) {
+                Some(Ok(filter)) if pipeline[0].len() == 1 => {
+                    let filter = filter.clone();
+                    pipeline.remove(0);
+                    filter
+                }
+                _ => Document::new(),
+            };
+            let entries = table.find(connection, &filter, usize::MAX)?;
+            aggregate(entries, &pipeline, &|name, field, values| {
+                Table::new(name).find(connection, &doc! { field: { "$in": values } }, usize::MAX)
+            })
+        }).await?;
+        Ok(stream::iter(found).map(Ok).boxed())
+    }
+
+    async fn update_many_pipeline(&self, filter: Document, pipeline: Vec<Document>) -> Res<u64> {
+        let changed = self.run(None, move |table, connection| {
+            table.modify(connection, &filter, usize::MAX, |document| apply_pipeline(document, &pipeline))
+        }).await?;
+        Ok(changed.len() as u64)
+    }
+
+    async fn find_one_with_session(&self, filter: Document, session: &mut Transaction) -> Res<Option<T>> {
+        decode(self.run(Some(session), move |table, connection| table.find(connection, &filter, 1)).await?)
+    }
+
+    async fn insert_one_with_session(&self, entry: &T, session: &mut Transaction) -> Res<()> {
+        let document = to_document(entry).map_err(anyhow::Error::from)?;
+        self.run(Some(session), move |table, connection| table.insert(connection, document)).await
+    }
+
+    async fn update_one_with_session(&self, filter: Document, update: Document, session: &mut Transaction) -> Res<u64> {
+        self.run(Some(session), move |table, connection| table.update(connection, &filter, &update, 1)).await
+    }
+
+    async fn update_many_with_session(&self, filter: Document, update: Document, session: &mut Transaction) -> Res<u64> {
+        self.run(Some(session), move |table, connection| table.update(connection, &filter, &update, usize::MAX)).await
+    }
+
+    async fn replace_references_with_session(&self, filter: Document, field: &str, duplicates: &[BsonId], survivor: &BsonId, session: &mut Transaction) -> Res<u64> {
+        let (field, duplicates, survivor) = (field.to_owned(), duplicates.to_vec(), *survivor);
+        self.run(Some(session), move |table, connection| table.replace_references(connection, &filter, &field, &duplicates, &survivor)).await
+    }
+
+    async fn delete_one_with_session(&self, filter: Document, session: &mut Transaction) -> Res<u64> {
+        Ok(self.run(Some(session), move |table, connection| table.delete(connection, &filter, 1)).await?.len() as u64)
+    }
+
+    async fn delete_many_with_session(&self, filter: Document, session: &mut Transaction) -> Res<u64> {
+        Ok(self.run(Some(session), move |table, connection| table.delete(connection, &filter, usize::MAX)).await?.len() as u64)
+    }
+
+    async fn find_one_and_delete_with_session(&self, filter: Document, session: &mut Transaction) -> Res<Option<T>> {
+        decode(self.run(Some(session), move |table, connection| table.delete(connection, &filter, 1)).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+    use mongodb::bson::doc;
+    use uuid::Uuid;
+    use crate::data::model::{InviteCode, LyricsStatus, TrackLyrics};
+    use super::*;
+
+    /// Path of a new database file in the temporary directory
+    fn database_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("astral_test_{}.sqlite3", Uuid::new_v4()))
+    }
+
+    fn database() -> SqliteDatabase {
+        SqliteDatabase::open(database_path()).unwrap()
+    }
+
+    fn invite_code(code: &str) -> InviteCode {
+        InviteCode { code: code.to_owned(), issued_by: BsonId::new(), expires_at: 0, permissions: vec![] }
+    }
+
+    fn lyrics(track_id: BsonId) -> TrackLyrics {
+        TrackLyrics { track_id, status: LyricsStatus::Unsynced { lines: vec![String::from("abc")] } }
+    }
+
+    #[tokio::test]
+    async fn stores_and_queries_entries() {
+        let repository = database().repository::<InviteCode>("invite_codes").unwrap();
+        repository.insert_one(&invite_code("a")).await.unwrap();
+        repository.insert_one(&invite_code("b")).await.unwrap();
+
+        assert_eq!(repository.find_one(doc! { "code": "b" }).await.unwrap().unwrap().code, "b");
+        assert_eq!(repository.count(doc! { "code": { "$in": ["a", "c"] } }).await.unwrap(), 1);
+        assert_eq!(repository.update_many(doc! { }, doc! { "$set": { "expires_at": 5i64 } }).await.unwrap(), 2);
+        let found: Vec<InviteCode> = repository.find(doc! { }).await.unwrap().try_collect().await.unwrap();
+        assert!(found.iter().all(|it| it.expires_at == 5));
+        assert_eq!(repository.delete_one(doc! { "code": "a" }).await.unwrap(), 1);
+        assert_eq!(repository.count(doc! { }).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn key_columns_follow_updates() {
+        let repository = database().repository::<InviteCode>("invite_codes").unwrap();
+        repository.insert_many(&[invite_code("a"), invite_code("b")]).await.unwrap();
+
+        repository.update_one(doc! { "code": "a" }, doc! { "$set": { "code": "c" } }).await.unwrap();
+        assert!(repository.find_one(doc! { "code": "a" }).await.unwrap().is_none());
+        assert_eq!(repository.count(doc! { "code": "c" }).await.unwrap(), 1);
+        assert_eq!(repository.count(doc! { "code": { "$in": [] } }).await.unwrap(), 0);
+        // filters on other fields still scan the table
+        assert_eq!(repository.count(doc! { "expires_at": 0i64 }).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn narrows_index_filters_by_key_columns() {
+        let repository = database().repository::<Document>("albums_metadata").unwrap();
+        repository.insert_many(&[
+            doc! { "album_id": 1, "album_type": "single", "release_date": 10i64 },
+            doc! { "album_id": 2, "release_date": 20i64, "name": "b" },
+            doc! { "album_id": 3, "album_type": "album", "release_date": "unknown" },
+        ]).await.unwrap();
+
+        assert_eq!(repository.count(doc! { "album_type": { "$in": ["album", null] } }).await.unwrap(), 2);
+        // text sorts after integers in SQLite, but is never in a numeric range
+        assert_eq!(repository.count(doc! { "$and": [{ "release_date": { "$gte": 15i64 } }] }).await.unwrap(), 1);
+        assert_eq!(repository.count(doc! { "album_id": { "$in": [1, 2] }, "$and": [{ "name": "b" }] }).await.unwrap(), 1);
+        let found: Vec<Document> = repository.find(doc! { "$and": [{ "album_type": "single" }, { "release_date": { "$lt": 15i64 } }] }).await.unwrap()
+            .try_collect().await.unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fills_key_columns_of_older_tables() {
+        let path = database_path();
+        {
+            let connection = Connection::open(&path).unwrap();
+            connection.execute("CREATE TABLE invite_codes (id INTEGER PRIMARY KEY AUTOINCREMENT, document BLOB NOT NULL)", []).unwrap();
+            let document = to_document(&invite_code("a")).unwrap();
+            connection.execute("INSERT INTO invite_codes (document) VALUES (?1)", params![encode(&document).unwrap()]).unwrap();
+        }
+
+        let repository = SqliteDatabase::open(&path).unwrap().repository::<Document>("invite_codes").unwrap();
+        assert_eq!(repository.count(doc! { "code": "a" }).await.unwrap(), 1);
+        assert!(repository.find_one(doc! { }).await.unwrap().unwrap().get_object_id("_id").is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_taken_ids() {
+        let repository = database().repository::<Document>("schema").unwrap();
+        assert!(repository.try_insert_one(&doc! { "_id": "lock" }).await.unwrap());
+        assert!(!repository.try_insert_one(&doc! { "_id": "lock" }).await.unwrap());
+        assert!(repository.insert_one(&doc! { "_id": "lock" }).await.is_err());
+        repository.upsert_one(doc! { "_id": "lock" }, &doc! { "owner": "a" }).await.unwrap();
+        assert_eq!(repository.find_one(doc! { "owner": "a" }).await.unwrap().unwrap().get_str("_id").unwrap(), "lock");
+    }
+
+    #[tokio::test]
+    async fn transactions_roll_back_unless_committed() {
+        let database = database();
+        let repository = database.repository::<TrackLyrics>("lyrics").unwrap();
+        let kept = BsonId::new();
+        repository.insert_one(&lyrics(kept)).await.unwrap();
+
+        let mut aborted = Transaction { sqlite: Some(database.begin().await.unwrap()), ..Default::default() };
+        assert_eq!(repository.delete_one_with_session(doc! { "track_id": kept }, &mut aborted).await.unwrap(), 1);
+        repository.insert_one_with_session(&lyrics(BsonId::new()), &mut aborted).await.unwrap();
+        // the transaction reads its own writes, which are not visible outside of it
+        assert!(repository.find_one_with_session(doc! { "track_id": kept }, &mut aborted).await.unwrap().is_none());
+        assert_eq!(repository.count(doc! { "track_id": kept }).await.unwrap(), 1);
+        drop(aborted);
+        let found: Vec<TrackLyrics> = repository.find(doc! { }).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(found.iter().map(|it| it.track_id).collect::<Vec<_>>(), vec![kept]);
+
+        let added = BsonId::new();
+        let mut session = Transaction { sqlite: Some(database.begin().await.unwrap()), ..Default::default() };
+        repository.insert_one_with_session(&lyrics(added), &mut session).await.unwrap();
+        repository.delete_one_with_session(doc! { "track_id": kept }, &mut session).await.unwrap();
+        session.sqlite.take().unwrap().commit().await.unwrap();
+        let found: Vec<TrackLyrics> = repository.find(doc! { }).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(found.iter().map(|it| it.track_id).collect::<Vec<_>>(), vec![added]);
+    }
+
+    #[tokio::test]
+    async fn aggregates_with_lookups_into_other_tables() {
+        let database = database();
+        let tracks = database.repository::<Document>("tracks_metadata").unwrap();
+        let albums = database.repository::<Document>("albums_metadata").unwrap();
+        let (first, second) = (BsonId::new(), BsonId::new());
+        albums.insert_many(&[doc! { "album_id": first, "name": "b" }, doc! { "album_id": second, "name": "a" }]).await.unwrap();
+        tracks.insert_many(&[
+            doc! { "name": "x", "albums": [first], "genres": ["punk"] },
+            doc! { "name": "y", "albums": [second], "genres": ["punk", "techno"] },
+        ]).await.unwrap();
+
+        let found: Vec<Document> = tracks.aggregate(vec![
+            doc! { "$match": { "genres": "punk" } },
+            doc! { "$lookup": { "from": "albums_metadata", "localField": "albums", "foreignField": "album_id", "as": "album_objects" } },
+            doc! { "$sort": { "album_objects.0.name": 1 } },
+            doc! { "$project": { "_id": 0, "name": 1 } },
+        ]).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(found, vec![doc! { "name": "y" }, doc! { "name": "x" }]);
+
+        assert_eq!(tracks.update_many_pipeline(doc! { "name": "x" }, vec![doc! { "$set": { "added_at": { "$toLong": { "$toDate": "$_id" } } } }]).await.unwrap(), 1);
+        assert!(tracks.find_one(doc! { "name": "x" }).await.unwrap().unwrap().get_i64("added_at").unwrap() > 0);
+    }
+}
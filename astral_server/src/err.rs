@@ -50,7 +50,19 @@ pub enum AstralError {
     BsonDocError(#[from] ValueAccessError),
     /// BSON deserialization error
     #[error("Failed to deserialize data from BSON: {0}")]
-    BsonDeError(#[from] mongodb::bson::de::Error)
+    BsonDeError(#[from] mongodb::bson::de::Error),
+    /// ZIP archive error
+    #[error("An error occurred when writing ZIP archive: {0}")]
+    ZipError(#[from] zip::result::ZipError),
+    /// Search index error
+    #[error("An error occurred within the search index: {0}")]
+    SearchError(#[from] tantivy::TantivyError),
+    /// Image decoding or encoding error
+    #[error("An error occurred when processing an image: {0}")]
+    ImageError(#[from] image::ImageError),
+    /// SQLite error
+    #[error("An error occurred within the SQLite database: {0}")]
+    SqliteError(#[from] rusqlite::Error)
 }
 
 // <editor-fold defaultstate="collapsed" desc="impl macro">
@@ -118,6 +130,10 @@ error_impls! {
     JsonError: (INTERNAL_SERVER_ERROR, "json");
     BsonDocError: (INTERNAL_SERVER_ERROR, "bson");
     BsonDeError: (INTERNAL_SERVER_ERROR, "bson_de");
+    ZipError: (INTERNAL_SERVER_ERROR, "zip");
+    SearchError: (INTERNAL_SERVER_ERROR, "search");
+    ImageError: (INTERNAL_SERVER_ERROR, "image");
+    SqliteError: (INTERNAL_SERVER_ERROR, "sqlite");
 }
 
 pub type Res<T> = axum::response::Result<T, AstralError>;
\ No newline at end of file
@@ -0,0 +1,10 @@
+/// Expires stale pending uploads
+pub mod sweeper;
+/// Writes database metadata back into the audio file tags
+pub mod tag_writer;
+/// Periodically rebuilds the search index to keep popularity of entries fresh, and commits its changes in batches
+pub mod search_refresher;
+/// Computes acoustic fingerprints of ingested tracks
+pub mod fingerprinter;
+/// Stores file hashes of tracks ingested before they were hashed on upload
+pub mod hasher;
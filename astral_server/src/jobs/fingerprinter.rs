@@ -0,0 +1,83 @@
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, from_document};
+use serde::Deserialize;
+use crate::data::AstralDatabase;
+use crate::data::model::BsonId;
+use crate::metadata::acoustid::acoustid_key;
+use crate::metadata::fingerprint::{fingerprint_track, lookup_fingerprint};
+use crate::Res;
+
+/// Spawns a background task that fingerprints a newly ingested track, unless it already has a fingerprint
+pub fn spawn_fingerprinter(db: AstralDatabase, track_id: BsonId) {
+    tokio::spawn(async move {
+        if !matches!(db.fingerprints.count(doc! { "track_id": &track_id }).await, Ok(0)) {
+            return
+        }
+        // failed tracks are stored as such and are not retried
+        let _ = fingerprint_track(&db, &track_id).await;
+    });
+}
+
+/// Track found by the backfill
+#[derive(Debug, Deserialize)]
+struct TrackRef {
+    track_id: BsonId,
+}
+
+/// Fingerprints all tracks that were never fingerprinted one by one, then looks up fingerprints that were not
+/// looked up in AcoustID yet. Failed attempts are stored as well, so tracks that could not be fingerprinted
+/// are not retried. Returns amount of processed tracks.
+pub async fn backfill_fingerprints(db: &AstralDatabase) -> Res<u64> {
+    let missing: Vec<TrackRef> = db.tracks_metadata.aggregate(vec![
+        doc! {
+            "$lookup": {
+                "from": "fingerprints",
+                "localField": "track_id",
+                "foreignField": "track_id",
+                "pipeline": [{ "$project": { "_id": 1 } }, { "$limit": 1 }],
+                "as": "fingerprint",
+            }
+        },
+        // failed attempts are stored fingerprints too, so they are skipped here
+        doc! { "$match": { "fingerprint": { "$size": 0 } } },
+        doc! { "$project": { "_id": 0, "track_id": 1 } },
+    ]).await?
+        .and_then(|it| std::future::ready(from_document::<TrackRef>(it).map_err(Into::into)))
+        .try_collect().await?;
+
+    let mut processed = 0;
+    for track in missing {
+        if fingerprint_track(db, &track.track_id).await.is_ok() {
+            processed += 1;
+        }
+    }
+
+    if acoustid_key().is_none() {
+        return Ok(processed)
+    }
+    let pending: Vec<_> = db.fingerprints.find(doc! { "failed": { "$ne": true }, "looked_up_at": null }).await?
+        .try_collect().await?;
+    for mut entry in pending {
+        // lookups failing because of network errors are retried on the next start
+        if lookup_fingerprint(db, &mut entry).await.is_err() {
+            continue
+        }
+        db.fingerprints.update_one(doc! { "track_id": &entry.track_id }, doc! {
+            "$set": {
+                "acoustid": &entry.acoustid,
+                "recording_ids": &entry.recording_ids,
+                "looked_up_at": entry.looked_up_at.map(|it| it as i64),
+            }
+        }).await?;
+        processed += 1;
+    }
+    Ok(processed)
+}
+
+/// Spawns a background task that fingerprints all tracks ingested before fingerprinting was introduced
+pub fn spawn_fingerprint_backfill(db: AstralDatabase) {
+    tokio::spawn(async move {
+        // the backfill continues where it stopped on the next start
+        let _ = backfill_fingerprints(&db).await;
+    });
+}
@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use crate::data::AstralDatabase;
+use crate::data::model::BsonId;
+use crate::Res;
+
+/// Spawns a background task that hashes files of tracks ingested before file hashes were stored
+pub fn spawn_hash_backfill(db: AstralDatabase) {
+    tokio::spawn(async move {
+        // tracks that failed to be hashed are retried on the next start
+        let _ = backfill_hashes(&db).await;
+    });
+}
+
+/// Hashes the stored file of a track, same as it was hashed on upload
+async fn hash_track_file(id: &BsonId) -> Res<String> {
+    let mut file = tokio::fs::File::open(PathBuf::from("astral_tracks").join(format!("{id}.bin"))).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex::encode(&hasher.finalize()[..]))
+}
+
+/// Stores file hashes of all tracks that do not have one yet, one by one. Returns amount of hashed tracks.
+pub async fn backfill_hashes(db: &AstralDatabase) -> Res<u64> {
+    let missing = db.tracks_metadata.find(doc! { "hash": null }).await?
+        .map_ok(|it| it.track_id)
+        .try_collect::<Vec<_>>().await?;
+
+    let mut processed = 0;
+    for track_id in missing {
+        // files can be missing if the library is broken, such tracks are only matched by fingerprint and title
+        let Ok(hash) = hash_track_file(&track_id).await else {
+            continue
+        };
+        db.tracks_metadata.update_one(doc! { "track_id": &track_id }, doc! { "$set": { "hash": &hash } }).await?;
+        processed += 1;
+    }
+    Ok(processed)
+}
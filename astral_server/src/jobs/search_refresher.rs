@@ -0,0 +1,41 @@
+use std::env;
+use std::time::Duration;
+use crate::data::AstralDatabase;
+use crate::search::engine::{rebuild_search_index, SearchIndex};
+
+/// Default amount of hours between search index rebuilds
+const DEFAULT_REFRESH_HOURS: u64 = 24;
+/// How often changes to the search index are committed
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Spawns a background task that rebuilds the search index every `ASTRAL_SEARCH_REFRESH_HOURS`
+/// (24 hours by default). The first rebuild happens right away, so an empty or outdated index
+/// is filled on startup.
+pub fn spawn_search_refresher(db: AstralDatabase, search: SearchIndex) {
+    let refresh_hours = env::var("ASTRAL_SEARCH_REFRESH_HOURS").ok()
+        .and_then(|it| it.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_REFRESH_HOURS);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(refresh_hours * 60 * 60));
+        loop {
+            interval.tick().await;
+            // a failed rebuild keeps the current index, which is still updated on every change
+            let _ = rebuild_search_index(&db, &search).await;
+        }
+    });
+}
+
+/// Spawns a background task that commits changes to the search index every second,
+/// so bursts of changes are committed together instead of one by one.
+pub fn spawn_search_committer(search: SearchIndex) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(COMMIT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let search = search.clone();
+            // failed commits keep the changes pending, so they are retried on the next tick
+            let _ = tokio::task::spawn_blocking(move || search.commit()).await;
+        }
+    });
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::time::Duration;
+use chrono::Utc;
+use futures_util::StreamExt;
+use mongodb::bson::doc;
+use crate::api::paths::upload::discard_undefined_track;
+use crate::data::AstralDatabase;
+use crate::data::model::UndefinedTrack;
+use crate::Res;
+
+/// How often pending uploads are checked for expiry
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Default amount of hours a pending upload is kept without metadata
+const DEFAULT_PENDING_TTL_HOURS: u64 = 72;
+
+/// How long a pending upload is kept without metadata, read from `ASTRAL_PENDING_TTL_HOURS` (72 hours by default)
+pub fn pending_ttl() -> Duration {
+    let ttl_hours = env::var("ASTRAL_PENDING_TTL_HOURS").ok()
+        .and_then(|it| it.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_PENDING_TTL_HOURS);
+    Duration::from_secs(ttl_hours * 60 * 60)
+}
+
+/// Spawns a background task that periodically discards pending uploads older than [pending_ttl]
+pub fn spawn_pending_sweeper(db: AstralDatabase) {
+    let ttl = pending_ttl();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            // failed uploads are retried on the next sweep
+            let _ = sweep_pending_uploads(&db, ttl).await;
+        }
+    });
+}
+
+/// Discards all pending uploads older than the provided ttl. Returns amount of discarded uploads.
+/// A failing upload does not stop the sweep, it is retried on the next one instead. Uploads whose metadata started being
+/// guessed since they were found have a refreshed upload date, and are kept.
+pub async fn sweep_pending_uploads(db: &AstralDatabase, ttl: Duration) -> Res<u64> {
+    let now = Utc::now().timestamp_millis();
+    // uploads from before the upload date was stored start expiring from now on
+    db.undefined_tracks.update_many(doc! { "uploaded_at": { "$exists": false } }, doc! { "$set": { "uploaded_at": now } }).await?;
+
+    let cutoff = now - ttl.as_millis() as i64;
+    let stale = db.undefined_tracks.find(doc! { "uploaded_at": { "$lt": cutoff } }).await?
+        .filter_map(|each| async { each.ok() })
+        .collect::<Vec<_>>().await;
+
+    let mut swept = 0;
+    for track in stale {
+        if matches!(discard_if_stale(db, &track, cutoff).await, Ok(true)) {
+            swept += 1;
+        }
+    }
+    Ok(swept)
+}
+
+/// Discards the upload only if it was not touched since the cutoff. Returns whether it was discarded.
+async fn discard_if_stale(db: &AstralDatabase, track: &UndefinedTrack, cutoff: i64) -> Res<bool> {
+    let filter = doc! { "track_id": &track.track_id, "uploaded_at": { "$lt": cutoff } };
+    if db.undefined_tracks.find_one_and_delete(filter).await?.is_none() {
+        return Ok(false)
+    }
+    discard_undefined_track(db, track).await?;
+    Ok(true)
+}
@@ -0,0 +1,30 @@
+use futures_util::StreamExt;
+use mongodb::bson::Document;
+use crate::data::AstralDatabase;
+use crate::data::model::BsonId;
+use crate::metadata::writer::write_track_tags;
+use crate::Res;
+
+/// Writes database metadata into the file tags of all tracks matching the filter. Returns amount of successfully tagged tracks.
+pub async fn write_tags_matching(db: &AstralDatabase, filter: Document) -> Res<u64> {
+    let track_ids = db.tracks_metadata.find(filter).await?
+        .filter_map(|each| async { each.ok() })
+        .map(|each| each.track_id)
+        .collect::<Vec<BsonId>>().await;
+
+    let mut processed = 0;
+    for track_id in track_ids {
+        // a track that can not be tagged does not stop the others, its tags are written again on the next change
+        if write_track_tags(db, track_id).await.is_ok() {
+            processed += 1;
+        }
+    }
+    Ok(processed)
+}
+
+/// Spawns a background task that writes database metadata into the file tags of all tracks matching the filter
+pub fn spawn_tag_writer(db: AstralDatabase, filter: Document) {
+    tokio::spawn(async move {
+        let _ = write_tags_matching(&db, filter).await;
+    });
+}
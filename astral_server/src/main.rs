@@ -1,15 +1,107 @@
+use std::env;
 use crate::api::start_axum;
+use crate::data::AstralDatabase;
+use crate::data::backup::{export_library, import_library};
+use crate::data::integrity::check_integrity;
+use crate::data::migrations::{latest_version, pending_migrations, run_migrations, schema_state};
+use crate::search::engine::{rebuild_search_index, SearchIndex};
 
 mod api;
 pub mod data;
+pub mod jobs;
 pub mod err;
 pub mod metadata;
+pub mod search;
 
 pub use err::Res;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    start_axum().await?;
+    match env::args().nth(1).as_deref() {
+        // the server has to be stopped, as it holds the search index lock
+        Some("rebuild-search-index") => {
+            let db = AstralDatabase::connect().await?;
+            let indexed = rebuild_search_index(&db, &SearchIndex::open()?).await?;
+            println!("Rebuilt search index with {indexed} entries");
+        }
+        // pass `--repair` to also fix found issues, and `--remove-missing-tracks` to also remove tracks whose audio files are missing.
+        // The server has to be stopped, same as for rebuilding the search index
+        Some("check-integrity") => {
+            let repair = env::args().skip(2).any(|it| it == "--repair");
+            let remove_missing_tracks = env::args().skip(2).any(|it| it == "--remove-missing-tracks");
+            let db = AstralDatabase::connect().await?;
+            let search_index = SearchIndex::open()?;
+            let issues = check_integrity(&db, &search_index, repair, remove_missing_tracks).await?;
+            search_index.commit()?;
+            for issue in &issues {
+                println!("{issue}");
+            }
+            match (issues.len(), repair) {
+                (0, _) => println!("No integrity issues found"),
+                (found, true) => println!("Repaired {found} integrity issues"),
+                (found, false) => println!("Found {found} integrity issues, run with `--repair` to fix them"),
+            }
+        }
+        Some("migration-status") => {
+            let db = AstralDatabase::open().await?;
+            let state = schema_state(&db).await?;
+            println!("Schema version {} of {}", state.version, latest_version());
+            if !db.supports_transactions() {
+                println!("MongoDB is running as a standalone server, metadata writes are not transactional. Run it as a replica set to enable transactions.");
+            }
+            for applied in &state.applied {
+                println!("  applied {} `{}`, changed {} documents", applied.version, applied.name, applied.affected);
+            }
+            for pending in pending_migrations(&db).await? {
+                println!("  pending {} `{}`", pending.version, pending.name);
+            }
+        }
+        // pass `--dry-run` to only count documents that would be changed
+        Some("migrate") => {
+            let dry_run = env::args().skip(2).any(|it| it == "--dry-run");
+            let db = AstralDatabase::open().await?;
+            let outcomes = run_migrations(&db, dry_run).await?;
+            for outcome in &outcomes {
+                if dry_run {
+                    println!("Migration {} `{}` would change {} documents", outcome.version, outcome.name, outcome.affected);
+                } else {
+                    println!("Applied migration {} `{}`, changed {} documents", outcome.version, outcome.name, outcome.affected);
+                }
+            }
+            if outcomes.is_empty() {
+                println!("Database is up to date");
+            }
+        }
+        // writes collections, original artwork and audio files into a portable archive
+        Some("export") => {
+            let Some(path) = env::args().nth(2) else {
+                anyhow::bail!("Usage: export <archive path>")
+            };
+            let db = AstralDatabase::connect().await?;
+            let manifest = export_library(&db, path.as_ref()).await?;
+            let documents: u64 = manifest.collections.values().sum();
+            println!("Exported {documents} documents and {} files to {path}", manifest.files.len());
+            for track_id in &manifest.missing_audio {
+                println!("Skipped missing audio file of track {track_id}");
+            }
+        }
+        // restores an exported archive into an empty instance, a failed import leaves it empty again.
+        // The server has to be stopped, as the search index is rebuilt
+        Some("import") => {
+            let Some(path) = env::args().nth(2) else {
+                anyhow::bail!("Usage: import <archive path>")
+            };
+            // migrations are applied by the import once the archived data is restored
+            let db = AstralDatabase::open().await?;
+            let manifest = import_library(&db, path.as_ref()).await?;
+            let documents: u64 = manifest.collections.values().sum();
+            println!("Imported {documents} documents and {} files from {path}", manifest.files.len());
+            let indexed = rebuild_search_index(&db, &SearchIndex::open()?).await?;
+            println!("Rebuilt search index with {indexed} entries");
+        }
+        Some(other) => anyhow::bail!("Unknown command: {other}"),
+        None => start_axum().await?,
+    }
 
     Ok(())
 }
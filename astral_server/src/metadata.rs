@@ -1,69 +1,157 @@
 pub mod binary;
+pub mod cover;
 pub mod musix;
 pub mod merged;
+pub mod provider;
+pub mod musicbrainz;
+pub mod preview;
+pub mod writer;
+pub mod genres;
+pub mod artwork;
+pub mod palette;
+pub mod merge;
+pub mod duplicates;
+pub mod fingerprint;
+pub mod acoustid;
 
 use audiotags::{MimeType, Picture};
-use futures_util::{AsyncWriteExt, StreamExt};
-use mongodb::bson::doc;
-use mongodb::options::GridFsUploadOptions;
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson, Document};
 use reqwest::Url;
-use crate::data::AstralDatabase;
-use crate::data::model::{AlbumMetadata, ArtistMetadata, BsonId, LyricsStatus, TrackFormat, TrackLyrics, TrackMetadata};
+use crate::data::{AstralDatabase, Transaction};
+use crate::metadata::cover::download_cover;
+use crate::metadata::artwork::{delete_artwork, ArtworkKind};
+use crate::metadata::genres::{refresh_genres, top_genre_limit};
+use crate::metadata::palette::refresh_album_palette;
+use crate::metadata::writer::guess_image_mime;
+use crate::search::engine::{reindex_entries, SearchIndex};
+use crate::data::model::{AlbumDisc, AlbumMetadata, AlbumType, ArtistMetadata, BsonId, LyricsStatus, TrackFormat, TrackLyrics, TrackMetadata};
 use crate::Res;
 
-/// Classifies extracted metadata and inserts it into the Database. Will also download/upload album cover art and lyrics if needed.
+/// Classifies extracted metadata and inserts it into the Database, removing the pending upload with the same UUID.
+/// All metadata is written in a single transaction. Will also download/upload album cover art and lyrics if needed.
+/// Cover art is stored after the metadata is committed, so failing to store it fails the call with the track already inserted.
 pub async fn classify_insert_metadata(
     db: &AstralDatabase,
+    search: &SearchIndex,
     metadata: ExtractedTrackMetadata,
     new_uid: BsonId,
+    hash: String,
 ) -> Res<BsonId> {
+    let inserted = db.with_transaction((db, &metadata, &hash), move |session, (db, metadata, hash)| {
+        Box::pin(insert_metadata(db, metadata, new_uid, hash, session))
+    }).await?;
+    let (track, album) = match inserted {
+        InsertedMetadata::Existing(track_id) => {
+            // track already exists, so the uploaded file is not needed anymore
+            remove_track_files(&new_uid).await?;
+            return Ok(track_id)
+        }
+        InsertedMetadata::Track(inserted) => *inserted,
+    };
+
+    refresh_genres(db, &track.artists, &track.albums).await?;
+    reindex_entries(db, search, &[track.track_id], &track.albums, &track.artists).await?;
+
+    if let Some(picture) = metadata.cover_art {
+        store_cover_art(db, &album, picture).await?;
+    }
+    Ok(track.track_id)
+}
+
+/// Outcome of inserting metadata of an upload
+enum InsertedMetadata {
+    /// Same track is already in the library
+    Existing(BsonId),
+    /// A new track was inserted into the album
+    Track(Box<(TrackMetadata, AlbumMetadata)>),
+}
+
+/// Writes metadata of a new track along with its album, artists and lyrics as part of the transaction
+async fn insert_metadata(
+    db: &AstralDatabase,
+    metadata: &ExtractedTrackMetadata,
+    new_uid: BsonId,
+    hash: &str,
+    session: &mut Transaction,
+) -> Res<InsertedMetadata> {
+    db.undefined_tracks.delete_one_with_session(doc! { "track_id": &new_uid }, session).await?;
+
     // first check if track even exists
-    if let Some(track) = db.tracks_metadata.find_one(doc! { "name": &metadata.name, "length": metadata.duration as u32 }, None).await? {
-        // track already exists, we shouldn't do anything
-        return Ok(track.track_id)
+    if let Some(track) = db.tracks_metadata.find_one_with_session(doc! { "name": &metadata.name, "length": metadata.duration as u32 }, session).await? {
+        return Ok(InsertedMetadata::Existing(track.track_id))
     }
 
+    let now = Utc::now().timestamp_millis() as u64;
+    // tracks without a disc number are treated as being on the first disc
+    let disc = AlbumDisc {
+        number: metadata.disc_number.max(1),
+        subtitle: metadata.disc_subtitle.clone().filter(|it| !it.is_empty()),
+        track_total: Some(metadata.track_total).filter(|it| *it != 0),
+    };
     let mut new_track_metadata = TrackMetadata {
         track_id: new_uid,
-        name: metadata.name,
+        name: metadata.name.clone(),
         length: metadata.duration as u32,
         artists: vec![],
         albums: vec![],
         is_explicit: metadata.is_explicit,
         format: metadata.format,
         number: metadata.number,
-        disc_number: metadata.disc_number
+        disc_number: metadata.disc_number,
+        genres: metadata.genres.clone(),
+        added_at: now,
+        play_count: 0,
+        hash: Some(hash.to_owned()),
     };
 
-    // album
-    let (mut album, should_insert_album) = match db.albums_metadata.find_one(doc! { "name": &metadata.album_name }, None).await? {
+    // album, different editions of an album are stored as separate albums
+    let (mut album, should_insert_album) = match db.albums_metadata.find_one_with_session(album_edition(&metadata.album_name, metadata.edition.as_deref()), session).await? {
         Some(mut album) => {
             album.tracks.push(new_track_metadata.track_id.clone());
 
-            db.albums_metadata.update_one(doc! { "album_id": &album.album_id },  doc! { "$set": {"tracks": &album.tracks} }, None).await?;
+            let mut update = doc! { "tracks": &album.tracks };
+            if let Some(album_type) = metadata.album_type.filter(|it| *it != album.album_type) {
+                album.album_type = album_type;
+                update.insert("album_type", to_bson(&album_type).map_err(anyhow::Error::from)?);
+            }
+            if record_disc(&mut album, &disc, metadata.disc_total) {
+                update.insert("discs", to_bson(&album.discs).map_err(anyhow::Error::from)?);
+                update.insert("disc_total", album.disc_total.map(|it| it as i32));
+            }
+            db.albums_metadata.update_one_with_session(doc! { "album_id": &album.album_id },  doc! { "$set": update }, session).await?;
             new_track_metadata.albums.push(album.album_id.clone());
             (album, false)
         },
         None => {
-            let new_album = AlbumMetadata {
+            let mut new_album = AlbumMetadata {
                 album_id: BsonId::new(),
-                name: metadata.album_name,
+                name: metadata.album_name.clone(),
                 artists: vec![],
                 tracks: vec![new_track_metadata.track_id],
                 release_date: metadata.release_date,
-                genres: vec![],
+                genres: new_track_metadata.genres.iter().take(top_genre_limit()).cloned().collect(),
+                manual_genres: false,
+                added_at: now,
+                palette: None,
+                discs: vec![],
+                disc_total: None,
+                album_type: metadata.album_type.unwrap_or_default(),
+                edition: metadata.edition.clone(),
+                aliases: vec![],
             };
+            record_disc(&mut new_album, &disc, metadata.disc_total);
             new_track_metadata.albums.push(new_album.album_id.clone());
 
             (new_album, true)
         }
     };
 
-    let mut processed_artists: Vec<String> = vec![];
+    let mut processed_artists: Vec<&String> = vec![];
 
     // artists that produced this album as a whole
-    for artist in metadata.album_artists {
-        match db.artists_metadata.find_one(doc! { "name": &artist }, None).await? {
+    for artist in &metadata.album_artists {
+        match db.artists_metadata.find_one_with_session(name_or_alias(artist), session).await? {
             Some(mut artist) => {
                 album.artists.push(artist.artist_id);
                 if !artist.albums.contains(&album.album_id) {
@@ -72,7 +160,7 @@ pub async fn classify_insert_metadata(
                 artist.tracks.push(new_track_metadata.track_id.clone());
                 new_track_metadata.artists.push(artist.artist_id.clone());
 
-                db.artists_metadata.update_one(doc! { "artist_id": &artist.artist_id }, doc! { "$set": { "albums": &artist.albums, "tracks": &artist.tracks } }, None).await?;
+                db.artists_metadata.update_one_with_session(doc! { "artist_id": &artist.artist_id }, doc! { "$set": { "albums": &artist.albums, "tracks": &artist.tracks } }, session).await?;
             }
             None => {
                 let new_artist = ArtistMetadata {
@@ -82,27 +170,29 @@ pub async fn classify_insert_metadata(
                     tracks: vec![new_track_metadata.track_id.clone()],
                     genres: Default::default(),
                     about: "".to_string(),
+                    added_at: now,
+                    aliases: vec![],
                 };
                 new_track_metadata.artists.push(new_artist.artist_id.clone());
                 album.artists.push(new_artist.artist_id.clone());
-                db.artists_metadata.insert_one(&new_artist, None).await?;
+                db.artists_metadata.insert_one_with_session(&new_artist, session).await?;
             }
         };
         processed_artists.push(artist);
     }
 
     // artists that produced this song specifically
-    for artist in metadata.artists {
+    for artist in &metadata.artists {
         if processed_artists.contains(&artist) {
             // already fully processed artist as an album artist
             continue
         }
-        match db.artists_metadata.find_one(doc! { "name": &artist }, None).await? {
+        match db.artists_metadata.find_one_with_session(name_or_alias(artist), session).await? {
             Some(mut artist) => {
                 artist.tracks.push(new_track_metadata.track_id.clone());
                 new_track_metadata.artists.push(artist.artist_id.clone());
 
-                db.artists_metadata.update_one(doc! { "artist_id": &artist.artist_id }, doc! { "$set": { "tracks": &artist.tracks } }, None).await?;
+                db.artists_metadata.update_one_with_session(doc! { "artist_id": &artist.artist_id }, doc! { "$set": { "tracks": &artist.tracks } }, session).await?;
             }
             None => {
                 let new_artist = ArtistMetadata {
@@ -112,59 +202,135 @@ pub async fn classify_insert_metadata(
                     tracks: vec![new_track_metadata.track_id.clone()],
                     genres: Default::default(),
                     about: "".to_string(),
+                    added_at: now,
+                    aliases: vec![],
                 };
                 new_track_metadata.artists.push(new_artist.artist_id.clone());
-                db.artists_metadata.insert_one(&new_artist, None).await?;
+                db.artists_metadata.insert_one_with_session(&new_artist, session).await?;
             }
         };
     }
 
     if should_insert_album {
-        db.albums_metadata.insert_one(&album, None).await?;
+        db.albums_metadata.insert_one_with_session(&album, session).await?;
     }
 
-    db.tracks_metadata.insert_one(&new_track_metadata, None).await?;
+    db.tracks_metadata.insert_one_with_session(&new_track_metadata, session).await?;
 
     // lyrics
-    if let Some(lyrics) = metadata.lyrics {
-        db.lyrics.insert_one(TrackLyrics {
+    if let Some(lyrics) = &metadata.lyrics {
+        db.lyrics.insert_one_with_session(&TrackLyrics {
             track_id: new_track_metadata.track_id.clone(),
-            status: lyrics,
-        }, None).await?;
+            status: lyrics.clone(),
+        }, session).await?;
     }
+    Ok(InsertedMetadata::Track(Box::new((new_track_metadata, album))))
+}
 
-    // cover art
-    if let Some(picture) = metadata.cover_art {
-        let found = db.gridfs_album_arts.find(doc! { "filename": &album.album_id.to_string() }, None).await?;
-        if found.count().await > 0 {
-            return Ok(new_track_metadata.track_id)
+/// Stores cover art of a newly inserted album, unless it already has one
+async fn store_cover_art(db: &AstralDatabase, album: &AlbumMetadata, picture: AlbumArt) -> Res<()> {
+    if db.album_arts.find_by_name(&album.album_id.to_string()).await?.is_some() {
+        return Ok(())
+    }
+
+    match picture {
+        AlbumArt::Bytes(picture) => {
+            let mt: &str = picture.mime.into();
+            db.album_arts.upload(&album.album_id.to_string(), Some(doc! { "mime_type": mt }), &picture.data).await?;
+            refresh_album_palette(db, &album.album_id, picture.data).await?;
+        }
+        AlbumArt::Url(uri, mt) => {
+            let cover = download_cover(uri).await?;
+            // covers of unknown type are sniffed from their first bytes
+            let mt: &str = mt.map_or_else(|| guess_image_mime(&cover), Into::into);
+            db.album_arts.upload(&album.album_id.to_string(), Some(doc! { "mime_type": mt }), &cover).await?;
+            refresh_album_palette(db, &album.album_id, cover).await?;
         }
+    }
+    Ok(())
+}
 
-        match picture {
-            AlbumArt::Bytes(picture) => {
-                let mt: &str = picture.mime.into();
-                let mut upload_stream = db.gridfs_album_arts
-                    .open_upload_stream(album.album_id.to_string(), GridFsUploadOptions::builder().metadata(doc! { "mime_type": mt }).build());
-                upload_stream.write_all(&picture.data).await?;
-                upload_stream.flush().await?;
-                upload_stream.close().await?;
-            }
-            AlbumArt::Url(uri, mt) => {
-                let mt: String = mt.into();
-
-                let mut d_stream = reqwest::get(uri).await?.bytes_stream();
-                let mut u_stream = db.gridfs_album_arts
-                    .open_upload_stream(album.album_id.to_string(), GridFsUploadOptions::builder().metadata(doc! { "mime_type": mt }).build());
-                while let Some(Ok(mut chunk)) = d_stream.next().await {
-                    u_stream.write(&mut chunk).await?;
-                }
-                u_stream.flush().await?;
-                u_stream.close().await?;
-            }
+/// Completely removes a track along with its files, lyrics and artwork, and all references to it.
+/// References are removed in a single transaction, and files only after it was committed. Returns the removed track, if it existed.
+pub async fn remove_track(db: &AstralDatabase, search: &SearchIndex, id: &BsonId) -> Res<Option<TrackMetadata>> {
+    let removed = db.with_transaction((db, *id), |session, (db, id)| Box::pin(remove_track_entries(db, *id, session))).await?;
+    let Some(track) = removed else {
+        return Ok(None)
+    };
+
+    delete_artwork(db, ArtworkKind::TrackArt, id).await?;
+    remove_track_files(id).await?;
+    refresh_genres(db, &track.artists, &track.albums).await?;
+    reindex_entries(db, search, &[*id], &track.albums, &track.artists).await?;
+    Ok(Some(track))
+}
+
+/// Removes metadata of a track and all references to it as part of the transaction. Returns the removed track, if it existed.
+async fn remove_track_entries(db: &AstralDatabase, id: BsonId, session: &mut Transaction) -> Res<Option<TrackMetadata>> {
+    let Some(track) = db.tracks_metadata.find_one_and_delete_with_session(doc! { "track_id": id }, session).await? else {
+        return Ok(None)
+    };
+    db.lyrics.delete_one_with_session(doc! { "track_id": id }, session).await?;
+    db.fingerprints.delete_one_with_session(doc! { "track_id": id }, session).await?;
+    // references are looked up on both sides, so stale ones are cleaned up as well
+    db.artists_metadata.update_many_with_session(doc! { "tracks": id }, doc! { "$pull": { "tracks": id } }, session).await?;
+    db.albums_metadata.update_many_with_session(doc! { "tracks": id }, doc! { "$pull": { "tracks": id } }, session).await?;
+    db.accounts.update_many_with_session(doc! { "loved_tracks": id }, doc! { "$pull": { "loved_tracks": id } }, session).await?;
+    Ok(Some(track))
+}
+
+/// Removes the stored audio file of a track along with its transcoded copies
+pub async fn remove_track_files(id: &BsonId) -> Res<()> {
+    let files_dir = std::path::Path::new("astral_tracks");
+    let filename = format!("{id}.bin");
+    for path in [files_dir.join(&filename), files_dir.join("transcoded_low").join(&filename), files_dir.join("transcoded_medium").join(&filename)] {
+        if path.exists() {
+            tokio::fs::remove_file(&path).await?;
         }
     }
+    Ok(())
+}
 
-    Ok(new_track_metadata.track_id)
+/// Filter matching an artist or an album by its name or one of its aliases
+pub fn name_or_alias(name: &str) -> Document {
+    doc! { "$or": [{ "name": name }, { "aliases": name }] }
+}
+
+/// Filter matching the edition of an album by its name or one of its aliases. Different editions are separate albums.
+pub fn album_edition(name: &str, edition: Option<&str>) -> Document {
+    let mut filter = name_or_alias(name);
+    filter.insert("edition", edition);
+    filter
+}
+
+/// Records disc subtitle and total counts of a track into its album. Returns whether anything changed.
+fn record_disc(album: &mut AlbumMetadata, disc: &AlbumDisc, disc_total: u16) -> bool {
+    let mut changed = false;
+    if disc_total != 0 && album.disc_total != Some(disc_total) {
+        album.disc_total = Some(disc_total);
+        changed = true;
+    }
+    if disc.subtitle.is_none() && disc.track_total.is_none() {
+        return changed
+    }
+
+    let existing = match album.discs.iter().position(|it| it.number == disc.number) {
+        Some(idx) => &mut album.discs[idx],
+        None => {
+            album.discs.push(AlbumDisc { number: disc.number, subtitle: None, track_total: None });
+            album.discs.sort_by_key(|it| it.number);
+            album.discs.iter_mut().find(|it| it.number == disc.number).unwrap()
+        }
+    };
+    if disc.subtitle.is_some() && existing.subtitle != disc.subtitle {
+        existing.subtitle = disc.subtitle.clone();
+        changed = true;
+    }
+    if disc.track_total.is_some() && existing.track_total != disc.track_total {
+        existing.track_total = disc.track_total;
+        changed = true;
+    }
+    changed
 }
 
 /// Extracted metadata for a single track
@@ -188,12 +354,24 @@ pub struct ExtractedTrackMetadata {
     pub number: u16,
     /// Number of the disc this track appears on
     pub disc_number: u16,
+    /// Total amount of tracks on the disc, `0` if unknown
+    pub track_total: u16,
+    /// Total amount of discs in the album, `0` if unknown
+    pub disc_total: u16,
+    /// Subtitle of the disc this track appears on
+    pub disc_subtitle: Option<String>,
+    /// Type of the album release, if known
+    pub album_type: Option<AlbumType>,
+    /// Edition or version of the album release
+    pub edition: Option<String>,
     /// Unix timestamp of the album release date
     pub release_date: u64,
     /// Whether this track contains explicit lyrics
     pub is_explicit: bool,
     /// Lyrics of this track.
-    pub lyrics: Option<LyricsStatus>
+    pub lyrics: Option<LyricsStatus>,
+    /// Normalised genres of this track
+    pub genres: Vec<String>,
 }
 
 /// Cover art of some album
@@ -201,8 +379,8 @@ pub struct ExtractedTrackMetadata {
 pub enum AlbumArt {
     /// Bytes extracted from the track metadata
     Bytes(PictureOwned),
-    /// URL pointing to where this album's art is stored
-    Url(Url, MimeType)
+    /// URL pointing to where this album's art is stored, along with its mime type if it is known before downloading
+    Url(Url, Option<MimeType>)
 }
 
 /// Owned bytes of album cover art
@@ -0,0 +1,137 @@
+use std::env;
+use std::time::Duration;
+use reqwest::header::{HeaderValue, USER_AGENT};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use crate::err::AstralError;
+use crate::Res;
+
+/// Minimal score of an AcoustID result for it to be considered the same recording
+const MIN_LOOKUP_SCORE: f64 = 0.8;
+/// AcoustID allows at most three requests per second
+const LOOKUP_INTERVAL: Duration = Duration::from_millis(334);
+/// Chromaprint algorithm the fingerprints are computed with, `TEST2` is the one used by AcoustID
+const ALGORITHM: u8 = 1;
+/// Largest difference between set bits of a subfingerprint that is stored in the three bit array
+const MAX_NORMAL_VALUE: u8 = 7;
+/// Alphabet of the url safe base64 used by Chromaprint, without padding
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// When the last lookup request was sent, shared by all lookups to keep under the rate limit
+static LAST_LOOKUP: Mutex<Option<Instant>> = Mutex::const_new(None);
+
+/// Recording found in AcoustID for a fingerprint
+#[derive(Debug, Clone)]
+pub struct AcoustIdMatch {
+    /// AcoustID of the recording
+    pub acoustid: String,
+    /// MusicBrainz IDs of the recordings linked to the AcoustID
+    pub recording_ids: Vec<String>,
+}
+
+/// AcoustID application key read from `ASTRAL_ACOUSTID_KEY`. Lookups are disabled when it is not set.
+pub fn acoustid_key() -> Option<String> {
+    env::var("ASTRAL_ACOUSTID_KEY").ok().filter(|it| !it.is_empty())
+}
+
+/// Packs values into a little endian bit stream, using the provided amount of bits for each
+fn pack_bits(out: &mut Vec<u8>, values: &[u8], width: usize) {
+    let start = out.len();
+    out.resize(start + (values.len() * width).div_ceil(8), 0);
+    for (idx, value) in values.iter().enumerate() {
+        for bit in 0..width {
+            if value >> bit & 1 == 1 {
+                let pos = idx * width + bit;
+                out[start + pos / 8] |= 1 << (pos % 8);
+            }
+        }
+    }
+}
+
+/// Encodes bytes with the base64 variant used by Chromaprint
+fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |acc, (idx, byte)| acc | (*byte as u32) << (16 - idx * 8));
+        for idx in 0..=chunk.len() {
+            out.push(BASE64_ALPHABET[(group >> (18 - idx * 6) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Compresses the fingerprint the same way `chromaprint_encode_fingerprint` does, which is the format AcoustID expects
+pub fn encode_fingerprint(fingerprint: &[u32]) -> String {
+    let mut normal = vec![];
+    let mut exceptional = vec![];
+    let mut previous = 0;
+    for item in fingerprint {
+        // positions of set bits in the difference to the previous item are stored as gaps between them
+        let (mut diff, mut bit, mut last_bit) = (item ^ previous, 1u8, 0u8);
+        while diff != 0 {
+            if diff & 1 == 1 {
+                let value = bit - last_bit;
+                if value >= MAX_NORMAL_VALUE {
+                    normal.push(MAX_NORMAL_VALUE);
+                    exceptional.push(value - MAX_NORMAL_VALUE);
+                } else {
+                    normal.push(value);
+                }
+                last_bit = bit;
+            }
+            diff >>= 1;
+            bit += 1;
+        }
+        normal.push(0);
+        previous = *item;
+    }
+
+    let size = fingerprint.len() as u32;
+    let mut out = vec![ALGORITHM, (size >> 16) as u8, (size >> 8) as u8, size as u8];
+    pack_bits(&mut out, &normal, 3);
+    pack_bits(&mut out, &exceptional, 5);
+    encode_base64(&out)
+}
+
+/// Waits until a new request can be sent without exceeding the AcoustID rate limit
+async fn wait_for_rate_limit() {
+    let mut last = LAST_LOOKUP.lock().await;
+    if let Some(last) = *last {
+        tokio::time::sleep_until(last + LOOKUP_INTERVAL).await;
+    }
+    *last = Some(Instant::now());
+}
+
+/// Looks the fingerprint up in AcoustID. Returns the best matching recording, if its score is high enough.
+pub async fn acoustid_lookup(key: &str, fingerprint: &[u32], duration: u32) -> Res<Option<AcoustIdMatch>> {
+    const BASE_URL: &str = "https://api.acoustid.org/v2/lookup";
+
+    wait_for_rate_limit().await;
+    let client = reqwest::Client::new();
+    let json = client.post(BASE_URL)
+        .header(USER_AGENT, HeaderValue::from_static(concat!("AstralPlayer/", env!("CARGO_PKG_VERSION"), " ( https://github.com/Maxuss/AstralPlayer )")))
+        .form(&[
+            ("client", key),
+            ("format", "json"),
+            ("meta", "recordingids"),
+            ("duration", &duration.to_string()),
+            ("fingerprint", &encode_fingerprint(fingerprint)),
+        ])
+        .send().await?
+        .json::<Value>().await?;
+
+    if json["status"].as_str() != Some("ok") {
+        let message = json["error"]["message"].as_str().unwrap_or("unknown error");
+        return Err(AstralError::Unknown(anyhow::anyhow!("AcoustID lookup failed: {message}")))
+    }
+    let best = json["results"].as_array()
+        .and_then(|results| results.iter().max_by(|a, b| a["score"].as_f64().unwrap_or(0.0).total_cmp(&b["score"].as_f64().unwrap_or(0.0))))
+        .filter(|it| it["score"].as_f64().unwrap_or(0.0) >= MIN_LOOKUP_SCORE);
+    Ok(best.and_then(|result| Some(AcoustIdMatch {
+        acoustid: result["id"].as_str()?.to_owned(),
+        recording_ids: result["recordings"].as_array()
+            .map(|recordings| recordings.iter().filter_map(|it| it["id"].as_str()).map(String::from).collect())
+            .unwrap_or_default(),
+    })))
+}
@@ -0,0 +1,251 @@
+use std::io::Cursor;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::error::ImageError;
+use image::{DynamicImage, ImageReader, Limits};
+use mongodb::bson::{doc, Bson};
+use serde::Deserialize;
+use utoipa::ToSchema;
+use crate::data::AstralDatabase;
+use crate::data::bucket::Bucket;
+use crate::data::model::BsonId;
+use crate::err::AstralError;
+use crate::metadata::writer::guess_image_mime;
+use crate::Res;
+
+/// Sizes artwork variants are generated in. Requested sizes are rounded up to the closest one,
+/// so only a handful of variants are cached per artwork.
+pub const ARTWORK_SIZES: [u32; 5] = [64, 128, 256, 512, 1024];
+/// Quality of generated JPEG variants
+const JPEG_QUALITY: u8 = 85;
+/// Largest width and height of artwork that is decoded to generate variants
+const MAX_ARTWORK_DIMENSION: u32 = 8192;
+/// Most memory the decoder may allocate for a single artwork
+const MAX_ARTWORK_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// Kind of stored artwork, along with what it belongs to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArtworkKind {
+    /// Cover art of an album
+    AlbumCover,
+    /// Photo of an artist
+    ArtistPhoto,
+    /// Wide banner shown on top of the artist page
+    ArtistBanner,
+    /// Artwork of a single track, overriding the album cover
+    TrackArt,
+}
+
+impl ArtworkKind {
+    /// Bucket the artwork of this kind is stored in
+    fn bucket(self, db: &AstralDatabase) -> &Bucket {
+        match self {
+            ArtworkKind::AlbumCover => &db.album_arts,
+            _ => &db.artwork,
+        }
+    }
+
+    /// Name of the original artwork file. Album covers are keyed by the album UUID alone.
+    fn filename(self, owner: &BsonId) -> String {
+        match self {
+            ArtworkKind::AlbumCover => owner.to_string(),
+            ArtworkKind::ArtistPhoto => format!("artist_photo/{owner}"),
+            ArtworkKind::ArtistBanner => format!("artist_banner/{owner}"),
+            ArtworkKind::TrackArt => format!("track_art/{owner}"),
+        }
+    }
+
+    /// Finds kind and owner of the stored artwork file or its resized variant from the file name
+    pub fn parse_filename(filename: &str) -> Option<(ArtworkKind, BsonId)> {
+        let original = filename.split_once('@').map_or(filename, |(original, _)| original);
+        let (kind, owner) = match original.split_once('/') {
+            None => (ArtworkKind::AlbumCover, original),
+            Some(("artist_photo", owner)) => (ArtworkKind::ArtistPhoto, owner),
+            Some(("artist_banner", owner)) => (ArtworkKind::ArtistBanner, owner),
+            Some(("track_art", owner)) => (ArtworkKind::TrackArt, owner),
+            Some(_) => return None,
+        };
+        let owner = uuid::Uuid::parse_str(owner).ok()?;
+        Some((kind, BsonId::from_uuid_1(owner)))
+    }
+}
+
+/// Image format of a resized artwork variant
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CoverFormat {
+    /// Lossy JPEG, supported by every client
+    #[default]
+    Jpeg,
+    /// Lossless WebP
+    Webp,
+}
+
+impl CoverFormat {
+    fn mime_type(self) -> &'static str {
+        match self {
+            CoverFormat::Jpeg => "image/jpeg",
+            CoverFormat::Webp => "image/webp",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            CoverFormat::Jpeg => "jpg",
+            CoverFormat::Webp => "webp",
+        }
+    }
+}
+
+/// A stored artwork file, ready to be served
+#[derive(Debug, Clone)]
+pub struct ArtworkFile {
+    /// Kind of the artwork, used to find its bucket
+    pub kind: ArtworkKind,
+    /// ID of the file in its bucket
+    pub file_id: Bson,
+    /// Mime type of the image
+    pub mime_type: String,
+    /// Contents of the file, if they were just generated and are already in memory
+    pub data: Option<Vec<u8>>,
+}
+
+impl ArtworkFile {
+    /// Entity tag of this file. Changes whenever the artwork or its variant is replaced.
+    pub fn etag(&self) -> String {
+        match &self.file_id {
+            Bson::ObjectId(id) => format!("\"{}\"", id.to_hex()),
+            other => format!("\"{other}\""),
+        }
+    }
+
+    /// Bucket this file is stored in
+    pub fn bucket<'a>(&self, db: &'a AstralDatabase) -> &'a Bucket {
+        self.kind.bucket(db)
+    }
+}
+
+/// Finds artwork of the kind belonging to the track, album or artist. If the size is provided, a variant
+/// fitting into a square of at least this size is returned instead, generating and caching it in the bucket
+/// if it does not exist yet. Artwork is never upscaled.
+pub async fn find_artwork(db: &AstralDatabase, kind: ArtworkKind, owner: &BsonId, size: Option<u32>, format: CoverFormat) -> Res<Option<ArtworkFile>> {
+    let bucket = kind.bucket(db);
+    let filename = kind.filename(owner);
+    let Some(original) = bucket.find_by_name(&filename).await? else {
+        return Ok(None)
+    };
+
+    let Some(size) = size else {
+        let mime_type = match original.metadata.as_ref().and_then(|it| it.get_str("mime_type").ok()) {
+            Some(mime_type) => mime_type.to_owned(),
+            // artwork uploaded without a mime type is sniffed from its first bytes
+            None => guess_image_mime(&bucket.read(&original.id).await?).to_owned(),
+        };
+        return Ok(Some(ArtworkFile { kind, file_id: original.id, mime_type, data: None }))
+    };
+
+    let size = ARTWORK_SIZES.iter().copied().find(|it| *it >= size).unwrap_or(ARTWORK_SIZES[ARTWORK_SIZES.len() - 1]);
+    let variant_name = format!("{filename}@{size}.{}", format.extension());
+    for variant in bucket.find(doc! { "filename": &variant_name }).await? {
+        // variants of replaced artwork are stale and regenerated
+        if variant.metadata.as_ref().and_then(|it| it.get("source")) == Some(&original.id) {
+            return Ok(Some(ArtworkFile { kind, file_id: variant.id, mime_type: format.mime_type().to_owned(), data: None }))
+        }
+        bucket.delete(&variant.id).await?;
+    }
+
+    let data = bucket.read(&original.id).await?;
+    let resized = tokio::task::spawn_blocking(move || resize_artwork(&data, size, format)).await
+        .map_err(|err| AstralError::Unknown(err.into()))??;
+
+    let file_id = bucket.upload(&variant_name, Some(doc! {
+        "mime_type": format.mime_type(),
+        "variant_of": &filename,
+        "source": &original.id,
+        "size": size,
+    }), &resized).await?;
+
+    Ok(Some(ArtworkFile { kind, file_id, mime_type: format.mime_type().to_owned(), data: Some(resized) }))
+}
+
+/// Reads the original artwork along with its mime type
+pub async fn read_artwork(db: &AstralDatabase, kind: ArtworkKind, owner: &BsonId) -> Res<Option<(Vec<u8>, String)>> {
+    let bucket = kind.bucket(db);
+    let Some(found) = bucket.find_by_name(&kind.filename(owner)).await? else {
+        return Ok(None)
+    };
+    let data = bucket.read(&found.id).await?;
+
+    let mime = found.metadata
+        .and_then(|it| it.get_str("mime_type").ok().map(String::from))
+        .unwrap_or_else(|| guess_image_mime(&data).to_owned());
+    Ok(Some((data, mime)))
+}
+
+/// Replaces artwork of the kind with the provided image, removing all resized variants of the old one
+pub async fn store_artwork(db: &AstralDatabase, kind: ArtworkKind, owner: &BsonId, data: &[u8]) -> Res<()> {
+    if data.is_empty() {
+        return Err(AstralError::BadRequest(String::from("Artwork can not be empty")))
+    }
+    delete_artwork(db, kind, owner).await?;
+
+    let mime_type = guess_image_mime(data);
+    kind.bucket(db).upload(&kind.filename(owner), Some(doc! { "mime_type": mime_type }), data).await?;
+    Ok(())
+}
+
+/// Removes artwork of the kind along with all of its resized variants
+pub async fn delete_artwork(db: &AstralDatabase, kind: ArtworkKind, owner: &BsonId) -> Res<()> {
+    let bucket = kind.bucket(db);
+    let filename = kind.filename(owner);
+    for file in bucket.find(doc! { "$or": [{ "filename": &filename }, { "metadata.variant_of": &filename }] }).await? {
+        bucket.delete(&file.id).await?;
+    }
+    Ok(())
+}
+
+/// Moves artwork of the kind from one owner to another, unless the other owner already has one.
+/// Artwork left on the old owner is removed. Returns whether the artwork was moved.
+pub async fn move_artwork(db: &AstralDatabase, kind: ArtworkKind, from: &BsonId, to: &BsonId) -> Res<bool> {
+    let bucket = kind.bucket(db);
+    let has_target = bucket.find_by_name(&kind.filename(to)).await?.is_some();
+    let source = bucket.find_by_name(&kind.filename(from)).await?;
+
+    let moved = match source {
+        Some(source) if !has_target => {
+            bucket.rename(&source.id, &kind.filename(to)).await?;
+            true
+        }
+        _ => false,
+    };
+    // variants of the moved file are still keyed by the old name, so they are dropped as well
+    delete_artwork(db, kind, from).await?;
+    Ok(moved)
+}
+
+/// Shrinks the image to fit into a square of the provided size and encodes it in the format.
+/// Images too large to decode within the limits are rejected as a bad request.
+fn resize_artwork(data: &[u8], size: u32, format: CoverFormat) -> Res<Vec<u8>> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_ARTWORK_DIMENSION);
+    limits.max_image_height = Some(MAX_ARTWORK_DIMENSION);
+    limits.max_alloc = Some(MAX_ARTWORK_ALLOC);
+    reader.limits(limits);
+    let mut image = reader.decode().map_err(|err| match err {
+        ImageError::Limits(err) => AstralError::BadRequest(format!("Artwork is too large to process: {err}")),
+        err => err.into(),
+    })?;
+    if image.width() > size || image.height() > size {
+        image = image.thumbnail(size, size);
+    }
+
+    let mut out = Cursor::new(vec![]);
+    match format {
+        CoverFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?,
+        CoverFormat::Webp => DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_with_encoder(WebPEncoder::new_lossless(&mut out))?,
+    }
+    Ok(out.into_inner())
+}
@@ -1,11 +1,17 @@
-use std::io::Cursor;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::path::Path;
 use audiotags::{AudioTagEdit, FlacTag, Id3v2Tag, Mp4Tag};
-use crate::data::model::TrackFormat;
+use id3::TagLike;
+use mp4ameta::FreeformIdent;
+use crate::data::model::{AlbumType, TrackFormat};
 use crate::metadata::{AlbumArt, ExtractedTrackMetadata, PictureOwned};
+use crate::metadata::genres::normalize_genres;
+use crate::metadata::provider::{MetadataProvider, MetadataSource, PartialTrackMetadata, ProviderContext};
 use crate::Res;
 
 macro_rules! build_from_tag {
-    ($tag:ident, $format:ident) => {
+    ($tag:ident, $format:ident, $extras:ident) => {
         {
             let common_metadata = ExtractedTrackMetadata {
                 name: $tag.title().unwrap_or_default().to_owned(),
@@ -16,9 +22,15 @@ macro_rules! build_from_tag {
                 duration: $tag.duration().unwrap_or(0f64).floor(),
                 number: $tag.track_number().unwrap_or(0u16),
                 disc_number: $tag.disc_number().unwrap_or(0u16),
+                track_total: $tag.total_tracks().unwrap_or(0u16),
+                disc_total: $tag.total_discs().unwrap_or(0u16),
+                disc_subtitle: $extras.disc_subtitle,
+                album_type: $extras.album_type,
+                edition: $extras.edition,
                 release_date: 0,
                 is_explicit: false,
                 lyrics: None,
+                genres: normalize_genres(&$tag.genre().map(|it| vec![it]).unwrap_or_default()),
                 $format
             };
             Ok(common_metadata.clone())
@@ -26,23 +38,96 @@ macro_rules! build_from_tag {
     };
 }
 
+/// Tags that are not exposed by audiotags and are read from the raw tag instead
+struct RawTagExtras {
+    disc_subtitle: Option<String>,
+    album_type: Option<AlbumType>,
+    edition: Option<String>,
+}
+
 pub fn extract_metadata_from_bytes(
     bytes: &[u8],
     format: TrackFormat,
 ) -> Res<ExtractedTrackMetadata> {
-    let mut reader = Cursor::new(bytes);
+    extract_metadata_from_reader(&mut Cursor::new(bytes), format)
+}
+
+/// Extracts metadata from the tags of the audio file. Only the tags are read, not the whole file.
+pub fn extract_metadata_from_file(path: &Path, format: TrackFormat) -> Res<ExtractedTrackMetadata> {
+    extract_metadata_from_reader(&mut BufReader::new(File::open(path)?), format)
+}
+
+fn extract_metadata_from_reader<R: Read + Seek>(
+    reader: &mut R,
+    format: TrackFormat,
+) -> Res<ExtractedTrackMetadata> {
     return match &format {
         TrackFormat::Flac => {
-            let tag = FlacTag::from(metaflac::Tag::read_from(&mut reader)?);
-            build_from_tag!(tag, format)
+            let raw = metaflac::Tag::read_from(reader)?;
+            let vorbis = |key: &str| raw.get_vorbis(key).map(|it| it.collect::<Vec<_>>().join(";")).filter(|it| !it.is_empty());
+            let extras = RawTagExtras {
+                disc_subtitle: vorbis("DISCSUBTITLE"),
+                album_type: vorbis("RELEASETYPE").or_else(|| vorbis("MUSICBRAINZ_ALBUMTYPE")).and_then(|it| AlbumType::parse_release_type(&it)),
+                edition: vorbis("MUSICBRAINZ_ALBUMCOMMENT"),
+            };
+            let tag = FlacTag::from(raw);
+            build_from_tag!(tag, format, extras)
         }
         TrackFormat::M4a => {
-            let tag = Mp4Tag::from(mp4ameta::Tag::read_from(&mut reader)?);
-            build_from_tag!(tag, format)
+            let raw = mp4ameta::Tag::read_from(reader)?;
+            let freeform = |name: &'static str| Some(raw.strings_of(&FreeformIdent::new("com.apple.iTunes", name)).collect::<Vec<_>>().join(";")).filter(|it| !it.is_empty());
+            let extras = RawTagExtras {
+                disc_subtitle: freeform("DISCSUBTITLE"),
+                album_type: freeform("RELEASETYPE").or_else(|| freeform("MusicBrainz Album Type")).and_then(|it| AlbumType::parse_release_type(&it)),
+                edition: freeform("MusicBrainz Album Comment"),
+            };
+            let tag = Mp4Tag::from(raw);
+            build_from_tag!(tag, format, extras)
         }
         TrackFormat::Mp3 => {
-            let tag = Id3v2Tag::from(id3::Tag::read_from(&mut reader)?);
-            build_from_tag!(tag, format)
+            let raw = id3::Tag::read_from(reader)?;
+            let extended = |description: &str| raw.extended_texts().find(|it| it.description == description).map(|it| it.value.clone()).filter(|it| !it.is_empty());
+            let extras = RawTagExtras {
+                // TSST is the ID3v2.4 set subtitle frame
+                disc_subtitle: raw.get("TSST").and_then(|it| it.content().text()).map(String::from),
+                album_type: extended("RELEASETYPE").or_else(|| extended("MusicBrainz Album Type")).and_then(|it| AlbumType::parse_release_type(&it)),
+                edition: extended("MusicBrainz Album Comment"),
+            };
+            let tag = Id3v2Tag::from(raw);
+            build_from_tag!(tag, format, extras)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Provides metadata embedded in the audio file tags
+pub struct EmbeddedTagsProvider;
+
+#[axum::async_trait]
+impl MetadataProvider for EmbeddedTagsProvider {
+    fn source(&self) -> MetadataSource {
+        MetadataSource::Embedded
+    }
+
+    async fn provide(&self, ctx: &ProviderContext<'_>) -> Res<PartialTrackMetadata> {
+        let extracted = extract_metadata_from_bytes(ctx.bytes, ctx.format)?;
+        Ok(PartialTrackMetadata {
+            name: Some(extracted.name).filter(|it| !it.is_empty()),
+            album_name: Some(extracted.album_name).filter(|it| !it.is_empty()),
+            artists: Some(extracted.artists).filter(|it| !it.is_empty()),
+            album_artists: Some(extracted.album_artists).filter(|it| !it.is_empty()),
+            cover_art: extracted.cover_art,
+            duration: Some(extracted.duration).filter(|it| *it as i32 != 0),
+            number: Some(extracted.number).filter(|it| *it != 0),
+            disc_number: Some(extracted.disc_number).filter(|it| *it != 0),
+            track_total: Some(extracted.track_total).filter(|it| *it != 0),
+            disc_total: Some(extracted.disc_total).filter(|it| *it != 0),
+            disc_subtitle: extracted.disc_subtitle.filter(|it| !it.is_empty()),
+            album_type: extracted.album_type,
+            edition: extracted.edition,
+            release_date: Some(extracted.release_date).filter(|it| *it != 0),
+            is_explicit: None,
+            lyrics: extracted.lyrics,
+            genres: Some(extracted.genres).filter(|it| !it.is_empty()),
+        })
+    }
+}
@@ -0,0 +1,81 @@
+use std::net::{IpAddr, SocketAddr};
+use reqwest::redirect::Policy;
+use reqwest::{header, Url};
+use crate::err::AstralError;
+use crate::Res;
+
+/// Hosts cover art may be downloaded from, along with their subdomains.
+/// These are the hosts used by the Musixmatch CDN and the Cover Art Archive, which redirects to the Internet Archive.
+const COVER_HOSTS: [&str; 4] = ["mxmcdn.net", "musixmatch.com", "coverartarchive.org", "archive.org"];
+/// Maximum amount of redirects followed when downloading cover art
+const MAX_REDIRECTS: usize = 5;
+
+/// Checks whether the address is publicly routable, rejecting loopback, private, link-local and other special addresses
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || ip.is_multicast() || ip.is_documentation()
+                // shared address space and reserved ranges
+                || (a == 100 && (64..128).contains(&b)) || a == 0 || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                    // unique local and link-local ranges
+                    || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Checks that the url points to one of the known cover art hosts over HTTPS
+pub fn check_cover_url(url: &Url) -> Res<()> {
+    let allowed = url.scheme() == "https" && url.domain().is_some_and(|domain| {
+        COVER_HOSTS.iter().any(|host| domain == *host || domain.strip_suffix(host).is_some_and(|it| it.ends_with('.')))
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err(AstralError::BadRequest(format!("Cover art can not be downloaded from {url}")))
+    }
+}
+
+/// Resolves the host of the url, making sure all of its addresses are public
+async fn resolve_public(url: &Url) -> Res<(String, SocketAddr)> {
+    let host = url.host_str().ok_or_else(|| AstralError::BadRequest(String::from("Cover url has no host")))?.to_owned();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port)).await?.collect();
+    match addresses.first() {
+        Some(address) if addresses.iter().all(|it| is_public(it.ip())) => Ok((host, *address)),
+        _ => Err(AstralError::BadRequest(format!("Cover host {host} does not resolve to a public address"))),
+    }
+}
+
+/// Downloads cover art from one of the known cover art hosts.
+/// Every redirect is checked the same way, and connections are pinned to the checked address, so the server can not be
+/// made to request internal addresses.
+pub async fn download_cover(mut url: Url) -> Res<Vec<u8>> {
+    for _ in 0..=MAX_REDIRECTS {
+        check_cover_url(&url)?;
+        let (host, address) = resolve_public(&url).await?;
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .resolve(&host, address)
+            .build()?;
+        let response = client.get(url.clone()).send().await?;
+        if response.status().is_redirection() {
+            let location = response.headers().get(header::LOCATION)
+                .and_then(|it| it.to_str().ok())
+                .and_then(|it| url.join(it).ok())
+                .ok_or_else(|| AstralError::BadRequest(String::from("Invalid redirect while downloading cover art")))?;
+            url = location;
+            continue
+        }
+        return Ok(response.error_for_status()?.bytes().await?.to_vec())
+    }
+    Err(AstralError::BadRequest(String::from("Too many redirects while downloading cover art")))
+}
@@ -0,0 +1,274 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use crate::data::{AstralDatabase, Transaction};
+use crate::data::model::{BsonId, TrackFormat, TrackMetadata};
+use crate::err::AstralError;
+use crate::metadata::artwork::{move_artwork, ArtworkKind};
+use crate::metadata::fingerprint::{fingerprint_similarity, SAME_RECORDING_SIMILARITY};
+use crate::metadata::genres::refresh_genres;
+use crate::metadata::{remove_track_entries, remove_track_files};
+use crate::search::engine::{reindex_entries, SearchIndex};
+use crate::search::tokenize;
+use crate::Res;
+
+/// Maximum difference in length, in seconds, for tracks with the same title to be considered the same recording
+const LENGTH_TOLERANCE: u32 = 2;
+
+/// Why tracks were considered duplicates
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateReason {
+    /// Uploaded files are byte for byte identical
+    SameFile,
+    /// Tracks have matching acoustic fingerprints and close lengths, regardless of their tags, format or bitrate
+    SameRecording,
+    /// Tracks have the same normalised title and artists, close lengths and the same format
+    SameTitle,
+    /// Same track uploaded in different formats
+    DifferentFormat,
+}
+
+/// A track that is likely a duplicate of other tracks in its group
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DuplicateCandidate {
+    /// UUID of the track
+    pub track_id: Uuid,
+    /// Name of the track
+    #[schema(example = "Clay")]
+    pub track_name: String,
+    /// Names of the track artists
+    pub artists: Vec<String>,
+    /// UUIDs of the albums this track is in
+    pub albums: Vec<Uuid>,
+    /// Length of the track in seconds
+    pub track_length: u32,
+    /// File format of the track
+    pub format: TrackFormat,
+    /// Size of the stored file in bytes
+    pub file_size: u64,
+    /// Average bitrate of the stored file in kbps
+    pub bitrate: u32,
+    /// Amount of times this track was streamed
+    pub play_count: u32,
+}
+
+/// Group of tracks that are likely the same recording
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DuplicateGroup {
+    /// Why these tracks were grouped together
+    pub reason: DuplicateReason,
+    /// UUID of the track with the best quality, which is suggested to be kept
+    pub suggested_keep: Uuid,
+    /// Tracks in this group, best quality first
+    pub tracks: Vec<DuplicateCandidate>,
+}
+
+/// Quality rank of the format, lossless formats first
+fn format_rank(format: TrackFormat) -> u8 {
+    match format {
+        TrackFormat::Flac => 2,
+        TrackFormat::M4a => 1,
+        TrackFormat::Mp3 => 0,
+    }
+}
+
+/// Finds the representative of the union-find set containing the index, compressing the path along the way
+fn root(parents: &mut [usize], mut idx: usize) -> usize {
+    while parents[idx] != idx {
+        parents[idx] = parents[parents[idx]];
+        idx = parents[idx];
+    }
+    idx
+}
+
+fn track_path(id: &BsonId) -> PathBuf {
+    PathBuf::from("astral_tracks").join(format!("{id}.bin"))
+}
+
+/// Finds groups of tracks that are likely duplicates of each other. Only stored file hashes and fingerprints are used,
+/// tracks added before file hashes were stored are matched by file once [crate::jobs::hasher] hashes them.
+pub async fn find_duplicates(db: &AstralDatabase) -> Res<Vec<DuplicateGroup>> {
+    let tracks: Vec<TrackMetadata> = db.tracks_metadata.find(doc! { }).await?.try_collect().await?;
+
+    let artist_names: HashMap<BsonId, String> = db.artists_metadata.find(doc! { }).await?
+        .map_ok(|it| (it.artist_id, it.name))
+        .try_collect().await?;
+
+    let mut groups: Vec<(DuplicateReason, Vec<&TrackMetadata>)> = vec![];
+
+    let mut by_hash: HashMap<&str, Vec<&TrackMetadata>> = HashMap::new();
+    for track in &tracks {
+        if let Some(hash) = &track.hash {
+            by_hash.entry(hash.as_str()).or_default().push(track);
+        }
+    }
+    let mut same_file: Vec<HashSet<BsonId>> = vec![];
+    for group in by_hash.into_values().filter(|it| it.len() > 1) {
+        same_file.push(group.iter().map(|it| it.track_id).collect());
+        groups.push((DuplicateReason::SameFile, group));
+    }
+
+    let fingerprints: HashMap<BsonId, Vec<u32>> = db.fingerprints.find(doc! { "failed": { "$ne": true } }).await?
+        .map_ok(|it| (it.track_id, it.fingerprint))
+        .try_collect().await?;
+    let mut fingerprinted = tracks.iter().filter(|it| fingerprints.contains_key(&it.track_id)).collect::<Vec<_>>();
+    fingerprinted.sort_by_key(|it| it.length);
+    // tracks are grouped with union-find, so matches of matches end up in the same group
+    let mut parents = (0..fingerprinted.len()).collect::<Vec<_>>();
+    for (i, track) in fingerprinted.iter().enumerate() {
+        let fingerprint = &fingerprints[&track.track_id];
+        for (j, other) in fingerprinted.iter().enumerate().skip(i + 1) {
+            if other.length - track.length > LENGTH_TOLERANCE {
+                break
+            }
+            if fingerprint_similarity(fingerprint, &fingerprints[&other.track_id]) >= SAME_RECORDING_SIMILARITY {
+                let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                parents[a] = b;
+            }
+        }
+    }
+    let mut by_recording: HashMap<usize, Vec<&TrackMetadata>> = HashMap::new();
+    for (idx, track) in fingerprinted.iter().enumerate() {
+        by_recording.entry(root(&mut parents, idx)).or_default().push(*track);
+    }
+    let mut same_recording: Vec<HashSet<BsonId>> = vec![];
+    for group in by_recording.into_values().filter(|it| it.len() > 1) {
+        let ids = group.iter().map(|it| it.track_id).collect::<HashSet<_>>();
+        // already reported as identical files
+        if same_file.iter().any(|it| ids.is_subset(it)) {
+            continue
+        }
+        same_recording.push(ids);
+        groups.push((DuplicateReason::SameRecording, group));
+    }
+
+    let mut by_title: HashMap<(Vec<String>, Vec<String>), Vec<&TrackMetadata>> = HashMap::new();
+    for track in &tracks {
+        let title = tokenize(&track.name);
+        if title.is_empty() {
+            continue
+        }
+        let mut artists = track.artists.iter()
+            .filter_map(|it| artist_names.get(it))
+            .flat_map(|it| tokenize(it))
+            .collect::<Vec<_>>();
+        artists.sort();
+        by_title.entry((title, artists)).or_default().push(track);
+    }
+    for mut candidates in by_title.into_values().filter(|it| it.len() > 1) {
+        candidates.sort_by_key(|it| it.length);
+        // tracks are chained into a cluster as long as every next one is close enough in length
+        let mut clusters: Vec<Vec<&TrackMetadata>> = vec![];
+        for track in candidates {
+            match clusters.last_mut() {
+                Some(cluster) if track.length - cluster[cluster.len() - 1].length <= LENGTH_TOLERANCE => cluster.push(track),
+                _ => clusters.push(vec![track]),
+            }
+        }
+        for cluster in clusters.into_iter().filter(|it| it.len() > 1) {
+            // already reported as identical files or recordings
+            if same_file.iter().chain(&same_recording).any(|group| cluster.iter().all(|it| group.contains(&it.track_id))) {
+                continue
+            }
+            let format = format_rank(cluster[0].format);
+            let reason = if cluster.iter().all(|it| format_rank(it.format) == format) {
+                DuplicateReason::SameTitle
+            } else {
+                DuplicateReason::DifferentFormat
+            };
+            groups.push((reason, cluster));
+        }
+    }
+
+    let mut report = vec![];
+    for (reason, group) in groups {
+        let mut candidates = vec![];
+        for track in group {
+            let file_size = tokio::fs::metadata(track_path(&track.track_id)).await.map(|it| it.len()).unwrap_or(0);
+            candidates.push(DuplicateCandidate {
+                track_id: track.track_id.to_uuid_1(),
+                track_name: track.name.clone(),
+                artists: track.artists.iter().filter_map(|it| artist_names.get(it).cloned()).collect(),
+                albums: track.albums.iter().map(|it| it.to_uuid_1()).collect(),
+                track_length: track.length,
+                format: track.format,
+                file_size,
+                bitrate: (file_size * 8 / 1000).checked_div(track.length as u64).unwrap_or(0) as u32,
+                play_count: track.play_count,
+            });
+        }
+        candidates.sort_by_key(|it| std::cmp::Reverse((format_rank(it.format), it.bitrate)));
+        report.push(DuplicateGroup {
+            reason,
+            suggested_keep: candidates[0].track_id,
+            tracks: candidates,
+        });
+    }
+    report.sort_by(|a, b| a.tracks[0].track_name.cmp(&b.tracks[0].track_name));
+    Ok(report)
+}
+
+/// Keeps a single track out of a group of duplicates and removes the rest. Play counts, likes,
+/// lyrics and artwork of the removed tracks are carried over to the kept one when it lacks them,
+/// and the kept track takes the place of the removed ones in their albums.
+pub async fn resolve_duplicates(db: &AstralDatabase, search: &SearchIndex, keep: &BsonId, remove: &[BsonId]) -> Res<()> {
+    let remove = remove.iter().filter(|it| *it != keep).copied().collect::<HashSet<_>>().into_iter().collect::<Vec<_>>();
+    if remove.is_empty() {
+        return Err(AstralError::BadRequest(String::from("Provide at least one track to remove other than the kept one")))
+    }
+    let removed = db.with_transaction((db, *keep, &remove), |session, (db, keep, remove)| {
+        Box::pin(resolve_duplicate_entries(db, *keep, remove, session))
+    }).await?;
+
+    let mut artists = vec![];
+    let mut albums = vec![];
+    for track in &removed {
+        move_artwork(db, ArtworkKind::TrackArt, &track.track_id, keep).await?;
+        remove_track_files(&track.track_id).await?;
+        artists.extend(track.artists.iter().copied());
+        albums.extend(track.albums.iter().copied());
+    }
+    let kept = db.tracks_metadata.find_one(doc! { "track_id": keep }).await?;
+    artists.extend(kept.iter().flat_map(|it| it.artists.iter().copied()));
+    let artists = artists.into_iter().collect::<HashSet<_>>().into_iter().collect::<Vec<_>>();
+    let albums = albums.into_iter().collect::<HashSet<_>>().into_iter().collect::<Vec<_>>();
+    refresh_genres(db, &artists, &albums).await?;
+    let tracks = remove.iter().chain(std::iter::once(keep)).copied().collect::<Vec<_>>();
+    reindex_entries(db, search, &tracks, &albums, &artists).await
+}
+
+/// Carries data of the removed tracks over to the kept one and removes them as part of the transaction.
+/// Returns the removed tracks.
+async fn resolve_duplicate_entries(db: &AstralDatabase, keep: BsonId, remove: &[BsonId], session: &mut Transaction) -> Res<Vec<TrackMetadata>> {
+    db.tracks_metadata.find_one_with_session(doc! { "track_id": keep }, session).await?
+        .ok_or_else(|| AstralError::NotFound(format!("Could not find track with UUID: {keep}")))?;
+    let mut removed = Vec::with_capacity(remove.len());
+    for id in remove {
+        let track = db.tracks_metadata.find_one_with_session(doc! { "track_id": id }, session).await?
+            .ok_or_else(|| AstralError::NotFound(String::from("Could not find some of the removed tracks")))?;
+        removed.push(track);
+    }
+
+    let plays = removed.iter().map(|it| it.play_count as i64).sum::<i64>();
+    let albums = removed.iter().flat_map(|it| it.albums.iter().copied()).collect::<HashSet<_>>().into_iter().collect::<Vec<_>>();
+    db.tracks_metadata.update_one_with_session(doc! { "track_id": keep }, doc! {
+        "$inc": { "play_count": plays },
+        "$addToSet": { "albums": { "$each": &albums } },
+    }, session).await?;
+    // the kept track takes the position of the removed ones, and is not repeated in albums that had several of them
+    db.albums_metadata.replace_references_with_session(doc! { }, "tracks", remove, &keep, session).await?;
+    db.accounts.replace_references_with_session(doc! { }, "loved_tracks", remove, &keep, session).await?;
+
+    for track in &removed {
+        if db.lyrics.find_one_with_session(doc! { "track_id": keep }, session).await?.is_none() {
+            db.lyrics.update_one_with_session(doc! { "track_id": &track.track_id }, doc! { "$set": { "track_id": keep } }, session).await?;
+        }
+        remove_track_entries(db, track.track_id, session).await?;
+    }
+    Ok(removed)
+}
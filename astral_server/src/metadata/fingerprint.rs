@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use chrono::Utc;
+use mongodb::bson::doc;
+use rusty_chromaprint::{Configuration, Fingerprinter};
+use tokio::process::Command;
+use crate::data::AstralDatabase;
+use crate::data::model::{BsonId, TrackFingerprint};
+use crate::err::AstralError;
+use crate::metadata::acoustid::{acoustid_key, acoustid_lookup};
+use crate::Res;
+
+/// Sample rate the audio is decoded in before fingerprinting, same as the one used by Chromaprint itself
+const SAMPLE_RATE: u32 = 11025;
+/// Only the beginning of the track is fingerprinted, same as AcoustID does
+const MAX_SECONDS: u32 = 120;
+/// Maximum shift, in fingerprint items, tried when aligning two fingerprints. A single item covers ~0.12s of audio.
+const MAX_ALIGN_OFFSET: usize = 16;
+/// Minimal amount of overlapping fingerprint items for a comparison to be meaningful
+const MIN_OVERLAP: usize = 40;
+/// Minimal similarity of two fingerprints for them to be considered the same recording
+pub const SAME_RECORDING_SIMILARITY: f32 = 0.85;
+
+/// Decodes the beginning of the stored track with ffmpeg and computes its Chromaprint fingerprint
+pub async fn compute_fingerprint(track_id: &BsonId) -> Res<Vec<u32>> {
+    let path = PathBuf::from("astral_tracks").join(format!("{track_id}.bin"));
+    let output = Command::new("ffmpeg")
+        .stdin(Stdio::null())
+        .arg("-v")
+        .arg("0")
+        .arg("-i")
+        .arg(&path)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-t")
+        .arg(MAX_SECONDS.to_string())
+        .arg("-ac")
+        .arg("1")
+        .arg("-ar")
+        .arg(SAMPLE_RATE.to_string())
+        .arg("-f")
+        .arg("s16le")
+        .arg("-")
+        .output().await?;
+    if !output.status.success() {
+        return Err(AstralError::Unknown(anyhow::anyhow!("ffmpeg failed to decode track {track_id}: {}", output.status)))
+    }
+    let samples = output.stdout.chunks_exact(2)
+        .map(|it| i16::from_le_bytes([it[0], it[1]]))
+        .collect::<Vec<_>>();
+    if samples.is_empty() {
+        return Err(AstralError::BadRequest(format!("Track {track_id} does not contain any audio")))
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let mut printer = Fingerprinter::new(&Configuration::preset_test2());
+        printer.start(SAMPLE_RATE, 1)
+            .map_err(|err| AstralError::Unknown(anyhow::anyhow!("Failed to start fingerprinting: {err:?}")))?;
+        printer.consume(&samples);
+        printer.finish();
+        Ok(printer.fingerprint().to_vec())
+    }).await.map_err(|err| AstralError::Unknown(err.into()))?
+}
+
+/// Computes fingerprint of the track and stores it, replacing the old one. The fingerprint is looked up in AcoustID
+/// if a key is configured. Tracks that could not be fingerprinted are stored as failed, so they are not retried on every start.
+pub async fn fingerprint_track(db: &AstralDatabase, track_id: &BsonId) -> Res<()> {
+    let computed = compute_fingerprint(track_id).await;
+    let mut entry = TrackFingerprint {
+        track_id: *track_id,
+        fingerprint: vec![],
+        computed_at: Utc::now().timestamp_millis() as u64,
+        failed: computed.is_err(),
+        acoustid: None,
+        recording_ids: vec![],
+        looked_up_at: None,
+    };
+    if let Ok(fingerprint) = &computed {
+        entry.fingerprint.clone_from(fingerprint);
+        // failed lookups are retried by the backfill
+        let _ = lookup_fingerprint(db, &mut entry).await;
+    }
+    db.fingerprints.upsert_one(doc! { "track_id": track_id }, &entry).await?;
+    computed.map(|_| ())
+}
+
+/// Looks the stored fingerprint up in AcoustID, filling in the found recording. Does nothing if no AcoustID key is configured.
+pub async fn lookup_fingerprint(db: &AstralDatabase, entry: &mut TrackFingerprint) -> Res<()> {
+    let Some(key) = acoustid_key() else {
+        return Ok(())
+    };
+    let track = db.tracks_metadata.find_one(doc! { "track_id": &entry.track_id }).await?
+        .ok_or_else(|| AstralError::NotFound(format!("Could not find track with UUID: {}", entry.track_id)))?;
+    let found = acoustid_lookup(&key, &entry.fingerprint, track.length).await?;
+    entry.acoustid = found.as_ref().map(|it| it.acoustid.clone());
+    entry.recording_ids = found.map(|it| it.recording_ids).unwrap_or_default();
+    entry.looked_up_at = Some(Utc::now().timestamp_millis() as u64);
+    Ok(())
+}
+
+/// Similarity of two fingerprints from `0` to `1`, as the share of matching bits when they are best aligned.
+/// Unrelated recordings are usually around `0.5`.
+pub fn fingerprint_similarity(a: &[u32], b: &[u32]) -> f32 {
+    let mut best = 0f32;
+    for offset in 0..=MAX_ALIGN_OFFSET {
+        // the same recording can start a bit later in either of the files
+        for (a, b) in [(a.get(offset..), Some(b)), (Some(a), b.get(offset..))] {
+            let (Some(a), Some(b)) = (a, b) else {
+                continue
+            };
+            let overlap = a.len().min(b.len());
+            if overlap < MIN_OVERLAP {
+                continue
+            }
+            let differing = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum::<u32>();
+            best = best.max(1.0 - differing as f32 / (overlap as f32 * 32.0));
+        }
+    }
+    best
+}
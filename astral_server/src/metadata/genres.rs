@@ -0,0 +1,229 @@
+use std::env;
+use std::collections::HashMap;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document, from_document};
+use serde::Deserialize;
+use crate::data::AstralDatabase;
+use crate::data::repository::EntryStream;
+use crate::data::model::BsonId;
+use crate::Res;
+
+/// Known genres as canonical name, its aliases and its parent genre
+const GENRE_TAXONOMY: &[(&str, &[&str], Option<&str>)] = &[
+    ("rock", &["rock music", "rock & roll", "rock and roll", "rock'n'roll"], None),
+    ("alternative rock", &["alternative", "alt rock", "alt-rock", "alternative & punk"], Some("rock")),
+    ("indie rock", &["indie", "indie-rock"], Some("rock")),
+    ("hard rock", &["hard-rock"], Some("rock")),
+    ("psychedelic rock", &["psychedelic", "psych rock", "psych"], Some("rock")),
+    ("progressive rock", &["prog rock", "prog", "progressive"], Some("rock")),
+    ("experimental rock", &["experimental-rock"], Some("rock")),
+    ("punk", &["punk rock", "punk-rock"], Some("rock")),
+    ("post-punk", &["post punk", "postpunk"], Some("punk")),
+    ("art punk", &["art-punk"], Some("punk")),
+    ("hardcore punk", &["hardcore", "hxc"], Some("punk")),
+    ("metal", &["heavy metal", "metal music"], None),
+    ("death metal", &["death-metal"], Some("metal")),
+    ("black metal", &["black-metal"], Some("metal")),
+    ("thrash metal", &["thrash", "thrash-metal"], Some("metal")),
+    ("metalcore", &["metal core", "metal-core"], Some("metal")),
+    ("nu metal", &["nu-metal", "numetal"], Some("metal")),
+    ("pop", &["pop music"], None),
+    ("indie pop", &["indie-pop"], Some("pop")),
+    ("synth-pop", &["synthpop", "synth pop"], Some("pop")),
+    ("k-pop", &["kpop", "k pop", "korean pop"], Some("pop")),
+    ("hip hop", &["hip-hop", "hiphop", "hip hop/rap", "hip-hop/rap", "rap/hip hop"], None),
+    ("rap", &["rap music"], Some("hip hop")),
+    ("trap", &["trap music"], Some("hip hop")),
+    ("r&b", &["rnb", "r'n'b", "r and b", "rhythm and blues", "rhythm & blues", "r&b/soul"], None),
+    ("soul", &["soul music"], Some("r&b")),
+    ("electronic", &["electronica", "electro", "electronic music", "edm", "dance/electronic"], None),
+    ("house", &["house music"], Some("electronic")),
+    ("techno", &["techno music"], Some("electronic")),
+    ("drum and bass", &["drum & bass", "drum'n'bass", "dnb", "d&b"], Some("electronic")),
+    ("dubstep", &["dub step", "dub-step"], Some("electronic")),
+    ("ambient", &["ambient music"], Some("electronic")),
+    ("trance", &["trance music"], Some("electronic")),
+    ("jazz", &["jazz music"], None),
+    ("blues", &["blues music"], None),
+    ("classical", &["classical music", "classic"], None),
+    ("country", &["country music", "country & western"], None),
+    ("folk", &["folk music"], None),
+    ("reggae", &["reggae music"], None),
+    ("latin", &["latin music", "latino"], None),
+    ("soundtrack", &["ost", "original soundtrack", "soundtracks", "score", "film score"], None),
+    ("singer-songwriter", &["singer songwriter", "singer/songwriter"], None),
+    ("funk", &["funk music"], None),
+    ("disco", &["disco music"], None),
+    ("shoegaze", &["shoe gaze", "shoegazing"], Some("alternative rock")),
+    ("emo", &["emocore"], Some("punk")),
+    ("grunge", &["grunge rock"], Some("alternative rock")),
+];
+
+/// Genres of ID3v1 and its Winamp extensions, indexed by their numeric ID3v1 code
+const ID3V1_GENRES: &[&str] = &[
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop", "Jazz", "Metal",
+    "New Age", "Oldies", "Other", "Pop", "R&B", "Rap", "Reggae", "Rock", "Techno", "Industrial",
+    "Alternative", "Ska", "Death Metal", "Pranks", "Soundtrack", "Euro-Techno", "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk",
+    "Fusion", "Trance", "Classical", "Instrumental", "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise",
+    "Alternative Rock", "Bass", "Soul", "Punk", "Space", "Meditative", "Instrumental Pop", "Instrumental Rock", "Ethnic", "Gothic",
+    "Darkwave", "Techno-Industrial", "Electronic", "Pop-Folk", "Eurodance", "Dream", "Southern Rock", "Comedy", "Cult", "Gangsta",
+    "Top 40", "Christian Rap", "Pop/Funk", "Jungle", "Native American", "Cabaret", "New Wave", "Psychedelic", "Rave", "Showtunes",
+    "Trailer", "Lo-Fi", "Tribal", "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical", "Rock & Roll", "Hard Rock",
+    "Folk", "Folk-Rock", "National Folk", "Swing", "Fast Fusion", "Bebop", "Latin", "Revival", "Celtic", "Bluegrass",
+    "Avantgarde", "Gothic Rock", "Progressive Rock", "Psychedelic Rock", "Symphonic Rock", "Slow Rock", "Big Band", "Chorus", "Easy Listening", "Acoustic",
+    "Humour", "Speech", "Chanson", "Opera", "Chamber Music", "Sonata", "Symphony", "Booty Bass", "Primus", "Porn Groove",
+    "Satire", "Slow Jam", "Club", "Tango", "Samba", "Folklore", "Ballad", "Power Ballad", "Rhythmic Soul", "Freestyle",
+    "Duet", "Punk Rock", "Drum Solo", "A Cappella", "Euro-House", "Dance Hall", "Goa", "Drum & Bass", "Club-House", "Hardcore",
+    "Terror", "Indie", "Britpop", "Afro-Punk", "Polsk Punk", "Beat", "Christian Gangsta Rap", "Heavy Metal", "Black Metal", "Crossover",
+    "Contemporary Christian", "Christian Rock", "Merengue", "Salsa", "Thrash Metal", "Anime", "J-Pop", "Synthpop",
+];
+
+/// Characters separating multiple genres inside a single tag value
+const GENRE_SEPARATORS: &[char] = &[';', ',', '\0'];
+/// Default amount of most prominent genres shown for albums and artists
+const DEFAULT_TOP_GENRES: usize = 3;
+
+/// Amount of most prominent genres kept for albums and shown for artists, read from `ASTRAL_TOP_GENRES`.
+/// Defaults to 3, and `0` keeps all genres.
+pub fn top_genre_limit() -> usize {
+    match env::var("ASTRAL_TOP_GENRES").ok().and_then(|it| it.parse::<usize>().ok()) {
+        Some(0) => usize::MAX,
+        Some(limit) => limit,
+        None => DEFAULT_TOP_GENRES,
+    }
+}
+
+/// Resolves numeric ID3v1 genre references like `(17)` or `17` into genre names.
+/// A refinement following the reference, as in `(4)Eurodisco`, is preferred over the referenced genre.
+fn resolve_id3v1_genre(raw: &str) -> &str {
+    let (code, refinement) = raw.strip_prefix('(')
+        .and_then(|it| it.split_once(')'))
+        .unwrap_or((raw, ""));
+    if !refinement.trim().is_empty() {
+        return refinement
+    }
+    code.parse::<usize>().ok()
+        .and_then(|it| ID3V1_GENRES.get(it).copied())
+        .unwrap_or(raw)
+}
+
+/// Normalises a single raw genre into its canonical name. Returns [None] for empty genres.
+pub fn normalize_genre(raw: &str) -> Option<String> {
+    // dots and dollar signs can not be used in document keys of artist genre counts
+    let cleaned = resolve_id3v1_genre(raw.trim()).to_lowercase().replace('_', " ").replace(['.', '$'], "");
+    let cleaned = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+    if cleaned.is_empty() {
+        return None
+    }
+    let canonical = GENRE_TAXONOMY.iter()
+        .find(|(name, aliases, _)| *name == cleaned || aliases.contains(&cleaned.as_str()))
+        .map(|(name, _, _)| name.to_string());
+    Some(canonical.unwrap_or(cleaned))
+}
+
+/// Splits raw genre tag values into normalised, deduplicated genres
+pub fn normalize_genres<S: AsRef<str>>(raw: &[S]) -> Vec<String> {
+    let mut genres: Vec<String> = vec![];
+    for genre in raw.iter().flat_map(|it| it.as_ref().split(GENRE_SEPARATORS)).filter_map(normalize_genre) {
+        if !genres.contains(&genre) {
+            genres.push(genre);
+        }
+    }
+    genres
+}
+
+/// Parent of a canonical genre in the taxonomy, if it has one
+pub fn parent_genre(genre: &str) -> Option<&'static str> {
+    GENRE_TAXONOMY.iter()
+        .find(|(name, _, _)| *name == genre)
+        .and_then(|(_, _, parent)| *parent)
+}
+
+/// Returns up to `count` genres with the highest counts
+pub fn top_genres(genres: &HashMap<String, u32>, count: usize) -> Vec<String> {
+    let mut sorted = genres.iter().collect::<Vec<_>>();
+    sorted.sort_by(|(a_name, a_count), (b_name, b_count)| b_count.cmp(a_count).then(a_name.cmp(b_name)));
+    sorted.into_iter().take(count).map(|(name, _)| name.clone()).collect()
+}
+
+#[derive(Deserialize)]
+struct GenreCount {
+    #[serde(rename = "_id")]
+    genre: String,
+    count: u32,
+}
+
+/// Collects results of a genre counting aggregation
+async fn collect_genre_counts(mut cursor: EntryStream<Document>) -> Res<HashMap<String, u32>> {
+    let mut counts = HashMap::new();
+    while let Some(each) = cursor.try_next().await? {
+        let each = from_document::<GenreCount>(each)?;
+        counts.insert(each.genre, each.count);
+    }
+    Ok(counts)
+}
+
+/// Counts genres of all tracks matching the filter
+pub async fn count_track_genres(db: &AstralDatabase, filter: Document) -> Res<HashMap<String, u32>> {
+    let cursor = db.tracks_metadata.aggregate(vec![
+        doc! { "$match": filter },
+        doc! { "$unwind": "$genres" },
+        doc! { "$group": { "_id": "$genres", "count": { "$sum": 1 } } },
+    ]).await?;
+    collect_genre_counts(cursor).await
+}
+
+/// Counts genres of the provided tracks. Tracks are read one by one instead of being aggregated,
+/// which works with every repository and is cheap for the few tracks of a single artist or album.
+async fn count_genres_of(db: &AstralDatabase, tracks: &[BsonId]) -> Res<HashMap<String, u32>> {
+    let mut counts = HashMap::new();
+    let mut found = db.tracks_metadata.find(doc! { "track_id": { "$in": tracks } }).await?;
+    while let Some(track) = found.try_next().await? {
+        for genre in track.genres {
+            *counts.entry(genre).or_insert(0) += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// Counts amount of albums each genre is prominent in
+pub async fn count_album_genres(db: &AstralDatabase) -> Res<HashMap<String, u32>> {
+    let cursor = db.albums_metadata.aggregate(vec![
+        doc! { "$unwind": "$genres" },
+        doc! { "$group": { "_id": "$genres", "count": { "$sum": 1 } } },
+    ]).await?;
+    collect_genre_counts(cursor).await
+}
+
+/// Counts amount of artists that have at least a single track of each genre
+pub async fn count_artist_genres(db: &AstralDatabase) -> Res<HashMap<String, u32>> {
+    let cursor = db.artists_metadata.aggregate(vec![
+        doc! { "$project": { "genres": { "$objectToArray": "$genres" } } },
+        doc! { "$unwind": "$genres" },
+        doc! { "$group": { "_id": "$genres.k", "count": { "$sum": 1 } } },
+    ]).await?;
+    collect_genre_counts(cursor).await
+}
+
+/// Recalculates genre counts of artists and most prominent genres of albums from their tracks.
+/// Albums with manually set genres are left as they are.
+/// Should be called whenever tracks or albums are added, changed or removed.
+pub async fn refresh_genres(db: &AstralDatabase, artists: &[BsonId], albums: &[BsonId]) -> Res<()> {
+    for artist_id in artists {
+        let Some(artist) = db.artists_metadata.find_one(doc! { "artist_id": artist_id }).await? else {
+            continue
+        };
+        let counts = count_genres_of(db, &artist.tracks).await?;
+        let counts = counts.into_iter().map(|(genre, count)| (genre, Bson::Int64(count as i64))).collect::<Document>();
+        db.artists_metadata.update_one(doc! { "artist_id": artist_id }, doc! { "$set": { "genres": counts } }).await?;
+    }
+    for album_id in albums {
+        let Some(album) = db.albums_metadata.find_one(doc! { "album_id": album_id, "manual_genres": { "$ne": true } }).await? else {
+            continue
+        };
+        let counts = count_genres_of(db, &album.tracks).await?;
+        // albums whose tracks have no genres left are cleared, so removed genres do not linger
+        db.albums_metadata.update_one(doc! { "album_id": album_id }, doc! { "$set": { "genres": top_genres(&counts, top_genre_limit()) } }).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,279 @@
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, to_bson, Document};
+use crate::data::AstralDatabase;
+use crate::data::model::{AlbumMetadata, ArtistMetadata, BsonId, TrackMetadata};
+use crate::err::AstralError;
+use crate::metadata::artwork::{move_artwork, ArtworkKind};
+use crate::metadata::genres::refresh_genres;
+use crate::search::engine::{reindex_entries, SearchIndex};
+use crate::Res;
+
+/// Update pipeline replacing references to any of the duplicates inside the array field with the survivor.
+/// Order of the array is kept and repeated references are removed.
+pub fn replace_references(field: &str, duplicates: &[BsonId], survivor: &BsonId) -> Vec<Document> {
+    vec![doc! {
+        "$set": {
+            field: {
+                "$reduce": {
+                    "input": {
+                        "$map": {
+                            "input": format!("${field}"),
+                            "in": { "$cond": [{ "$in": ["$$this", duplicates] }, survivor, "$$this"] }
+                        }
+                    },
+                    "initialValue": [],
+                    "in": { "$cond": [{ "$in": ["$$this", "$$value"] }, "$$value", { "$concatArrays": ["$$value", ["$$this"]] }] }
+                }
+            }
+        }
+    }]
+}
+
+/// Credits the artists first and removes the replaced ones from the current artists. Remaining artists keep their order.
+fn replace_artists(current: &[BsonId], replaced: &[BsonId], artists: &[BsonId]) -> Vec<BsonId> {
+    let mut result = artists.to_vec();
+    extend_unique(&mut result, &current.iter().copied().filter(|it| !replaced.contains(it)).collect::<Vec<_>>());
+    result
+}
+
+/// Appends ids that are not in the list yet, keeping their order
+fn extend_unique(ids: &mut Vec<BsonId>, other: &[BsonId]) {
+    for id in other {
+        if !ids.contains(id) {
+            ids.push(*id);
+        }
+    }
+}
+
+/// Records the name and aliases of a merged entry as aliases of the survivor
+fn extend_aliases(name: &str, aliases: &mut Vec<String>, merged_name: &str, merged_aliases: &[String]) {
+    for alias in std::iter::once(merged_name).chain(merged_aliases.iter().map(String::as_str)) {
+        if alias != name && !aliases.iter().any(|it| it == alias) {
+            aliases.push(alias.to_owned());
+        }
+    }
+}
+
+/// Checks merged ids and removes the survivor and repeated ids from them
+fn prepare_duplicates(survivor: &BsonId, duplicates: &[BsonId]) -> Res<Vec<BsonId>> {
+    let mut prepared = vec![];
+    for id in duplicates {
+        if id != survivor && !prepared.contains(id) {
+            prepared.push(*id);
+        }
+    }
+    if prepared.is_empty() {
+        return Err(AstralError::BadRequest(String::from("Nothing to merge, provide at least one entry other than the merge target")))
+    }
+    Ok(prepared)
+}
+
+/// Merges duplicate artists into the survivor. All tracks and albums of the duplicates are moved to the survivor,
+/// names of the duplicates become its aliases and the duplicates are removed.
+pub async fn merge_artists(db: &AstralDatabase, search: &SearchIndex, survivor_id: &BsonId, duplicates: &[BsonId]) -> Res<()> {
+    let duplicates = prepare_duplicates(survivor_id, duplicates)?;
+    let mut survivor = db.artists_metadata.find_one(doc! { "artist_id": survivor_id }).await?
+        .ok_or_else(|| AstralError::NotFound(format!("Could not find artist with UUID: {survivor_id}")))?;
+    let merged: Vec<ArtistMetadata> = db.artists_metadata.find(doc! { "artist_id": { "$in": &duplicates } }).await?
+        .try_collect().await?;
+    if merged.len() != duplicates.len() {
+        return Err(AstralError::NotFound(String::from("Could not find some of the merged artists")))
+    }
+
+    for artist in &merged {
+        extend_unique(&mut survivor.albums, &artist.albums);
+        extend_unique(&mut survivor.tracks, &artist.tracks);
+        extend_aliases(&survivor.name, &mut survivor.aliases, &artist.name, &artist.aliases);
+        if survivor.about.is_empty() {
+            survivor.about = artist.about.clone();
+        }
+        if artist.added_at != 0 && (survivor.added_at == 0 || artist.added_at < survivor.added_at) {
+            survivor.added_at = artist.added_at;
+        }
+    }
+
+    let mut session = db.start_transaction().await?;
+    db.tracks_metadata.replace_references_with_session(doc! { }, "artists", &duplicates, survivor_id, &mut session).await?;
+    db.albums_metadata.replace_references_with_session(doc! { }, "artists", &duplicates, survivor_id, &mut session).await?;
+    db.artists_metadata.update_one_with_session(doc! { "artist_id": survivor_id }, doc! {
+        "$set": {
+            "albums": &survivor.albums,
+            "tracks": &survivor.tracks,
+            "aliases": &survivor.aliases,
+            "about": &survivor.about,
+            "added_at": survivor.added_at as i64,
+        }
+    }, &mut session).await?;
+    db.artists_metadata.delete_many_with_session(doc! { "artist_id": { "$in": &duplicates } }, &mut session).await?;
+    db.commit_transaction(&mut session).await?;
+
+    // artwork buckets can not take part in the transaction
+    for duplicate in &duplicates {
+        move_artwork(db, ArtworkKind::ArtistPhoto, duplicate, survivor_id).await?;
+        move_artwork(db, ArtworkKind::ArtistBanner, duplicate, survivor_id).await?;
+    }
+
+    // genre counts are recalculated from the merged discography
+    refresh_genres(db, &[*survivor_id], &survivor.albums).await?;
+    let mut artists = vec![*survivor_id];
+    artists.extend(duplicates);
+    reindex_entries(db, search, &survivor.tracks, &survivor.albums, &artists).await
+}
+
+/// Merges duplicate albums into the survivor. All tracks of the duplicates are moved to the survivor,
+/// names of the duplicates become its aliases and the duplicates are removed.
+pub async fn merge_albums(db: &AstralDatabase, search: &SearchIndex, survivor_id: &BsonId, duplicates: &[BsonId]) -> Res<()> {
+    let duplicates = prepare_duplicates(survivor_id, duplicates)?;
+    let mut survivor = db.albums_metadata.find_one(doc! { "album_id": survivor_id }).await?
+        .ok_or_else(|| AstralError::NotFound(format!("Could not find album with UUID: {survivor_id}")))?;
+    let merged: Vec<AlbumMetadata> = db.albums_metadata.find(doc! { "album_id": { "$in": &duplicates } }).await?
+        .try_collect().await?;
+    if merged.len() != duplicates.len() {
+        return Err(AstralError::NotFound(String::from("Could not find some of the merged albums")))
+    }
+
+    for album in &merged {
+        extend_unique(&mut survivor.tracks, &album.tracks);
+        extend_unique(&mut survivor.artists, &album.artists);
+        extend_aliases(&survivor.name, &mut survivor.aliases, &album.name, &album.aliases);
+        for disc in &album.discs {
+            if !survivor.discs.iter().any(|it| it.number == disc.number) {
+                survivor.discs.push(disc.clone());
+            }
+        }
+        survivor.disc_total = survivor.disc_total.max(album.disc_total);
+        if survivor.release_date == 0 {
+            survivor.release_date = album.release_date;
+        }
+        if survivor.edition.is_none() {
+            survivor.edition = album.edition.clone();
+        }
+        if album.added_at != 0 && (survivor.added_at == 0 || album.added_at < survivor.added_at) {
+            survivor.added_at = album.added_at;
+        }
+    }
+    survivor.discs.sort_by_key(|it| it.number);
+
+    let mut session = db.start_transaction().await?;
+    db.tracks_metadata.replace_references_with_session(doc! { }, "albums", &duplicates, survivor_id, &mut session).await?;
+    db.artists_metadata.replace_references_with_session(doc! { }, "albums", &duplicates, survivor_id, &mut session).await?;
+    db.accounts.replace_references_with_session(doc! { }, "loved_albums", &duplicates, survivor_id, &mut session).await?;
+    db.albums_metadata.update_one_with_session(doc! { "album_id": survivor_id }, doc! {
+        "$set": {
+            "tracks": &survivor.tracks,
+            "artists": &survivor.artists,
+            "aliases": &survivor.aliases,
+            "discs": to_bson(&survivor.discs).map_err(anyhow::Error::from)?,
+            "disc_total": survivor.disc_total.map(|it| it as i32),
+            "release_date": survivor.release_date as i64,
+            "edition": &survivor.edition,
+            "added_at": survivor.added_at as i64,
+        }
+    }, &mut session).await?;
+    db.albums_metadata.delete_many_with_session(doc! { "album_id": { "$in": &duplicates } }, &mut session).await?;
+    db.commit_transaction(&mut session).await?;
+
+    // artwork buckets can not take part in the transaction
+    for duplicate in &duplicates {
+        if move_artwork(db, ArtworkKind::AlbumCover, duplicate, survivor_id).await? {
+            let palette = merged.iter().find(|it| it.album_id == *duplicate).and_then(|it| it.palette.clone());
+            db.albums_metadata.update_one(doc! { "album_id": survivor_id }, doc! {
+                "$set": { "palette": to_bson(&palette).map_err(anyhow::Error::from)? }
+            }).await?;
+        }
+    }
+
+    refresh_genres(db, &survivor.artists, &[*survivor_id]).await?;
+    let mut albums = vec![*survivor_id];
+    albums.extend(duplicates);
+    reindex_entries(db, search, &survivor.tracks, &albums, &survivor.artists).await
+}
+
+/// Moves tracks of a wrongly merged album into a new album with the provided name.
+/// The new album is credited to the provided artists, or to artists of the original album. Returns UUID of the new album.
+pub async fn split_album(
+    db: &AstralDatabase,
+    search: &SearchIndex,
+    album_id: &BsonId,
+    tracks: &[BsonId],
+    name: String,
+    artists: Option<Vec<BsonId>>,
+) -> Res<BsonId> {
+    if name.trim().is_empty() {
+        return Err(AstralError::BadRequest(String::from("Name of the new album can not be empty")))
+    }
+    let mut album = db.albums_metadata.find_one(doc! { "album_id": album_id }).await?
+        .ok_or_else(|| AstralError::NotFound(format!("Could not find album with UUID: {album_id}")))?;
+
+    if let Some(track) = tracks.iter().find(|it| !album.tracks.contains(it)) {
+        return Err(AstralError::BadRequest(format!("Track {track} does not belong to this album")))
+    }
+    // tracks are kept in the order they had in the original album
+    let (moved, kept): (Vec<BsonId>, Vec<BsonId>) = album.tracks.iter().copied().partition(|it| tracks.contains(it));
+    if moved.is_empty() {
+        return Err(AstralError::BadRequest(String::from("Provide at least one track to move into the new album")))
+    }
+    if kept.is_empty() {
+        return Err(AstralError::BadRequest(String::from("Can not move all tracks out of an album, rename it instead")))
+    }
+
+    let artists = match artists {
+        Some(artists) => {
+            let found = db.artists_metadata.count(doc! { "artist_id": { "$in": &artists } }).await?;
+            if found as usize != artists.len() {
+                return Err(AstralError::NotFound(String::from("Could not find some of the provided artists")))
+            }
+            artists
+        }
+        None => album.artists.clone(),
+    };
+    let moved_tracks: Vec<TrackMetadata> = db.tracks_metadata.find(doc! { "track_id": { "$in": &moved } }).await?.try_collect().await?;
+
+    let new_album = AlbumMetadata {
+        album_id: BsonId::new(),
+        name,
+        artists: artists.clone(),
+        tracks: moved.clone(),
+        release_date: album.release_date,
+        genres: vec![],
+        manual_genres: false,
+        added_at: Utc::now().timestamp_millis() as u64,
+        palette: None,
+        discs: vec![],
+        disc_total: None,
+        album_type: album.album_type,
+        edition: None,
+        aliases: vec![],
+    };
+    // otherwise future ingests of the split album would end up in the original one again
+    album.aliases.retain(|it| *it != new_album.name);
+
+    let mut session = db.start_transaction().await?;
+    db.albums_metadata.insert_one_with_session(&new_album, &mut session).await?;
+    db.albums_metadata.update_one_with_session(doc! { "album_id": album_id }, doc! { "$set": { "tracks": &kept, "aliases": &album.aliases } }, &mut session).await?;
+    db.tracks_metadata.replace_references_with_session(doc! { "track_id": { "$in": &moved } }, "albums", &[*album_id], &new_album.album_id, &mut session).await?;
+    if artists != album.artists {
+        // artists of the original album are replaced with the new ones, featured artists of the tracks are kept
+        let replaced: Vec<BsonId> = album.artists.iter().copied().filter(|it| !artists.contains(it)).collect();
+        for track in &moved_tracks {
+            db.tracks_metadata.update_one_with_session(doc! { "track_id": &track.track_id }, doc! {
+                "$set": { "artists": replace_artists(&track.artists, &replaced, &artists) }
+            }, &mut session).await?;
+        }
+        db.artists_metadata.update_many_with_session(doc! { "artist_id": { "$in": &replaced } }, doc! {
+            "$pull": { "tracks": { "$in": &moved } }
+        }, &mut session).await?;
+    }
+    db.artists_metadata.update_many_with_session(doc! { "artist_id": { "$in": &artists } }, doc! {
+        "$addToSet": { "albums": &new_album.album_id, "tracks": { "$each": &moved } }
+    }, &mut session).await?;
+    db.commit_transaction(&mut session).await?;
+
+    let mut affected_artists = album.artists.clone();
+    extend_unique(&mut affected_artists, &artists);
+    let albums = [*album_id, new_album.album_id];
+    refresh_genres(db, &affected_artists, &albums).await?;
+    reindex_entries(db, search, &moved, &albums, &affected_artists).await?;
+    Ok(new_album.album_id)
+}
@@ -1,90 +1,242 @@
-use audiotags::MimeType;
-use chrono::NaiveDateTime;
-use reqwest::Url;
-use crate::api::paths::lyrics::extract_lyrics_from_musix;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use crate::data::model::TrackFormat;
 use crate::err::AstralError;
-use crate::metadata::binary::extract_metadata_from_bytes;
-use crate::metadata::{AlbumArt, ExtractedTrackMetadata};
-use crate::metadata::musix::musix_request;
+use crate::metadata::ExtractedTrackMetadata;
+use crate::metadata::provider::{MetadataProvider, MetadataSource, PartialTrackMetadata, ProviderContext};
 use crate::Res;
 
-/// Attempts to extract metadata from two sources at the same time:
-/// 1. Track mp3/flac/m4a metadata
-/// 2. MusixMatch services
+/// Decides which metadata source wins for each field.
 ///
-/// It then merges these sources.
+/// Every field contains sources in the order of their priority. Sources that are not listed
+/// for a field are still used as a fallback, in the order the providers were run.
+/// By default manual overrides win every field, and fields missing from a provided policy keep that default.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct MergePolicy {
+    /// Priority of sources for the track name
+    pub name: Vec<MetadataSource>,
+    /// Priority of sources for the track and album artists
+    pub artists: Vec<MetadataSource>,
+    /// Priority of sources for the album name, type and edition
+    pub album: Vec<MetadataSource>,
+    /// Priority of sources for the album release date
+    pub release_date: Vec<MetadataSource>,
+    /// Priority of sources for the album cover art
+    pub cover: Vec<MetadataSource>,
+    /// Priority of sources for the track lyrics
+    pub lyrics: Vec<MetadataSource>,
+    /// Priority of sources for the track genres
+    pub genres: Vec<MetadataSource>,
+    /// Priority of sources for all other fields (duration, positional numbers, disc structure, explicitness)
+    pub other: Vec<MetadataSource>,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        Self::preferring(MetadataSource::Manual)
+    }
+}
+
+/// Priority list starting with manual overrides, followed by the provided sources
+fn after_manual(sources: &[MetadataSource]) -> Vec<MetadataSource> {
+    let mut priority = vec![MetadataSource::Manual];
+    priority.extend(sources.iter().copied().filter(|it| *it != MetadataSource::Manual));
+    priority
+}
+
+impl MergePolicy {
+    /// Creates a policy where the provided source wins every field after manual overrides, except the cover art,
+    /// which is still taken from the embedded tags when they have one.
+    pub fn preferring(source: MetadataSource) -> Self {
+        Self {
+            name: after_manual(&[source]),
+            artists: after_manual(&[source]),
+            album: after_manual(&[source]),
+            release_date: after_manual(&[source]),
+            cover: after_manual(&[MetadataSource::Embedded, source]),
+            lyrics: after_manual(&[source]),
+            genres: after_manual(&[source]),
+            other: after_manual(&[source]),
+        }
+    }
+}
+
+/// Report about which source each field of merged metadata came from.
+///
+/// Fields that are `null` were not provided by any source and contain default values.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct MetadataProvenance {
+    /// Source of the track name
+    pub name: Option<MetadataSource>,
+    /// Source of the track artists
+    pub artists: Option<MetadataSource>,
+    /// Source of the album artists
+    pub album_artists: Option<MetadataSource>,
+    /// Source of the album name
+    pub album: Option<MetadataSource>,
+    /// Source of the album release date
+    pub release_date: Option<MetadataSource>,
+    /// Source of the album cover art
+    pub cover: Option<MetadataSource>,
+    /// Source of the track lyrics
+    pub lyrics: Option<MetadataSource>,
+    /// Source of the track genres
+    pub genres: Option<MetadataSource>,
+    /// Source of the track duration
+    pub duration: Option<MetadataSource>,
+    /// Source of the positional track number
+    pub number: Option<MetadataSource>,
+    /// Source of the disc number
+    pub disc_number: Option<MetadataSource>,
+    /// Source of the total amount of tracks on the disc
+    pub track_total: Option<MetadataSource>,
+    /// Source of the total amount of discs
+    pub disc_total: Option<MetadataSource>,
+    /// Source of the disc subtitle
+    pub disc_subtitle: Option<MetadataSource>,
+    /// Source of the album type
+    pub album_type: Option<MetadataSource>,
+    /// Source of the album edition
+    pub edition: Option<MetadataSource>,
+    /// Source of the explicitness flag
+    pub is_explicit: Option<MetadataSource>,
+    /// Sources that failed to provide metadata, along with the error message
+    pub failed: Vec<(MetadataSource, String)>,
+}
+
+/// Attempts to extract metadata from all provided sources and merges them with the policy.
+///
+/// Providers are run in the order they are passed, each of them receiving metadata
+/// merged from the previous providers as a hint. A provider failing is not fatal,
+/// unless no source was able to provide a track name.
 pub async fn extract_merged_metadata(
     bytes: &[u8],
     format: TrackFormat,
-    prioritize_musix: bool,
-    musix_artist_override: Option<String>,
-    musix_album_override: Option<String>,
-    musix_name_override: Option<String>
-) -> Res<ExtractedTrackMetadata> {
-    let extracted = extract_metadata_from_bytes(bytes, format)?;
-    // we might need to fix track name/album/artist
+    providers: &[Box<dyn MetadataProvider>],
+    policy: &MergePolicy,
+) -> Res<(ExtractedTrackMetadata, MetadataProvenance)> {
+    let mut partials: Vec<(MetadataSource, PartialTrackMetadata)> = vec![];
+    let mut failed: Vec<(MetadataSource, AstralError)> = vec![];
+    let mut known = PartialTrackMetadata::default();
 
-    let body = musix_request(
-        musix_name_override.as_ref().unwrap_or(&extracted.name),
-        musix_artist_override.as_ref().unwrap_or(extracted.artists.first().unwrap()),
-        &musix_album_override.or(Some(extracted.album_name.clone())), &None)
-        .await?;
-
-    let status_code = body["matcher.track.get"]["message"]["header"]["status_code"].as_i64().unwrap();
-    if status_code != 200 {
-        return match status_code {
-            404 => Err(AstralError::NotFound(String::from("Could not find this track in Musixmatch"))),
-            401 => Err(AstralError::BadRequest(String::from("Timed out. Wait a few minutes before trying again."))),
-            other => Err(AstralError::BadRequest(format!("Request error {other}: {:?}", body["matcher.track.get"]["message"]["header"])))
+    for provider in providers {
+        let ctx = ProviderContext { bytes, format, known: &known };
+        match provider.provide(&ctx).await {
+            Ok(partial) => partials.push((provider.source(), partial)),
+            Err(err) => failed.push((provider.source(), err)),
         }
+        known = merge_partials(&partials, policy).0;
     }
 
-    let meta = &body["matcher.track.get"]["message"]["body"]["track"];
-
-    let name = if prioritize_musix { meta["track_name"].as_str().unwrap().to_owned() } else { extracted.name };
-    let album_name = if prioritize_musix { meta["album_name"].as_str().unwrap().to_owned() } else { extracted.album_name };
-    let release_date = if prioritize_musix || extracted.release_date == 0 {
-        NaiveDateTime::parse_from_str(meta["first_release_date"].as_str().unwrap(), "%+").unwrap().timestamp_millis() as u64
-    } else {
-        extracted.release_date
-    };
-    let artists = meta["artist_name"].as_str().unwrap();
-    let artists = if prioritize_musix { artists.split("feat.").map(str::trim).map(String::from).collect::<Vec<_>>() } else { extracted.artists };
-    let duration = if prioritize_musix || extracted.duration as i32 == 0 { meta["track_length"].as_f64().unwrap() } else { extracted.duration };
-    let is_explicit = meta["explicit"].as_i64().unwrap() == 0;
-    let cover_art = if let None = extracted.cover_art {
-        let mut cover_art = None;
-        for cover_quality in ["800x800", "500x500", "350x350", "100x100"] {
-            let cover = meta[&format!("album_coverart_{cover_quality}")].as_str().unwrap();
-            // downloading highest quality cover art
-            if !cover.is_empty() {
-                let mime_type = match cover.split(".").last().unwrap() {
-                    "jpg" => MimeType::Jpeg,
-                    "png" => MimeType::Png,
-                    other => return Err(AstralError::BadRequest(format!("Unhandled mime type! This is an error, mime type: {other}!")))
-                };
-                cover_art = Some(AlbumArt::Url(Url::parse(cover).unwrap(), mime_type));
-                    break;
-                }
-        };
-        cover_art
-    } else {
-        extracted.cover_art
+    let (merged, mut provenance) = merge_partials(&partials, policy);
+    let name = match merged.name {
+        Some(name) => name,
+        None => return Err(failed.into_iter().next().map(|(_, err)| err)
+            .unwrap_or_else(|| AstralError::BadRequest(String::from("Could not determine track name from any metadata source"))))
     };
+    provenance.failed = failed.into_iter().map(|(source, err)| (source, err.to_string())).collect();
 
-    Ok(ExtractedTrackMetadata {
+    Ok((ExtractedTrackMetadata {
         name,
-        album_name,
-        artists: artists.clone(),
-        album_artists: if prioritize_musix { artists } else { extracted.album_artists },
-        cover_art,
-        duration,
-        format: extracted.format,
-        number: extracted.number,
-        disc_number: extracted.disc_number,
-        release_date,
-        is_explicit,
-        lyrics: extract_lyrics_from_musix(&body).ok()
-    })
-}
\ No newline at end of file
+        album_name: merged.album_name.unwrap_or_default(),
+        artists: merged.artists.unwrap_or_default(),
+        album_artists: merged.album_artists.unwrap_or_default(),
+        cover_art: merged.cover_art,
+        duration: merged.duration.unwrap_or(0f64),
+        format,
+        number: merged.number.unwrap_or(0),
+        disc_number: merged.disc_number.unwrap_or(0),
+        track_total: merged.track_total.unwrap_or(0),
+        disc_total: merged.disc_total.unwrap_or(0),
+        disc_subtitle: merged.disc_subtitle,
+        album_type: merged.album_type,
+        edition: merged.edition,
+        release_date: merged.release_date.unwrap_or(0),
+        is_explicit: merged.is_explicit.unwrap_or(false),
+        lyrics: merged.lyrics,
+        genres: merged.genres.unwrap_or_default(),
+    }, provenance))
+}
+
+/// Merges partial metadata field by field using the policy
+fn merge_partials(
+    partials: &[(MetadataSource, PartialTrackMetadata)],
+    policy: &MergePolicy,
+) -> (PartialTrackMetadata, MetadataProvenance) {
+    let run_order = partials.iter().map(|(source, _)| *source).collect::<Vec<_>>();
+
+    let name = pick(partials, &policy.name, &run_order, |it| it.name.clone());
+    let artists = pick(partials, &policy.artists, &run_order, |it| it.artists.clone());
+    let album_artists = pick(partials, &policy.artists, &run_order, |it| it.album_artists.clone());
+    let album_name = pick(partials, &policy.album, &run_order, |it| it.album_name.clone());
+    let release_date = pick(partials, &policy.release_date, &run_order, |it| it.release_date);
+    let cover_art = pick(partials, &policy.cover, &run_order, |it| it.cover_art.clone());
+    let lyrics = pick(partials, &policy.lyrics, &run_order, |it| it.lyrics.clone());
+    let genres = pick(partials, &policy.genres, &run_order, |it| it.genres.clone());
+    let duration = pick(partials, &policy.other, &run_order, |it| it.duration);
+    let number = pick(partials, &policy.other, &run_order, |it| it.number);
+    let disc_number = pick(partials, &policy.other, &run_order, |it| it.disc_number);
+    let track_total = pick(partials, &policy.other, &run_order, |it| it.track_total);
+    let disc_total = pick(partials, &policy.other, &run_order, |it| it.disc_total);
+    let disc_subtitle = pick(partials, &policy.other, &run_order, |it| it.disc_subtitle.clone());
+    let album_type = pick(partials, &policy.album, &run_order, |it| it.album_type);
+    let edition = pick(partials, &policy.album, &run_order, |it| it.edition.clone());
+    let is_explicit = pick(partials, &policy.other, &run_order, |it| it.is_explicit);
+
+    let provenance = MetadataProvenance {
+        name: name.as_ref().map(|(_, source)| *source),
+        artists: artists.as_ref().map(|(_, source)| *source),
+        album_artists: album_artists.as_ref().map(|(_, source)| *source),
+        album: album_name.as_ref().map(|(_, source)| *source),
+        release_date: release_date.as_ref().map(|(_, source)| *source),
+        cover: cover_art.as_ref().map(|(_, source)| *source),
+        lyrics: lyrics.as_ref().map(|(_, source)| *source),
+        genres: genres.as_ref().map(|(_, source)| *source),
+        duration: duration.as_ref().map(|(_, source)| *source),
+        number: number.as_ref().map(|(_, source)| *source),
+        disc_number: disc_number.as_ref().map(|(_, source)| *source),
+        track_total: track_total.as_ref().map(|(_, source)| *source),
+        disc_total: disc_total.as_ref().map(|(_, source)| *source),
+        disc_subtitle: disc_subtitle.as_ref().map(|(_, source)| *source),
+        album_type: album_type.as_ref().map(|(_, source)| *source),
+        edition: edition.as_ref().map(|(_, source)| *source),
+        is_explicit: is_explicit.as_ref().map(|(_, source)| *source),
+        failed: vec![],
+    };
+
+    (PartialTrackMetadata {
+        name: name.map(|(it, _)| it),
+        album_name: album_name.map(|(it, _)| it),
+        artists: artists.map(|(it, _)| it),
+        album_artists: album_artists.map(|(it, _)| it),
+        cover_art: cover_art.map(|(it, _)| it),
+        duration: duration.map(|(it, _)| it),
+        number: number.map(|(it, _)| it),
+        disc_number: disc_number.map(|(it, _)| it),
+        track_total: track_total.map(|(it, _)| it),
+        disc_total: disc_total.map(|(it, _)| it),
+        disc_subtitle: disc_subtitle.map(|(it, _)| it),
+        album_type: album_type.map(|(it, _)| it),
+        edition: edition.map(|(it, _)| it),
+        release_date: release_date.map(|(it, _)| it),
+        is_explicit: is_explicit.map(|(it, _)| it),
+        lyrics: lyrics.map(|(it, _)| it),
+        genres: genres.map(|(it, _)| it),
+    }, provenance)
+}
+
+/// Picks the first value provided for a field, going through sources in the priority order first
+fn pick<T>(
+    partials: &[(MetadataSource, PartialTrackMetadata)],
+    priority: &[MetadataSource],
+    run_order: &[MetadataSource],
+    field: impl Fn(&PartialTrackMetadata) -> Option<T>,
+) -> Option<(T, MetadataSource)> {
+    priority.iter()
+        .chain(run_order.iter().filter(|it| !priority.contains(it)))
+        .find_map(|source| partials.iter()
+            .find(|(each, _)| each == source)
+            .and_then(|(_, partial)| field(partial))
+            .map(|value| (value, *source)))
+}
@@ -0,0 +1,142 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use reqwest::header::{HeaderValue, USER_AGENT};
+use reqwest::Url;
+use serde_json::Value;
+use crate::data::model::AlbumType;
+use crate::err::AstralError;
+use crate::metadata::AlbumArt;
+use crate::metadata::genres::normalize_genres;
+use crate::metadata::provider::{MetadataProvider, MetadataSource, PartialTrackMetadata, ProviderContext};
+use crate::Res;
+
+/// Minimal search score for a MusicBrainz recording to be considered a match
+const MIN_MATCH_SCORE: i64 = 90;
+
+/// Sends a recording search request to MusicBrainz api
+pub async fn musicbrainz_request(title: &str, artist: &str, album: &Option<String>) -> Res<Value> {
+    const BASE_URL: &str = "https://musicbrainz.org/ws/2/recording";
+
+    let mut query = format!("recording:\"{}\" AND artist:\"{}\"", escape_lucene(title), escape_lucene(artist));
+    if let Some(album) = album {
+        query.push_str(&format!(" AND release:\"{}\"", escape_lucene(album)));
+    }
+    let uri = Url::parse_with_params(BASE_URL, [
+        ("query", query.as_str()),
+        ("fmt", "json"),
+        ("limit", "1"),
+    ]).unwrap();
+
+    let client = reqwest::Client::new();
+    let json = client.get(uri)
+        .header(USER_AGENT, HeaderValue::from_static(concat!("AstralPlayer/", env!("CARGO_PKG_VERSION"), " ( https://github.com/Maxuss/AstralPlayer )")))
+        .send().await?
+        .json::<Value>().await?;
+
+    Ok(json)
+}
+
+/// Provides metadata from MusicBrainz database, using already known metadata as a search hint
+#[derive(Debug, Clone, Default)]
+pub struct MusicBrainzProvider;
+
+#[axum::async_trait]
+impl MetadataProvider for MusicBrainzProvider {
+    fn source(&self) -> MetadataSource {
+        MetadataSource::MusicBrainz
+    }
+
+    async fn provide(&self, ctx: &ProviderContext<'_>) -> Res<PartialTrackMetadata> {
+        let name = ctx.known.name.as_ref()
+            .ok_or_else(|| AstralError::BadRequest(String::from("Track name is required to search MusicBrainz")))?;
+        let artist = ctx.known.artists.as_ref().and_then(|it| it.first())
+            .ok_or_else(|| AstralError::BadRequest(String::from("Track artist is required to search MusicBrainz")))?;
+
+        let body = musicbrainz_request(name, artist, &ctx.known.album_name).await?;
+        let recording = &body["recordings"][0];
+        if !recording.is_object() || recording["score"].as_i64().unwrap_or(0) < MIN_MATCH_SCORE {
+            return Err(AstralError::NotFound(String::from("Could not find this track in MusicBrainz")))
+        }
+
+        let artists = recording["artist-credit"].as_array()
+            .map(|credits| credits.iter().filter_map(|it| it["name"].as_str()).map(String::from).collect::<Vec<_>>())
+            .filter(|it| !it.is_empty());
+        let genres = recording["tags"].as_array()
+            .map(|tags| tags.iter().filter_map(|it| it["name"].as_str()).collect::<Vec<_>>())
+            .map(|it| normalize_genres(&it))
+            .unwrap_or_default();
+        let release = &recording["releases"][0];
+        let album_artists = release["artist-credit"].as_array()
+            .map(|credits| credits.iter().filter_map(|it| it["name"].as_str()).map(String::from).collect::<Vec<_>>())
+            .filter(|it| !it.is_empty())
+            .or_else(|| artists.clone());
+        let release_group = &release["release-group"];
+        let album_type = release_group["secondary-types"].as_array().into_iter().flatten()
+            .chain(std::iter::once(&release_group["primary-type"]))
+            .filter_map(|it| it.as_str())
+            .collect::<Vec<_>>();
+        let album_type = AlbumType::parse_release_type(&album_type.join(";"));
+        let (medium, track) = recording_medium(release, recording);
+        // the archive serves covers in their original format, so the mime type is only known once downloaded
+        let cover_art = release["id"].as_str()
+            .and_then(|id| Url::parse(&format!("https://coverartarchive.org/release/{id}/front-500")).ok())
+            .map(|url| AlbumArt::Url(url, None));
+
+        Ok(PartialTrackMetadata {
+            name: recording["title"].as_str().map(String::from),
+            album_name: release["title"].as_str().map(String::from),
+            artists,
+            album_artists,
+            cover_art,
+            duration: recording["length"].as_f64().map(|it| (it / 1000f64).floor()),
+            number: track["number"].as_str().and_then(|it| it.parse().ok()),
+            disc_number: medium["position"].as_u64().map(|it| it as u16),
+            track_total: medium["track-count"].as_u64().map(|it| it as u16),
+            disc_total: None,
+            disc_subtitle: medium["title"].as_str().filter(|it| !it.is_empty()).map(String::from),
+            album_type,
+            edition: release["disambiguation"].as_str().filter(|it| !it.is_empty()).map(String::from),
+            release_date: release["date"].as_str().and_then(parse_release_date),
+            is_explicit: None,
+            lyrics: None,
+            genres: Some(genres).filter(|it| !it.is_empty()),
+        })
+    }
+}
+
+/// Finds the medium of the release containing the recording, along with the track of the recording on it.
+/// Tracks are matched by their recording ID or title, falling back to the first listed track.
+fn recording_medium<'a>(release: &'a Value, recording: &Value) -> (&'a Value, &'a Value) {
+    let is_recording = |track: &Value| {
+        (track["recording"]["id"].is_string() && track["recording"]["id"] == recording["id"])
+            || (track["title"].is_string() && track["title"] == recording["title"])
+    };
+    let media = release["media"].as_array().map(Vec::as_slice).unwrap_or_default();
+    let found = media.iter().find_map(|medium| {
+        let track = medium["track"].as_array()?.iter().find(|it| is_recording(it))?;
+        Some((medium, track))
+    });
+    found.unwrap_or((&release["media"][0], &release["media"][0]["track"][0]))
+}
+
+/// Parses MusicBrainz partial dates (`YYYY`, `YYYY-MM` or `YYYY-MM-DD`) into a millis timestamp
+fn parse_release_date(date: &str) -> Option<u64> {
+    let mut parts = date.split('-').map(str::parse::<u32>);
+    let year = parts.next()?.ok()? as i32;
+    let month = parts.next().and_then(Result::ok).unwrap_or(1);
+    let day = parts.next().and_then(Result::ok).unwrap_or(1);
+    NaiveDate::from_ymd_opt(year, month, day)
+        .and_then(|it| it.and_hms_opt(0, 0, 0))
+        .map(|it: NaiveDateTime| it.and_utc().timestamp_millis() as u64)
+}
+
+/// Escapes special characters of the Lucene query syntax used by MusicBrainz search
+fn escape_lucene(query: &str) -> String {
+    let mut escaped = String::with_capacity(query.len());
+    for c in query.chars() {
+        if "+-&|!(){}[]^\"~*?:\\/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -1,6 +1,13 @@
+use audiotags::MimeType;
+use chrono::NaiveDateTime;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Url;
 use serde_json::Value;
+use crate::api::paths::lyrics::extract_lyrics_from_musix;
+use crate::err::AstralError;
+use crate::metadata::AlbumArt;
+use crate::metadata::genres::normalize_genres;
+use crate::metadata::provider::{MetadataProvider, MetadataSource, PartialTrackMetadata, ProviderContext};
 use crate::Res;
 
 /// Sends a request to MusixMatch api
@@ -32,3 +39,93 @@ pub async fn musix_request(
 
     Ok(json["message"]["body"]["macro_calls"].clone())
 }
+
+/// Provides metadata from MusixMatch services, using already known metadata as a search hint
+#[derive(Debug, Clone, Default)]
+pub struct MusixmatchProvider {
+    /// Custom override for track artist when searching
+    pub artist_override: Option<String>,
+    /// Custom override for track album when searching
+    pub album_override: Option<String>,
+    /// Custom override for track name when searching
+    pub name_override: Option<String>,
+}
+
+#[axum::async_trait]
+impl MetadataProvider for MusixmatchProvider {
+    fn source(&self) -> MetadataSource {
+        MetadataSource::Musixmatch
+    }
+
+    async fn provide(&self, ctx: &ProviderContext<'_>) -> Res<PartialTrackMetadata> {
+        let name = self.name_override.as_ref().or(ctx.known.name.as_ref())
+            .ok_or_else(|| AstralError::BadRequest(String::from("Track name is required to search Musixmatch")))?;
+        let artist = self.artist_override.as_ref().or(ctx.known.artists.as_ref().and_then(|it| it.first()))
+            .ok_or_else(|| AstralError::BadRequest(String::from("Track artist is required to search Musixmatch")))?;
+        let album = self.album_override.clone().or_else(|| ctx.known.album_name.clone());
+
+        let body = musix_request(name, artist, &album, &None).await?;
+
+        let status_code = body["matcher.track.get"]["message"]["header"]["status_code"].as_i64()
+            .ok_or_else(|| AstralError::BadRequest(String::from("Unexpected response from Musixmatch")))?;
+        if status_code != 200 {
+            return match status_code {
+                404 => Err(AstralError::NotFound(String::from("Could not find this track in Musixmatch"))),
+                401 => Err(AstralError::BadRequest(String::from("Timed out. Wait a few minutes before trying again."))),
+                other => Err(AstralError::BadRequest(format!("Request error {other}: {:?}", body["matcher.track.get"]["message"]["header"])))
+            }
+        }
+
+        let meta = &body["matcher.track.get"]["message"]["body"]["track"];
+
+        let release_date = meta["first_release_date"].as_str()
+            .and_then(|it| NaiveDateTime::parse_from_str(it, "%+").ok())
+            .map(|it| it.and_utc().timestamp_millis() as u64);
+        let artists = meta["artist_name"].as_str()
+            .map(|it| it.split("feat.").map(str::trim).map(String::from).collect::<Vec<_>>());
+
+        let genres = meta["primary_genres"]["music_genre_list"].as_array()
+            .map(|list| list.iter().filter_map(|it| it["music_genre"]["music_genre_name"].as_str()).collect::<Vec<_>>())
+            .map(|it| normalize_genres(&it))
+            .unwrap_or_default();
+
+        let mut cover_art = None;
+        for cover_quality in ["800x800", "500x500", "350x350", "100x100"] {
+            let cover = meta[&format!("album_coverart_{cover_quality}")].as_str().unwrap_or_default();
+            // downloading highest quality cover art
+            if !cover.is_empty() {
+                let mime_type = match cover.rsplit('.').next() {
+                    Some("jpg") => MimeType::Jpeg,
+                    Some("png") => MimeType::Png,
+                    other => return Err(AstralError::BadRequest(format!("Unhandled mime type! This is an error, mime type: {other:?}!")))
+                };
+                // malformed links are skipped in favour of lower quality covers
+                let Ok(url) = Url::parse(cover) else {
+                    continue
+                };
+                cover_art = Some(AlbumArt::Url(url, Some(mime_type)));
+                break;
+            }
+        }
+
+        Ok(PartialTrackMetadata {
+            name: meta["track_name"].as_str().map(String::from),
+            album_name: meta["album_name"].as_str().map(String::from),
+            album_artists: artists.clone(),
+            artists,
+            cover_art,
+            duration: meta["track_length"].as_f64().filter(|it| *it as i32 != 0),
+            number: None,
+            disc_number: None,
+            track_total: None,
+            disc_total: None,
+            disc_subtitle: None,
+            album_type: None,
+            edition: None,
+            release_date,
+            is_explicit: meta["explicit"].as_i64().map(|it| it != 0),
+            lyrics: extract_lyrics_from_musix(&body).ok(),
+            genres: Some(genres).filter(|it| !it.is_empty()),
+        })
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use mongodb::bson::{doc, to_bson};
+use crate::data::AstralDatabase;
+use crate::data::model::{BsonId, CoverPalette};
+use crate::err::AstralError;
+use crate::Res;
+
+/// Size of the square the image is shrunk into before sampling its colours
+const SAMPLE_SIZE: u32 = 64;
+/// Bits kept from every colour channel when grouping similar colours
+const QUANTIZE_BITS: u8 = 4;
+
+/// A group of similar colours found in the image
+#[derive(Debug, Clone, Copy)]
+struct Swatch {
+    rgb: [u8; 3],
+    population: u32,
+    saturation: f32,
+    lightness: f32,
+}
+
+/// Extracts the dominant, vibrant and muted colours of an image
+pub fn extract_palette(data: &[u8]) -> Res<CoverPalette> {
+    let image = image::load_from_memory(data)?.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgba8();
+
+    let shift = 8 - QUANTIZE_BITS;
+    let mut buckets: HashMap<[u8; 3], (u64, u64, u64, u32)> = HashMap::new();
+    for pixel in image.pixels() {
+        let [r, g, b, a] = pixel.0;
+        // mostly transparent pixels are not visible, so they should not affect the palette
+        if a < 128 {
+            continue
+        }
+        let bucket = buckets.entry([r >> shift, g >> shift, b >> shift]).or_default();
+        bucket.0 += r as u64;
+        bucket.1 += g as u64;
+        bucket.2 += b as u64;
+        bucket.3 += 1;
+    }
+
+    let swatches = buckets.into_values()
+        .map(|(r, g, b, population)| {
+            let n = population as u64;
+            let rgb = [(r / n) as u8, (g / n) as u8, (b / n) as u8];
+            let (saturation, lightness) = saturation_lightness(rgb);
+            Swatch { rgb, population, saturation, lightness }
+        })
+        .collect::<Vec<_>>();
+    let dominant = swatches.iter().max_by_key(|it| it.population)
+        .ok_or_else(|| AstralError::BadRequest(String::from("Image does not have any visible pixels")))?;
+    let max_population = dominant.population as f32;
+
+    let vibrant = best_swatch(&swatches, max_population, 1.0, 0.5).unwrap_or(dominant);
+    let muted = best_swatch(&swatches, max_population, 0.3, 0.5).unwrap_or(dominant);
+
+    Ok(CoverPalette {
+        dominant: to_hex(dominant.rgb),
+        vibrant: to_hex(vibrant.rgb),
+        muted: to_hex(muted.rgb),
+    })
+}
+
+/// Finds the swatch closest to the target saturation and lightness, preferring more common colours.
+/// Very dark and very light swatches are skipped.
+fn best_swatch(swatches: &[Swatch], max_population: f32, saturation: f32, lightness: f32) -> Option<&Swatch> {
+    let score = |it: &Swatch| {
+        (1.0 - (it.saturation - saturation).abs()) * 3.0
+            + (1.0 - (it.lightness - lightness).abs()) * 6.0
+            + it.population as f32 / max_population
+    };
+    swatches.iter()
+        .filter(|it| (0.2..=0.8).contains(&it.lightness) && (it.saturation - saturation).abs() <= 0.35)
+        .max_by(|a, b| score(a).total_cmp(&score(b)))
+}
+
+/// HSL saturation and lightness of the colour, both from `0` to `1`
+fn saturation_lightness([r, g, b]: [u8; 3]) -> (f32, f32) {
+    let max = r.max(g).max(b) as f32 / 255.0;
+    let min = r.min(g).min(b) as f32 / 255.0;
+    let lightness = (max + min) / 2.0;
+    let saturation = if max == min {
+        0.0
+    } else {
+        (max - min) / (1.0 - (2.0 * lightness - 1.0).abs())
+    };
+    (saturation, lightness)
+}
+
+fn to_hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Extracts palette from the new cover of an album and stores it. The palette is removed if the cover can not be decoded.
+pub async fn refresh_album_palette(db: &AstralDatabase, album_id: &BsonId, cover: Vec<u8>) -> Res<()> {
+    let palette = tokio::task::spawn_blocking(move || extract_palette(&cover).ok()).await
+        .map_err(|err| AstralError::Unknown(err.into()))?;
+    db.albums_metadata.update_one(doc! { "album_id": album_id }, doc! { "$set": { "palette": to_bson(&palette).map_err(anyhow::Error::from)? } }).await?;
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use audiotags::MimeType;
+use mongodb::bson::doc;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use crate::data::AstralDatabase;
+use crate::data::model::{AlbumType, LyricsStatus, TrackFormat};
+use crate::err::AstralError;
+use crate::metadata::{album_edition, name_or_alias, AlbumArt, ExtractedTrackMetadata};
+use crate::metadata::binary::extract_metadata_from_bytes;
+use crate::metadata::cover::check_cover_url;
+use crate::metadata::genres::normalize_genres;
+use crate::Res;
+
+/// Proposed metadata for a track that was not committed yet. Can be edited before committing.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MetadataProposal {
+    /// Track name
+    #[schema(example = "AMPM Truck")]
+    pub name: String,
+    /// Name of the album
+    #[schema(example = "Kiss My Super Bowl Ring")]
+    pub album_name: String,
+    /// Artists who worked on this track
+    pub artists: Vec<String>,
+    /// Artists who worked on this album
+    pub album_artists: Vec<String>,
+    /// Cover art of this track's album
+    pub cover: ProposedCover,
+    /// Duration of this track in seconds
+    #[schema(example = 340)]
+    pub duration: f64,
+    /// Index of this track in the album
+    pub number: u16,
+    /// Number of the disc this track appears on
+    pub disc_number: u16,
+    /// Total amount of tracks on the disc, `0` if unknown
+    #[serde(default)]
+    pub track_total: u16,
+    /// Total amount of discs in the album, `0` if unknown
+    #[serde(default)]
+    pub disc_total: u16,
+    /// Subtitle of the disc this track appears on
+    #[serde(default)]
+    pub disc_subtitle: Option<String>,
+    /// Type of the album release
+    #[serde(default)]
+    pub album_type: Option<AlbumType>,
+    /// Edition or version of the album release
+    #[serde(default)]
+    pub edition: Option<String>,
+    /// Unix timestamp in millis of the album release date
+    pub release_date: u64,
+    /// Whether this track contains explicit lyrics
+    pub is_explicit: bool,
+    /// Lyrics of this track
+    #[schema(value_type = Option<Object>)]
+    pub lyrics: Option<LyricsStatus>,
+    /// Genres of this track
+    #[serde(default)]
+    pub genres: Vec<String>,
+}
+
+/// Where the proposed cover art will be taken from
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "source")]
+pub enum ProposedCover {
+    /// No cover art will be assigned
+    None,
+    /// Cover art embedded inside the uploaded file
+    Embedded,
+    /// Cover art downloaded from the url
+    Url {
+        /// Url to download the cover from
+        url: String,
+        /// Mime type of the cover, if it is known before downloading it
+        #[schema(example = "image/jpeg")]
+        #[serde(default)]
+        mime_type: Option<String>,
+    },
+}
+
+/// Existing database entries the proposed metadata would attach to, and entries that would be newly created
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct ProposalAttachments {
+    /// UUID of an already existing track with the same name and length.
+    /// Committing will not insert a new track if this is present.
+    pub existing_track: Option<Uuid>,
+    /// Existing album with the proposed name and edition, as album ID to album name pair
+    pub existing_album: Option<(Uuid, String)>,
+    /// Existing artists with the proposed names, as artist ID to artist name pairs
+    pub existing_artists: Vec<(Uuid, String)>,
+    /// Name of the album that would be newly created
+    pub new_album: Option<String>,
+    /// Names of the artists that would be newly created
+    pub new_artists: Vec<String>,
+}
+
+impl From<ExtractedTrackMetadata> for MetadataProposal {
+    fn from(value: ExtractedTrackMetadata) -> Self {
+        Self {
+            name: value.name,
+            album_name: value.album_name,
+            artists: value.artists,
+            album_artists: value.album_artists,
+            cover: match value.cover_art {
+                None => ProposedCover::None,
+                Some(AlbumArt::Bytes(_)) => ProposedCover::Embedded,
+                Some(AlbumArt::Url(url, mime)) => ProposedCover::Url { url: url.to_string(), mime_type: mime.map(String::from) },
+            },
+            duration: value.duration,
+            number: value.number,
+            disc_number: value.disc_number,
+            track_total: value.track_total,
+            disc_total: value.disc_total,
+            disc_subtitle: value.disc_subtitle,
+            album_type: value.album_type,
+            edition: value.edition,
+            release_date: value.release_date,
+            is_explicit: value.is_explicit,
+            lyrics: value.lyrics,
+            genres: value.genres,
+        }
+    }
+}
+
+impl MetadataProposal {
+    /// Converts the proposal back into extracted metadata, reading the embedded cover from the audio file if needed
+    pub fn into_extracted(self, bytes: &[u8], format: TrackFormat) -> Res<ExtractedTrackMetadata> {
+        if self.name.is_empty() {
+            return Err(AstralError::BadRequest(String::from("Track name can not be empty")))
+        }
+        let cover_art = match self.cover {
+            ProposedCover::None => None,
+            ProposedCover::Embedded => extract_metadata_from_bytes(bytes, format)?.cover_art,
+            ProposedCover::Url { url, mime_type } => {
+                let url = Url::parse(&url).map_err(|_| AstralError::BadRequest(String::from("Invalid cover url")))?;
+                check_cover_url(&url)?;
+                let mime = mime_type.map(|it| MimeType::try_from(it.as_str()).map_err(|_| AstralError::BadRequest(format!("Unsupported cover mime type: {it}")))).transpose()?;
+                Some(AlbumArt::Url(url, mime))
+            }
+        };
+        Ok(ExtractedTrackMetadata {
+            name: self.name,
+            album_name: self.album_name,
+            artists: self.artists,
+            album_artists: self.album_artists,
+            cover_art,
+            duration: self.duration,
+            format,
+            number: self.number,
+            disc_number: self.disc_number,
+            track_total: self.track_total,
+            disc_total: self.disc_total,
+            disc_subtitle: self.disc_subtitle.filter(|it| !it.is_empty()),
+            album_type: self.album_type,
+            edition: self.edition.filter(|it| !it.is_empty()),
+            release_date: self.release_date,
+            is_explicit: self.is_explicit,
+            lyrics: self.lyrics,
+            genres: normalize_genres(&self.genres),
+        })
+    }
+}
+
+/// Finds which existing entries the metadata would attach to when inserted with [crate::metadata::classify_insert_metadata],
+/// without modifying the database.
+pub async fn preview_attachments(db: &AstralDatabase, metadata: &ExtractedTrackMetadata) -> Res<ProposalAttachments> {
+    let mut attachments = ProposalAttachments::default();
+    if let Some(track) = db.tracks_metadata.find_one(doc! { "name": &metadata.name, "length": metadata.duration as u32 }).await? {
+        attachments.existing_track = Some(track.track_id.to_uuid_1());
+        return Ok(attachments)
+    }
+
+    match db.albums_metadata.find_one(album_edition(&metadata.album_name, metadata.edition.as_deref())).await? {
+        Some(album) => attachments.existing_album = Some((album.album_id.to_uuid_1(), album.name)),
+        None => attachments.new_album = Some(metadata.album_name.clone()),
+    }
+
+    for artist in metadata.album_artists.iter().chain(metadata.artists.iter()) {
+        if attachments.new_artists.contains(artist) || attachments.existing_artists.iter().any(|(_, name)| name == artist) {
+            continue
+        }
+        match db.artists_metadata.find_one(name_or_alias(artist)).await? {
+            // several aliases can resolve to the same artist
+            Some(found) if attachments.existing_artists.iter().any(|(id, _)| *id == found.artist_id.to_uuid_1()) => {}
+            Some(found) => attachments.existing_artists.push((found.artist_id.to_uuid_1(), found.name)),
+            None => attachments.new_artists.push(artist.clone()),
+        }
+    }
+
+    Ok(attachments)
+}
@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::data::model::{AlbumType, LyricsStatus, TrackFormat};
+use crate::metadata::AlbumArt;
+use crate::metadata::genres::normalize_genres;
+use crate::Res;
+
+/// A single source of track metadata
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataSource {
+    /// Tags embedded inside the uploaded audio file
+    Embedded,
+    /// MusixMatch services
+    Musixmatch,
+    /// MusicBrainz database
+    MusicBrainz,
+    /// Values provided manually by the uploader
+    Manual,
+}
+
+/// Metadata for a single track as provided by a single source. Every field is optional,
+/// as most sources only know a part of the metadata.
+#[derive(Debug, Clone, Default)]
+pub struct PartialTrackMetadata {
+    /// Track name
+    pub name: Option<String>,
+    /// Name of the album
+    pub album_name: Option<String>,
+    /// Artists who worked on this track
+    pub artists: Option<Vec<String>>,
+    /// Artists who worked on this album
+    pub album_artists: Option<Vec<String>>,
+    /// Cover art of this track's album
+    pub cover_art: Option<AlbumArt>,
+    /// Duration of this track in seconds
+    pub duration: Option<f64>,
+    /// Index of this track in the album
+    pub number: Option<u16>,
+    /// Number of the disc this track appears on
+    pub disc_number: Option<u16>,
+    /// Total amount of tracks on the disc
+    pub track_total: Option<u16>,
+    /// Total amount of discs in the album
+    pub disc_total: Option<u16>,
+    /// Subtitle of the disc this track appears on
+    pub disc_subtitle: Option<String>,
+    /// Type of the album release
+    pub album_type: Option<AlbumType>,
+    /// Edition or version of the album release
+    pub edition: Option<String>,
+    /// Unix timestamp of the album release date
+    pub release_date: Option<u64>,
+    /// Whether this track contains explicit lyrics
+    pub is_explicit: Option<bool>,
+    /// Lyrics of this track
+    pub lyrics: Option<LyricsStatus>,
+    /// Normalised genres of this track
+    pub genres: Option<Vec<String>>,
+}
+
+/// Data available to a provider when it is asked for metadata
+pub struct ProviderContext<'a> {
+    /// Raw bytes of the audio file
+    pub bytes: &'a [u8],
+    /// Format of the audio file
+    pub format: TrackFormat,
+    /// Metadata merged from all providers that ran before this one.
+    /// Remote providers use it as a search hint.
+    pub known: &'a PartialTrackMetadata,
+}
+
+/// A source of track metadata that can take part in merging
+#[axum::async_trait]
+pub trait MetadataProvider: Send + Sync {
+    /// The source this provider represents
+    fn source(&self) -> MetadataSource;
+
+    /// Provides all metadata this source knows about the track
+    async fn provide(&self, ctx: &ProviderContext<'_>) -> Res<PartialTrackMetadata>;
+}
+
+/// Values provided manually by the uploader
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+pub struct ManualOverrides {
+    /// Track name
+    #[schema(example = "AMPM Truck")]
+    pub name: Option<String>,
+    /// Name of the album
+    #[schema(example = "Kiss My Super Bowl Ring")]
+    pub album_name: Option<String>,
+    /// Artists who worked on this track
+    pub artists: Option<Vec<String>>,
+    /// Artists who worked on this album
+    pub album_artists: Option<Vec<String>>,
+    /// Index of this track in the album
+    pub number: Option<u16>,
+    /// Number of the disc this track appears on
+    pub disc_number: Option<u16>,
+    /// Total amount of tracks on the disc
+    pub track_total: Option<u16>,
+    /// Total amount of discs in the album
+    pub disc_total: Option<u16>,
+    /// Subtitle of the disc this track appears on
+    #[schema(example = "Live at Wembley")]
+    pub disc_subtitle: Option<String>,
+    /// Type of the album release
+    pub album_type: Option<AlbumType>,
+    /// Edition or version of the album release
+    #[schema(example = "Deluxe Edition")]
+    pub edition: Option<String>,
+    /// Unix timestamp in millis of the album release date
+    pub release_date: Option<u64>,
+    /// Whether this track contains explicit lyrics
+    pub is_explicit: Option<bool>,
+    /// Genres of this track
+    pub genres: Option<Vec<String>>,
+}
+
+/// Provides metadata overridden manually by the uploader
+pub struct ManualOverrideProvider(pub ManualOverrides);
+
+#[axum::async_trait]
+impl MetadataProvider for ManualOverrideProvider {
+    fn source(&self) -> MetadataSource {
+        MetadataSource::Manual
+    }
+
+    async fn provide(&self, _ctx: &ProviderContext<'_>) -> Res<PartialTrackMetadata> {
+        let overrides = self.0.clone();
+        Ok(PartialTrackMetadata {
+            name: overrides.name,
+            album_name: overrides.album_name,
+            album_artists: overrides.album_artists.or_else(|| overrides.artists.clone()),
+            artists: overrides.artists,
+            number: overrides.number,
+            disc_number: overrides.disc_number,
+            track_total: overrides.track_total,
+            disc_total: overrides.disc_total,
+            disc_subtitle: overrides.disc_subtitle,
+            album_type: overrides.album_type,
+            edition: overrides.edition,
+            release_date: overrides.release_date,
+            is_explicit: overrides.is_explicit,
+            genres: overrides.genres.map(|it| normalize_genres(&it)).filter(|it| !it.is_empty()),
+            ..Default::default()
+        })
+    }
+}
@@ -0,0 +1,271 @@
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Datelike};
+use futures_util::StreamExt;
+use mongodb::bson::doc;
+use crate::data::AstralDatabase;
+use crate::data::model::{ArtistMetadata, BsonId, LyricsStatus, TrackFormat, TrackMetadata};
+use crate::err::AstralError;
+use crate::metadata::artwork::{read_artwork, ArtworkKind};
+use crate::Res;
+
+/// All metadata that is written back into the audio file tags
+#[derive(Debug, Clone)]
+pub struct TagPayload {
+    /// Track name
+    pub name: String,
+    /// Names of artists who worked on this track
+    pub artists: Vec<String>,
+    /// Name of the album
+    pub album_name: Option<String>,
+    /// Names of artists who worked on this album
+    pub album_artists: Vec<String>,
+    /// Index of this track in the album
+    pub number: u16,
+    /// Number of the disc this track appears on
+    pub disc_number: u16,
+    /// Milliseconds unix timestamp of the album release date
+    pub release_date: Option<u64>,
+    /// Whether this track contains explicit lyrics
+    pub is_explicit: bool,
+    /// Genres of this track
+    pub genres: Vec<String>,
+    /// Lyrics of this track
+    pub lyrics: Option<LyricsStatus>,
+    /// Cover art bytes and their mime type
+    pub cover: Option<(Vec<u8>, String)>,
+}
+
+/// Writes current database metadata of a track into the tags of its stored file.
+/// Transcoded copies of the track are removed, so they are regenerated with new tags.
+pub async fn write_track_tags(db: &AstralDatabase, track_id: BsonId) -> Res<()> {
+    let track = db.tracks_metadata.find_one(doc! { "track_id": &track_id }).await?
+        .ok_or_else(|| AstralError::NotFound(format!("Could not find track with UUID: {track_id}")))?;
+    let format = track.format;
+    let payload = collect_tag_payload(db, track).await?;
+
+    let files_dir = Path::new("astral_tracks");
+    let filename = format!("{track_id}.bin");
+    let path = files_dir.join(&filename);
+    tokio::task::spawn_blocking(move || write_tags_to_file(&path, format, &payload)).await
+        .map_err(anyhow::Error::from)??;
+
+    for transcoded in ["transcoded_low", "transcoded_medium"] {
+        let path = files_dir.join(transcoded).join(&filename);
+        if path.exists() {
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Collects database metadata of a track into tag payload
+pub async fn collect_tag_payload(db: &AstralDatabase, track: TrackMetadata) -> Res<TagPayload> {
+    let artists = db.artists_metadata.find(doc! { "artist_id": { "$in": &track.artists } }).await?
+        .filter_map(|each| async { each.ok() })
+        .collect::<Vec<ArtistMetadata>>().await;
+    // keeping the order of artists assigned to the track
+    let artist_names = track.artists.iter()
+        .filter_map(|id| artists.iter().find(|it| &it.artist_id == id))
+        .map(|it| it.name.clone())
+        .collect::<Vec<_>>();
+
+    let album = match track.albums.first() {
+        Some(album_id) => db.albums_metadata.find_one(doc! { "album_id": album_id }).await?,
+        None => None
+    };
+    let album_artists = match &album {
+        Some(album) => db.artists_metadata.find(doc! { "artist_id": { "$in": &album.artists } }).await?
+            .filter_map(|each| async { each.ok() })
+            .map(|it| it.name)
+            .collect::<Vec<_>>().await,
+        None => vec![]
+    };
+    // per-track artwork takes precedence over the album cover
+    let cover = match read_artwork(db, ArtworkKind::TrackArt, &track.track_id).await? {
+        Some(cover) => Some(cover),
+        None => match &album {
+            Some(album) => read_artwork(db, ArtworkKind::AlbumCover, &album.album_id).await?,
+            None => None
+        }
+    };
+    let lyrics = db.lyrics.find_one(doc! { "track_id": &track.track_id }).await?.map(|it| it.status);
+
+    Ok(TagPayload {
+        name: track.name,
+        artists: artist_names,
+        album_name: album.as_ref().map(|it| it.name.clone()),
+        album_artists,
+        number: track.number,
+        disc_number: track.disc_number,
+        release_date: album.as_ref().map(|it| it.release_date).filter(|it| *it != 0),
+        is_explicit: track.is_explicit,
+        genres: track.genres,
+        lyrics,
+        cover,
+    })
+}
+
+/// Guesses mime type of an image from its magic bytes
+pub fn guess_image_mime(data: &[u8]) -> &'static str {
+    if data.starts_with(&[0x89, b'P', b'N', b'G']) {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Writes tag payload into the audio file at the provided path.
+/// Tags are written into a copy of the file in the same directory, which then replaces the original,
+/// so a failed or interrupted write never leaves a partially written audio file behind.
+pub fn write_tags_to_file(path: &PathBuf, format: TrackFormat, payload: &TagPayload) -> Res<()> {
+    let file_name = path.file_name().ok_or_else(|| AstralError::BadRequest(String::from("Invalid audio file path")))?;
+    let mut temp_name = file_name.to_os_string();
+    temp_name.push(".tagging");
+    let temp = path.with_file_name(temp_name);
+
+    let written = std::fs::copy(path, &temp).map_err(AstralError::from)
+        .and_then(|_| write_tags_in_place(&temp, format, payload))
+        .and_then(|_| Ok(std::fs::File::open(&temp)?.sync_all()?))
+        .and_then(|_| Ok(std::fs::rename(&temp, path)?));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Writes tag payload directly into the audio file at the provided path
+fn write_tags_in_place(path: &PathBuf, format: TrackFormat, payload: &TagPayload) -> Res<()> {
+    let release_date = payload.release_date
+        .and_then(|it| DateTime::from_timestamp_millis(it as i64));
+    let lyrics = payload.lyrics.as_ref().and_then(lyrics_to_text);
+
+    match format {
+        TrackFormat::Flac => {
+            let mut tag = metaflac::Tag::read_from_path(path)?;
+            let comments = tag.vorbis_comments_mut();
+            comments.set_title(vec![payload.name.clone()]);
+            comments.set_artist(payload.artists.clone());
+            if let Some(album) = &payload.album_name {
+                comments.set_album(vec![album.clone()]);
+            }
+            comments.set_album_artist(payload.album_artists.clone());
+            comments.set_track(payload.number as u32);
+            comments.set("DISCNUMBER", vec![payload.disc_number.to_string()]);
+            if let Some(date) = release_date {
+                comments.set("DATE", vec![date.format("%Y-%m-%d").to_string()]);
+            }
+            comments.set_genre(payload.genres.clone());
+            comments.set("ITUNESADVISORY", vec![if payload.is_explicit { "1" } else { "0" }]);
+            match &lyrics {
+                Some(lyrics) => comments.set_lyrics(vec![lyrics.clone()]),
+                None => comments.remove_lyrics(),
+            }
+            if let Some((data, mime)) = &payload.cover {
+                tag.remove_picture_type(metaflac::block::PictureType::CoverFront);
+                tag.add_picture(mime.clone(), metaflac::block::PictureType::CoverFront, data.clone());
+            }
+            tag.save()?;
+        }
+        TrackFormat::Mp3 => {
+            use id3::TagLike;
+
+            let mut tag = id3::Tag::read_from_path(path).unwrap_or_else(|_| id3::Tag::new());
+            tag.set_title(&payload.name);
+            // id3v2.4 separates multiple values with a null character
+            tag.set_artist(payload.artists.join("\0"));
+            if let Some(album) = &payload.album_name {
+                tag.set_album(album);
+            }
+            tag.set_album_artist(payload.album_artists.join("\0"));
+            tag.set_track(payload.number as u32);
+            tag.set_disc(payload.disc_number as u32);
+            tag.set_genre(payload.genres.join("\0"));
+            if let Some(date) = release_date {
+                tag.set_year(date.year());
+                tag.set_date_released(id3::Timestamp {
+                    year: date.year(),
+                    month: Some(date.month() as u8),
+                    day: Some(date.day() as u8),
+                    hour: None,
+                    minute: None,
+                    second: None,
+                });
+            }
+            tag.remove_extended_text(Some("ITUNESADVISORY"), None);
+            tag.add_frame(id3::frame::ExtendedText {
+                description: String::from("ITUNESADVISORY"),
+                value: String::from(if payload.is_explicit { "1" } else { "0" }),
+            });
+            tag.remove_all_lyrics();
+            tag.remove_all_synchronised_lyrics();
+            if let Some(LyricsStatus::Synced { lines }) = &payload.lyrics {
+                tag.add_frame(id3::frame::SynchronisedLyrics {
+                    lang: String::from("eng"),
+                    timestamp_format: id3::frame::TimestampFormat::Ms,
+                    content_type: id3::frame::SynchronisedLyricsType::Lyrics,
+                    description: String::new(),
+                    content: lines.iter().map(|it| (it.start_time_ms, it.line.clone())).collect(),
+                });
+            }
+            if let Some(lyrics) = &lyrics {
+                tag.add_frame(id3::frame::Lyrics {
+                    lang: String::from("eng"),
+                    description: String::new(),
+                    text: lyrics.clone(),
+                });
+            }
+            if let Some((data, mime)) = &payload.cover {
+                tag.remove_picture_by_type(id3::frame::PictureType::CoverFront);
+                tag.add_frame(id3::frame::Picture {
+                    mime_type: mime.clone(),
+                    picture_type: id3::frame::PictureType::CoverFront,
+                    description: String::new(),
+                    data: data.clone(),
+                });
+            }
+            tag.write_to_path(path, id3::Version::Id3v24)?;
+        }
+        TrackFormat::M4a => {
+            let mut tag = mp4ameta::Tag::read_from_path(path)?;
+            tag.set_title(&payload.name);
+            tag.set_artists(payload.artists.clone());
+            if let Some(album) = &payload.album_name {
+                tag.set_album(album);
+            }
+            tag.set_album_artists(payload.album_artists.clone());
+            tag.set_track_number(payload.number);
+            tag.set_disc_number(payload.disc_number);
+            tag.set_genres(payload.genres.clone());
+            if let Some(date) = release_date {
+                tag.set_year(date.format("%Y-%m-%d").to_string());
+            }
+            tag.set_advisory_rating(if payload.is_explicit { mp4ameta::AdvisoryRating::Explicit } else { mp4ameta::AdvisoryRating::Clean });
+            match &lyrics {
+                Some(lyrics) => tag.set_lyrics(lyrics),
+                None => tag.remove_lyrics(),
+            }
+            if let Some((data, mime)) = &payload.cover {
+                let image = match mime.as_str() {
+                    "image/png" => mp4ameta::Img::png(data.clone()),
+                    "image/bmp" => mp4ameta::Img::bmp(data.clone()),
+                    _ => mp4ameta::Img::jpeg(data.clone()),
+                };
+                tag.set_artwork(image);
+            }
+            tag.write_to_path(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Converts lyrics into plain text. Synced lyrics are converted into LRC format
+fn lyrics_to_text(lyrics: &LyricsStatus) -> Option<String> {
+    match lyrics {
+        LyricsStatus::NoLyrics { .. } => None,
+        LyricsStatus::Unsynced { lines } => Some(lines.join("\n")),
+        LyricsStatus::Synced { lines } => Some(lines.iter()
+            .map(|it| format!("[{:02}:{:02}.{:02}]{}", it.start_time_ms / 60000, (it.start_time_ms / 1000) % 60, (it.start_time_ms % 1000) / 10, it.line))
+            .collect::<Vec<_>>()
+            .join("\n")),
+    }
+}
@@ -0,0 +1,99 @@
+pub mod engine;
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Minimal score for a text to be considered matching a query
+pub const MIN_MATCH_SCORE: f32 = 0.5;
+
+/// Lowercases the text and strips diacritics from it, so `Beyoncé` matches `beyonce`
+pub fn fold_text(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Splits folded text into alphanumeric words
+pub fn tokenize(text: &str) -> Vec<String> {
+    fold_text(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|it| !it.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Amount of typos tolerated in a word of this length
+pub fn allowed_typos(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Levenshtein distance between two words, giving up once it exceeds the limit
+fn edit_distance(a: &[char], b: &[char], limit: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > limit {
+        return None
+    }
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        if current.iter().min().copied().unwrap_or(0) > limit {
+            return None
+        }
+        previous = current;
+    }
+    Some(previous[b.len()]).filter(|it| *it <= limit)
+}
+
+/// Scores how well a single query word matches a single text word
+fn score_word(query: &str, word: &str) -> f32 {
+    if query == word {
+        return 1.0
+    }
+    if word.starts_with(query) {
+        return 0.8
+    }
+    let query = query.chars().collect::<Vec<_>>();
+    let word = word.chars().collect::<Vec<_>>();
+    let limit = allowed_typos(query.len());
+    if limit == 0 {
+        return 0.0
+    }
+    // also compare against the word prefix, so typos in partially typed words are tolerated
+    let prefix = &word[..word.len().min(query.len())];
+    match edit_distance(&query, &word, limit).or_else(|| edit_distance(&query, prefix, limit).map(|it| it + 1)) {
+        Some(distance) => 0.7 - 0.15 * distance as f32,
+        None => 0.0,
+    }
+}
+
+/// Scores how well the text matches the tokenized query, from `0` to `1.4`.
+///
+/// Every query word is matched against the best fitting text word, tolerating typos.
+/// Texts that contain the whole folded query get a bonus of `0.1`, texts starting with it `0.2`, and texts equal to it `0.4`.
+pub fn match_score(query: &[String], text: &str) -> f32 {
+    if query.is_empty() {
+        return 0.0
+    }
+    let words = tokenize(text);
+    let total = query.iter()
+        .map(|q| words.iter().map(|w| score_word(q, w)).fold(0f32, f32::max))
+        .sum::<f32>();
+    let mut score = total / query.len() as f32;
+    if words.starts_with(query) {
+        score += 0.2;
+        if words.len() == query.len() {
+            score += 0.2;
+        }
+    } else if words.windows(query.len()).any(|it| it == query) {
+        score += 0.1;
+    }
+    score
+}
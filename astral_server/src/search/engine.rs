@@ -0,0 +1,524 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use futures_util::{StreamExt, TryStreamExt};
+use mongodb::bson::doc;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, BoostQuery, FuzzyTermQuery, Occur, Query, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED, STORED, STRING};
+use tantivy::tokenizer::{TextAnalyzer, WhitespaceTokenizer};
+use tantivy::{DocId, Index, IndexReader, IndexWriter, ReloadPolicy, Score, SegmentReader, TantivyDocument, TantivyError, Term};
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use crate::data::AstralDatabase;
+use crate::data::model::{AlbumMetadata, ArtistMetadata, BsonId, LyricsStatus, TrackLyrics, TrackMetadata};
+use crate::err::AstralError;
+use crate::search::{allowed_typos, match_score, tokenize, MIN_MATCH_SCORE};
+use crate::Res;
+
+/// Directory the search index is stored in
+const INDEX_DIR: &str = "astral_search";
+/// Version of the index schema. Every version is stored in its own subdirectory, so an index with an outdated schema
+/// is never opened, and the new one is filled by the rebuild on startup.
+const SCHEMA_VERSION: u32 = 2;
+/// Kind of indexed lyric lines. Lines are not a [SearchKind], as they are only found through [SearchIndex::search_lyrics].
+const LYRICS_KIND: &str = "lyrics";
+/// Amount of candidate lines fetched from the index for every requested lyrics hit, as only the best line of each track is kept
+const LYRICS_CANDIDATES_PER_HIT: usize = 10;
+/// Name of the tokenizer used for text fields. Text is folded before indexing, so it only splits on whitespace.
+const TOKENIZER: &str = "astral_folded";
+/// Maximal length of indexed word prefixes
+const MAX_PREFIX_LEN: usize = 20;
+/// Memory budget of the index writer in bytes
+const WRITER_MEMORY: usize = 50_000_000;
+
+/// Kind of a searchable entry
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchKind {
+    /// A single track
+    Track,
+    /// An album
+    Album,
+    /// An artist
+    Artist,
+}
+
+impl SearchKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            SearchKind::Track => "track",
+            SearchKind::Album => "album",
+            SearchKind::Artist => "artist",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "track" => Some(SearchKind::Track),
+            "album" => Some(SearchKind::Album),
+            "artist" => Some(SearchKind::Artist),
+            _ => None,
+        }
+    }
+}
+
+/// A single entry stored in the search index
+#[derive(Debug, Clone)]
+pub struct SearchEntry {
+    /// UUID of the track, album or artist
+    pub id: Uuid,
+    /// Kind of the entry
+    pub kind: SearchKind,
+    /// Name of the entry
+    pub name: String,
+    /// Total play count of the entry, used to rank popular entries higher
+    pub popularity: u64,
+    /// Whether the entry is or contains explicit tracks
+    pub explicit: bool,
+}
+
+/// Lyrics of a single track stored in the search index
+#[derive(Debug, Clone)]
+pub struct LyricsEntry {
+    /// UUID of the track
+    pub track_id: Uuid,
+    /// Lines of the lyrics along with their start time if they are synced
+    pub lines: Vec<(Option<u32>, String)>,
+}
+
+impl From<TrackLyrics> for LyricsEntry {
+    fn from(value: TrackLyrics) -> Self {
+        let lines = match value.status {
+            LyricsStatus::NoLyrics { .. } => vec![],
+            LyricsStatus::Synced { lines } => lines.into_iter().map(|it| (Some(it.start_time_ms), it.line)).collect(),
+            LyricsStatus::Unsynced { lines } => lines.into_iter().map(|it| (None, it)).collect(),
+        };
+        Self { track_id: value.track_id.to_uuid_1(), lines }
+    }
+}
+
+/// A single lyric line found in the search index
+#[derive(Debug, Clone)]
+pub struct LyricsMatch {
+    /// UUID of the track
+    pub track_id: Uuid,
+    /// The matching line
+    pub line: String,
+    /// Start time of the line in milliseconds if the lyrics are synced
+    pub start_time_ms: Option<u32>,
+    /// Relevance of this match, see [match_score]
+    pub score: f32,
+}
+
+/// A single entry found in the search index
+#[derive(Debug, Clone)]
+pub struct SearchMatch {
+    /// UUID of the track, album or artist
+    pub id: Uuid,
+    /// Kind of the entry
+    pub kind: SearchKind,
+    /// Name of the entry
+    pub name: String,
+    /// Relevance of this match, boosted by popularity
+    pub score: f32,
+}
+
+#[derive(Clone, Copy)]
+struct SearchFields {
+    id: Field,
+    kind: Field,
+    name: Field,
+    words: Field,
+    prefixes: Field,
+    popularity: Field,
+    explicit: Field,
+    lyrics_of: Field,
+    start_time: Field,
+}
+
+/// Embedded full text index of track, album and artist names and lyric lines.
+///
+/// Supports prefix and typo tolerant search, and ranks popular entries higher.
+/// Has to be kept in sync with the database with [reindex_entries] whenever entries are changed.
+/// Changes are committed in batches with [SearchIndex::commit], changes that were not committed before the server
+/// stopped are restored by the rebuild on startup.
+#[derive(Clone)]
+pub struct SearchIndex {
+    reader: IndexReader,
+    writer: Arc<Mutex<IndexWriter>>,
+    fields: SearchFields,
+    /// Whether the writer holds changes that were not committed yet
+    uncommitted: Arc<AtomicBool>,
+    /// Held by [reindex_entries] while it reads and stages changes, and exclusively by [rebuild_search_index]
+    /// for the whole rebuild, so the rebuild can not overwrite changes made while it reads the database
+    rebuilding: Arc<tokio::sync::RwLock<()>>,
+}
+
+impl SearchIndex {
+    /// Opens the search index in the default directory, creating it if it does not exist yet
+    pub fn open() -> Res<Self> {
+        Self::open_in(&Path::new(INDEX_DIR).join(format!("v{SCHEMA_VERSION}")))
+    }
+
+    /// Opens the search index in the directory, creating it if it does not exist yet.
+    /// Fails if the directory contains an index with a different schema.
+    pub fn open_in(path: &Path) -> Res<Self> {
+        std::fs::create_dir_all(path)?;
+
+        let mut schema = Schema::builder();
+        let text = TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer(TOKENIZER)
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions)
+        );
+        let fields = SearchFields {
+            id: schema.add_text_field("id", STRING | STORED),
+            kind: schema.add_text_field("kind", STRING | STORED),
+            name: schema.add_text_field("name", STORED),
+            words: schema.add_text_field("words", text.clone()),
+            prefixes: schema.add_text_field("prefixes", text),
+            popularity: schema.add_u64_field("popularity", FAST | STORED),
+            explicit: schema.add_bool_field("explicit", INDEXED),
+            lyrics_of: schema.add_text_field("lyrics_of", STRING | STORED),
+            start_time: schema.add_u64_field("start_time", STORED),
+        };
+        let schema = schema.build();
+
+        let directory = MmapDirectory::open(path).map_err(TantivyError::from)?;
+        let index = Index::open_or_create(directory, schema)?;
+        index.tokenizers().register(TOKENIZER, TextAnalyzer::from(WhitespaceTokenizer::default()));
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::OnCommitWithDelay).try_into()?;
+        let writer = index.writer(WRITER_MEMORY)?;
+
+        Ok(Self {
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
+            fields,
+            uncommitted: Arc::new(AtomicBool::new(false)),
+            rebuilding: Arc::new(tokio::sync::RwLock::new(())),
+        })
+    }
+
+    fn writer(&self) -> Res<MutexGuard<'_, IndexWriter>> {
+        self.writer.lock().map_err(|_| AstralError::Unknown(anyhow::anyhow!("Search index writer failed during a previous write")))
+    }
+
+    /// Replaces entries with the provided IDs with new entries, and lyrics of the tracks with the provided IDs with new lyrics.
+    /// IDs without a new entry or lyrics are removed. Changes become visible once they are committed.
+    pub fn replace(&self, ids: &[Uuid], entries: &[SearchEntry], lyrics: &[LyricsEntry]) -> Res<()> {
+        let writer = self.writer()?;
+        for id in ids.iter().chain(entries.iter().map(|it| &it.id)) {
+            writer.delete_term(Term::from_field_text(self.fields.id, &id.to_string()));
+            writer.delete_term(Term::from_field_text(self.fields.lyrics_of, &id.to_string()));
+        }
+        for entry in entries {
+            writer.add_document(self.to_document(entry))?;
+        }
+        for entry in lyrics {
+            writer.delete_term(Term::from_field_text(self.fields.lyrics_of, &entry.track_id.to_string()));
+            for line in self.to_lyrics_documents(entry) {
+                writer.add_document(line)?;
+            }
+        }
+        self.uncommitted.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Commits changes made since the last commit and makes them visible to searches. Does nothing if there are none.
+    pub fn commit(&self) -> Res<()> {
+        let mut writer = self.writer()?;
+        if !self.uncommitted.load(Ordering::Acquire) {
+            return Ok(())
+        }
+        // changes are only staged while holding the writer, so none can be missed between the commit and the reset
+        writer.commit()?;
+        self.uncommitted.store(false, Ordering::Release);
+        drop(writer);
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Removes all entries and inserts the provided ones instead, committing right away
+    pub fn replace_all(&self, entries: &[SearchEntry], lyrics: &[LyricsEntry]) -> Res<()> {
+        let mut writer = self.writer()?;
+        writer.delete_all_documents()?;
+        for entry in entries {
+            writer.add_document(self.to_document(entry))?;
+        }
+        for entry in lyrics {
+            for line in self.to_lyrics_documents(entry) {
+                writer.add_document(line)?;
+            }
+        }
+        writer.commit()?;
+        self.uncommitted.store(false, Ordering::Release);
+        drop(writer);
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Query matching every query token in indexed words, either exactly, as a prefix or with typos
+    fn token_clauses(&self, tokens: &[String]) -> Vec<(Occur, Box<dyn Query>)> {
+        tokens.iter().map(|token| {
+            let mut alternatives: Vec<(Occur, Box<dyn Query>)> = vec![
+                (Occur::Should, Box::new(BoostQuery::new(Box::new(TermQuery::new(Term::from_field_text(self.fields.words, token), IndexRecordOption::WithFreqs)), 2.0))),
+                (Occur::Should, Box::new(self.prefix_query(token))),
+            ];
+            let typos = allowed_typos(token.chars().count()) as u8;
+            if typos > 0 {
+                alternatives.push((Occur::Should, Box::new(BoostQuery::new(Box::new(FuzzyTermQuery::new(Term::from_field_text(self.fields.words, token), typos, true)), 0.5))));
+                alternatives.push((Occur::Should, Box::new(BoostQuery::new(Box::new(FuzzyTermQuery::new_prefix(Term::from_field_text(self.fields.words, token), typos, true)), 0.3))));
+            }
+            (Occur::Must, Box::new(BooleanQuery::new(alternatives)) as Box<dyn Query>)
+        }).collect()
+    }
+
+    /// Query matching words starting with the token. Only prefixes up to [MAX_PREFIX_LEN] are indexed,
+    /// so longer tokens are looked up by their longest indexed prefix.
+    fn prefix_query(&self, token: &str) -> TermQuery {
+        let prefix = token.char_indices().nth(MAX_PREFIX_LEN).map_or(token, |(idx, _)| &token[..idx]);
+        TermQuery::new(Term::from_field_text(self.fields.prefixes, prefix), IndexRecordOption::Basic)
+    }
+
+    fn kind_clause(&self, occur: Occur, kind: &str) -> (Occur, Box<dyn Query>) {
+        (occur, Box::new(TermQuery::new(Term::from_field_text(self.fields.kind, kind), IndexRecordOption::Basic)))
+    }
+
+    /// Searches entries of the kind, or of all kinds if it is not provided. Most relevant entries come first.
+    pub fn search(&self, query: &str, kind: Option<SearchKind>, limit: usize) -> Res<Vec<SearchMatch>> {
+        let tokens = tokenize(query);
+        if tokens.is_empty() || limit == 0 {
+            return Ok(vec![])
+        }
+
+        let mut clauses = self.token_clauses(&tokens);
+        clauses.push(match kind {
+            Some(kind) => self.kind_clause(Occur::Must, kind.as_str()),
+            None => self.kind_clause(Occur::MustNot, LYRICS_KIND),
+        });
+        self.collect_matches(&BooleanQuery::new(clauses), limit, None)
+    }
+
+    /// Searches lyric lines, keeping only the best line of each track. Candidates are found in the index
+    /// and ranked with [match_score], so the best lines contain the whole query. Most relevant lines come first.
+    pub fn search_lyrics(&self, query: &str, limit: usize) -> Res<Vec<LyricsMatch>> {
+        let tokens = tokenize(query);
+        if tokens.is_empty() || limit == 0 {
+            return Ok(vec![])
+        }
+
+        let mut clauses = self.token_clauses(&tokens);
+        clauses.push(self.kind_clause(Occur::Must, LYRICS_KIND));
+        let searcher = self.reader.searcher();
+        let found = searcher.search(&BooleanQuery::new(clauses), &TopDocs::with_limit(limit * LYRICS_CANDIDATES_PER_HIT))?;
+
+        let mut matches = Vec::with_capacity(found.len());
+        for (_, address) in found {
+            let document = searcher.doc::<TantivyDocument>(address)?;
+            let track_id = document.get_first(self.fields.lyrics_of).and_then(|it| it.as_str()).and_then(|it| Uuid::parse_str(it).ok());
+            let line = document.get_first(self.fields.name).and_then(|it| it.as_str()).map(String::from);
+            let start_time_ms = document.get_first(self.fields.start_time).and_then(|it| it.as_u64()).map(|it| it as u32);
+            if let (Some(track_id), Some(line)) = (track_id, line) {
+                let score = match_score(&tokens, &line);
+                if score >= MIN_MATCH_SCORE {
+                    matches.push(LyricsMatch { track_id, line, start_time_ms, score });
+                }
+            }
+        }
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+        let mut seen = HashSet::new();
+        matches.retain(|it| seen.insert(it.track_id));
+        matches.truncate(limit);
+        Ok(matches)
+    }
+
+    /// Suggests entries whose words start with the words of the query. Cheaper than [SearchIndex::search],
+    /// as typos are not tolerated. Explicit entries are skipped if `hide_explicit` is set.
+    /// Once the deadline passes, no more work is started and the suggestions found so far are returned.
+    pub fn suggest(&self, query: &str, hide_explicit: bool, limit: usize, deadline: Instant) -> Res<Vec<SearchMatch>> {
+        let tokens = tokenize(query);
+        if tokens.is_empty() || limit == 0 || Instant::now() >= deadline {
+            return Ok(vec![])
+        }
+
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = tokens.iter()
+            .map(|token| (Occur::Must, Box::new(self.prefix_query(token)) as Box<dyn Query>))
+            .collect();
+        clauses.push(self.kind_clause(Occur::MustNot, LYRICS_KIND));
+        if hide_explicit {
+            clauses.push((Occur::MustNot, Box::new(TermQuery::new(Term::from_field_bool(self.fields.explicit, true), IndexRecordOption::Basic))));
+        }
+        self.collect_matches(&BooleanQuery::new(clauses), limit, Some(deadline))
+    }
+
+    /// Runs the query, boosting popular entries, and reads found entries until the deadline passes
+    fn collect_matches(&self, query: &dyn Query, limit: usize, deadline: Option<Instant>) -> Res<Vec<SearchMatch>> {
+        // popular entries are boosted logarithmically, so they can not outweigh better matches
+        let collector = TopDocs::with_limit(limit).tweak_score(move |segment: &SegmentReader| {
+            // segments without the column are ranked without the boost
+            let column = segment.fast_fields().u64("popularity").ok();
+            move |doc: DocId, score: Score| {
+                let popularity = column.as_ref().and_then(|it| it.first(doc)).unwrap_or(0);
+                score * (1.0 + (popularity as f32).ln_1p() * 0.1)
+            }
+        });
+
+        let searcher = self.reader.searcher();
+        let found = searcher.search(query, &collector)?;
+        let mut matches = Vec::with_capacity(found.len());
+        for (score, address) in found {
+            if deadline.is_some_and(|it| Instant::now() >= it) {
+                break
+            }
+            let document = searcher.doc::<TantivyDocument>(address)?;
+            let id = document.get_first(self.fields.id).and_then(|it| it.as_str()).and_then(|it| Uuid::parse_str(it).ok());
+            let kind = document.get_first(self.fields.kind).and_then(|it| it.as_str()).and_then(SearchKind::parse);
+            let name = document.get_first(self.fields.name).and_then(|it| it.as_str()).map(String::from);
+            if let (Some(id), Some(kind), Some(name)) = (id, kind, name) {
+                matches.push(SearchMatch { id, kind, name, score });
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Adds the text as searchable words along with their prefixes
+    fn add_searchable_text(&self, document: &mut TantivyDocument, text: &str) {
+        let words = tokenize(text);
+        let prefixes = words.iter()
+            .flat_map(|word| {
+                let chars = word.chars().collect::<Vec<_>>();
+                (1..=chars.len().min(MAX_PREFIX_LEN)).map(move |len| chars[..len].iter().collect::<String>())
+            })
+            .collect::<Vec<_>>();
+        document.add_text(self.fields.words, words.join(" "));
+        document.add_text(self.fields.prefixes, prefixes.join(" "));
+    }
+
+    fn to_lyrics_documents(&self, entry: &LyricsEntry) -> Vec<TantivyDocument> {
+        entry.lines.iter()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(start_time_ms, line)| {
+                let mut document = TantivyDocument::default();
+                document.add_text(self.fields.lyrics_of, entry.track_id.to_string());
+                document.add_text(self.fields.kind, LYRICS_KIND);
+                document.add_text(self.fields.name, line);
+                self.add_searchable_text(&mut document, line);
+                if let Some(start_time_ms) = start_time_ms {
+                    document.add_u64(self.fields.start_time, *start_time_ms as u64);
+                }
+                document
+            })
+            .collect()
+    }
+
+    fn to_document(&self, entry: &SearchEntry) -> TantivyDocument {
+        let mut document = TantivyDocument::default();
+        document.add_text(self.fields.id, entry.id.to_string());
+        document.add_text(self.fields.kind, entry.kind.as_str());
+        document.add_text(self.fields.name, &entry.name);
+        self.add_searchable_text(&mut document, &entry.name);
+        document.add_u64(self.fields.popularity, entry.popularity);
+        document.add_bool(self.fields.explicit, entry.explicit);
+        document
+    }
+}
+
+/// Total play count of the tracks and whether any of them is explicit
+async fn track_stats(db: &AstralDatabase, tracks: &[BsonId]) -> Res<(u64, bool)> {
+    let stats = db.tracks_metadata.find(doc! { "track_id": { "$in": tracks } }).await?
+        .filter_map(|each| async { each.ok() })
+        .fold((0u64, false), |(plays, explicit), each| async move { (plays + each.play_count as u64, explicit || each.is_explicit) }).await;
+    Ok(stats)
+}
+
+fn track_entry(track: TrackMetadata) -> SearchEntry {
+    SearchEntry {
+        id: track.track_id.to_uuid_1(),
+        kind: SearchKind::Track,
+        name: track.name,
+        popularity: track.play_count as u64,
+        explicit: track.is_explicit,
+    }
+}
+
+async fn album_entry(db: &AstralDatabase, album: AlbumMetadata) -> Res<SearchEntry> {
+    let (popularity, explicit) = track_stats(db, &album.tracks).await?;
+    Ok(SearchEntry { id: album.album_id.to_uuid_1(), kind: SearchKind::Album, name: album.name, popularity, explicit })
+}
+
+async fn artist_entry(db: &AstralDatabase, artist: ArtistMetadata) -> Res<SearchEntry> {
+    let (popularity, _) = track_stats(db, &artist.tracks).await?;
+    // artists themselves are never hidden, only their explicit tracks and albums
+    Ok(SearchEntry { id: artist.artist_id.to_uuid_1(), kind: SearchKind::Artist, name: artist.name, popularity, explicit: false })
+}
+
+/// Updates search index entries of the provided tracks, albums and artists, and lyrics of the tracks, from the database.
+/// Entries that no longer exist in the database are removed from the index. Changes become visible with the next commit.
+/// Should be called whenever tracks, albums or artists are added, renamed or removed.
+pub async fn reindex_entries(db: &AstralDatabase, search: &SearchIndex, tracks: &[BsonId], albums: &[BsonId], artists: &[BsonId]) -> Res<()> {
+    let _rebuilding = search.rebuilding.read().await;
+    let mut entries = vec![];
+    // explicitness and popularity of albums follow their tracks, so albums of changed tracks are reindexed as well
+    let mut albums = albums.to_vec();
+    let mut found = db.tracks_metadata.find(doc! { "track_id": { "$in": tracks } }).await?;
+    while let Some(track) = found.next().await {
+        let track = track?;
+        for album in &track.albums {
+            if !albums.contains(album) {
+                albums.push(*album);
+            }
+        }
+        entries.push(track_entry(track));
+    }
+    let albums = albums.as_slice();
+    let mut found = db.albums_metadata.find(doc! { "album_id": { "$in": albums } }).await?;
+    while let Some(album) = found.next().await {
+        entries.push(album_entry(db, album?).await?);
+    }
+    let mut found = db.artists_metadata.find(doc! { "artist_id": { "$in": artists } }).await?;
+    while let Some(artist) = found.next().await {
+        entries.push(artist_entry(db, artist?).await?);
+    }
+    let lyrics = db.lyrics.find(doc! { "track_id": { "$in": tracks } }).await?
+        .map_ok(LyricsEntry::from)
+        .try_collect::<Vec<_>>().await?;
+
+    let ids = tracks.iter().chain(albums).chain(artists).map(|it| it.to_uuid_1()).collect::<Vec<_>>();
+    let search = search.clone();
+    tokio::task::spawn_blocking(move || search.replace(&ids, &entries, &lyrics)).await
+        .map_err(|err| AstralError::Unknown(err.into()))?
+}
+
+/// Rebuilds the whole search index from the database, refreshing popularity of all entries
+pub async fn rebuild_search_index(db: &AstralDatabase, search: &SearchIndex) -> Res<usize> {
+    // changes made during the rebuild wait for it, and are then staged on top of the rebuilt index
+    let _rebuilding = search.rebuilding.write().await;
+    let mut entries = vec![];
+    let mut found = db.tracks_metadata.find(doc! { }).await?;
+    while let Some(track) = found.next().await {
+        entries.push(track_entry(track?));
+    }
+    let mut found = db.albums_metadata.find(doc! { }).await?;
+    while let Some(album) = found.next().await {
+        entries.push(album_entry(db, album?).await?);
+    }
+    let mut found = db.artists_metadata.find(doc! { }).await?;
+    while let Some(artist) = found.next().await {
+        entries.push(artist_entry(db, artist?).await?);
+    }
+
+    let lyrics = db.lyrics.find(doc! { }).await?
+        .map_ok(LyricsEntry::from)
+        .try_collect::<Vec<_>>().await?;
+
+    let count = entries.len();
+    let search = search.clone();
+    tokio::task::spawn_blocking(move || search.replace_all(&entries, &lyrics)).await
+        .map_err(|err| AstralError::Unknown(err.into()))??;
+    Ok(count)
+}